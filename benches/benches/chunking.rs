@@ -0,0 +1,38 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// The chunk size limits to compare: the defaults, smaller chunks and larger ones
+const SETTINGS: &[(usize, usize)] = &[
+    (dedup_core::MIN_CHUNK_SIZE, dedup_core::MAX_CHUNK_SIZE),
+    (512, 4096),
+    (8192, 65536),
+];
+
+// Finding the boundaries in each corpus, without hashing the chunks
+fn chunking(c: &mut Criterion) {
+    let corpora = benches::corpora(benches::CORPUS_BYTES);
+    let mut group = c.benchmark_group("chunking");
+    group.throughput(Throughput::Bytes(benches::CORPUS_BYTES as u64));
+    for &(min, max) in SETTINGS {
+        let builder = rabin::chunker::ChunkerBuilder::new(min, max).unwrap();
+        for (name, data) in corpora.iter() {
+            let id = BenchmarkId::new(format!("{}-{}", min, max), name);
+            group.bench_with_input(id, data, |b, data| {
+                b.iter(|| builder.build(black_box(data)).count())
+            });
+        }
+    }
+    // Fixed-size chunks cost nothing to find, so this is the most any chunking could get through
+    for (name, data) in corpora.iter() {
+        group.bench_with_input(BenchmarkId::new("fixed", name), data, |b, data| {
+            b.iter(|| {
+                black_box(data)
+                    .chunks(dedup_core::pipeline::FIXED_CHUNK_SIZE)
+                    .count()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, chunking);
+criterion_main!(benches);
@@ -0,0 +1,73 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// The rolling hash is run on every byte that isn't skipped for the minimum chunk size, so it is the hottest code there is
+fn rolling_hash(c: &mut Criterion) {
+    let data = benches::random(benches::CORPUS_BYTES, 1);
+    let mut group = c.benchmark_group("rolling_hash");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("hash_byte", |b| {
+        b.iter(|| {
+            let mut hasher = rabin::rolling_hash::RollingHash::new();
+            for &byte in data.iter() {
+                hasher.hash_byte(byte);
+            }
+            black_box(hasher.hash())
+        })
+    });
+
+    // hash_bytes skips to the last window for anything longer than two of them, which is how a chunk starts
+    for len in [16, 32, 64, 1856] {
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(
+            BenchmarkId::new("hash_bytes", len),
+            &data[..len],
+            |b, bytes| {
+                let mut hasher = rabin::rolling_hash::RollingHash::new();
+                b.iter(|| {
+                    hasher.reset();
+                    hasher.hash_bytes(black_box(bytes));
+                    black_box(hasher.hash())
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+// Every chunk is hashed once for its id and once more for the SHA2 check, at the sizes chunks usually are
+fn strong_hash(c: &mut Criterion) {
+    let data = benches::random(65536, 2);
+    let mut group = c.benchmark_group("strong_hash");
+    for len in [4096, 16384, 65536] {
+        let chunk = &data[..len];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new("sha3_256", len), chunk, |b, chunk| {
+            b.iter(|| rabin::hash_chunk::<sha3::Sha3_256>(black_box(chunk)))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("sha3_256_truncated_144", len),
+            chunk,
+            |b, chunk| {
+                let mut hasher = rabin::Truncated144::<sha3::Sha3_256>::default();
+                b.iter(|| hasher.hash_chunk(black_box(chunk)))
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("sha256", len), chunk, |b, chunk| {
+            b.iter(|| rabin::hash_chunk_sha256(black_box(chunk)))
+        });
+        group.bench_with_input(BenchmarkId::new("sha512", len), chunk, |b, chunk| {
+            b.iter(|| rabin::hash_chunk::<sha2::Sha512>(black_box(chunk)))
+        });
+        // What the scan does for each chunk's id
+        group.bench_with_input(BenchmarkId::new("hash_key", len), chunk, |b, chunk| {
+            let mut hasher = sha3::Sha3_256::default();
+            b.iter(|| {
+                dedup_core::pipeline::hash_key(&mut hasher, black_box(chunk), dedup_core::KEY_LEN)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, rolling_hash, strong_hash);
+criterion_main!(benches);
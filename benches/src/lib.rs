@@ -0,0 +1,75 @@
+// Synthetic data for the benchmarks in benches/. Every corpus is generated from a seed, so each run measures exactly
+// the same bytes and results can be compared from one change to the next.
+
+// How much data each benchmark chunks or hashes at a time
+pub const CORPUS_BYTES: usize = 4 * 1024 * 1024;
+
+// Words for the text corpus. Text has far less entropy than random data, which changes how often boundaries are found.
+const WORDS: &[&str] = &[
+    "the",
+    "chunk",
+    "boundary",
+    "of",
+    "and",
+    "hash",
+    "rolling",
+    "window",
+    "backup",
+    "a",
+    "file",
+    "to",
+    "data",
+    "duplicate",
+    "in",
+    "is",
+    "deduplication",
+    "polynomial",
+    "with",
+    "unique",
+];
+
+// A xorshift generator: fast, and the same sequence for the same seed everywhere
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// Bytes with no pattern at all, like compressed or encrypted files
+pub fn random(len: usize, seed: u64) -> Vec<u8> {
+    let mut rng = XorShift(seed.max(1));
+    (0..len).map(|_| (rng.next() >> 56) as u8).collect()
+}
+
+// Words picked at random and separated by spaces and the odd newline, like logs or source code
+pub fn text(len: usize, seed: u64) -> Vec<u8> {
+    let mut rng = XorShift(seed.max(1));
+    let mut data = Vec::with_capacity(len + 32);
+    while data.len() < len {
+        let r = rng.next();
+        data.extend_from_slice(WORDS[(r % WORDS.len() as u64) as usize].as_bytes());
+        data.push(if r >> 60 == 0 { b'\n' } else { b' ' });
+    }
+    data.truncate(len);
+    data
+}
+
+// The same byte over and over, like a sparse or preallocated file. No window ever finds a boundary, so every chunk is
+// cut at the largest size.
+pub fn zeros(len: usize) -> Vec<u8> {
+    vec![0; len]
+}
+
+// Every corpus by name, for benchmarks that run over all of them
+pub fn corpora(len: usize) -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("random", random(len, 1)),
+        ("text", text(len, 1)),
+        ("zeros", zeros(len)),
+    ]
+}
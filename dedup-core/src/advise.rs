@@ -0,0 +1,121 @@
+use std::fs;
+use std::ops;
+
+// Tells the kernel how files are about to be read, so it can read ahead of the chunker rather than one page fault or
+// small read at a time. On spinning disks and network filesystems that is the difference between streaming and
+// seeking. The hints are only hints: where they aren't supported, or the kernel refuses them, nothing changes.
+
+// Mapped files are read from start to end once, so pages behind the chunker can be dropped early and the ones ahead of
+// it read in large batches
+pub fn sequential(map: &[u8]) {
+    #[cfg(unix)]
+    madvise(map, 0..map.len(), libc::MADV_SEQUENTIAL);
+    #[cfg(not(unix))]
+    let _ = map;
+}
+
+// Asks for the part of a mapped file the chunker will get to next to be read in the background
+pub fn will_need(map: &[u8], range: ops::Range<usize>) {
+    #[cfg(unix)]
+    madvise(map, range, libc::MADV_WILLNEED);
+    #[cfg(not(unix))]
+    let _ = (map, range);
+}
+
+// The same for a file that is read through a buffer
+pub fn file_sequential(file: &fs::File) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fadvise(file, 0..0, libc::POSIX_FADV_SEQUENTIAL);
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = file;
+}
+
+pub fn file_will_need(file: &fs::File, range: ops::Range<usize>) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fadvise(file, range, libc::POSIX_FADV_WILLNEED);
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = (file, range);
+}
+
+#[cfg(unix)]
+fn madvise(map: &[u8], range: ops::Range<usize>, advice: libc::c_int) {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let range = page_range(map.as_ptr() as usize, map.len(), range, page_size);
+    if range.is_empty() {
+        return;
+    }
+    // Safety: the range is inside the pages of the map, and advice doesn't change what they hold
+    let result = unsafe { libc::madvise(range.start as *mut libc::c_void, range.len(), advice) };
+    if result != 0 {
+        tracing::trace!(error = %std::io::Error::last_os_error(), "madvise failed");
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn fadvise(file: &fs::File, range: ops::Range<usize>, advice: libc::c_int) {
+    use std::os::unix::io::AsRawFd;
+    let result = unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            range.start as libc::off_t,
+            range.len() as libc::off_t,
+            advice,
+        )
+    };
+    if result != 0 {
+        tracing::trace!(error = %std::io::Error::from_raw_os_error(result), "posix_fadvise failed");
+    }
+}
+
+// The addresses of the whole pages that cover 'range' of a map that starts at 'base' and is 'len' bytes long. madvise
+// only takes page-aligned addresses; a map always starts on a page, so rounding down never leaves it.
+fn page_range(
+    base: usize,
+    len: usize,
+    range: ops::Range<usize>,
+    page_size: usize,
+) -> ops::Range<usize> {
+    let end = range.end.min(len);
+    if range.start >= end {
+        return 0..0;
+    }
+    let start = (base + range.start) / page_size * page_size;
+    start..base + end
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_page_range() {
+        assert_eq!(
+            crate::advise::page_range(8192, 10000, 5000..9000, 4096),
+            12288..17192
+        );
+        assert_eq!(
+            crate::advise::page_range(8192, 10000, 4096..20000, 4096),
+            12288..18192
+        );
+        assert_eq!(
+            crate::advise::page_range(8192, 10000, 10000..20000, 4096),
+            0..0
+        );
+
+        // Advising a real map, or a file, doesn't change what is read from it
+        let dir = std::env::temp_dir().join(format!("test_chunks_advise_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(dir.join("a"), &data).unwrap();
+        let file = fs::File::open(dir.join("a")).unwrap();
+        let map = unsafe { memmap::Mmap::map(&file).unwrap() };
+        crate::advise::sequential(&map);
+        crate::advise::will_need(&map, 5000..60000);
+        crate::advise::file_sequential(&file);
+        crate::advise::file_will_need(&file, 5000..60000);
+        assert_eq!(&map[..], &data[..]);
+
+        drop(map);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
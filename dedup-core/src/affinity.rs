@@ -0,0 +1,104 @@
+use std::io;
+
+// Where the pipeline's threads may run. On a large machine shared with other work, pinning the hashing threads to a
+// set of cores (ideally on one NUMA node, near the memory and the disk controller) keeps the scan from spreading over
+// every core and competing with everything else on them.
+
+// Parses a list of CPU numbers and ranges the way taskset and /sys/devices/system/cpu do (i.e. "0-3,8,10-11"). The
+// numbers come back in the order given, without repeats.
+pub fn parse_cpus(list: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = vec![];
+    for part in list.split(',').map(str::trim) {
+        let number = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("'{}' isn't a CPU number or a range of them", part))
+        };
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (number(first)?, number(last)?),
+            None => (number(part)?, number(part)?),
+        };
+        if last < first {
+            return Err(format!("the range '{}' is backwards", part));
+        }
+        for cpu in first..=last {
+            if !cpus.contains(&cpu) {
+                cpus.push(cpu);
+            }
+        }
+    }
+    Ok(cpus)
+}
+
+// Keeps the calling thread on the given CPUs from now on. Only Linux lets a thread be pinned.
+pub fn pin(cpus: &[usize]) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        // Safety: a cpu_set_t is a plain bitmask, and all zeros is the empty set
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("CPU {} is past the largest this system can pin to", cpu),
+                ));
+            }
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        // A pid of 0 is the calling thread
+        let result =
+            unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = cpus;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "threads can only be pinned to CPUs on Linux",
+        ))
+    }
+}
+
+// The CPUs the calling thread may run on
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn current() -> io::Result<Vec<usize>> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let result =
+        unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_cpus() {
+        assert_eq!(
+            crate::affinity::parse_cpus("0-3,8, 10-11"),
+            Ok(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(crate::affinity::parse_cpus("5,2-3,3"), Ok(vec![5, 2, 3]));
+        assert!(crate::affinity::parse_cpus("3-1").is_err());
+        assert!(crate::affinity::parse_cpus("one").is_err());
+        assert!(crate::affinity::parse_cpus("").is_err());
+
+        // Pin a thread of its own to one of the CPUs it could already run on, so the test doesn't depend on the machine
+        #[cfg(target_os = "linux")]
+        std::thread::spawn(|| {
+            let allowed = crate::affinity::current().unwrap();
+            let cpu = *allowed.last().unwrap();
+            crate::affinity::pin(&[cpu]).unwrap();
+            assert_eq!(crate::affinity::current().unwrap(), vec![cpu]);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+use std::io;
+use std::path;
+
+use serde_derive::Serialize;
+
+// One of the output directories being aggregated, and how the unique chunks grew when it was added to the ones before
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct RunSummary {
+    pub dir: String,
+    // When the directory was last merged, in milliseconds since the Unix epoch, if its history says
+    pub merged: Option<u64>,
+    // The unique chunks of this run on its own
+    pub unique_chunks: u64,
+    pub unique_bytes: u64,
+    // The unique chunks none of the runs before it had
+    pub new_chunks: u64,
+    pub new_bytes: u64,
+    // The unique chunks of this run and every one before it together
+    pub total_unique_chunks: u64,
+    pub total_unique_bytes: u64,
+}
+
+// What the merged files of several output directories have in common
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Aggregate {
+    pub runs: Vec<RunSummary>,
+    // The bytes of the unique chunks each pair of runs both have, with each run's own unique bytes on the diagonal
+    pub overlap: Vec<Vec<u64>>,
+    // Chunks with the same id in several runs that aren't really the same. They count as different chunks.
+    pub collisions: u64,
+}
+
+// Walks every merged file in key order at once, so no more than one entry from each is in memory
+pub fn aggregate(dirs: &[&path::Path]) -> io::Result<Aggregate> {
+    let mut readers = vec![];
+    for dir in dirs.iter() {
+        readers.push(
+            crate::run::RunReader::open(&dir.join(crate::merge::MERGED_FILE_NAME))
+                .map_err(|e| in_dir(dir, e))?,
+        );
+    }
+    if let Some(first) = readers.first() {
+        if let Some(other) = readers.iter().find(|r| r.params() != first.params()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the scans were made with different settings ({} versus {}) and can't be aggregated",
+                    first.params(),
+                    other.params()
+                ),
+            ));
+        }
+    }
+
+    let mut aggregate = Aggregate {
+        runs: dirs
+            .iter()
+            .map(|dir| RunSummary {
+                dir: dir.to_string_lossy().into_owned(),
+                ..Default::default()
+            })
+            .collect(),
+        overlap: vec![vec![0; dirs.len()]; dirs.len()],
+        collisions: 0,
+    };
+    let mut entries = vec![];
+    for (reader, dir) in readers.iter_mut().zip(dirs.iter()) {
+        entries.push(reader.next_entry().map_err(|e| in_dir(dir, e))?);
+    }
+    while let Some(key) = entries.iter().flatten().map(|e| e.key).min() {
+        // The runs with this id, split into the ones that have each chunk that got it
+        let mut chunks: Vec<(crate::run::Entry, Vec<usize>)> = vec![];
+        for (run, entry) in entries.iter_mut().enumerate() {
+            let e = match entry {
+                Some(e) if e.key == key => *e,
+                _ => continue,
+            };
+            match chunks.iter_mut().find(|(chunk, _)| chunk.same_chunk(&e)) {
+                Some((_, runs)) => runs.push(run),
+                None => chunks.push((e, vec![run])),
+            }
+            *entry = readers[run]
+                .next_entry()
+                .map_err(|e| in_dir(dirs[run], e))?;
+        }
+        aggregate.collisions += chunks.len() as u64 - 1;
+
+        for (chunk, runs) in chunks.iter() {
+            let size = chunk.size as u64;
+            for &i in runs.iter() {
+                for &j in runs.iter() {
+                    aggregate.overlap[i][j] += size;
+                }
+                aggregate.runs[i].unique_chunks += 1;
+                aggregate.runs[i].unique_bytes += size;
+            }
+            // Runs are in order, so the first to have the chunk is the one it was new in
+            aggregate.runs[runs[0]].new_chunks += 1;
+            aggregate.runs[runs[0]].new_bytes += size;
+        }
+    }
+
+    let (mut chunks, mut bytes) = (0, 0);
+    for summary in aggregate.runs.iter_mut() {
+        chunks += summary.new_chunks;
+        bytes += summary.new_bytes;
+        summary.total_unique_chunks = chunks;
+        summary.total_unique_bytes = bytes;
+    }
+    Ok(aggregate)
+}
+
+// Says which output directory an error came from, keeping its kind for the caller
+fn in_dir(dir: &path::Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{:?}: {}", dir, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_aggregate() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_aggregate_{}", std::process::id()));
+        let dirs: Vec<std::path::PathBuf> = (0..3).map(|i| dir.join(i.to_string())).collect();
+
+        let entry = |key: u8, size: u32| crate::run::Entry {
+            key: crate::run::key_from(&[key; crate::KEY_LEN], crate::KEY_LEN),
+            size,
+            check: key as u32,
+            count: 1,
+            ..Default::default()
+        };
+        let write_merged = |dir: &std::path::Path, entries: &[crate::run::Entry]| {
+            fs::create_dir_all(dir).unwrap();
+            let mut out = fs::File::create(dir.join(crate::merge::MERGED_FILE_NAME)).unwrap();
+            let params = crate::run::Params {
+                key_len: crate::KEY_LEN,
+                ..Default::default()
+            };
+            crate::run::write_header(&mut out, &params).unwrap();
+            for e in entries.iter() {
+                crate::run::write_entry(&mut out, e, crate::KEY_LEN).unwrap();
+            }
+            out.flush().unwrap();
+        };
+
+        // Chunk 1 is in every run, 2 is dropped by the last, and the last has different data under id 3
+        write_merged(&dirs[0], &[entry(1, 100), entry(2, 200)]);
+        write_merged(&dirs[1], &[entry(1, 100), entry(2, 200), entry(3, 300)]);
+        let mut changed = entry(3, 350);
+        changed.check = 0;
+        write_merged(&dirs[2], &[entry(1, 100), changed, entry(4, 400)]);
+
+        let paths: Vec<&std::path::Path> = dirs.iter().map(|d| d.as_path()).collect();
+        let aggregate = crate::aggregate::aggregate(&paths).unwrap();
+        assert_eq!(
+            aggregate.overlap,
+            vec![
+                vec![300, 300, 100],
+                vec![300, 600, 100],
+                vec![100, 100, 850]
+            ]
+        );
+        assert_eq!(aggregate.collisions, 1);
+        let growth: Vec<(u64, u64, u64)> = aggregate
+            .runs
+            .iter()
+            .map(|r| (r.new_chunks, r.new_bytes, r.total_unique_bytes))
+            .collect();
+        assert_eq!(growth, vec![(2, 300, 300), (1, 300, 600), (2, 750, 1350)]);
+        assert_eq!(aggregate.runs[2].unique_chunks, 3);
+        assert_eq!(aggregate.runs[2].total_unique_chunks, 5);
+
+        // Runs chunked differently don't share chunks in any meaningful way
+        let mut out = fs::File::create(dirs[2].join(crate::merge::MERGED_FILE_NAME)).unwrap();
+        let params = crate::run::Params {
+            key_len: crate::KEY_LEN,
+            chunking: crate::run::CHUNKING_FIXED + 1,
+            ..Default::default()
+        };
+        crate::run::write_header(&mut out, &params).unwrap();
+        drop(out);
+        let e = crate::aggregate::aggregate(&paths).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+        // A directory whose merged file can't be read is named in the error
+        fs::write(dirs[1].join(crate::merge::MERGED_FILE_NAME), b"garbage").unwrap();
+        let e = crate::aggregate::aggregate(&paths).unwrap_err();
+        assert!(e.to_string().contains(&format!("{:?}", dirs[1])));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
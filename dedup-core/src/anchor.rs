@@ -0,0 +1,385 @@
+use std::fs;
+use std::io::Read;
+use std::ops;
+use std::path;
+
+// Some files are a series of records of their own, and the same record turns up in many of them: the same message in
+// several mailboxes, or the same row in every night's database dump. The chunker only cuts where the content says to,
+// so the same record gets cut differently depending on what comes before it, and its chunks don't match. Anchors are
+// places a kind of file is always cut as well, at the edges of its records, so that records start chunks of their own
+// wherever they are.
+
+// Enough of the start of a file to tell what kind it is
+const HEAD_LEN: usize = 512;
+
+// The line that starts each message in an mbox file
+const MBOX_FROM: &[u8] = b"From ";
+
+// How SQL dumps start, after any blank lines: mysqldump, MariaDB's, pg_dump and sqlite3's .dump
+const SQL_DUMP_STARTS: &[&[u8]] = &[
+    b"-- MySQL dump",
+    b"-- MariaDB dump",
+    b"--\n-- PostgreSQL database dump",
+    b"-- PostgreSQL database dump",
+    b"PRAGMA foreign_keys=OFF;\nBEGIN TRANSACTION;",
+];
+
+// Enough of a line of SQL to tell whether it starts a table, or is an INSERT
+const SQL_HEAD_LEN: usize = 7;
+const SQL_TABLE_STARTS: &[&[u8]] = &[b"CREATE ", b"COPY "];
+
+// Rows are anchored about this often
+const SQL_ANCHOR_BYTES: u64 = 8192;
+
+// FNV-1a, which is plenty to tell rows apart
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// The kinds of files anchors can be found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    // A mailbox in one file, with every message starting at a "From " line. Messages are cut at the blank line before
+    // it and just after it, so each one is chunked just as it would be as a file of its own (i.e. in a Maildir).
+    Mbox,
+    // A database dump, with a row on each line (i.e. pg_dump's COPY data, or an INSERT for each row) or many rows to
+    // an INSERT (mysqldump). Rows are picked to cut after by their contents, and every table starts a chunk.
+    Sql,
+}
+
+impl Kind {
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "mbox" => Some(Kind::Mbox),
+            "sql" => Some(Kind::Sql),
+            _ => None,
+        }
+    }
+
+    // Whether a file that starts with these bytes is of this kind
+    pub fn detect(self, head: &[u8]) -> bool {
+        match self {
+            Kind::Mbox => head.starts_with(MBOX_FROM),
+            Kind::Sql => {
+                let start = head.iter().position(|&b| b != b'\n').unwrap_or(head.len());
+                SQL_DUMP_STARTS.iter().any(|s| head[start..].starts_with(s))
+            }
+        }
+    }
+
+    // How many bytes past an anchor have to be seen before it is found
+    fn lag(self) -> u64 {
+        match self {
+            // The blank line before a "From " line, which is found once the whole of "From " has been
+            Kind::Mbox => MBOX_FROM.len() as u64 + 1,
+            // The start of a table, which is found once enough of its first line has been
+            Kind::Sql => SQL_HEAD_LEN as u64,
+        }
+    }
+}
+
+// Which of the kinds the file at the path is, by its first few bytes. Files that can't be read are none of them.
+pub fn detect(kinds: &[Kind], path: &path::Path) -> Option<Kind> {
+    if kinds.is_empty() {
+        return None;
+    }
+    detect_in(kinds, fs::File::open(path).ok()?)
+}
+
+// The same for data that is read some other way than straight from a file (i.e. decompressed)
+pub fn detect_in(kinds: &[Kind], data: impl Read) -> Option<Kind> {
+    if kinds.is_empty() {
+        return None;
+    }
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    data.take(HEAD_LEN as u64).read_to_end(&mut head).ok()?;
+    kinds.iter().copied().find(|kind| kind.detect(&head))
+}
+
+// Finds the anchors in a file that is read from start to end a piece at a time
+pub struct Finder {
+    kind: Kind,
+    // Where the next byte fed is in the file
+    offset: u64,
+    lines: Lines,
+}
+
+enum Lines {
+    Mbox(Mbox),
+    Sql(Sql),
+}
+
+impl Finder {
+    // Starts looking at 'offset' in the file, which is taken to be the start of a line
+    pub fn new(kind: Kind, offset: u64) -> Finder {
+        Finder {
+            kind,
+            offset,
+            lines: match kind {
+                Kind::Mbox => Lines::Mbox(Mbox {
+                    line_start: offset,
+                    blank_before: false,
+                    matched: Some(0),
+                    from_line: false,
+                }),
+                Kind::Sql => Lines::Sql(Sql::new(offset)),
+            },
+        }
+    }
+
+    // Looks through the next bytes of the file and adds the offset of every anchor found to 'anchors', in order. An
+    // anchor may not be found until a few bytes after it have been fed; all of the ones up to known() have been.
+    pub fn feed(&mut self, data: &[u8], anchors: &mut Vec<u64>) {
+        for &b in data {
+            match &mut self.lines {
+                Lines::Mbox(mbox) => mbox.byte(self.offset, b, anchors),
+                Lines::Sql(sql) => sql.byte(self.offset, b, anchors),
+            }
+            self.offset += 1;
+        }
+    }
+
+    // Every anchor up to this offset in the file has been found
+    pub fn known(&self) -> u64 {
+        self.offset.saturating_sub(self.kind.lag())
+    }
+}
+
+struct Mbox {
+    line_start: u64,
+    // Whether the line before this one was empty
+    blank_before: bool,
+    // How much of "From " the line has started with, while it still might be a "From " line
+    matched: Option<usize>,
+    from_line: bool,
+}
+
+impl Mbox {
+    fn byte(&mut self, offset: u64, b: u8, anchors: &mut Vec<u64>) {
+        if let Some(matched) = self.matched {
+            if b != MBOX_FROM[matched] {
+                self.matched = None;
+            } else if matched + 1 < MBOX_FROM.len() {
+                self.matched = Some(matched + 1);
+            } else {
+                self.matched = None;
+                self.from_line = true;
+                // The previous message ends before the blank line, if there is one. The first line of the file is
+                // already a cut.
+                let end = self.line_start - self.blank_before as u64;
+                if end > 0 {
+                    anchors.push(end);
+                }
+            }
+        }
+        if b == b'\n' {
+            if self.from_line {
+                anchors.push(offset + 1);
+                self.from_line = false;
+            }
+            self.blank_before = offset == self.line_start;
+            self.line_start = offset + 1;
+            self.matched = Some(0);
+        }
+    }
+}
+
+struct Sql {
+    line_start: u64,
+    // The first few bytes of the line, until there are enough to tell what kind of line it is
+    head: [u8; SQL_HEAD_LEN],
+    head_len: usize,
+    // Whether the line is an INSERT, whose rows may all be on the one line
+    insert: bool,
+    // The quote the INSERT is inside, if any, and whether the byte before was a backslash inside it
+    quote: Option<u8>,
+    escaped: bool,
+    // The last two bytes of the INSERT outside quotes
+    last: [u8; 2],
+    // The row so far
+    row_len: u64,
+    row_hash: u64,
+}
+
+impl Sql {
+    fn new(line_start: u64) -> Sql {
+        Sql {
+            line_start,
+            head: [0; SQL_HEAD_LEN],
+            head_len: 0,
+            insert: false,
+            quote: None,
+            escaped: false,
+            last: [0; 2],
+            row_len: 0,
+            row_hash: FNV_OFFSET,
+        }
+    }
+
+    fn byte(&mut self, offset: u64, b: u8, anchors: &mut Vec<u64>) {
+        if self.head_len < SQL_HEAD_LEN && b != b'\n' {
+            self.head[self.head_len] = b;
+            self.head_len += 1;
+            if self.head_len == SQL_HEAD_LEN {
+                self.insert = self.head.starts_with(b"INSERT ");
+                // Each table starts a chunk of its own. The row before may well have ended with a cut already.
+                if SQL_TABLE_STARTS.iter().any(|s| self.head.starts_with(s))
+                    && self.line_start > 0
+                    && anchors.last() != Some(&self.line_start)
+                {
+                    anchors.push(self.line_start);
+                }
+            }
+        }
+        if self.insert {
+            match self.quote {
+                Some(_) if self.escaped => self.escaped = false,
+                Some(_) if b == b'\\' => self.escaped = true,
+                Some(quote) if b == quote => self.quote = None,
+                Some(_) => {}
+                None => {
+                    // A row of an INSERT with several, which starts after the "),"
+                    if b == b'(' && self.last == *b")," {
+                        self.row_end(offset, anchors);
+                    }
+                    if b == b'\'' || b == b'"' || b == b'`' {
+                        self.quote = Some(b);
+                    }
+                    self.last = [self.last[1], b];
+                }
+            }
+        }
+        self.row_hash = (self.row_hash ^ b as u64).wrapping_mul(FNV_PRIME);
+        self.row_len += 1;
+        if b == b'\n' {
+            self.row_end(offset + 1, anchors);
+            *self = Sql::new(offset + 1);
+        }
+    }
+
+    // Rows are cut after at random, by their hash, but more often the longer they are: every SQL_ANCHOR_BYTES or so
+    // on average. The same row is always cut after or not, so the cuts stay put when rows around them change.
+    fn row_end(&mut self, offset: u64, anchors: &mut Vec<u64>) {
+        let mut hash = self.row_hash;
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        if hash % SQL_ANCHOR_BYTES < self.row_len {
+            anchors.push(offset);
+        }
+        self.row_len = 0;
+        self.row_hash = FNV_OFFSET;
+    }
+}
+
+// Every anchor in the data, which starts 'offset' bytes into its file
+pub fn find(kind: Kind, data: &[u8], offset: u64) -> Vec<u64> {
+    let mut anchors = vec![];
+    Finder::new(kind, offset).feed(data, &mut anchors);
+    anchors
+}
+
+// Splits the range at each of the anchors inside it, which are in order
+pub fn split(range: ops::Range<usize>, anchors: &[usize]) -> Vec<ops::Range<usize>> {
+    let mut pieces = vec![];
+    let mut start = range.start;
+    for &anchor in anchors
+        .iter()
+        .filter(|&&a| a > range.start && a < range.end)
+    {
+        if anchor > start {
+            pieces.push(start..anchor);
+            start = anchor;
+        }
+    }
+    if start < range.end {
+        pieces.push(start..range.end);
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_mbox_anchors() {
+        let first = "From a@example.com Mon Jan  1 00:00:00 2024\n";
+        let message = "Subject: hi\n\n>From the top\nbye\n";
+        let second = "\nFrom b@example.com Tue Jan  2 00:00:00 2024\n";
+        let mbox = format!("{}{}{}{}", first, message, second, message);
+        assert!(crate::anchor::Kind::Mbox.detect(mbox.as_bytes()));
+        assert!(!crate::anchor::Kind::Mbox.detect(message.as_bytes()));
+
+        // Each message is a piece of its own, without the lines around it
+        let anchors = crate::anchor::find(crate::anchor::Kind::Mbox, mbox.as_bytes(), 0);
+        let anchors: Vec<usize> = anchors.iter().map(|&a| a as usize).collect();
+        let pieces: Vec<&str> = crate::anchor::split(0..mbox.len(), &anchors)
+            .into_iter()
+            .map(|piece| &mbox[piece])
+            .collect();
+        assert_eq!(pieces, vec![first, message, second, message]);
+
+        // Fed a byte at a time, the same anchors are found, and never later than known() says
+        let mut finder = crate::anchor::Finder::new(crate::anchor::Kind::Mbox, 0);
+        let mut found = vec![];
+        for b in mbox.as_bytes().chunks(1) {
+            finder.feed(b, &mut found);
+            let known = finder.known() as usize;
+            assert!(anchors
+                .iter()
+                .filter(|&&a| a <= known)
+                .all(|&a| found.contains(&(a as u64))));
+        }
+        assert_eq!(found.len(), anchors.len());
+    }
+
+    #[test]
+    fn test_sql_anchors() {
+        assert!(crate::anchor::Kind::Sql.detect(b"-- MySQL dump 10.13  Distrib 8.0.36\n"));
+        assert!(crate::anchor::Kind::Sql.detect(b"\n--\n-- PostgreSQL database dump\n--\n"));
+        assert!(!crate::anchor::Kind::Sql.detect(b"-- a comment in some other language\n"));
+
+        // Rows longer than SQL_ANCHOR_BYTES are always cut after, but never inside quotes
+        let long = "x".repeat(9000);
+        let line = format!(
+            "INSERT INTO `t` VALUES (1,'{}),(\\'),(',\"),(\"),(2,'{}');\n",
+            long, long
+        );
+        let dump = format!("CREATE TABLE `t`;\n{}CREATE TABLE `u`;\n", line);
+        let second_row = dump.find("(2,").unwrap() as u64;
+        let table = dump.rfind("CREATE").unwrap() as u64;
+        let anchors = crate::anchor::find(crate::anchor::Kind::Sql, dump.as_bytes(), 0);
+        assert_eq!(anchors, vec![second_row, table]);
+
+        // A night's changes to a few rows only change the chunks around them, and the rows that move to another INSERT
+        let row = |i: u32| format!("({},'row {} of the table',{})", i, i, i * 31 % 1000);
+        let dump = |rows: &[u32]| {
+            let mut dump = "-- MySQL dump 10.13\n\nCREATE TABLE `t`;\n".to_string();
+            for lines in rows.chunks(5000) {
+                let rows: Vec<String> = lines.iter().map(|&i| row(i)).collect();
+                dump.push_str(&format!("INSERT INTO `t` VALUES {};\n", rows.join(",")));
+            }
+            dump
+        };
+        let pieces = |dump: &str| {
+            let anchors: Vec<usize> =
+                crate::anchor::find(crate::anchor::Kind::Sql, dump.as_bytes(), 0)
+                    .iter()
+                    .map(|&a| a as usize)
+                    .collect();
+            crate::anchor::split(0..dump.len(), &anchors)
+                .into_iter()
+                .map(|piece| dump[piece].to_string())
+                .collect::<std::collections::HashSet<_>>()
+        };
+        let monday: Vec<u32> = (0..20_000).collect();
+        let tuesday: Vec<u32> = (0..20_000)
+            .filter(|&i| i != 7000)
+            .chain(20_000..20_001)
+            .collect();
+        let (monday, tuesday) = (dump(&monday), dump(&tuesday));
+        let shared: usize = pieces(&monday)
+            .intersection(&pieces(&tuesday))
+            .map(|p| p.len())
+            .sum();
+        assert!(shared > monday.len() * 9 / 10);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections;
+
+// An index of the unique chunks that stays within a fixed amount of memory without writing anything to disk. While the
+// chunks fit it is exact. Once they don't, it keeps a random sample of them instead: a chunk is kept only when the low
+// 'level' bits of its fingerprint are zero, and each time the index fills up the level goes up by one and the chunks
+// that no longer qualify are dropped, halving the sample. Every copy of a chunk has the same fingerprint, so a chunk is
+// either always in the sample or never, and the sampled unique chunks scaled up by the sampling rate are an unbiased
+// estimate of all of them.
+
+// What each entry costs: its fingerprint and size, plus the spare room a hash table keeps
+pub const ENTRY_BYTES: u64 = 36;
+
+pub struct Index {
+    capacity: usize,
+    level: u32,
+    // The size of each unique chunk kept, by fingerprint
+    entries: collections::HashMap<u64, u32>,
+    total_chunks: u64,
+    total_bytes: u64,
+}
+
+// What the index knows, or estimates, about the chunks inserted so far
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub total_chunks: u64,
+    pub total_bytes: u64,
+    pub unique_chunks: f64,
+    pub unique_bytes: f64,
+    // The standard errors of the estimates, in chunks and bytes. Both are 0 while the index is exact.
+    pub unique_chunks_error: f64,
+    pub unique_bytes_error: f64,
+    // The fraction of the unique chunks that were kept: 1 while the index is exact, and halving each time it fills up
+    pub sample_rate: f64,
+}
+
+impl Estimate {
+    pub fn exact(&self) -> bool {
+        self.sample_rate >= 1.0
+    }
+}
+
+impl Index {
+    // An index that uses at most about 'memory' bytes
+    pub fn new(memory: u64) -> Index {
+        Index {
+            capacity: (memory / ENTRY_BYTES).max(1) as usize,
+            level: 0,
+            entries: collections::HashMap::new(),
+            total_chunks: 0,
+            total_bytes: 0,
+        }
+    }
+
+    // Adds a chunk by its id, which is already a cryptographic hash, so its first 64 bits make a good fingerprint
+    pub fn insert(&mut self, key: &[u8], size: u32) {
+        self.total_chunks += 1;
+        self.total_bytes += size as u64;
+
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&key[0..8]);
+        let fingerprint = u64::from_be_bytes(prefix);
+        if !self.sampled(fingerprint) {
+            return;
+        }
+        self.entries.entry(fingerprint).or_insert(size);
+        while self.entries.len() > self.capacity {
+            self.level += 1;
+            tracing::debug!(
+                level = self.level,
+                "the approximate index is full, so it only keeps half the chunks it did"
+            );
+            let mask = self.mask();
+            self.entries
+                .retain(|fingerprint, _| fingerprint & mask == 0);
+        }
+    }
+
+    pub fn estimate(&self) -> Estimate {
+        // Each unique chunk is in the sample with probability p. Scaling the sample by 1/p estimates the sum over every
+        // unique chunk, and the variance of that is (1 - p) / p^2 times the sum of the squares over the sample.
+        let p = 1.0 / (1u64 << self.level) as f64;
+        let (mut bytes, mut squares) = (0.0, 0.0);
+        for &size in self.entries.values() {
+            bytes += size as f64;
+            squares += size as f64 * size as f64;
+        }
+        let chunks = self.entries.len() as f64;
+        Estimate {
+            total_chunks: self.total_chunks,
+            total_bytes: self.total_bytes,
+            unique_chunks: (chunks / p).min(self.total_chunks as f64),
+            unique_bytes: (bytes / p).min(self.total_bytes as f64),
+            unique_chunks_error: ((1.0 - p) * chunks).sqrt() / p,
+            unique_bytes_error: ((1.0 - p) * squares).sqrt() / p,
+            sample_rate: p,
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        (1u64 << self.level) - 1
+    }
+
+    fn sampled(&self, fingerprint: u64) -> bool {
+        fingerprint & self.mask() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_index() {
+        // Chunks of 1000 to 1999 bytes, each inserted three times, with fingerprints from the hll test's generator
+        let mut keys = vec![];
+        for i in 0..100_000u64 {
+            let mut x = i.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            keys.push(((x ^ (x >> 31)).to_be_bytes(), 1000 + (i % 1000) as u32));
+        }
+        let unique_bytes: u64 = keys.iter().map(|&(_, size)| size as u64).sum();
+
+        // With room for every chunk the numbers are exact
+        let mut exact = crate::approximate::Index::new(100_000 * crate::approximate::ENTRY_BYTES);
+        let mut small = crate::approximate::Index::new(5_000 * crate::approximate::ENTRY_BYTES);
+        for _ in 0..3 {
+            for (key, size) in keys.iter() {
+                exact.insert(key, *size);
+                small.insert(key, *size);
+            }
+        }
+        let estimate = exact.estimate();
+        assert!(estimate.exact());
+        assert_eq!(estimate.total_bytes, unique_bytes * 3);
+        assert_eq!(estimate.unique_chunks, 100_000.0);
+        assert_eq!(estimate.unique_bytes, unique_bytes as f64);
+        assert_eq!(estimate.unique_bytes_error, 0.0);
+
+        // A twentieth of the room is enough for a sample that is off by a few percent at most
+        let estimate = small.estimate();
+        assert!(!estimate.exact());
+        assert!(estimate.sample_rate <= 1.0 / 32.0);
+        assert_eq!(estimate.total_bytes, unique_bytes * 3);
+        let error = (estimate.unique_bytes - unique_bytes as f64).abs();
+        assert!(estimate.unique_bytes_error > 0.0);
+        assert!(
+            error < 4.0 * estimate.unique_bytes_error,
+            "error was {} against a standard error of {}",
+            error,
+            estimate.unique_bytes_error
+        );
+        assert!(estimate.unique_bytes_error < unique_bytes as f64 * 0.05);
+    }
+}
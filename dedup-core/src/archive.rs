@@ -0,0 +1,406 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path;
+
+// An archive is chunked as one file, so the same file in two archives only dedups if it happens to be cut the same way
+// in both, and never if the archives compress their members. Members can instead be read out of the archive and each
+// chunked as a file of its own, named by the archive's path followed by the member's path in it (as though the
+// archive were a directory).
+
+// Enough of the start of a file to find a tar header's magic
+const HEAD_LEN: usize = 512;
+
+// Where the magic is in a tar header, which is the same for POSIX and GNU tar
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+
+// Zip files start with a member's local header, or with the end of the central directory if they have no members
+const ZIP_MAGICS: &[&[u8]] = &[b"PK\x03\x04", b"PK\x05\x06"];
+const SEVEN_ZIP_MAGIC: &[u8] = b"7z\xbc\xaf\x27\x1c";
+
+// Members are read into memory whole, so an archive with a member larger than this is chunked as a file instead
+pub const MAX_MEMBER_SIZE: u64 = 256 * 1024 * 1024;
+
+// The kinds of archive whose members can be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // A tar file, which may be compressed as a whole (.tar.gz and the like)
+    Tar(Option<crate::decompress::Codec>),
+    Zip,
+    SevenZip,
+}
+
+// A regular file in an archive, read into memory
+pub struct Member {
+    // The archive's path, then the member's path in the archive
+    pub path: String,
+    pub metadata: crate::catalog::Metadata,
+    pub data: Vec<u8>,
+}
+
+// The kind of archive the file at the path is, by its first few bytes (after decompressing it, for a tar file that is
+// compressed). Files that can't be read aren't archives.
+pub fn detect(path: &path::Path) -> Option<Format> {
+    let head = read_head(fs::File::open(path).ok()?)?;
+    if is_tar(&head) {
+        return Some(Format::Tar(None));
+    }
+    if ZIP_MAGICS.iter().any(|magic| head.starts_with(magic)) {
+        return Some(Format::Zip);
+    }
+    if head.starts_with(SEVEN_ZIP_MAGIC) {
+        return Some(Format::SevenZip);
+    }
+    let codec =
+        crate::compressed::detect_head(&head).and_then(crate::decompress::Codec::from_format)?;
+    let inside = read_head(crate::decompress::Reader::open(path, codec).ok()?)?;
+    if is_tar(&inside) {
+        return Some(Format::Tar(Some(codec)));
+    }
+    None
+}
+
+fn read_head(data: impl Read) -> Option<Vec<u8>> {
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    data.take(HEAD_LEN as u64).read_to_end(&mut head).ok()?;
+    Some(head)
+}
+
+fn is_tar(head: &[u8]) -> bool {
+    head.len() >= TAR_MAGIC_OFFSET + TAR_MAGIC.len()
+        && &head[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()] == TAR_MAGIC
+}
+
+// Reads each regular file in the archive, in the order the archive has them, and hands it to 'each'. Directories,
+// links and the like are left out. Archives inside the archive aren't opened; they are members like any other.
+// Members that were read before an error were handed over already; the rest of the archive is lost.
+//
+// Members larger than MAX_MEMBER_SIZE are an error. Zip and 7z archives list their members' sizes up front, and an
+// uncompressed tar file's headers can be read without reading the members, so for those the error comes before
+// anything is handed over. A compressed tar file has to be read to find its headers, so there the error comes when the
+// member is reached. The size in a member's header is never trusted for more than that: a member whose data is
+// shorter than its header says (in an archive that was cut short, say) is an error too.
+//
+// Tar members keep their own times, mode and owner. Zip and 7z members take the archive's, apart from the length:
+// zip times are in whatever the local time was where the archive was made, and 7z only stores Windows attributes.
+pub fn members(path: &path::Path, format: Format, each: &mut dyn FnMut(Member)) -> io::Result<()> {
+    let archive_metadata = crate::catalog::Metadata::read(path, crate::walk::Found::File);
+    let member_path = |name: &str| {
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        format!("{}/{}", path.display(), name)
+    };
+    match format {
+        Format::Tar(codec) => {
+            if codec.is_none() {
+                let mut archive = tar::Archive::new(fs::File::open(path)?);
+                for entry in archive.entries_with_seek()? {
+                    let entry = entry?;
+                    if entry.header().entry_type().is_file() {
+                        check_size(&entry.path()?.to_string_lossy(), entry.size())?;
+                    }
+                }
+            }
+            let data: Box<dyn Read> = match codec {
+                Some(codec) => Box::new(crate::decompress::Reader::open(path, codec)?),
+                None => Box::new(io::BufReader::new(fs::File::open(path)?)),
+            };
+            let mut archive = tar::Archive::new(data);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let header = entry.header();
+                let metadata = crate::catalog::Metadata {
+                    len: entry.size(),
+                    mtime: header.mtime().unwrap_or(0) as i64,
+                    mtime_nanos: 0,
+                    mode: header.mode().unwrap_or(0),
+                    uid: header.uid().unwrap_or(0) as u32,
+                    gid: header.gid().unwrap_or(0) as u32,
+                };
+                let name = entry.path()?.to_string_lossy().into_owned();
+                let data = read_member(&name, entry.size(), &mut entry)?;
+                each(Member {
+                    path: member_path(&name),
+                    metadata,
+                    data,
+                });
+            }
+        }
+        Format::Zip => {
+            let mut archive =
+                zip::ZipArchive::new(fs::File::open(path)?).map_err(io::Error::other)?;
+            for i in 0..archive.len() {
+                let member = archive.by_index_raw(i).map_err(io::Error::other)?;
+                if member.is_file() {
+                    check_size(member.name(), member.size())?;
+                }
+            }
+            for i in 0..archive.len() {
+                let mut member = archive.by_index(i).map_err(io::Error::other)?;
+                if !member.is_file() {
+                    continue;
+                }
+                let metadata = crate::catalog::Metadata {
+                    len: member.size(),
+                    ..archive_metadata
+                };
+                let name = member.name().to_string();
+                let data = read_member(&name, member.size(), &mut member)?;
+                each(Member {
+                    path: member_path(&name),
+                    metadata,
+                    data,
+                });
+            }
+        }
+        Format::SevenZip => {
+            let mut archive = sevenz_rust::SevenZReader::open(path, sevenz_rust::Password::empty())
+                .map_err(io::Error::other)?;
+            for entry in archive.archive().files.iter() {
+                if !entry.is_directory() && !entry.is_anti_item {
+                    check_size(entry.name(), entry.size())?;
+                }
+            }
+            archive
+                .for_each_entries(|entry, reader| {
+                    if entry.is_directory() || entry.is_anti_item {
+                        return Ok(true);
+                    }
+                    let metadata = crate::catalog::Metadata {
+                        len: entry.size(),
+                        ..archive_metadata
+                    };
+                    let data = read_member(entry.name(), entry.size(), reader)?;
+                    each(Member {
+                        path: member_path(entry.name()),
+                        metadata,
+                        data,
+                    });
+                    Ok(true)
+                })
+                .map_err(io::Error::other)?;
+        }
+    }
+    Ok(())
+}
+
+// Imports every regular file in a tar stream into a .dedup archive (see dedup_archive), chunking each member's contents
+// separately so that the chunk boundaries don't depend on the tar headers around them. Each member gets its own
+// manifest under its path in the tar. Directories, links and other special entries have no contents and are skipped.
+// Members are chunked as they are read, so unlike with members they can be larger than memory.
+pub fn import_tar<W: Write + Seek, R: Read>(
+    writer: &mut crate::dedup_archive::ArchiveWriter<W>,
+    tar: R,
+) -> io::Result<()> {
+    for entry in tar::Archive::new(tar).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+        writer.add_reader(&path, &mut entry)?;
+    }
+    Ok(())
+}
+
+fn check_size(name: &str, size: u64) -> io::Result<()> {
+    if size > MAX_MEMBER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is {} bytes, more than the {} a member can be read into memory with",
+                name, size, MAX_MEMBER_SIZE
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Reads a member whose header gives its size. Memory is only set aside as the data arrives, and never for more than
+// MAX_MEMBER_SIZE, whatever the header says. Reading on past the end lets a zip member check its CRC.
+fn read_member(name: &str, size: u64, member: &mut dyn Read) -> io::Result<Vec<u8>> {
+    check_size(name, size)?;
+    let mut data = vec![];
+    member.take(size + 1).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "{} is {} bytes, but its header says {}",
+                name,
+                data.len(),
+                size
+            ),
+        ));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::io::Write;
+
+    #[test]
+    fn test_members() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_archive_{}", std::process::id()));
+        fs::create_dir_all(dir.join("src").join("sub")).unwrap();
+        fs::write(dir.join("src").join("a"), b"first member").unwrap();
+        fs::write(dir.join("src").join("sub").join("b"), b"second member").unwrap();
+
+        let mut tar = tar::Builder::new(vec![]);
+        tar.append_dir_all(".", dir.join("src")).unwrap();
+        let tar = tar.into_inner().unwrap();
+        fs::write(dir.join("plain.tar"), &tar).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(&tar).unwrap();
+        fs::write(dir.join("packed.tgz"), gzip.finish().unwrap()).unwrap();
+
+        let mut zip = zip::ZipWriter::new(fs::File::create(dir.join("packed.zip")).unwrap());
+        let deflated =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        zip.add_directory("sub/", deflated).unwrap();
+        zip.start_file("a", deflated).unwrap();
+        zip.write_all(b"first member").unwrap();
+        zip.start_file("sub/b", deflated).unwrap();
+        zip.write_all(b"second member").unwrap();
+        zip.finish().unwrap();
+
+        sevenz_rust::compress_to_path(dir.join("src"), dir.join("packed.7z")).unwrap();
+
+        for (name, format) in [
+            ("plain.tar", crate::archive::Format::Tar(None)),
+            (
+                "packed.tgz",
+                crate::archive::Format::Tar(Some(crate::decompress::Codec::Gzip)),
+            ),
+            ("packed.zip", crate::archive::Format::Zip),
+            ("packed.7z", crate::archive::Format::SevenZip),
+        ] {
+            let path = dir.join(name);
+            assert_eq!(crate::archive::detect(&path), Some(format));
+            let mut members = vec![];
+            crate::archive::members(&path, format, &mut |member| {
+                assert_eq!(member.metadata.len, member.data.len() as u64);
+                members.push((member.path, member.data));
+            })
+            .unwrap();
+            members.sort();
+            assert_eq!(
+                members,
+                vec![
+                    (format!("{}/a", path.display()), b"first member".to_vec()),
+                    (
+                        format!("{}/sub/b", path.display()),
+                        b"second member".to_vec()
+                    ),
+                ],
+                "{}",
+                name
+            );
+        }
+        assert_eq!(crate::archive::detect(&dir.join("src").join("a")), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_members() {
+        let dir = std::env::temp_dir().join(format!(
+            "test_chunks_archive_damaged_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        // A header that claims far more than there is, or than could be read into memory
+        let mut header = tar::Header::new_gnu();
+        header.set_path("huge").unwrap();
+        header.set_size(u64::MAX / 2);
+        header.set_cksum();
+        let mut huge = header.as_bytes().to_vec();
+        huge.extend_from_slice(&[0; 2048]);
+        fs::write(dir.join("huge.tar"), &huge).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(&huge).unwrap();
+        fs::write(dir.join("huge.tgz"), gzip.finish().unwrap()).unwrap();
+
+        // Archives cut short in the middle of their first member
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1000);
+        header.set_cksum();
+        tar.append_data(&mut header, "a", &[7u8; 1000][..]).unwrap();
+        fs::write(dir.join("cut.tar"), &tar.into_inner().unwrap()[..1000]).unwrap();
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        zip.start_file("a", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(&[7u8; 1000]).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+        fs::write(dir.join("cut.zip"), &zip[..zip.len() / 2]).unwrap();
+
+        for (name, format) in [
+            ("huge.tar", crate::archive::Format::Tar(None)),
+            (
+                "huge.tgz",
+                crate::archive::Format::Tar(Some(crate::decompress::Codec::Gzip)),
+            ),
+            ("cut.tar", crate::archive::Format::Tar(None)),
+            ("cut.zip", crate::archive::Format::Zip),
+        ] {
+            let mut members = 0;
+            let result = crate::archive::members(&dir.join(name), format, &mut |_| members += 1);
+            assert!(result.is_err(), "{}", name);
+            assert_eq!(members, 0, "{}", name);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_tar() {
+        let mut random = crate::stability::Random::new(1595);
+        let contents: Vec<u8> = (0..64 * 1024).map(|_| random.next_u64() as u8).collect();
+
+        // A tarball with the same file stored twice, plus a directory
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "dir/", io::empty())
+            .unwrap();
+        for path in ["dir/one", "dir/two"].iter() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            builder
+                .append_data(&mut header, path, &contents[..])
+                .unwrap();
+        }
+        let tarball = builder.into_inner().unwrap();
+
+        let mut writer =
+            crate::dedup_archive::ArchiveWriter::new(io::Cursor::new(vec![]), 1856, 11300).unwrap();
+        crate::archive::import_tar(&mut writer, &tarball[..]).unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let mut reader =
+            crate::dedup_archive::ArchiveReader::open(io::Cursor::new(archive)).unwrap();
+        let paths: Vec<&str> = reader.list().iter().map(|m| m.path.as_str()).collect();
+        assert_eq!(paths, vec!["dir/one", "dir/two"]);
+        assert_eq!(reader.stats().stored_bytes, contents.len() as u64);
+
+        let mut extracted = vec![];
+        reader.extract("dir/two", &mut extracted).unwrap();
+        assert_eq!(extracted, contents);
+
+        // A stream that isn't a tar file
+        let mut writer =
+            crate::dedup_archive::ArchiveWriter::new(io::Cursor::new(vec![]), 1856, 11300).unwrap();
+        assert!(crate::archive::import_tar(&mut writer, &[1u8; 1000][..]).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use std::io;
+use std::path;
+
+// The merged index of an earlier scan, standing in for a backup repository that already holds its chunks. A scan
+// against a baseline only stores the chunks the baseline doesn't have, the way an incremental backup would. Nothing in
+// the baseline's output directory is changed.
+pub struct Baseline {
+    index: crate::run::RunIndex,
+    // The files the baseline's merged entries refer to
+    files: Vec<crate::files::FileRecord>,
+}
+
+impl Baseline {
+    // Opens the merged file and file report of the output directory. Returns a NotFound error if it hasn't been merged.
+    pub fn open(out_dir: &path::Path) -> io::Result<Baseline> {
+        let index = crate::run::RunIndex::open(&out_dir.join(crate::merge::MERGED_FILE_NAME))?;
+        let files = crate::files::read_records(&out_dir.join(crate::files::FILE_REPORT_NAME))?;
+        Ok(Baseline { index, files })
+    }
+
+    pub fn key_len(&self) -> usize {
+        self.index.key_len()
+    }
+
+    // The baseline's entry for the key, if it has one. It may still be a different chunk that got the same id.
+    pub fn find(&self, key: &crate::run::Key) -> Option<crate::run::Entry> {
+        self.index.find(key)
+    }
+
+    // Where the baseline found one of its chunks
+    pub fn location(&self, entry: &crate::run::Entry) -> crate::collisions::Location {
+        crate::collisions::Location {
+            path: self
+                .files
+                .get(entry.file as usize)
+                .map_or_else(String::new, |f| f.path.clone()),
+            offset: entry.offset,
+            size: entry.size,
+            check: entry.check,
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections;
+use std::fs;
+use std::io;
+use std::path;
+
+use serde_derive::{Deserialize, Serialize};
+
+// What has to stay the same for a file to be taken as unchanged. A file that is rewritten in place keeps its inode
+// but nearly always gets a new modification time, and one that is replaced by renaming another over it gets a new
+// inode even when the time and length are the same.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub len: u64,
+    pub mtime: i64,
+    pub mtime_nanos: u32,
+    // The device and inode (or volume and file index on Windows), or zeros where there is no such thing
+    pub file_id: (u64, u64),
+}
+
+impl Identity {
+    // Reads the identity of whatever is scanned at the path, which is the link itself for a recorded symbolic link.
+    // Files that can't be looked at have none.
+    pub fn read(path: &path::Path, found: crate::walk::Found) -> Option<Identity> {
+        let metadata = match found {
+            crate::walk::Found::File => fs::metadata(path),
+            crate::walk::Found::Symlink => fs::symlink_metadata(path),
+        }
+        .ok()?;
+        let crate::catalog::Metadata {
+            len,
+            mtime,
+            mtime_nanos,
+            ..
+        } = crate::catalog::Metadata::from(&metadata);
+        Some(Identity {
+            len,
+            mtime,
+            mtime_nanos,
+            file_id: crate::walk::file_id(path, &metadata).unwrap_or((0, 0)),
+        })
+    }
+}
+
+// The chunk settings the cache was made with are kept under this name. A cache made with other settings would have
+// other chunks, so it is no use.
+const SETTINGS_KEY: &str = "cache";
+
+// What the cache keeps for each file, with every one of the file's chunks in order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedFile {
+    identity: Identity,
+    chunks: Vec<crate::pipeline::HashedChunk>,
+}
+
+// The chunks of the files earlier scans read, so a file that hasn't changed since doesn't have to be read and hashed
+// again. The cache is in the output directory's history database, so scans appended to the directory all share it.
+pub struct Cache {
+    db: redb::Database,
+    settings: String,
+    // Whether the cache was made with the same settings as this scan. If not, nothing in it is used, and it is emptied
+    // before this scan's files are added.
+    usable: bool,
+}
+
+impl Cache {
+    pub fn open(out_dir: &path::Path, settings: String) -> io::Result<Cache> {
+        let db = crate::history::open(out_dir)?;
+        let transaction = db.begin_read().map_err(io::Error::other)?;
+        let usable = match transaction.open_table(crate::history::SETTINGS) {
+            Ok(table) => match table.get(SETTINGS_KEY).map_err(io::Error::other)? {
+                Some(made_with) => made_with.value() == settings,
+                None => false,
+            },
+            Err(redb::TableError::TableDoesNotExist(_)) => false,
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        drop(transaction);
+        if !usable {
+            tracing::info!("the cache is empty or was made with other chunk settings");
+        }
+        Ok(Cache {
+            db,
+            settings,
+            usable,
+        })
+    }
+
+    // The chunks of the file, if it hasn't changed since they were cached. A cache that can't be read just means the
+    // file is read again.
+    pub fn get(
+        &self,
+        path: &str,
+        identity: &Identity,
+    ) -> Option<Vec<crate::pipeline::HashedChunk>> {
+        if !self.usable {
+            return None;
+        }
+        let transaction = self.db.begin_read().ok()?;
+        let table = transaction.open_table(crate::history::FILES).ok()?;
+        let value = table.get(path).ok()??;
+        let cached: CachedFile = bincode::deserialize(value.value()).ok()?;
+        if cached.identity == *identity {
+            Some(cached.chunks)
+        } else {
+            None
+        }
+    }
+
+    // Starts adding the files a scan finds to the cache. Nothing is changed until the recorder is finished, and then
+    // files that were in the cache under the walked directories, but weren't found this time, are taken out.
+    pub fn record(&self, walked: Vec<path::PathBuf>) -> io::Result<Recorder> {
+        let transaction = self.db.begin_write().map_err(io::Error::other)?;
+        if !self.usable {
+            match transaction.delete_table(crate::history::FILES) {
+                Ok(_) => {}
+                Err(e) => return Err(io::Error::other(e)),
+            }
+            let mut table = transaction
+                .open_table(crate::history::SETTINGS)
+                .map_err(io::Error::other)?;
+            table
+                .insert(SETTINGS_KEY, self.settings.as_str())
+                .map_err(io::Error::other)?;
+        }
+        Ok(Recorder {
+            transaction,
+            walked,
+            seen: collections::HashSet::new(),
+            pending: collections::HashMap::new(),
+        })
+    }
+}
+
+// A file whose chunks are still coming in
+struct Pending {
+    path: String,
+    identity: Identity,
+    chunks: Vec<(u64, crate::pipeline::HashedChunk)>,
+    // How many chunks the file has, once it is Finished
+    total: Option<u64>,
+}
+
+// Adds each file to the cache as soon as all of its chunks have been seen
+pub struct Recorder {
+    transaction: redb::WriteTransaction,
+    walked: Vec<path::PathBuf>,
+    // The files that were added
+    seen: collections::HashSet<String>,
+    pending: collections::HashMap<u32, Pending>,
+}
+
+impl Recorder {
+    // Watches the pipeline's events go by. Files found without an identity aren't cached.
+    pub fn observe(&mut self, event: &crate::pipeline::Event) -> io::Result<()> {
+        let file = match event {
+            crate::pipeline::Event::Found {
+                file,
+                path,
+                identity,
+                ..
+            } => {
+                if let Some(identity) = *identity {
+                    self.pending.insert(
+                        *file,
+                        Pending {
+                            path: path.clone(),
+                            identity,
+                            chunks: vec![],
+                            total: None,
+                        },
+                    );
+                }
+                return Ok(());
+            }
+            crate::pipeline::Event::Hashed(batch) => {
+                if let Some(pending) = self.pending.get_mut(&batch.file) {
+                    pending
+                        .chunks
+                        .extend((batch.first_chunk..).zip(batch.chunks.iter().cloned()));
+                }
+                batch.file
+            }
+            crate::pipeline::Event::Finished { file, chunks } => {
+                if let Some(pending) = self.pending.get_mut(file) {
+                    pending.total = Some(*chunks);
+                }
+                *file
+            }
+        };
+
+        match self.pending.get(&file) {
+            Some(pending) if pending.total == Some(pending.chunks.len() as u64) => {}
+            _ => return Ok(()),
+        }
+        let mut pending = self.pending.remove(&file).unwrap();
+        // A file with data but no chunks couldn't be read, and it mustn't look empty the next time
+        if pending.chunks.is_empty() && pending.identity.len > 0 {
+            return Ok(());
+        }
+        pending.chunks.sort_by_key(|&(number, _)| number);
+        let cached = CachedFile {
+            identity: pending.identity,
+            chunks: pending.chunks.into_iter().map(|(_, c)| c).collect(),
+        };
+        let value = bincode::serialize(&cached).map_err(io::Error::other)?;
+        let mut table = self
+            .transaction
+            .open_table(crate::history::FILES)
+            .map_err(io::Error::other)?;
+        table
+            .insert(pending.path.as_str(), value.as_slice())
+            .map_err(io::Error::other)?;
+        drop(table);
+        self.seen.insert(pending.path);
+        Ok(())
+    }
+
+    // Commits the files to the cache. Files that never finished (because only some of their chunks were scanned) are
+    // left out.
+    pub fn finish(self) -> io::Result<()> {
+        let Recorder {
+            transaction,
+            walked,
+            seen,
+            ..
+        } = self;
+        let mut table = transaction
+            .open_table(crate::history::FILES)
+            .map_err(io::Error::other)?;
+        table
+            .retain(|path, _| {
+                seen.contains(path)
+                    || !walked
+                        .iter()
+                        .any(|dir| path::Path::new(path).starts_with(dir))
+            })
+            .map_err(io::Error::other)?;
+        drop(table);
+        transaction.commit().map_err(io::Error::other)
+    }
+}
+
+// The options that change the chunks a file is made of. A cache can only be used by scans made the same way.
+pub fn settings(options: &crate::pipeline::Options) -> String {
+    // Only noted when it is set, so caches made before disk images could be read still match
+    let mut settings = format!(
+        "{:?} key_len={} compress_every={:?}",
+        options.chunking, options.key_len, options.compress_every
+    );
+    if options.disk_images {
+        settings.push_str(" disk_images");
+    }
+    if !options.anchors.is_empty() {
+        settings.push_str(&format!(" anchors={:?}", options.anchors));
+    }
+    if options.compressed == crate::compressed::Policy::Decompress {
+        settings.push_str(" decompress");
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_cache() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_cache_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let settings = |key_len| format!("key_len={}", key_len);
+        let identity = crate::cache::Identity {
+            len: 300,
+            mtime: 1,
+            mtime_nanos: 2,
+            file_id: (3, 4),
+        };
+        let chunk = |k: u8, size: u32, offset: u64| crate::pipeline::HashedChunk {
+            key: crate::run::key_from(&[k; crate::KEY_LEN], crate::KEY_LEN),
+            check: k as u32,
+            size,
+            offset,
+            compressed: 0,
+            high_entropy: false,
+        };
+        let found = |file: u32, path: &str| crate::pipeline::Event::Found {
+            file,
+            root: 0,
+            path: path.to_string(),
+            metadata: crate::catalog::Metadata::default(),
+            identity: Some(identity),
+            decompressed: None,
+        };
+        let batch = |file, first_chunk, chunks| {
+            crate::pipeline::Event::Hashed(crate::pipeline::HashedBatch {
+                file,
+                chunks,
+                first_chunk,
+                sub_blocks: vec![],
+            })
+        };
+
+        let record = |settings, events: &[crate::pipeline::Event]| {
+            let cache = crate::cache::Cache::open(&dir, settings).unwrap();
+            let mut recorder = cache.record(vec!["/w".into()]).unwrap();
+            for event in events.iter() {
+                recorder.observe(event).unwrap();
+            }
+            recorder.finish().unwrap();
+        };
+
+        // Batches arrive out of order, and the file is Finished before the last of them
+        record(
+            settings(32),
+            &[
+                found(0, "/w/a"),
+                batch(0, 1, vec![chunk(2, 200, 100)]),
+                crate::pipeline::Event::Finished { file: 0, chunks: 2 },
+                batch(0, 0, vec![chunk(1, 100, 0)]),
+                found(1, "/w/gone"),
+                batch(1, 0, vec![chunk(4, 300, 0)]),
+                crate::pipeline::Event::Finished { file: 1, chunks: 1 },
+                found(2, "/x/other"),
+                batch(2, 0, vec![chunk(3, 300, 0)]),
+                crate::pipeline::Event::Finished { file: 2, chunks: 1 },
+                // Couldn't be read
+                found(3, "/w/b"),
+                crate::pipeline::Event::Finished { file: 3, chunks: 0 },
+                // Never finished
+                found(4, "/w/c"),
+                batch(4, 0, vec![chunk(3, 300, 0)]),
+            ],
+        );
+        let cache = crate::cache::Cache::open(&dir, settings(32)).unwrap();
+        assert_eq!(
+            cache.get("/w/a", &identity).unwrap(),
+            vec![chunk(1, 100, 0), chunk(2, 200, 100)]
+        );
+        let touched = crate::cache::Identity {
+            mtime_nanos: 5,
+            ..identity
+        };
+        assert_eq!(cache.get("/w/a", &touched), None);
+        assert_eq!(cache.get("/w/b", &identity), None);
+        assert_eq!(cache.get("/w/c", &identity), None);
+        drop(cache);
+
+        // Files under the walked directory that weren't found again are gone, and the rest are kept
+        let cache = crate::cache::Cache::open(&dir, settings(32)).unwrap();
+        assert!(cache.get("/w/gone", &identity).is_some());
+        drop(cache);
+        record(
+            settings(32),
+            &[
+                found(0, "/w/a"),
+                batch(0, 0, vec![chunk(1, 300, 0)]),
+                crate::pipeline::Event::Finished { file: 0, chunks: 1 },
+            ],
+        );
+        let cache = crate::cache::Cache::open(&dir, settings(32)).unwrap();
+        assert_eq!(
+            cache.get("/w/a", &identity).unwrap(),
+            vec![chunk(1, 300, 0)]
+        );
+        assert_eq!(cache.get("/w/gone", &identity), None);
+        assert_eq!(
+            cache.get("/x/other", &identity).unwrap(),
+            vec![chunk(3, 300, 0)]
+        );
+
+        // Chunks made another way don't match
+        drop(cache);
+        let cache = crate::cache::Cache::open(&dir, settings(20)).unwrap();
+        assert_eq!(cache.get("/x/other", &identity), None);
+        drop(cache);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
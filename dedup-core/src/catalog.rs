@@ -0,0 +1,152 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+
+use serde_derive::{Deserialize, Serialize};
+
+// Written by the merge, with one JSON line for each file in the file report
+pub const CATALOG_NAME: &str = "catalog.jsonl";
+
+// What the filesystem says about a file when it was scanned. Together with the file's chunks this is what a backup
+// needs to restore it, and a later scan can compare it to tell whether the file has changed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    // The length of the file, holes included
+    pub len: u64,
+    // When the file was last modified, in seconds and nanoseconds since the Unix epoch
+    pub mtime: i64,
+    pub mtime_nanos: u32,
+    // The Unix file type and permission bits. Windows only has a read-only flag, which clears the write bits.
+    pub mode: u32,
+    // The owning user and group on Unix, and 0 elsewhere
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Metadata {
+    // Reads the metadata of whatever was scanned: a symbolic link itself if the link was recorded, and otherwise the
+    // file (which may be the target of a link being followed). Files that vanished have none.
+    pub fn read(path: &path::Path, found: crate::walk::Found) -> Metadata {
+        let metadata = match found {
+            crate::walk::Found::File => fs::metadata(path),
+            crate::walk::Found::Symlink => fs::symlink_metadata(path),
+        };
+        metadata.map_or_else(|_| Metadata::default(), |m| Metadata::from(&m))
+    }
+}
+
+#[cfg(unix)]
+impl From<&fs::Metadata> for Metadata {
+    fn from(metadata: &fs::Metadata) -> Metadata {
+        use std::os::unix::fs::MetadataExt;
+        Metadata {
+            len: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nanos: metadata.mtime_nsec() as u32,
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl From<&fs::Metadata> for Metadata {
+    fn from(metadata: &fs::Metadata) -> Metadata {
+        let (mtime, mtime_nanos) = match metadata
+            .modified()
+            .map(|m| m.duration_since(std::time::UNIX_EPOCH))
+        {
+            Ok(Ok(since)) => (since.as_secs() as i64, since.subsec_nanos()),
+            Ok(Err(before)) => (
+                -(before.duration().as_secs() as i64),
+                before.duration().subsec_nanos(),
+            ),
+            Err(_) => (0, 0),
+        };
+        Metadata {
+            len: metadata.len(),
+            mtime,
+            mtime_nanos,
+            mode: if metadata.permissions().readonly() {
+                0o444
+            } else {
+                0o644
+            },
+            uid: 0,
+            gid: 0,
+        }
+    }
+}
+
+// One line of the catalog. 'file' is the number the merged file's entries use for it.
+#[derive(Debug, Serialize)]
+struct CatalogEntry<'a> {
+    file: usize,
+    path: &'a str,
+    #[serde(flatten)]
+    metadata: &'a Metadata,
+    // How the file's data was turned into what was chunked, if it was (i.e. "gzip" for a file that was decompressed)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transform: Option<crate::decompress::Codec>,
+}
+
+pub fn write_catalog(out: &mut dyn Write, records: &[crate::files::FileRecord]) -> io::Result<()> {
+    for (file, record) in records.iter().enumerate() {
+        let entry = CatalogEntry {
+            file,
+            path: &record.path,
+            metadata: &record.metadata,
+            transform: record.decompressed,
+        };
+        serde_json::to_writer(&mut *out, &entry).map_err(io::Error::other)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_metadata() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_catalog_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"hello").unwrap();
+
+        let metadata = crate::catalog::Metadata::read(&dir.join("a"), crate::walk::Found::File);
+        assert_eq!(metadata.len, 5);
+        assert!(metadata.mtime > 0);
+        let missing =
+            crate::catalog::Metadata::read(&dir.join("missing"), crate::walk::Found::File);
+        assert_eq!(missing, crate::catalog::Metadata::default());
+
+        let record = crate::files::FileRecord {
+            path: "a".to_string(),
+            metadata,
+            ..Default::default()
+        };
+        let mut out = vec![];
+        crate::catalog::write_catalog(&mut out, &[record]).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(line["file"], 0);
+        assert_eq!(line["path"], "a");
+        assert_eq!(line["len"], 5);
+        assert_eq!(line["mtime"], metadata.mtime);
+        assert!(line.get("transform").is_none());
+
+        let record = crate::files::FileRecord {
+            path: "a.gz".to_string(),
+            decompressed: Some(crate::decompress::Codec::Gzip),
+            ..Default::default()
+        };
+        let mut out = vec![];
+        crate::catalog::write_catalog(&mut out, &[record]).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(line["transform"], "gzip");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use std::ops;
+
+use serde_derive::{Deserialize, Serialize};
+
+// How far a scan has got through one file. Chunks are numbered from zero in the order they appear in the file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    // The chunks that are safely in a staged run, as sorted ranges that don't touch each other
+    stored: Vec<ops::Range<u64>>,
+    // How many chunks the file has, once it has been chunked to the end
+    chunks: Option<u64>,
+}
+
+impl Progress {
+    pub fn add(&mut self, range: ops::Range<u64>) {
+        let at = self.stored.partition_point(|r| r.end < range.start);
+        let mut merged = range;
+        while at < self.stored.len() && self.stored[at].start <= merged.end {
+            let r = self.stored.remove(at);
+            merged = merged.start.min(r.start)..merged.end.max(r.end);
+        }
+        self.stored.insert(at, merged);
+    }
+
+    pub fn finish(&mut self, chunks: u64) {
+        self.chunks = Some(chunks);
+    }
+
+    // Whether every chunk in the file is stored, so there is no need to read it again
+    pub fn is_complete(&self) -> bool {
+        match self.chunks {
+            Some(0) => true,
+            Some(chunks) => self.stored.len() == 1 && self.stored[0] == (0..chunks),
+            None => false,
+        }
+    }
+
+    pub fn is_stored(&self, chunk: u64) -> bool {
+        let at = self.stored.partition_point(|r| r.end <= chunk);
+        at < self.stored.len() && self.stored[at].start <= chunk
+    }
+}
+
+// Everything a scan needs to carry on after it was killed. One is written to the transaction's staging area each time
+// a run is staged, and it describes exactly the chunks in the runs staged so far.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    // What was being scanned and how. A scan can only carry on from a checkpoint with the same directories and
+    // settings.
+    pub directories: Vec<String>,
+    pub settings: String,
+    // How many runs had been staged
+    pub runs: usize,
+    // The files found so far, with the size and chunks of what is stored, and how far each one got
+    pub files: Vec<crate::files::FileRecord>,
+    pub progress: Vec<Progress>,
+    pub collisions: u64,
+    pub collided: Vec<crate::collisions::Collision>,
+    // Chunks that weren't stored because the baseline already had them
+    pub baseline_chunks: u64,
+    pub baseline_bytes: u64,
+    // Bytes in chunks that look already compressed or encrypted
+    pub high_entropy_bytes: u64,
+    pub elapsed_ms: u64,
+}
+
+// The options that change what ends up in the runs. Chunks can only be added to runs made the same way.
+pub fn settings(
+    options: &crate::pipeline::Options,
+    baseline: Option<&str>,
+    directory_depth: usize,
+) -> String {
+    format!(
+        "{:?} key_len={} compress_every={:?} baseline={:?} directory_depth={}",
+        options.chunking, options.key_len, options.compress_every, baseline, directory_depth
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checkpoint::Progress;
+
+    #[test]
+    fn test_progress() {
+        let mut progress = Progress::default();
+        assert!(!progress.is_complete());
+        assert!(!progress.is_stored(0));
+
+        // Batches are hashed in parallel, so they are stored in any order
+        progress.add(5..8);
+        progress.add(0..2);
+        progress.add(10..12);
+        assert!(progress.is_stored(0) && progress.is_stored(7) && progress.is_stored(11));
+        assert!(!progress.is_stored(2) && !progress.is_stored(8) && !progress.is_stored(12));
+
+        progress.finish(12);
+        assert!(!progress.is_complete());
+        progress.add(2..5);
+        progress.add(8..10);
+        assert!(progress.is_complete());
+
+        let mut empty = Progress::default();
+        empty.finish(0);
+        assert!(empty.is_complete());
+    }
+}
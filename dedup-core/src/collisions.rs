@@ -0,0 +1,119 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+
+use serde_derive::{Deserialize, Serialize};
+
+// Every collision a scan finds is appended to this file in the output directory, one JSON object per line
+pub const SCAN_COLLISIONS_NAME: &str = "collisions.jsonl";
+
+// The collisions the merge finds between runs. Each merge replaces the file.
+pub const MERGE_COLLISIONS_NAME: &str = "merge_collisions.jsonl";
+
+// Where one of the two colliding chunks was found
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub path: String,
+    pub offset: u64,
+    pub size: u32,
+    pub check: u32,
+}
+
+// A chunk id with both of the chunks that were given it
+pub type Collision = (crate::run::Key, Location, Location);
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    found_by: &'a str,
+    key: String,
+    first: Side,
+    second: Side,
+}
+
+#[derive(Debug, Serialize)]
+struct Side {
+    path: String,
+    offset: u64,
+    size: u32,
+    check: String,
+    sha256: Option<String>,
+}
+
+// Writes one collision as a line of JSON: the id both chunks were given, and for each of them the file, offset, size,
+// SHA2 check and full SHA-256. The SHA-256 is read back from the file, so it is null if the file can no longer be read.
+pub fn write(
+    out: &mut dyn Write,
+    found_by: &str,
+    key: &[u8],
+    first: &Location,
+    second: &Location,
+) -> io::Result<()> {
+    let record = Record {
+        found_by,
+        key: crate::hex_key(key),
+        first: side(first),
+        second: side(second),
+    };
+    serde_json::to_writer(&mut *out, &record)?;
+    writeln!(out)
+}
+
+fn side(location: &Location) -> Side {
+    Side {
+        path: location.path.clone(),
+        offset: location.offset,
+        size: location.size,
+        check: format!("{:08x}", location.check),
+        sha256: chunk_sha256(location)
+            .ok()
+            .map(|hash| crate::hex_key(&hash)),
+    }
+}
+
+fn chunk_sha256(location: &Location) -> io::Result<[u8; 32]> {
+    let mut file = fs::File::open(&location.path)?;
+    file.seek(io::SeekFrom::Start(location.offset))?;
+    let mut chunk = vec![0u8; location.size as usize];
+    file.read_exact(&mut chunk)?;
+    Ok(rabin::hash_chunk_sha256(&chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_write() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_collisions_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"0123456789").unwrap();
+
+        let first = crate::collisions::Location {
+            path: dir.join("a").to_string_lossy().into_owned(),
+            offset: 2,
+            size: 3,
+            check: 0xabcd,
+        };
+        let second = crate::collisions::Location {
+            path: dir.join("missing").to_string_lossy().into_owned(),
+            ..first.clone()
+        };
+        let mut out = vec![];
+        crate::collisions::write(&mut out, "scan", &[1, 2], &first, &second).unwrap();
+
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.ends_with('\n'));
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["key"], "0102");
+        assert_eq!(record["first"]["offset"], 2);
+        assert_eq!(record["first"]["check"], "0000abcd");
+        assert_eq!(
+            record["first"]["sha256"],
+            crate::hex_key(&rabin::hash_chunk_sha256(b"234"))
+        );
+        assert!(record["second"]["sha256"].is_null());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
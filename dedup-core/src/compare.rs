@@ -0,0 +1,127 @@
+use std::io;
+use std::path;
+
+// How the chunks of a later scan line up with an earlier one
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Comparison {
+    // Every chunk the later scan found, duplicates included
+    pub chunks: u64,
+    pub bytes: u64,
+    // What is left of the later scan once it is deduplicated against itself
+    pub unique_chunks: u64,
+    pub unique_bytes: u64,
+    // The unique chunks that the earlier scan already has, so a backup wouldn't have to store them again
+    pub existing_chunks: u64,
+    pub existing_bytes: u64,
+    // Chunks with the same id in both scans that aren't really the same. They count as new.
+    pub collisions: u64,
+}
+
+impl Comparison {
+    pub fn new_chunks(&self) -> u64 {
+        self.unique_chunks - self.existing_chunks
+    }
+
+    pub fn new_bytes(&self) -> u64 {
+        self.unique_bytes - self.existing_bytes
+    }
+}
+
+// Walks both merged files in key order at once, so no more than one entry from each is in memory.
+pub fn compare(base_dir: &path::Path, out_dir: &path::Path) -> io::Result<Comparison> {
+    let mut base = crate::run::RunReader::open(&base_dir.join(crate::merge::MERGED_FILE_NAME))?;
+    let mut later = crate::run::RunReader::open(&out_dir.join(crate::merge::MERGED_FILE_NAME))?;
+    if base.key_len() != later.key_len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the scans used different --key-bits and can't be compared",
+        ));
+    }
+
+    let mut comparison = Comparison::default();
+    let mut base_entry = base.next_entry()?;
+    while let Some(entry) = later.next_entry()? {
+        while base_entry.is_some_and(|b| b.key < entry.key) {
+            base_entry = base.next_entry()?;
+        }
+
+        comparison.chunks += entry.count as u64;
+        comparison.bytes += entry.count as u64 * entry.size as u64;
+        comparison.unique_chunks += 1;
+        comparison.unique_bytes += entry.size as u64;
+        match base_entry {
+            Some(b) if b.same_chunk(&entry) => {
+                comparison.existing_chunks += 1;
+                comparison.existing_bytes += entry.size as u64;
+            }
+            Some(b) if b.key == entry.key => comparison.collisions += 1,
+            _ => {}
+        }
+    }
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_compare() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_compare_{}", std::process::id()));
+        let base_dir = dir.join("base");
+        let later_dir = dir.join("later");
+        fs::create_dir_all(&base_dir).unwrap();
+        fs::create_dir_all(&later_dir).unwrap();
+
+        let entry = |key: u8, size: u32, count: u32| crate::run::Entry {
+            key: crate::run::key_from(&[key; crate::KEY_LEN], crate::KEY_LEN),
+            size,
+            check: key as u32,
+            count,
+            file: 0,
+            offset: 0,
+            compressed: 0,
+            groups: 0,
+        };
+        let write_merged = |dir: &std::path::Path, entries: &[crate::run::Entry]| {
+            let mut out = fs::File::create(dir.join(crate::merge::MERGED_FILE_NAME)).unwrap();
+            let params = crate::run::Params {
+                key_len: crate::KEY_LEN,
+                ..Default::default()
+            };
+            crate::run::write_header(&mut out, &params).unwrap();
+            for e in entries.iter() {
+                crate::run::write_entry(&mut out, e, crate::KEY_LEN).unwrap();
+            }
+            out.flush().unwrap();
+        };
+
+        // The later scan kept chunk 2, dropped 1 and 3 and added 5. Its chunk 4 is different data that got the same id.
+        write_merged(
+            &base_dir,
+            &[
+                entry(1, 100, 1),
+                entry(2, 200, 1),
+                entry(3, 300, 1),
+                entry(4, 400, 1),
+            ],
+        );
+        let mut changed = entry(4, 450, 1);
+        changed.check = 0;
+        write_merged(&later_dir, &[entry(2, 200, 3), changed, entry(5, 500, 1)]);
+
+        let comparison = crate::compare::compare(&base_dir, &later_dir).unwrap();
+        assert_eq!(comparison.chunks, 5);
+        assert_eq!(comparison.bytes, 1550);
+        assert_eq!(comparison.unique_chunks, 3);
+        assert_eq!(comparison.unique_bytes, 1150);
+        assert_eq!(comparison.existing_chunks, 1);
+        assert_eq!(comparison.existing_bytes, 200);
+        assert_eq!(comparison.collisions, 1);
+        assert_eq!(comparison.new_chunks(), 2);
+        assert_eq!(comparison.new_bytes(), 950);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
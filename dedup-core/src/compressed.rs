@@ -0,0 +1,122 @@
+use std::fs;
+use std::io::Read;
+use std::path;
+
+// Compressed files barely dedup against anything: a change near the start of the original reshuffles every byte after
+// it, so two versions of the same file share almost no chunks, and neither do two copies compressed at different
+// levels. Scanning a directory of them takes just as long as scanning the originals and reports next to no
+// duplication, which says more about the compression than about the data. Such files are recognized by their magic
+// numbers, or failing that by looking like random data, and the scan can be told what to do with them.
+
+// Enough of the start of a file to tell whether it is compressed. A sample this size of random data has an entropy of
+// close to 8 bits per byte, and plain data rarely comes near it.
+const HEAD_LEN: usize = 4096;
+
+// Magic numbers of formats that are compressed through and through: general purpose compressors, archives that
+// compress their members, and images, audio and video
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x1f\x8b", "gzip"),
+    (b"\x28\xb5\x2f\xfd", "zstd"),
+    (b"\xfd7zXZ\x00", "xz"),
+    (b"BZh", "bzip2"),
+    (b"\x04\x22\x4d\x18", "lz4"),
+    (b"7z\xbc\xaf\x27\x1c", "7z"),
+    (b"Rar!\x1a\x07", "rar"),
+    (b"PK\x03\x04", "zip"),
+    (b"\xff\xd8\xff", "jpeg"),
+    (b"\x89PNG", "png"),
+    (b"GIF8", "gif"),
+    (b"OggS", "ogg"),
+    (b"fLaC", "flac"),
+    (b"ID3", "mp3"),
+];
+
+// What a scan does with the files that look compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    // They are chunked like any other file, without looking at them first
+    Chunk,
+    // They are passed over, and counted separately so the report says how much was left out
+    Skip,
+    // They are chunked as they are, and counted separately so the report says how much of the data they are
+    Flag,
+    // The ones in a format decompress::Codec knows are chunked decompressed, and the rest are flagged
+    Decompress,
+}
+
+impl Policy {
+    pub fn from_name(name: &str) -> Option<Policy> {
+        match name {
+            "chunk" => Some(Policy::Chunk),
+            "skip" => Some(Policy::Skip),
+            "flag" => Some(Policy::Flag),
+            "decompress" => Some(Policy::Decompress),
+            _ => None,
+        }
+    }
+}
+
+// The compressed format a file is in, by the name in MAGIC, "mp4" for ISO media files (which carry their magic after
+// the box size), or "high entropy" for a file whose start looks random without being a format we know. Encrypted files
+// end up there too, which don't dedup either. Files that can't be read aren't compressed as far as this is concerned;
+// the scan finds out about them when it reads them.
+pub fn detect(path: &path::Path) -> Option<&'static str> {
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    let file = fs::File::open(path).ok()?;
+    file.take(HEAD_LEN as u64).read_to_end(&mut head).ok()?;
+    detect_head(&head)
+}
+
+pub fn detect_head(head: &[u8]) -> Option<&'static str> {
+    if let Some((_, name)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(name);
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return Some("mp4");
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return Some("webp");
+    }
+    // A short sample can't be very random however it was made
+    if head.len() == HEAD_LEN && crate::entropy::is_high_entropy(head) {
+        return Some("high entropy");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_detect_head() {
+        assert_eq!(
+            crate::compressed::detect_head(b"\x1f\x8b\x08\x00rest"),
+            Some("gzip")
+        );
+        assert_eq!(
+            crate::compressed::detect_head(b"\x00\x00\x00\x20ftypisom"),
+            Some("mp4")
+        );
+        assert_eq!(crate::compressed::detect_head(b""), None);
+
+        // Pseudo-random bytes without a magic number
+        let mut x = 1u64;
+        let random: Vec<u8> = (0..4096)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect();
+        assert_eq!(
+            crate::compressed::detect_head(&random),
+            Some("high entropy")
+        );
+        // Too little of it to tell
+        assert_eq!(crate::compressed::detect_head(&random[..100]), None);
+
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(100);
+        assert_eq!(crate::compressed::detect_head(text.as_bytes()), None);
+    }
+}
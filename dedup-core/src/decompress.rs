@@ -0,0 +1,184 @@
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path;
+
+use serde_derive::{Deserialize, Serialize};
+
+// Two compressed copies of the same data share almost no chunks unless they were compressed the same way, from the
+// same start, and even then a change near the start reshuffles everything after it. Files in the formats here can be
+// decompressed as they are read, so the chunker sees the data that went in rather than what came out. Chunk offsets
+// are then offsets in the decompressed data, and the catalog notes which files they are for.
+
+// The formats that can be decompressed, by the names compressed::detect knows them by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+impl Codec {
+    pub fn from_format(format: &str) -> Option<Codec> {
+        match format {
+            "gzip" => Some(Codec::Gzip),
+            "zstd" => Some(Codec::Zstd),
+            "xz" => Some(Codec::Xz),
+            "bzip2" => Some(Codec::Bzip2),
+            _ => None,
+        }
+    }
+
+    // Reads the data inside the file. Files with several streams one after the other (as `cat a.gz b.gz` makes) are
+    // decompressed through to the end, as the command line tools do.
+    fn decoder(self, file: fs::File) -> io::Result<Box<dyn Read + Send>> {
+        let file = io::BufReader::new(file);
+        Ok(match self {
+            Codec::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(file)),
+            Codec::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+            Codec::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(file)),
+            Codec::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(file)),
+        })
+    }
+
+    // The same as decoder, for data that isn't a file of its own (i.e. a member of a tar file)
+    pub fn reader<'a, R: io::BufRead + 'a>(self, input: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Codec::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(input)),
+            Codec::Zstd => Box::new(zstd::Decoder::with_buffer(input)?),
+            Codec::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(input)),
+            Codec::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(input)),
+        })
+    }
+}
+
+// The decompressed data of a file, read from the start. Seeking forwards decompresses what is skipped over, and seeking
+// backwards starts again from the beginning, so it is only cheap when reading straight through.
+pub struct Reader {
+    path: path::PathBuf,
+    codec: Codec,
+    decoder: Box<dyn Read + Send>,
+    position: u64,
+}
+
+impl Reader {
+    pub fn open(path: &path::Path, codec: Codec) -> io::Result<Reader> {
+        Ok(Reader {
+            path: path.to_path_buf(),
+            codec,
+            decoder: codec.decoder(fs::File::open(path)?)?,
+            position: 0,
+        })
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.decoder.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Seek for Reader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            io::SeekFrom::Start(offset) => offset,
+            io::SeekFrom::Current(delta) => {
+                self.position.checked_add_signed(delta).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "seek before the start of the data",
+                    )
+                })?
+            }
+            io::SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the end of compressed data can't be found without reading it",
+                ))
+            }
+        };
+        if target < self.position {
+            *self = Reader::open(&self.path, self.codec)?;
+        }
+        let skip = target - self.position;
+        let skipped = io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+        if skipped < skip {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(self.position)
+    }
+}
+
+// How long the file is once decompressed. None of the formats reliably say so up front, so the whole file is
+// decompressed once to find out, without keeping any of it.
+pub fn decompressed_len(path: &path::Path, codec: Codec) -> io::Result<u64> {
+    io::copy(&mut Reader::open(path, codec)?, &mut io::sink())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Seek, Write};
+
+    #[test]
+    fn test_decompress() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_decompress_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = "a line of a log file that repeats\n".repeat(1000);
+
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(data.as_bytes()).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+        xz.write_all(data.as_bytes()).unwrap();
+        let mut bzip2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+        bzip2.write_all(data.as_bytes()).unwrap();
+        let compressed = [
+            ("gzip", gzip.finish().unwrap()),
+            ("zstd", zstd::encode_all(data.as_bytes(), 3).unwrap()),
+            ("xz", xz.finish().unwrap()),
+            ("bzip2", bzip2.finish().unwrap()),
+        ];
+
+        for (format, bytes) in compressed.iter() {
+            let path = dir.join(format);
+            fs::write(&path, bytes).unwrap();
+            assert_eq!(crate::compressed::detect(&path), Some(*format));
+            let codec = crate::decompress::Codec::from_format(format).unwrap();
+            assert_eq!(
+                crate::decompress::decompressed_len(&path, codec).unwrap(),
+                data.len() as u64
+            );
+
+            let mut reader = crate::decompress::Reader::open(&path, codec).unwrap();
+            let mut all = String::new();
+            reader.read_to_string(&mut all).unwrap();
+            assert_eq!(all, data);
+
+            // Backwards starts again, and forwards skips
+            reader.seek(std::io::SeekFrom::Start(34)).unwrap();
+            let mut line = [0u8; 34];
+            reader.read_exact(&mut line).unwrap();
+            assert_eq!(&line[..], &data.as_bytes()[34..68]);
+            reader.seek(std::io::SeekFrom::Current(34)).unwrap();
+            reader.read_exact(&mut line).unwrap();
+            assert_eq!(&line[..], &data.as_bytes()[102..136]);
+        }
+
+        // Two gzip streams one after the other are read as one
+        let path = dir.join("gzip");
+        let mut twice = fs::read(&path).unwrap();
+        twice.extend_from_slice(&twice.clone());
+        fs::write(&path, &twice).unwrap();
+        assert_eq!(
+            crate::decompress::decompressed_len(&path, crate::decompress::Codec::Gzip).unwrap(),
+            data.len() as u64 * 2
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
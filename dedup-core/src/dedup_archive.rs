@@ -0,0 +1,832 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// A .dedup archive is a single seekable file that holds deduplicated file contents. The layout is:
+//
+//    header       magic, version, chunking parameters and the offsets of the two trailing sections
+//    chunk data   every unique chunk, back to back: its raw bytes, or a delta against a similar chunk stored whole
+//    chunk index  the SHA256 of every unique chunk, its offset and length in the data region, its own length, and the
+//                 position in the index of the chunk it is a delta against (u32::MAX for a chunk stored whole)
+//    manifests    one entry per file: its path, total size, and the ordered list of chunk hashes
+//
+// The index and manifests are written last (and the header patched to point at them) so that an archive can be
+// created in a single pass without knowing ahead of time how many chunks or files it will hold. All integers are
+// stored little-endian.
+const MAGIC: &[u8; 8] = b"DEDUPARC";
+const VERSION: u32 = 2;
+const HEADER_LEN: u64 = 40;
+// The size of an entry in the chunk index: the chunk's hash, offset, stored length, length and base
+const INDEX_ENTRY_LEN: u64 = 32 + 8 + 4 + 4 + 4;
+// The base in the index entry of a chunk that isn't a delta
+const WHOLE: u32 = u32::MAX;
+
+// A delta is a list of operations that make a chunk out of its base: a 0 byte, then the offset and length (u32s) of a
+// range of the base to copy, or a 1 byte, then the length (u32) and the bytes to insert
+const OP_COPY: u8 = 0;
+const OP_LITERAL: u8 = 1;
+// How many bytes of a chunk have to match its base before they are copied rather than inserted
+const MATCH_LEN: usize = 16;
+// How much of a stream is read at a time to be chunked, unless twice the maximum chunk size is more
+const READ_BUFFER: usize = 1024 * 1024;
+
+pub use rabin::ChunkId;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ChunkLocation {
+    offset: u64,
+    // How many bytes the chunk takes up in the data region, which for a delta is less than its size
+    len: u32,
+    size: u32,
+    // The chunk stored whole that this one is a delta against, if it is one
+    base: Option<ChunkId>,
+}
+
+// Describes one file stored in the archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileManifest {
+    pub path: String,
+    pub size: u64,
+    pub chunks: Vec<ChunkId>,
+}
+
+// Repository-wide statistics for an archive, computed from its index and manifests.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ArchiveStats {
+    // The number of files in the archive
+    pub files: u64,
+    // The total size of all files, as they would be when extracted
+    pub logical_bytes: u64,
+    // The number of unique chunks stored in the data region
+    pub chunks: u64,
+    // The total size of all unique chunks
+    pub chunk_bytes: u64,
+    // How many of the unique chunks are stored as deltas against another
+    pub delta_chunks: u64,
+    // How much of the data region the unique chunks take up, which is less than chunk_bytes by what deltas save
+    pub stored_bytes: u64,
+    // Bytes in the data region that no file refers to anymore and could be reclaimed by rewriting the archive
+    pub unreferenced_bytes: u64,
+}
+
+impl ArchiveStats {
+    pub fn average_chunk_size(&self) -> u64 {
+        if self.chunks == 0 {
+            return 0;
+        }
+        self.chunk_bytes / self.chunks
+    }
+
+    // The bytes saved by compressing the unique chunks. Deltas are the only compression an archive has; chunks are
+    // otherwise stored as they are.
+    pub fn compression_savings(&self) -> u64 {
+        self.chunk_bytes - self.stored_bytes
+    }
+
+    // How many logical bytes are represented by each byte stored. 2.0 means the archive is half the size of its files.
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.logical_bytes, self.stored_bytes)
+    }
+}
+
+// What one file added to an archive. An archive doesn't group files into snapshots, so a file is the nearest thing to
+// one: a backup that adds each snapshot as a single file (say, a disk image) gets the dedup ratio of each snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStats {
+    pub path: String,
+    // The size of the file, as it would be when extracted
+    pub logical_bytes: u64,
+    // The space taken up by the chunks no file before this one has, which is what adding the file cost
+    pub stored_bytes: u64,
+}
+
+impl FileStats {
+    // How many of the file's bytes are represented by each byte it added to the archive. A file made entirely of
+    // chunks that were already there added nothing, and its ratio is infinite.
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.logical_bytes, self.stored_bytes)
+    }
+}
+
+fn ratio(logical: u64, stored: u64) -> f64 {
+    if logical == stored {
+        return 1.0;
+    }
+    logical as f64 / stored as f64
+}
+
+// Creates a new archive. Files are chunked as they are added and only chunks that are not already in the archive are
+// written to the data region.
+pub struct ArchiveWriter<W: Write + Seek> {
+    out: W,
+    offset: u64,
+    builder: rabin::chunker::ChunkerBuilder,
+    index: HashMap<ChunkId, ChunkLocation>,
+    order: Vec<ChunkId>,
+    manifests: Vec<FileManifest>,
+    deltas: Option<Deltas<W>>,
+}
+
+// What an archive writer needs to store chunks as deltas: the chunks stored whole by their sketches, and a way to read
+// one back from the output. Reading needs the output to be Read as well, which only with_deltas asks for.
+struct Deltas<W> {
+    bases: rabin::sketch::ResemblanceIndex<ChunkId>,
+    read: fn(&mut W, u64, &mut [u8]) -> io::Result<()>,
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    // Starts a new archive at the beginning of 'out', using 'min' and 'max' as the chunk size limits.
+    pub fn new(mut out: W, min: usize, max: usize) -> io::Result<ArchiveWriter<W>> {
+        let builder = rabin::chunker::ChunkerBuilder::new(min, max)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let (min, max) = (
+            to_u32("the minimum chunk size", min)?,
+            to_u32("the maximum chunk size", max)?,
+        );
+        out.seek(SeekFrom::Start(0))?;
+        write_header(&mut out, min, max, 0, 0)?;
+
+        Ok(ArchiveWriter {
+            out,
+            offset: HEADER_LEN,
+            builder,
+            index: HashMap::new(),
+            order: vec![],
+            manifests: vec![],
+            deltas: None,
+        })
+    }
+
+    // Chunks the contents of a file and adds it to the archive under the specified path.
+    pub fn add_file(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        let mut chunks = vec![];
+        for chunk in self.builder.build(data) {
+            chunks.push(self.store(chunk)?);
+        }
+
+        self.manifests.push(FileManifest {
+            path: path.to_string(),
+            size: data.len() as u64,
+            chunks,
+        });
+        Ok(())
+    }
+
+    // Chunks a file's contents as they are read and adds it to the archive under the specified path, giving the same
+    // chunks as add_file would. Only a little of the file is held in memory at once, so it can be of any size.
+    pub fn add_reader<R: Read>(&mut self, path: &str, mut data: R) -> io::Result<()> {
+        let builder = self.builder;
+        let want = READ_BUFFER.max(2 * builder.max());
+        let mut buffer = Vec::with_capacity(want);
+        let mut chunks = vec![];
+        let mut size = 0u64;
+        let mut finished = false;
+        while !finished {
+            let wanted = want - buffer.len();
+            finished = (&mut data).take(wanted as u64).read_to_end(&mut buffer)? < wanted;
+
+            // A chunk's end only depends on the bytes up to the maximum chunk size past its start, so a chunk that
+            // starts at least that far from the end of the buffer is cut just where it would be in the whole file
+            let mut used = 0;
+            for chunk in builder.build(&buffer) {
+                if !finished && used + builder.max() > buffer.len() {
+                    break;
+                }
+                chunks.push(self.store(chunk)?);
+                used += chunk.len();
+            }
+            size += used as u64;
+            buffer.drain(..used);
+        }
+
+        self.manifests.push(FileManifest {
+            path: path.to_string(),
+            size,
+            chunks,
+        });
+        Ok(())
+    }
+
+    // Writes a chunk to the data region unless it is there already, and returns its id. With deltas on, a chunk that
+    // resembles one stored whole is written as a delta against it when that saves at least a quarter of its size.
+    fn store(&mut self, chunk: &[u8]) -> io::Result<ChunkId> {
+        let id = rabin::hash_chunk_sha256(chunk);
+        if self.index.contains_key(&id) {
+            return Ok(id);
+        }
+
+        let size = to_u32("a chunk", chunk.len())?;
+        let mut delta = None;
+        if let Some(deltas) = self.deltas.as_mut() {
+            let sketch = rabin::sketch::Sketch::of(chunk);
+            if let Some((base, _)) = deltas.bases.find_similar(&sketch) {
+                let location = self.index[&base];
+                let mut whole = vec![0u8; location.len as usize];
+                (deltas.read)(&mut self.out, location.offset, &mut whole)?;
+                let ops = encode_delta(&whole, chunk);
+                if ops.len() * 4 <= chunk.len() * 3 {
+                    delta = Some((base, ops));
+                }
+            }
+            if delta.is_none() {
+                deltas.bases.insert(&sketch, id);
+            }
+        }
+
+        let (stored, base) = match &delta {
+            Some((base, ops)) => (&ops[..], Some(*base)),
+            None => (chunk, None),
+        };
+        self.out.write_all(stored)?;
+        self.index.insert(
+            id,
+            ChunkLocation {
+                offset: self.offset,
+                len: stored.len() as u32,
+                size,
+                base,
+            },
+        );
+        self.order.push(id);
+        self.offset += stored.len() as u64;
+        Ok(id)
+    }
+
+    // Writes the chunk index and manifests and patches the header to point at them. Returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let index_offset = self.offset;
+        let positions: HashMap<&ChunkId, u32> = self
+            .order
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (id, i as u32))
+            .collect();
+        write_u64(&mut self.out, self.order.len() as u64)?;
+        for id in self.order.iter() {
+            let location = self.index[id];
+            self.out.write_all(id)?;
+            write_u64(&mut self.out, location.offset)?;
+            write_u32(&mut self.out, location.len)?;
+            write_u32(&mut self.out, location.size)?;
+            write_u32(
+                &mut self.out,
+                location.base.map_or(WHOLE, |base| positions[&base]),
+            )?;
+        }
+
+        let manifest_offset = self.out.stream_position()?;
+        write_u64(&mut self.out, self.manifests.len() as u64)?;
+        for manifest in self.manifests.iter() {
+            write_u32(&mut self.out, to_u32("a path", manifest.path.len())?)?;
+            self.out.write_all(manifest.path.as_bytes())?;
+            write_u64(&mut self.out, manifest.size)?;
+            write_u64(&mut self.out, manifest.chunks.len() as u64)?;
+            for id in manifest.chunks.iter() {
+                self.out.write_all(id)?;
+            }
+        }
+
+        self.out.seek(SeekFrom::Start(0))?;
+        write_header(
+            &mut self.out,
+            to_u32("the minimum chunk size", self.builder.min())?,
+            to_u32("the maximum chunk size", self.builder.max())?,
+            index_offset,
+            manifest_offset,
+        )?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Read + Write + Seek> ArchiveWriter<W> {
+    // Stores each new chunk that resembles one already stored (see rabin::sketch) as a delta against it, when that is
+    // enough smaller. Only chunks stored whole are bases, so extracting a chunk never reads more than two. Bases are
+    // read back from the output, which is why it has to be readable. Chunks added before this are never bases.
+    pub fn with_deltas(mut self) -> ArchiveWriter<W> {
+        self.deltas = Some(Deltas {
+            bases: rabin::sketch::ResemblanceIndex::new(),
+            read: read_at::<W>,
+        });
+        self
+    }
+}
+
+// Reads part of what has been written so far, and goes back to the end to write more
+fn read_at<W: Read + Seek>(out: &mut W, offset: u64, buffer: &mut [u8]) -> io::Result<()> {
+    let end = out.stream_position()?;
+    out.seek(SeekFrom::Start(offset))?;
+    out.read_exact(buffer)?;
+    out.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+// Reads an existing archive. The index and manifests are loaded when the archive is opened; chunk data is read on
+// demand during extraction. Every count and length in the index and manifests is checked against the space left for it
+// before anything is read or set aside for it, so a damaged archive is an error rather than a huge allocation.
+pub struct ArchiveReader<R: Read + Seek> {
+    inner: R,
+    index: HashMap<ChunkId, ChunkLocation>,
+    manifests: Vec<FileManifest>,
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    pub fn open(mut inner: R) -> io::Result<ArchiveReader<R>> {
+        inner.seek(SeekFrom::Start(0))?;
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a dedup archive".to_string()));
+        }
+        let version = read_u32(&mut inner)?;
+        if version != VERSION {
+            return Err(invalid(format!(
+                "unsupported dedup archive version {}",
+                version
+            )));
+        }
+        let _min = read_u32(&mut inner)?;
+        let _max = read_u32(&mut inner)?;
+        let _reserved = read_u32(&mut inner)?;
+        let index_offset = read_u64(&mut inner)?;
+        let manifest_offset = read_u64(&mut inner)?;
+        let file_len = inner.seek(SeekFrom::End(0))?;
+        if index_offset < HEADER_LEN || manifest_offset < index_offset || manifest_offset > file_len
+        {
+            return Err(invalid("dedup archive was not finished".to_string()));
+        }
+
+        // Every chunk has to be in the data region, between the header and the index
+        inner.seek(SeekFrom::Start(index_offset))?;
+        let mut left = manifest_offset - index_offset;
+        take(&mut left, 1, 8)?;
+        let count = read_u64(&mut inner)?;
+        take(&mut left, count, INDEX_ENTRY_LEN)?;
+        let mut index = HashMap::new();
+        let mut order: Vec<ChunkId> = vec![];
+        for _ in 0..count {
+            let mut id = [0u8; 32];
+            inner.read_exact(&mut id)?;
+            let offset = read_u64(&mut inner)?;
+            let len = read_u32(&mut inner)?;
+            let size = read_u32(&mut inner)?;
+            let base = match read_u32(&mut inner)? {
+                WHOLE if len == size => None,
+                // A base comes before the chunks that are deltas against it, and is stored whole itself
+                base => match order.get(base as usize) {
+                    Some(base)
+                        if matches!(index.get(base), Some(ChunkLocation { base: None, .. })) =>
+                    {
+                        Some(*base)
+                    }
+                    _ => return Err(invalid("not a dedup archive".to_string())),
+                },
+            };
+            if offset < HEADER_LEN || offset.saturating_add(len as u64) > index_offset {
+                return Err(invalid("not a dedup archive".to_string()));
+            }
+            index.insert(
+                id,
+                ChunkLocation {
+                    offset,
+                    len,
+                    size,
+                    base,
+                },
+            );
+            order.push(id);
+        }
+
+        inner.seek(SeekFrom::Start(manifest_offset))?;
+        let mut left = file_len - manifest_offset;
+        take(&mut left, 1, 8)?;
+        let count = read_u64(&mut inner)?;
+        let mut manifests = vec![];
+        for _ in 0..count {
+            take(&mut left, 1, 4)?;
+            let path_len = read_u32(&mut inner)?;
+            take(&mut left, path_len as u64, 1)?;
+            let mut path = vec![0u8; path_len as usize];
+            inner.read_exact(&mut path)?;
+            let path = String::from_utf8(path)
+                .map_err(|_| invalid("file path is not UTF-8".to_string()))?;
+            take(&mut left, 2, 8)?;
+            let size = read_u64(&mut inner)?;
+            let chunk_count = read_u64(&mut inner)?;
+            take(&mut left, chunk_count, 32)?;
+            let mut chunks = Vec::with_capacity(chunk_count as usize);
+            for _ in 0..chunk_count {
+                let mut id = [0u8; 32];
+                inner.read_exact(&mut id)?;
+                chunks.push(id);
+            }
+            manifests.push(FileManifest { path, size, chunks });
+        }
+
+        Ok(ArchiveReader {
+            inner,
+            index,
+            manifests,
+        })
+    }
+
+    // Returns the manifest of every file in the archive, in the order they were added.
+    pub fn list(&self) -> &[FileManifest] {
+        &self.manifests
+    }
+
+    pub fn stats(&self) -> ArchiveStats {
+        let mut referenced: HashSet<&ChunkId> = HashSet::new();
+        let mut stats = ArchiveStats::default();
+        for manifest in self.manifests.iter() {
+            stats.files += 1;
+            stats.logical_bytes += manifest.size;
+            referenced.extend(manifest.chunks.iter());
+        }
+        // A chunk that only deltas refer to is still needed to extract them
+        let bases: Vec<&ChunkId> = referenced
+            .iter()
+            .filter_map(|id| {
+                self.index
+                    .get(*id)
+                    .and_then(|location| location.base.as_ref())
+            })
+            .collect();
+        referenced.extend(bases);
+        for (id, location) in self.index.iter() {
+            stats.chunks += 1;
+            stats.chunk_bytes += location.size as u64;
+            stats.delta_chunks += location.base.is_some() as u64;
+            stats.stored_bytes += location.len as u64;
+            if !referenced.contains(id) {
+                stats.unreferenced_bytes += location.len as u64;
+            }
+        }
+        stats
+    }
+
+    // What each file added to the archive, in the order they were added
+    pub fn file_stats(&self) -> Vec<FileStats> {
+        let mut seen: HashSet<&ChunkId> = HashSet::new();
+        self.manifests
+            .iter()
+            .map(|manifest| FileStats {
+                path: manifest.path.clone(),
+                logical_bytes: manifest.size,
+                stored_bytes: manifest
+                    .chunks
+                    .iter()
+                    .filter(|id| seen.insert(*id))
+                    .filter_map(|id| self.index.get(id))
+                    .map(|location| location.len as u64)
+                    .sum(),
+            })
+            .collect()
+    }
+
+    // Writes the contents of the file stored under 'path' to 'out'. Every chunk is checked against its hash as it is
+    // read, so a damaged archive results in an error rather than silently corrupt output.
+    pub fn extract<O: Write>(&mut self, path: &str, out: &mut O) -> io::Result<()> {
+        let manifest = match self.manifests.iter().find(|m| m.path == path) {
+            Some(manifest) => manifest,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{:?} is not in the archive", path),
+                ))
+            }
+        };
+
+        let mut buffer = vec![];
+        let mut base = vec![];
+        for id in manifest.chunks.iter() {
+            let location = match self.index.get(id) {
+                Some(location) => *location,
+                None => return Err(invalid("chunk is missing from the index".to_string())),
+            };
+
+            read_stored(&mut self.inner, location, &mut buffer)?;
+            if let Some(base_id) = location.base {
+                read_stored(&mut self.inner, self.index[&base_id], &mut base)?;
+                buffer = apply_delta(&base, &buffer, location.size)?;
+            }
+            if rabin::hash_chunk_sha256(&buffer) != *id {
+                return Err(invalid("chunk does not match its hash".to_string()));
+            }
+            out.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_stored<R: Read + Seek>(
+    inner: &mut R,
+    location: ChunkLocation,
+    buffer: &mut Vec<u8>,
+) -> io::Result<()> {
+    buffer.resize(location.len as usize, 0);
+    inner.seek(SeekFrom::Start(location.offset))?;
+    inner.read_exact(buffer)
+}
+
+// Works out the operations that make 'chunk' out of 'base'. Every run of MATCH_LEN bytes in the base is indexed, and
+// each match found in the chunk is copied for as far as it goes on matching. The rest of the chunk is inserted.
+fn encode_delta(base: &[u8], chunk: &[u8]) -> Vec<u8> {
+    let mut runs: HashMap<&[u8], usize> = HashMap::new();
+    for (at, run) in base.windows(MATCH_LEN).enumerate() {
+        runs.entry(run).or_insert(at);
+    }
+
+    let mut ops = vec![];
+    let (mut i, mut literal) = (0, 0);
+    while i + MATCH_LEN <= chunk.len() {
+        let at = match runs.get(&chunk[i..i + MATCH_LEN]) {
+            Some(&at) => at,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        let len = base[at..]
+            .iter()
+            .zip(chunk[i..].iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        push_literal(&mut ops, &chunk[literal..i]);
+        ops.push(OP_COPY);
+        ops.extend_from_slice(&(at as u32).to_le_bytes());
+        ops.extend_from_slice(&(len as u32).to_le_bytes());
+        i += len;
+        literal = i;
+    }
+    push_literal(&mut ops, &chunk[literal..]);
+    ops
+}
+
+fn push_literal(ops: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        ops.push(OP_LITERAL);
+        ops.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        ops.extend_from_slice(bytes);
+    }
+}
+
+// Makes a chunk of 'size' bytes out of its base and the operations stored for it. Every range is checked against what
+// is there, so a damaged delta is an error.
+fn apply_delta(base: &[u8], mut ops: &[u8], size: u32) -> io::Result<Vec<u8>> {
+    let damaged = || invalid("chunk's delta is damaged".to_string());
+    let mut chunk = Vec::with_capacity(size as usize);
+    while let Some((&op, rest)) = ops.split_first() {
+        let u32_at = |from: usize| -> io::Result<usize> {
+            let bytes = rest.get(from..from + 4).ok_or_else(damaged)?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+        };
+        let bytes = match op {
+            OP_COPY => {
+                let (at, len) = (u32_at(0)?, u32_at(4)?);
+                ops = &rest[8..];
+                base.get(at..at.saturating_add(len)).ok_or_else(damaged)?
+            }
+            OP_LITERAL => {
+                let len = u32_at(0)?;
+                let bytes = rest
+                    .get(4..4usize.saturating_add(len))
+                    .ok_or_else(damaged)?;
+                ops = &rest[4 + len..];
+                bytes
+            }
+            _ => return Err(damaged()),
+        };
+        if chunk.len() + bytes.len() > size as usize {
+            return Err(damaged());
+        }
+        chunk.extend_from_slice(bytes);
+    }
+    Ok(chunk)
+}
+
+fn write_header<W: Write>(
+    out: &mut W,
+    min: u32,
+    max: u32,
+    index_offset: u64,
+    manifest_offset: u64,
+) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    write_u32(out, VERSION)?;
+    write_u32(out, min)?;
+    write_u32(out, max)?;
+    write_u32(out, 0)?;
+    write_u64(out, index_offset)?;
+    write_u64(out, manifest_offset)
+}
+
+// Takes 'count' items of 'size' bytes each out of the 'left' bytes of a section, failing if they don't fit
+fn take(left: &mut u64, count: u64, size: u64) -> io::Result<()> {
+    match count.checked_mul(size) {
+        Some(len) if len <= *left => {
+            *left -= len;
+            Ok(())
+        }
+        _ => Err(invalid("not a dedup archive".to_string())),
+    }
+}
+
+fn to_u32(what: &str, size: usize) -> io::Result<u32> {
+    u32::try_from(size).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} ({}) is too large for a dedup archive", what, size),
+        )
+    })
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(out: &mut W, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn read_u32<R: Read>(inner: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    inner.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(inner: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    inner.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    fn random(seed: u64, len: usize) -> Vec<u8> {
+        let mut random = crate::stability::Random::new(seed);
+        (0..len).map(|_| random.next_u64() as u8).collect()
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        // Two files that share most of their contents
+        let first = random(1587, 256 * 1024);
+        let mut second = first.clone();
+        second.extend_from_slice(b"a few more bytes on the end");
+
+        let mut writer =
+            crate::dedup_archive::ArchiveWriter::new(std::io::Cursor::new(vec![]), 1856, 11300)
+                .unwrap();
+        writer.add_file("first", &first).unwrap();
+        writer.add_file("second", &second).unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        // The shared chunks are only stored once
+        assert!(archive.len() < first.len() + second.len() / 10);
+
+        let mut reader =
+            crate::dedup_archive::ArchiveReader::open(std::io::Cursor::new(archive)).unwrap();
+        let paths: Vec<&str> = reader.list().iter().map(|m| m.path.as_str()).collect();
+        assert_eq!(paths, vec!["first", "second"]);
+
+        let mut extracted = vec![];
+        reader.extract("second", &mut extracted).unwrap();
+        assert_eq!(extracted, second);
+
+        let stats = reader.stats();
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.logical_bytes, (first.len() + second.len()) as u64);
+        assert_eq!(stats.unreferenced_bytes, 0);
+        assert_eq!(stats.compression_savings(), 0);
+        assert!(stats.dedup_ratio() > 1.8);
+
+        // The second file only added the chunks at its end
+        let files = reader.file_stats();
+        assert_eq!(files[0].stored_bytes, first.len() as u64);
+        assert_eq!(files[1].logical_bytes, second.len() as u64);
+        assert!(files[1].dedup_ratio() > 10.0);
+    }
+
+    #[test]
+    fn test_add_reader() {
+        // Several times the read buffer, so the file is chunked over many reads
+        let contents = random(1587, 3 * crate::dedup_archive::READ_BUFFER + 12345);
+
+        let mut writer =
+            crate::dedup_archive::ArchiveWriter::new(std::io::Cursor::new(vec![]), 1856, 11300)
+                .unwrap();
+        writer.add_file("whole", &contents).unwrap();
+        writer.add_reader("streamed", &contents[..]).unwrap();
+        writer.add_reader("empty", std::io::empty()).unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let reader =
+            crate::dedup_archive::ArchiveReader::open(std::io::Cursor::new(archive)).unwrap();
+        let manifests = reader.list();
+        assert_eq!(manifests[1].size, contents.len() as u64);
+        assert_eq!(manifests[0].chunks, manifests[1].chunks);
+        assert_eq!((manifests[2].size, manifests[2].chunks.len()), (0, 0));
+        assert_eq!(reader.stats().stored_bytes, contents.len() as u64);
+    }
+
+    #[test]
+    fn test_deltas() {
+        // The second file is the first with a byte changed every few thousand, so hardly any chunks are the same
+        let first = random(1597, 256 * 1024);
+        let mut second = first.clone();
+        for i in (1000..second.len()).step_by(3000) {
+            second[i] ^= 0xff;
+        }
+
+        let write = |deltas: bool| {
+            let mut writer =
+                crate::dedup_archive::ArchiveWriter::new(std::io::Cursor::new(vec![]), 1856, 11300)
+                    .unwrap();
+            if deltas {
+                writer = writer.with_deltas();
+            }
+            writer.add_file("first", &first).unwrap();
+            writer.add_file("second", &second).unwrap();
+            writer.finish().unwrap().into_inner()
+        };
+        let (whole, deltas) = (write(false), write(true));
+        assert!(whole.len() > first.len() + second.len() * 9 / 10);
+        assert!(deltas.len() < first.len() + second.len() / 4);
+
+        let mut reader =
+            crate::dedup_archive::ArchiveReader::open(std::io::Cursor::new(deltas.clone()))
+                .unwrap();
+        for (path, data) in [("first", &first), ("second", &second)].iter() {
+            let mut extracted = vec![];
+            reader.extract(path, &mut extracted).unwrap();
+            assert_eq!(&extracted, *data);
+        }
+        let stats = reader.stats();
+        assert_eq!(stats.unreferenced_bytes, 0);
+        assert_eq!(
+            stats.chunk_bytes,
+            stats.stored_bytes + stats.compression_savings()
+        );
+        assert!(stats.delta_chunks > 0 && stats.compression_savings() > second.len() as u64 / 2);
+        assert_eq!(reader.file_stats()[0].dedup_ratio(), 1.0);
+
+        // A delta copies from its base, and one that copies past the end of it or was cut short is an error
+        let (base, chunk) = (b"0123456789abcdefghij", b"0123456789abcdefghi!");
+        let ops = crate::dedup_archive::encode_delta(base, chunk);
+        assert_eq!(ops.len(), 9 + 6);
+        assert_eq!(
+            crate::dedup_archive::apply_delta(base, &ops, 20).unwrap(),
+            chunk
+        );
+        assert!(crate::dedup_archive::apply_delta(&base[..10], &ops, 20).is_err());
+        assert!(crate::dedup_archive::apply_delta(base, &ops[..ops.len() - 1], 20).is_err());
+        assert!(crate::dedup_archive::apply_delta(base, &ops, 19).is_err());
+    }
+
+    #[test]
+    fn test_damaged_archive() {
+        let mut writer =
+            crate::dedup_archive::ArchiveWriter::new(std::io::Cursor::new(vec![]), 1856, 11300)
+                .unwrap();
+        writer.add_file("file", &[7u8; 10_000]).unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+        let offset = |at: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&archive[at..at + 8]);
+            u64::from_le_bytes(bytes) as usize
+        };
+        let (index_offset, manifest_offset) = (offset(24), offset(32));
+        let open = |archive: Vec<u8>| {
+            crate::dedup_archive::ArchiveReader::open(std::io::Cursor::new(archive))
+        };
+
+        // Counts and lengths far larger than the file are errors, not allocations
+        let mut damaged = archive.clone();
+        damaged[index_offset..index_offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(open(damaged), Err(ref e) if e.to_string() == "not a dedup archive"));
+        let mut damaged = archive.clone();
+        damaged[manifest_offset + 8..manifest_offset + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(open(damaged), Err(ref e) if e.to_string() == "not a dedup archive"));
+        let mut damaged = archive.clone();
+        damaged[index_offset + 8 + 32..index_offset + 8 + 40]
+            .copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(open(damaged), Err(ref e) if e.to_string() == "not a dedup archive"));
+
+        // An archive that was cut short
+        assert!(matches!(
+            open(archive[..manifest_offset].to_vec()),
+            Err(ref e) if e.to_string() == "not a dedup archive"
+        ));
+        assert!(matches!(
+            open(archive[..index_offset].to_vec()),
+            Err(ref e) if e.to_string() == "dedup archive was not finished"
+        ));
+        assert!(open(archive).is_ok());
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path;
+
+use sha3::Digest;
+
+// A delta turns the data a signature was made from into a newer version of it. Each new file is a list of operations:
+// copy a range of one of the signed files, or insert bytes the signed files don't have. The new data is chunked the
+// same way the signature's was, and every chunk found in the signature becomes a copy, so only the chunks that changed
+// are in the delta. The layout, all little-endian, is:
+//
+//    header   the magic, the format version, whether a tree (rather than one file) was compared, and the number of
+//             signed files the copies come from and of new files
+//    bases    the path of each signed file copied from (a u32 length and UTF-8 bytes)
+//    files    each new file's path, size, SHA3-256 hash and number of operations, followed by the operations: a 0 byte,
+//             the base (u32), offset (u64) and length (u64) of a copy, or a 1 byte, the length (u64) and the bytes of a
+//             literal
+const MAGIC: [u8; 4] = *b"DDLT";
+const FORMAT_VERSION: u16 = 1;
+
+const OP_COPY: u8 = 0;
+const OP_LITERAL: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    // Bytes from one of the signed files, by its position in the delta's bases
+    Copy { base: u32, offset: u64, len: u64 },
+    Literal(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDelta {
+    pub path: String,
+    pub size: u64,
+    // The SHA3-256 of the new file, which a file rebuilt from the delta has to match
+    pub hash: [u8; 32],
+    pub ops: Vec<Op>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Delta {
+    // Whether the new files are a directory tree, which is rebuilt as one, rather than a single file
+    pub tree: bool,
+    // The paths, as the signature has them, of the signed files that copies come from
+    pub bases: Vec<String>,
+    pub files: Vec<FileDelta>,
+}
+
+impl Delta {
+    // How many bytes of the new files are copied from the signed ones, and how many are in the delta itself
+    pub fn copied_bytes(&self) -> u64 {
+        self.ops()
+            .map(|op| match op {
+                Op::Copy { len, .. } => *len,
+                Op::Literal(_) => 0,
+            })
+            .sum()
+    }
+
+    pub fn literal_bytes(&self) -> u64 {
+        self.ops()
+            .map(|op| match op {
+                Op::Copy { .. } => 0,
+                Op::Literal(bytes) => bytes.len() as u64,
+            })
+            .sum()
+    }
+
+    fn ops(&self) -> impl Iterator<Item = &Op> {
+        self.files.iter().flat_map(|f| f.ops.iter())
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_all(&MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        out.write_all(&[self.tree as u8])?;
+        out.write_all(&(self.bases.len() as u32).to_le_bytes())?;
+        out.write_all(&(self.files.len() as u32).to_le_bytes())?;
+        for base in self.bases.iter() {
+            write_string(&mut out, base)?;
+        }
+        for file in self.files.iter() {
+            write_string(&mut out, &file.path)?;
+            out.write_all(&file.size.to_le_bytes())?;
+            out.write_all(&file.hash)?;
+            out.write_all(&(file.ops.len() as u32).to_le_bytes())?;
+            for op in file.ops.iter() {
+                match op {
+                    Op::Copy { base, offset, len } => {
+                        out.write_all(&[OP_COPY])?;
+                        out.write_all(&base.to_le_bytes())?;
+                        out.write_all(&offset.to_le_bytes())?;
+                        out.write_all(&len.to_le_bytes())?;
+                    }
+                    Op::Literal(bytes) => {
+                        out.write_all(&[OP_LITERAL])?;
+                        out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+                        out.write_all(bytes)?;
+                    }
+                }
+            }
+        }
+        out.flush()
+    }
+
+    pub fn read<R: Read>(mut input: R) -> io::Result<Delta> {
+        let mut header = [0u8; 15];
+        input.read_exact(&mut header)?;
+        if header[0..4] != MAGIC {
+            return Err(invalid("this isn't a delta file".to_string()));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!(
+                "the delta is format version {}, but only version {} can be read",
+                version, FORMAT_VERSION
+            )));
+        }
+        let tree = header[6] != 0;
+        let base_count = u32::from_le_bytes([header[7], header[8], header[9], header[10]]);
+        let file_count = u32::from_le_bytes([header[11], header[12], header[13], header[14]]);
+
+        let mut bases = vec![];
+        for _ in 0..base_count {
+            bases.push(read_string(&mut input)?);
+        }
+        let mut files = vec![];
+        for _ in 0..file_count {
+            let path = read_string(&mut input)?;
+            let size = read_u64(&mut input)?;
+            let mut hash = [0u8; 32];
+            input.read_exact(&mut hash)?;
+            let mut ops = vec![];
+            for _ in 0..read_u32(&mut input)? {
+                let mut tag = [0u8];
+                input.read_exact(&mut tag)?;
+                ops.push(match tag[0] {
+                    OP_COPY => {
+                        let base = read_u32(&mut input)?;
+                        if base >= base_count {
+                            return Err(invalid(format!(
+                                "{:?} copies from a file the delta doesn't name",
+                                path
+                            )));
+                        }
+                        Op::Copy {
+                            base,
+                            offset: read_u64(&mut input)?,
+                            len: read_u64(&mut input)?,
+                        }
+                    }
+                    OP_LITERAL => {
+                        let len = read_u64(&mut input)?;
+                        let mut bytes = vec![];
+                        input.by_ref().take(len).read_to_end(&mut bytes)?;
+                        if bytes.len() as u64 != len {
+                            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                        }
+                        Op::Literal(bytes)
+                    }
+                    other => {
+                        return Err(invalid(format!("unknown operation {} in the delta", other)))
+                    }
+                });
+            }
+            files.push(FileDelta {
+                path,
+                size,
+                hash,
+                ops,
+            });
+        }
+        Ok(Delta { tree, bases, files })
+    }
+}
+
+// Works out how to make the new file, or the new files in a directory tree, out of the signed files. The new data is
+// chunked with the signature's settings; a chunk whose weak hash, length and id all match one of the signed chunks is
+// copied from it, and anything else is sent as is. Adjoining copies and literals are combined. Files that can't be
+// read are skipped and recorded.
+pub fn compute(
+    signature: &crate::signature::Signature,
+    source: &path::Path,
+    symlinks: crate::walk::Symlinks,
+) -> io::Result<(Delta, crate::skipped::Skipped)> {
+    let params = &signature.params;
+    let chunking = match params.chunking() {
+        Some(chunking) if params.hash == crate::run::HASH_SHA3_256 => chunking,
+        _ => {
+            return Err(invalid(format!(
+                "the signature was made with {}, which this build can't",
+                params
+            )))
+        }
+    };
+
+    // Every signed chunk by its weak hash, as the file and position in that file's list
+    let mut weak: collections::HashMap<u32, Vec<(usize, usize)>> = collections::HashMap::new();
+    for (f, file) in signature.files.iter().enumerate() {
+        for (c, chunk) in file.chunks.iter().enumerate() {
+            weak.entry(chunk.weak).or_default().push((f, c));
+        }
+    }
+
+    let (paths, mut skipped) = crate::signature::files(source, symlinks);
+    let mut delta = Delta {
+        tree: source.is_dir(),
+        ..Default::default()
+    };
+    // Where each signed file that is copied from ends up in the delta's bases
+    let mut bases = collections::HashMap::new();
+    let mut hasher = sha3::Sha3_256::default();
+    for (name, path) in paths {
+        let data = match crate::signature::map(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                skipped.record(&path, &e);
+                continue;
+            }
+        };
+        let mut ops = vec![];
+        for chunk in chunking.chunks(&data) {
+            let candidates = weak.get(&crate::signature::weak_hash(chunk));
+            let key =
+                candidates.map(|_| crate::pipeline::hash_key(&mut hasher, chunk, params.key_len));
+            let found = candidates.into_iter().flatten().find(|&&(f, c)| {
+                let file = &signature.files[f];
+                Some(file.chunks[c].strong) == key && file.chunk_len(c) == chunk.len() as u64
+            });
+            match found {
+                Some(&(f, c)) => {
+                    let next = bases.len() as u32;
+                    let base = *bases.entry(f).or_insert_with(|| {
+                        delta.bases.push(signature.files[f].path.clone());
+                        next
+                    });
+                    push(
+                        &mut ops,
+                        Op::Copy {
+                            base,
+                            offset: signature.files[f].chunks[c].offset,
+                            len: chunk.len() as u64,
+                        },
+                    );
+                }
+                None => push(&mut ops, Op::Literal(chunk.to_vec())),
+            }
+        }
+        delta.files.push(FileDelta {
+            path: name,
+            size: data.len() as u64,
+            hash: sha3::Sha3_256::digest(&data[..]).into(),
+            ops,
+        });
+    }
+    Ok((delta, skipped))
+}
+
+// Adds the operation to the list, extending the last one instead when they join up
+fn push(ops: &mut Vec<Op>, op: Op) {
+    match (ops.last_mut(), op) {
+        (
+            Some(Op::Copy { base, offset, len }),
+            Op::Copy {
+                base: next_base,
+                offset: next_offset,
+                len: next_len,
+            },
+        ) if *base == next_base && *offset + *len == next_offset => *len += next_len,
+        (Some(Op::Literal(bytes)), Op::Literal(next)) => bytes.extend_from_slice(&next),
+        (_, op) => ops.push(op),
+    }
+}
+
+// Rebuilds the new data from the signed data and the delta. 'base' is what was signed: the directory the signed paths
+// are relative to, or the signed file itself. The new tree is written to the directory 'out', or the new file to 'out'
+// itself, which mustn't exist yet so the base can never be overwritten while it is still being read. Every file is
+// checked against the hash in the delta, so a base that changed since it was signed is caught.
+pub fn apply(delta: &Delta, base: &path::Path, out: &path::Path) -> io::Result<()> {
+    if out.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists", out),
+        ));
+    }
+    let mut bases: Vec<Option<fs::File>> = delta.bases.iter().map(|_| None).collect();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    for file in delta.files.iter() {
+        let target = if delta.tree {
+            let target = out.join(&file.path);
+            // A path from the delta must stay inside the output directory
+            if path::Path::new(&file.path)
+                .components()
+                .any(|c| !matches!(c, path::Component::Normal(_)))
+            {
+                return Err(invalid(format!("{:?} isn't a relative path", file.path)));
+            }
+            fs::create_dir_all(target.parent().unwrap())?;
+            target
+        } else {
+            out.to_path_buf()
+        };
+        let mut written = io::BufWriter::new(fs::File::create(&target)?);
+        let mut hasher = sha3::Sha3_256::default();
+        for op in file.ops.iter() {
+            match op {
+                Op::Copy {
+                    base: b,
+                    offset,
+                    len,
+                } => {
+                    let from = match &mut bases[*b as usize] {
+                        Some(from) => from,
+                        opened => {
+                            let name = &delta.bases[*b as usize];
+                            let path = if base.is_dir() {
+                                base.join(name)
+                            } else {
+                                base.to_path_buf()
+                            };
+                            opened.insert(fs::File::open(&path)?)
+                        }
+                    };
+                    from.seek(io::SeekFrom::Start(*offset))?;
+                    let mut left = *len;
+                    while left > 0 {
+                        let piece = &mut buffer[..left.min(1024 * 1024) as usize];
+                        from.read_exact(piece)?;
+                        hasher.update(&piece[..]);
+                        written.write_all(piece)?;
+                        left -= piece.len() as u64;
+                    }
+                }
+                Op::Literal(bytes) => {
+                    hasher.update(bytes);
+                    written.write_all(bytes)?;
+                }
+            }
+        }
+        written
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        if hasher.finalize()[..] != file.hash[..] {
+            return Err(invalid(format!(
+                "{:?} doesn't come out as it should; the base has changed since it was signed",
+                file.path
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn write_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    out.write_all(&(s.len() as u32).to_le_bytes())?;
+    out.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(input: &mut R) -> io::Result<String> {
+    let len = read_u32(input)?;
+    let mut bytes = vec![];
+    input.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    String::from_utf8(bytes).map_err(|_| invalid("a path in the delta isn't UTF-8".to_string()))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_delta_and_apply() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_delta_{}", std::process::id()));
+        fs::create_dir_all(dir.join("old/sub")).unwrap();
+        fs::create_dir_all(dir.join("new/sub")).unwrap();
+        let mut x = 7u64;
+        let data: Vec<u8> = (0..300_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        fs::write(dir.join("old/a"), &data[..200_000]).unwrap();
+        fs::write(dir.join("old/sub/b"), &data[200_000..]).unwrap();
+
+        // The new 'a' has a few bytes changed in the middle, and takes the start of the old 'b' too
+        let mut a = data[..200_000].to_vec();
+        a[100_000..100_010].copy_from_slice(b"0123456789");
+        a.extend_from_slice(&data[200_000..250_000]);
+        fs::write(dir.join("new/a"), &a).unwrap();
+        fs::write(dir.join("new/sub/b"), &data[250_000..]).unwrap();
+        fs::write(dir.join("new/sub/c"), b"brand new").unwrap();
+
+        let options = crate::pipeline::Options {
+            chunking: crate::pipeline::Chunking::Variable(
+                rabin::chunker::ChunkerBuilder::new(crate::MIN_CHUNK_SIZE, crate::MAX_CHUNK_SIZE)
+                    .unwrap()
+                    .average(4096)
+                    .unwrap(),
+            ),
+            key_len: crate::KEY_LEN,
+            threads: 1,
+            hash_cpus: None,
+            io_cpus: None,
+            symlinks: crate::walk::Symlinks::Skip,
+            detect_hard_links: true,
+            streams: false,
+            mmap: true,
+            uring: None,
+            direct_io: false,
+            resume: vec![],
+            compress_every: None,
+            only: None,
+            min_file_size: None,
+            max_file_size: None,
+            cache: None,
+            sub_blocks: None,
+            disk_images: false,
+            anchors: vec![],
+            compressed: crate::compressed::Policy::Chunk,
+            archives: false,
+        };
+        let (signature, _) = crate::signature::sign(&dir.join("old"), &options);
+        let (delta, skipped) =
+            crate::delta::compute(&signature, &dir.join("new"), crate::walk::Symlinks::Skip)
+                .unwrap();
+        assert_eq!(skipped.count(), 0);
+        assert!(delta.tree);
+        assert_eq!(delta.bases, ["a", "sub/b"]);
+        assert_eq!(
+            delta.copied_bytes() + delta.literal_bytes(),
+            250_000 + 50_000 + 9
+        );
+        // Only the chunks around the change, the chunks cut where the files were joined or split and the new file
+        assert!(
+            delta.literal_bytes() < 60_000,
+            "{} literal bytes",
+            delta.literal_bytes()
+        );
+
+        let mut bytes = vec![];
+        delta.write(&mut bytes).unwrap();
+        assert_eq!(crate::delta::Delta::read(&bytes[..]).unwrap(), delta);
+        assert!(crate::delta::Delta::read(&bytes[..bytes.len() - 1]).is_err());
+
+        crate::delta::apply(&delta, &dir.join("old"), &dir.join("rebuilt")).unwrap();
+        for name in ["a", "sub/b", "sub/c"].iter() {
+            assert_eq!(
+                fs::read(dir.join("rebuilt").join(name)).unwrap(),
+                fs::read(dir.join("new").join(name)).unwrap()
+            );
+        }
+        // The output is never written over
+        assert!(crate::delta::apply(&delta, &dir.join("old"), &dir.join("rebuilt")).is_err());
+
+        // One file on its own, against a base that changed after it was signed
+        let (signature, _) = crate::signature::sign(&dir.join("old/a"), &options);
+        let (delta, _) =
+            crate::delta::compute(&signature, &dir.join("new/a"), crate::walk::Symlinks::Skip)
+                .unwrap();
+        assert!(!delta.tree);
+        crate::delta::apply(&delta, &dir.join("old/a"), &dir.join("a")).unwrap();
+        assert_eq!(fs::read(dir.join("a")).unwrap(), a);
+        fs::write(dir.join("old/a"), &data[1..200_001]).unwrap();
+        let result = crate::delta::apply(&delta, &dir.join("old/a"), &dir.join("a2"));
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use serde_derive::Serialize;
+
+// Ways of finding where the chunks in a file end, which must all agree: chunking the whole file at once (slice),
+// chunking it through a small buffer the way a streamed file is (stream), and the rabin crate's plain reference chunker
+// (reference), which works every fingerprint out from scratch.
+pub const IMPLEMENTATIONS: &[&str] = &["slice", "stream", "reference"];
+
+// How many bytes on either side of a divergence to show
+pub const CONTEXT_BYTES: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Implementation {
+    Slice,
+    // Reads through a buffer of this many bytes
+    Stream(usize),
+    Reference,
+}
+
+impl Implementation {
+    // Looks up an implementation by its name in IMPLEMENTATIONS
+    pub fn from_name(name: &str, buffer_bytes: usize) -> Option<Implementation> {
+        match name {
+            "slice" => Some(Implementation::Slice),
+            "stream" => Some(Implementation::Stream(buffer_bytes)),
+            "reference" => Some(Implementation::Reference),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Implementation::Slice => "slice",
+            Implementation::Stream(_) => "stream",
+            Implementation::Reference => "reference",
+        }
+    }
+}
+
+// Where each chunk the implementation finds in the data ends
+pub fn boundaries(
+    implementation: Implementation,
+    data: &[u8],
+    chunking: &crate::pipeline::Chunking,
+) -> Vec<usize> {
+    match implementation {
+        Implementation::Slice => ends(chunking.chunks(data).map(|c| c.len()), 0),
+        Implementation::Stream(buffer_bytes) => {
+            // The same reads and cuts a streamed file gets, only from memory
+            let max_chunk = chunking.max_chunk();
+            let mut all = vec![];
+            let (mut buffer_start, mut read) = (0, 0);
+            while read < data.len() {
+                let buffered = read - buffer_start;
+                read += crate::pipeline::stream_read_len(
+                    buffered,
+                    data.len() - read,
+                    max_chunk,
+                    buffer_bytes,
+                );
+                let buffer = &data[buffer_start..read];
+                let ranges = crate::pipeline::stream_ranges(chunking, buffer, read == data.len());
+                all.extend(ends(ranges.iter().map(|r| r.len()), buffer_start));
+                buffer_start += ranges.last().map_or(0, |r| r.end);
+            }
+            all
+        }
+        Implementation::Reference => match chunking {
+            crate::pipeline::Chunking::Fixed => {
+                let size = crate::pipeline::FIXED_CHUNK_SIZE;
+                (1..=data.len().div_ceil(size))
+                    .map(|i| (i * size).min(data.len()))
+                    .collect()
+            }
+            crate::pipeline::Chunking::Variable(builder) => {
+                rabin::reference::boundaries(data, builder)
+            }
+        },
+    }
+}
+
+fn ends(sizes: impl Iterator<Item = usize>, start: usize) -> Vec<usize> {
+    sizes
+        .scan(start, |end, size| {
+            *end += size;
+            Some(*end)
+        })
+        .collect()
+}
+
+// The first place two implementations disagree: the chunk where they do, where each says it ends (None if one of them
+// has no more chunks), and the bytes around the earlier of the two
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Divergence {
+    pub chunk: usize,
+    pub first: Option<usize>,
+    pub second: Option<usize>,
+    pub context_start: usize,
+    pub context: Vec<u8>,
+}
+
+pub fn first_divergence(data: &[u8], first: &[usize], second: &[usize]) -> Option<Divergence> {
+    let chunk = (0..first.len().max(second.len())).find(|&i| first.get(i) != second.get(i))?;
+    let (a, b) = (first.get(chunk).copied(), second.get(chunk).copied());
+    let at = a.into_iter().chain(b).min().unwrap_or(data.len());
+    let context_start = at.saturating_sub(CONTEXT_BYTES);
+    Some(Divergence {
+        chunk,
+        first: a,
+        second: b,
+        context_start,
+        context: data[context_start..(at + CONTEXT_BYTES).min(data.len())].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::differential::Implementation;
+
+    #[test]
+    fn test_implementations_agree() {
+        let mut x = 11u64;
+        let data: Vec<u8> = (0..200_000)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect();
+
+        for chunking in [
+            crate::pipeline::Chunking::Fixed,
+            crate::pipeline::Chunking::Variable(
+                rabin::chunker::ChunkerBuilder::new(crate::MIN_CHUNK_SIZE, crate::MAX_CHUNK_SIZE)
+                    .unwrap(),
+            ),
+        ] {
+            let slice = crate::differential::boundaries(Implementation::Slice, &data, &chunking);
+            assert_eq!(slice.last(), Some(&data.len()));
+            // Buffers smaller than the largest chunk are topped up to it
+            for implementation in [
+                Implementation::Stream(1000),
+                Implementation::Stream(50_000),
+                Implementation::Reference,
+            ] {
+                let other = crate::differential::boundaries(implementation, &data, &chunking);
+                assert_eq!(
+                    crate::differential::first_divergence(&data, &slice, &other),
+                    None
+                );
+            }
+        }
+
+        // A divergence is reported at the first chunk that differs, with the bytes around the earlier end
+        let divergence =
+            crate::differential::first_divergence(&data, &[100, 250, 400], &[100, 240]).unwrap();
+        assert_eq!(divergence.chunk, 1);
+        assert_eq!(
+            (divergence.first, divergence.second),
+            (Some(250), Some(240))
+        );
+        assert_eq!(
+            divergence.context_start,
+            240 - crate::differential::CONTEXT_BYTES
+        );
+        assert_eq!(divergence.context, &data[208..272]);
+        let divergence =
+            crate::differential::first_divergence(&data, &[100, 250, 400], &[100, 250]).unwrap();
+        assert_eq!((divergence.first, divergence.second), (Some(400), None));
+    }
+}
@@ -0,0 +1,168 @@
+use std::alloc;
+use std::fs;
+use std::io;
+use std::path;
+
+// Reads that bypass the page cache have to start at, and be a multiple of, the device's block size, into memory aligned
+// the same way. 4096 covers every block size in common use.
+pub const ALIGNMENT: usize = 4096;
+
+// How much the bounce buffer holds. Each read is at most this big.
+const BUFFER_BYTES: usize = 1024 * 1024;
+
+// Opens the file so that reading it doesn't fill the page cache: O_DIRECT on Linux and F_NOCACHE on macOS. Some
+// filesystems (tmpfs, some FUSE ones) refuse O_DIRECT, and those files are opened the usual way instead.
+pub fn open(path: &path::Path) -> io::Result<fs::File> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        match fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+        {
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                tracing::debug!(path = %path.display(), "the filesystem doesn't allow direct IO");
+                fs::File::open(path)
+            }
+            opened => opened,
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let file = fs::File::open(path)?;
+        unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) };
+        Ok(file)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    {
+        fs::File::open(path)
+    }
+}
+
+// A heap buffer aligned for direct IO
+struct Aligned {
+    ptr: *mut u8,
+    layout: alloc::Layout,
+}
+
+impl Aligned {
+    fn new(len: usize) -> Aligned {
+        let layout = alloc::Layout::from_size_align(len, ALIGNMENT).unwrap();
+        // Safety: the layout isn't zero-sized
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Aligned { ptr, layout }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safety: the buffer is 'layout.size()' bytes, all initialized when it was allocated
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for Aligned {
+    fn drop(&mut self) {
+        // Safety: allocated with this layout in new
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+// The buffer is only ever used by the thread that owns the reader
+unsafe impl Send for Aligned {}
+
+// Reads any range of a file with aligned reads into a buffer of its own, and copies out the part that was asked for
+pub struct Reader {
+    buffer: Aligned,
+}
+
+impl Default for Reader {
+    fn default() -> Self {
+        Reader::new()
+    }
+}
+
+impl Reader {
+    pub fn new() -> Reader {
+        Reader {
+            buffer: Aligned::new(BUFFER_BYTES),
+        }
+    }
+
+    // Fills 'out' with the file's bytes from 'offset' on, or fails with UnexpectedEof if the file ends first
+    pub fn read_exact_at(
+        &mut self,
+        file: &fs::File,
+        mut offset: u64,
+        mut out: &mut [u8],
+    ) -> io::Result<()> {
+        let buffer = self.buffer.as_mut_slice();
+        while !out.is_empty() {
+            let aligned = offset & !(ALIGNMENT as u64 - 1);
+            let skip = (offset - aligned) as usize;
+            let want = (skip + out.len())
+                .next_multiple_of(ALIGNMENT)
+                .min(buffer.len());
+            // The last read of a file is short, which is the only time a read doesn't have to end on a block
+            let read = read_at(file, &mut buffer[..want], aligned)?;
+            if read <= skip {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            let taken = (read - skip).min(out.len());
+            out[..taken].copy_from_slice(&buffer[skip..skip + taken]);
+            out = &mut out[taken..];
+            offset += taken as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &fs::File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    loop {
+        match file.read_at(buffer, offset) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+#[cfg(windows)]
+fn read_at(file: &fs::File, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buffer, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_reader() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_direct_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("a"), &data).unwrap();
+        let file = crate::direct::open(&dir.join("a")).unwrap();
+
+        // Neither end has to be aligned, and a range can be bigger than the buffer
+        let mut reader = crate::direct::Reader::new();
+        for &(start, len) in [(0, 100), (4095, 2), (12345, 2_500_000), (2_999_000, 1000)].iter() {
+            let mut out = vec![0; len];
+            reader.read_exact_at(&file, start as u64, &mut out).unwrap();
+            assert_eq!(out, &data[start..start + len]);
+        }
+        let mut out = vec![0; 10];
+        let result = reader.read_exact_at(&file, 2_999_995, &mut out);
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+// Data with more bits of information per byte than this is almost certainly compressed or encrypted already. Text is
+// usually around 4 to 5 and executables around 6, while random data of chunk size comes out just under 8.
+pub const HIGH_ENTROPY_BITS: f64 = 7.5;
+
+// Estimates the information in each byte of the data from how often each byte value occurs (the Shannon entropy of the
+// byte histogram), from 0 for a single repeated byte to 8 for bytes that are evenly spread. It doesn't notice longer
+// repeated patterns, so it can only say data won't compress, not that it will.
+pub fn bits_per_byte(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0u32; 256];
+    for &b in data.iter() {
+        counts[b as usize] += 1;
+    }
+
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+pub fn is_high_entropy(data: &[u8]) -> bool {
+    bits_per_byte(data) > HIGH_ENTROPY_BITS
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_bits_per_byte() {
+        assert_eq!(crate::entropy::bits_per_byte(&[]), 0.0);
+        assert_eq!(crate::entropy::bits_per_byte(&[7u8; 4096]), 0.0);
+        assert_eq!(crate::entropy::bits_per_byte(&[0, 1, 0, 1]), 1.0);
+
+        // Every byte value equally often
+        let even: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+        assert_eq!(crate::entropy::bits_per_byte(&even), 8.0);
+        assert!(crate::entropy::is_high_entropy(&even));
+
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(100);
+        assert!(!crate::entropy::is_high_entropy(text.as_bytes()));
+    }
+}
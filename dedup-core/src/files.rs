@@ -0,0 +1,303 @@
+use std::collections;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path;
+
+use serde_derive::{Deserialize, Serialize};
+
+pub const FILE_REPORT_NAME: &str = "file_report";
+
+// One file that a scan read. Entries in the scan's runs refer to files by their position in the scan's file list. The
+// scan doesn't know which of a file's chunks will turn out to be unique, so 'unique_bytes' is only filled in by the
+// merge, which credits each unique chunk to the first file it was found in.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileRecord {
+    pub path: String,
+    pub size: u64,
+    pub chunks: u64,
+    pub unique_bytes: u64,
+    // The file's extension or detected type (see filetype::file_type)
+    pub kind: String,
+    // The directory the file is in, as deep below the scanned directory as the scan was told to look
+    pub group: String,
+    // The directory the scan was given that the file was found in
+    pub root: String,
+    pub metadata: crate::catalog::Metadata,
+    // The format the file was decompressed from before it was chunked, if it was, so its chunks are of the data inside
+    pub decompressed: Option<crate::decompress::Codec>,
+    // Sums up the file's chunk ids and where each is in the file (see add_chunk), so files with the same chunks in the
+    // same order have the same fingerprint
+    pub fingerprint: u64,
+}
+
+impl FileRecord {
+    pub fn duplicate_bytes(&self) -> u64 {
+        self.size - self.unique_bytes
+    }
+
+    // Adds the file's chunk number 'index' to the fingerprint. Chunks arrive in whatever order the hashing threads
+    // finish them, so the fingerprint is a sum that doesn't depend on the order it is taken in; multiplying each id by
+    // an odd number made from its position keeps files with the same chunks in a different order apart.
+    pub fn add_chunk(&mut self, index: u64, key: &crate::run::Key) {
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&key[0..8]);
+        let mixed = u64::from_be_bytes(prefix).wrapping_mul(index.wrapping_mul(2).wrapping_add(1));
+        self.fingerprint = self.fingerprint.wrapping_add(mixed);
+    }
+}
+
+pub fn write_records(file: &fs::File, records: &[FileRecord]) -> io::Result<()> {
+    let mut buffer = io::BufWriter::new(file);
+    for record in records {
+        bincode::serialize_into(&mut buffer, record).map_err(io::Error::other)?;
+    }
+    buffer.flush()
+}
+
+pub fn read_records(name: &path::Path) -> io::Result<Vec<FileRecord>> {
+    let mut reader = io::BufReader::new(fs::File::open(name)?);
+    let mut records = vec![];
+    while !reader.fill_buf()?.is_empty() {
+        records.push(bincode::deserialize_from(&mut reader).map_err(io::Error::other)?);
+    }
+    Ok(records)
+}
+
+// Entries record which directory groups their chunk was found in with one bit per group, so a scan can only tell
+// this many apart. Any more share the last bit.
+pub const MAX_GROUPS: usize = 64;
+const OTHER_GROUP: &str = "(other)";
+
+// Numbers the directory groups of a scan in the order their first file appears in its file list. That way the numbers
+// in a scan's runs can always be worked out again from the list.
+#[derive(Debug, Default)]
+pub struct Groups {
+    names: Vec<String>,
+    ids: collections::HashMap<String, usize>,
+}
+
+impl Groups {
+    // Numbers the groups of the files in a scan's list
+    pub fn from_records(records: &[FileRecord]) -> Groups {
+        let mut groups = Groups::default();
+        for record in records.iter() {
+            groups.bit(&record.group);
+        }
+        groups
+    }
+
+    // The bit for the group, numbering it if it hasn't been seen before
+    pub fn bit(&mut self, group: &str) -> u64 {
+        let id = match self.ids.get(group) {
+            Some(&id) => id,
+            None if self.names.len() < MAX_GROUPS - 1 => {
+                self.names.push(group.to_string());
+                self.ids.insert(group.to_string(), self.names.len() - 1);
+                self.names.len() - 1
+            }
+            None => MAX_GROUPS - 1,
+        };
+        1 << id
+    }
+
+    // The names of the groups whose bits are set
+    pub fn names(&self, bits: u64) -> impl Iterator<Item = &str> + '_ {
+        (0..MAX_GROUPS)
+            .filter(move |id| bits & (1 << id) != 0)
+            .map(move |id| self.names.get(id).map_or(OTHER_GROUP, |name| name.as_str()))
+    }
+}
+
+// The group of a file is the path of the directory it is in, relative to the scanned directory and cut off after
+// 'depth' components. Files directly in the scanned directory are in the group ".". Files from a list that aren't in
+// any scanned directory are grouped by their own path, leaving out where it starts (like "/" or "./").
+pub fn directory_group(root: &path::Path, file: &path::Path, depth: usize) -> String {
+    let relative = file.strip_prefix(root).unwrap_or(file);
+    let parent = relative.parent().unwrap_or_else(|| path::Path::new(""));
+    let components: Vec<_> = parent
+        .components()
+        .filter(|c| matches!(c, path::Component::Normal(_)))
+        .take(depth)
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    if components.is_empty() {
+        ".".to_string()
+    } else {
+        components.join("/")
+    }
+}
+
+// When a scan is given several directories, each group is named after the directory it is in as well. Files directly
+// in one of the directories are grouped under the directory's own name.
+pub fn rooted_group(root: &str, group: &str) -> String {
+    if group == "." {
+        root.to_string()
+    } else {
+        format!("{}/{}", root.trim_end_matches(['/', '\\']), group)
+    }
+}
+
+// The totals of a group of files
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Breakdown {
+    pub name: String,
+    pub files: u64,
+    pub bytes: u64,
+    pub unique_bytes: u64,
+    pub duplicate_bytes: u64,
+    pub percent_deduplicated: f64,
+}
+
+// Adds up the files in each group, and returns the 'top' groups with the most bytes
+pub fn breakdown<F>(records: &[FileRecord], group: F, top: usize) -> Vec<Breakdown>
+where
+    F: Fn(&FileRecord) -> String,
+{
+    let mut groups: collections::HashMap<String, Breakdown> = collections::HashMap::new();
+    for record in records {
+        let name = group(record);
+        let breakdown = groups.entry(name.clone()).or_insert_with(|| Breakdown {
+            name,
+            ..Breakdown::default()
+        });
+        breakdown.files += 1;
+        breakdown.bytes += record.size;
+        breakdown.unique_bytes += record.unique_bytes;
+        breakdown.duplicate_bytes += record.duplicate_bytes();
+    }
+
+    let mut groups: Vec<Breakdown> = groups.into_values().collect();
+    groups.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+    groups.truncate(top);
+    for breakdown in groups.iter_mut() {
+        if breakdown.bytes > 0 {
+            breakdown.percent_deduplicated =
+                breakdown.duplicate_bytes as f64 * 100.0 / breakdown.bytes as f64;
+        }
+    }
+    groups
+}
+
+// Writes the per-file report as CSV, with one row for each file in every scan.
+pub fn write_csv(out: &mut dyn Write, records: &[FileRecord]) -> io::Result<()> {
+    writeln!(
+        out,
+        "path,size,chunks,unique_bytes,duplicate_bytes,percent_deduplicated"
+    )?;
+    for record in records {
+        let percent = if record.size == 0 {
+            0.0
+        } else {
+            record.duplicate_bytes() as f64 * 100.0 / record.size as f64
+        };
+        writeln!(
+            out,
+            "{},{},{},{},{},{:0.4}",
+            csv_field(&record.path),
+            record.size,
+            record.chunks,
+            record.unique_bytes,
+            record.duplicate_bytes(),
+            percent
+        )?;
+    }
+    Ok(())
+}
+
+// Paths may contain anything, so quote them whenever they contain a character that means something in CSV
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_write_csv() {
+        let records = vec![
+            crate::files::FileRecord {
+                path: "plain.txt".to_string(),
+                size: 1000,
+                chunks: 2,
+                unique_bytes: 250,
+                kind: ".txt".to_string(),
+                group: ".".to_string(),
+                ..crate::files::FileRecord::default()
+            },
+            crate::files::FileRecord {
+                path: "a \"quoted\", name".to_string(),
+                ..crate::files::FileRecord::default()
+            },
+        ];
+
+        let mut out = vec![];
+        crate::files::write_csv(&mut out, &records).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[1], "plain.txt,1000,2,250,750,75.0000");
+        assert_eq!(lines[2], "\"a \"\"quoted\"\", name\",0,0,0,0,0.0000");
+    }
+
+    #[test]
+    fn test_groups() {
+        let root = std::path::Path::new("/data");
+        let group =
+            |file: &str, depth| crate::files::directory_group(root, &root.join(file), depth);
+        assert_eq!(group("a.txt", 1), ".");
+        assert_eq!(group("projects/one/src/main.rs", 1), "projects");
+        assert_eq!(group("projects/one/src/main.rs", 2), "projects/one");
+        assert_eq!(group("projects/one/main.rs", 5), "projects/one");
+        assert_eq!(
+            crate::files::directory_group(root, std::path::Path::new("./src/lib/a.rs"), 1),
+            "src"
+        );
+        assert_eq!(crate::files::rooted_group("/data/", "."), "/data/");
+        assert_eq!(
+            crate::files::rooted_group("/data/", "projects"),
+            "/data/projects"
+        );
+
+        // Every group after the first 63 shares the last bit
+        let mut groups = crate::files::Groups::default();
+        assert_eq!(groups.bit("x"), 1);
+        assert_eq!(groups.bit("y"), 2);
+        assert_eq!(groups.bit("x"), 1);
+        for i in 0..100 {
+            groups.bit(&i.to_string());
+        }
+        assert_eq!(groups.bit("another"), 1 << 63);
+        let names: Vec<&str> = groups.names(1 | 2 | 1 << 63).collect();
+        assert_eq!(names, vec!["x", "y", "(other)"]);
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let file = |kind: &str, size: u64, unique_bytes: u64| crate::files::FileRecord {
+            kind: kind.to_string(),
+            size,
+            unique_bytes,
+            ..crate::files::FileRecord::default()
+        };
+        let records = vec![
+            file(".jpg", 100, 100),
+            file(".vmdk", 1000, 400),
+            file(".jpg", 300, 200),
+            file("text", 10, 10),
+        ];
+
+        let types = crate::files::breakdown(&records, |r| r.kind.clone(), 2);
+        assert_eq!(types.len(), 2);
+        assert_eq!(types[0].name, ".vmdk");
+        assert_eq!(types[0].percent_deduplicated, 60.0);
+        assert_eq!(types[1].name, ".jpg");
+        assert_eq!(types[1].files, 2);
+        assert_eq!(types[1].bytes, 400);
+        assert_eq!(types[1].duplicate_bytes, 100);
+        assert_eq!(types[1].percent_deduplicated, 25.0);
+    }
+}
@@ -0,0 +1,89 @@
+use std::fs;
+use std::io::Read;
+use std::path;
+
+// Enough of the start of a file to find a tar header's magic, which is the furthest in of the ones we look for
+const HEAD_LEN: usize = 512;
+
+// Magic numbers at the start of common file formats, for files without an extension
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"%PDF", "pdf"),
+    (b"\x89PNG", "png"),
+    (b"\xff\xd8\xff", "jpeg"),
+    (b"GIF8", "gif"),
+    (b"PK\x03\x04", "zip"),
+    (b"\x1f\x8b", "gzip"),
+    (b"BZh", "bzip2"),
+    (b"\xfd7zXZ\x00", "xz"),
+    (b"\x28\xb5\x2f\xfd", "zstd"),
+    (b"7z\xbc\xaf\x27\x1c", "7z"),
+    (b"\x7fELF", "elf"),
+    (b"MZ", "exe"),
+    (b"SQLite format 3\x00", "sqlite"),
+    (b"QFI\xfb", "qcow"),
+    (b"KDMV", "vmdk"),
+];
+
+// What kind of file this is, for breaking the statistics down by type. Files with an extension are known by it, with a
+// leading dot (".jpg"). Files without one are identified by their first few bytes if possible ("elf"), and are
+// otherwise "text" or "data".
+pub fn file_type(path: &path::Path) -> String {
+    if let Some(extension) = path.extension() {
+        return format!(".{}", extension.to_string_lossy().to_lowercase());
+    }
+
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    if let Ok(file) = fs::File::open(path) {
+        let _ = file.take(HEAD_LEN as u64).read_to_end(&mut head);
+    }
+    detect(&head).to_string()
+}
+
+fn detect(head: &[u8]) -> &'static str {
+    if let Some((_, name)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return name;
+    }
+    if head.len() >= 262 && &head[257..262] == b"ustar" {
+        return "tar";
+    }
+    if head.is_empty() {
+        return "empty";
+    }
+
+    let text = head
+        .iter()
+        .all(|&b| b.is_ascii_graphic() || b.is_ascii_whitespace() || b >= 0x80);
+    if text {
+        "text"
+    } else {
+        "data"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_file_type() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_filetype_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let file_type = |name: &str, contents: &[u8]| {
+            fs::write(dir.join(name), contents).unwrap();
+            crate::filetype::file_type(&dir.join(name))
+        };
+        assert_eq!(file_type("photo.JPG", b"\xff\xd8\xff\xe0"), ".jpg");
+        assert_eq!(file_type("archive.tar.gz", b"\x1f\x8b"), ".gz");
+        assert_eq!(file_type("program", b"\x7fELF\x02\x01"), "elf");
+        assert_eq!(file_type("README", b"plain text\n"), "text");
+        assert_eq!(file_type("blob", &[0, 1, 2, 3]), "data");
+        assert_eq!(file_type("nothing", b""), "empty");
+
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(file_type("backup", &tar), "tar");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use std::io;
+use std::io::{Read, Write};
+
+// Asks a server which chunks it already has, so an uploader only sends the ones it doesn't. Asking about one chunk at
+// a time would cost a round trip per chunk, which over a link with any latency takes far longer than sending the data,
+// so the client sends its ids in batches and the server answers each batch with a bitmap. The client keeps several
+// batches in flight, so on a slow link it waits for one round trip for every IN_FLIGHT * BATCH_KEYS ids rather than
+// one for every batch. Everything is sent over one stream (i.e. a TcpStream), all little-endian:
+//
+//    hello    each side sends the magic, the format version and the key length it uses, client first. Ids are only
+//             comparable when both use the same length, so either side gives up if they differ.
+//    batch    the client sends the number of ids (u32, at most MAX_BATCH_KEYS) followed by the key_len bytes of each
+//    reply    the server sends a bitmap of the batch, one bit for each id in the order they were sent, lowest bit
+//             first, set if it has that chunk
+//
+// A batch of 0 ids tells the server the client is done.
+const MAGIC: [u8; 4] = *b"DHAV";
+const FORMAT_VERSION: u16 = 1;
+
+// How many ids the client sends in a batch. The reply to a full batch is 512 bytes.
+pub const BATCH_KEYS: usize = 4096;
+// The most ids the server accepts in a batch, so a bad client can't make it allocate without limit
+pub const MAX_BATCH_KEYS: usize = 65536;
+// How many batches the client sends before it waits for the first reply. The server answers each batch as it reads it,
+// and the client doesn't read replies while it sends, so the replies to every batch in flight have to fit in the
+// stream's buffers (a few KiB here) or both sides would be left waiting on each other.
+pub const IN_FLIGHT: usize = 8;
+
+// What a server answers from
+pub trait ChunkStore {
+    // How many bytes of each id the store keeps
+    fn key_len(&self) -> usize;
+    fn has(&self, key: &crate::run::Key) -> bool;
+}
+
+// A merged scan is the repository the chunks would be stored in
+impl ChunkStore for crate::baseline::Baseline {
+    fn key_len(&self) -> usize {
+        crate::baseline::Baseline::key_len(self)
+    }
+
+    fn has(&self, key: &crate::run::Key) -> bool {
+        self.find(key).is_some()
+    }
+}
+
+// What a server was asked over one connection
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Served {
+    pub batches: u64,
+    pub keys: u64,
+    pub found: u64,
+}
+
+// Answers one client's batches until it says it is done
+pub fn serve<S: Read + Write, C: ChunkStore + ?Sized>(stream: S, store: &C) -> io::Result<Served> {
+    let mut stream = io::BufReader::new(stream);
+    let key_len = store.key_len();
+    let client_key_len = read_hello(&mut stream);
+    write_hello(stream.get_mut(), key_len)?;
+    check_key_len(client_key_len?, key_len)?;
+
+    let mut served = Served::default();
+    let mut ids = vec![];
+    loop {
+        let mut count = [0u8; 4];
+        stream.read_exact(&mut count)?;
+        let count = u32::from_le_bytes(count) as usize;
+        if count == 0 {
+            return Ok(served);
+        }
+        if count > MAX_BATCH_KEYS {
+            return Err(invalid(format!(
+                "the client sent a batch of {} ids, but at most {} are allowed",
+                count, MAX_BATCH_KEYS
+            )));
+        }
+        ids.resize(count * key_len, 0);
+        stream.read_exact(&mut ids)?;
+        let mut bitmap = vec![0u8; count.div_ceil(8)];
+        for (i, id) in ids.chunks(key_len).enumerate() {
+            if store.has(&crate::run::key_from(id, key_len)) {
+                bitmap[i / 8] |= 1 << (i % 8);
+                served.found += 1;
+            }
+        }
+        stream.get_mut().write_all(&bitmap)?;
+        stream.get_mut().flush()?;
+        served.batches += 1;
+        served.keys += count as u64;
+    }
+}
+
+pub struct Client<S: Read + Write> {
+    stream: S,
+    key_len: usize,
+    batches: u64,
+}
+
+impl<S: Read + Write> Client<S> {
+    // Says hello to the server and checks it uses ids of the same length
+    pub fn connect(mut stream: S, key_len: usize) -> io::Result<Client<S>> {
+        write_hello(&mut stream, key_len)?;
+        check_key_len(key_len, read_hello(&mut stream)?)?;
+        Ok(Client {
+            stream,
+            key_len,
+            batches: 0,
+        })
+    }
+
+    // Whether the server has each of the chunks, in the same order
+    pub fn query(&mut self, keys: &[crate::run::Key]) -> io::Result<Vec<bool>> {
+        let batches: Vec<&[crate::run::Key]> = keys.chunks(BATCH_KEYS).collect();
+        let mut has = Vec::with_capacity(keys.len());
+        let mut sent = 0;
+        for (answered, batch) in batches.iter().enumerate() {
+            // Keep up to IN_FLIGHT batches unanswered, then wait for the oldest
+            while sent < batches.len() && sent < answered + IN_FLIGHT {
+                self.send(batches[sent])?;
+                sent += 1;
+            }
+            let mut bitmap = vec![0u8; batch.len().div_ceil(8)];
+            self.stream.read_exact(&mut bitmap)?;
+            has.extend((0..batch.len()).map(|i| bitmap[i / 8] & (1 << (i % 8)) != 0));
+        }
+        Ok(has)
+    }
+
+    // How many batches have been sent, which is how many round trips it would have taken without keeping several in
+    // flight
+    pub fn batches(&self) -> u64 {
+        self.batches
+    }
+
+    // Tells the server the client is done, and returns the stream
+    pub fn finish(mut self) -> io::Result<S> {
+        self.stream.write_all(&0u32.to_le_bytes())?;
+        self.stream.flush()?;
+        Ok(self.stream)
+    }
+
+    fn send(&mut self, batch: &[crate::run::Key]) -> io::Result<()> {
+        let mut message = Vec::with_capacity(4 + batch.len() * self.key_len);
+        message.extend_from_slice(&(batch.len() as u32).to_le_bytes());
+        for key in batch {
+            message.extend_from_slice(&key[..self.key_len]);
+        }
+        self.stream.write_all(&message)?;
+        self.stream.flush()?;
+        self.batches += 1;
+        Ok(())
+    }
+}
+
+fn write_hello<W: Write>(mut out: W, key_len: usize) -> io::Result<()> {
+    out.write_all(&MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    out.write_all(&[key_len as u8])?;
+    out.flush()
+}
+
+// Returns the key length the other side uses
+fn read_hello<R: Read>(mut input: R) -> io::Result<usize> {
+    let mut hello = [0u8; 7];
+    input.read_exact(&mut hello)?;
+    if hello[0..4] != MAGIC {
+        return Err(invalid(
+            "the other side doesn't speak this protocol".to_string(),
+        ));
+    }
+    let version = u16::from_le_bytes([hello[4], hello[5]]);
+    if version != FORMAT_VERSION {
+        return Err(invalid(format!(
+            "the other side speaks version {} of the protocol, but only version {} is understood",
+            version, FORMAT_VERSION
+        )));
+    }
+    let key_len = hello[6] as usize;
+    if key_len == 0 || key_len > crate::MAX_KEY_LEN {
+        return Err(invalid(format!("the other side uses {} byte ids", key_len)));
+    }
+    Ok(key_len)
+}
+
+fn check_key_len(client: usize, server: usize) -> io::Result<()> {
+    if client != server {
+        return Err(invalid(format!(
+            "the client uses {} byte ids, but the server uses {} byte ids",
+            client, server
+        )));
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections;
+    use std::net;
+    use std::thread;
+
+    struct Keys(collections::HashSet<crate::run::Key>);
+
+    impl crate::have::ChunkStore for Keys {
+        fn key_len(&self) -> usize {
+            crate::KEY_LEN
+        }
+
+        fn has(&self, key: &crate::run::Key) -> bool {
+            self.0.contains(key)
+        }
+    }
+
+    fn key(i: u64) -> crate::run::Key {
+        use sha3::Digest;
+        crate::run::key_from(&sha3::Sha3_256::digest(i.to_le_bytes()), crate::KEY_LEN)
+    }
+
+    #[test]
+    fn test_query() {
+        // The server has every third chunk
+        let store = Keys((0..30_000).step_by(3).map(key).collect());
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut served = vec![];
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                served.push(crate::have::serve(stream, &store).map_err(|e| e.kind()));
+            }
+            served
+        });
+
+        let keys: Vec<crate::run::Key> = (0..30_000).map(key).collect();
+        let mut client =
+            crate::have::Client::connect(net::TcpStream::connect(address).unwrap(), crate::KEY_LEN)
+                .unwrap();
+        let has = client.query(&keys).unwrap();
+        assert_eq!(has.len(), 30_000);
+        assert!(has.iter().enumerate().all(|(i, &has)| has == (i % 3 == 0)));
+        assert_eq!(client.query(&[]).unwrap(), Vec::<bool>::new());
+        assert_eq!(
+            client.query(&keys[..5]).unwrap(),
+            [true, false, false, true, false]
+        );
+        assert_eq!(
+            client.batches(),
+            30_000u64.div_ceil(crate::have::BATCH_KEYS as u64) + 1
+        );
+        client.finish().unwrap();
+
+        // Ids of different lengths can't be compared
+        let refused = crate::have::Client::connect(net::TcpStream::connect(address).unwrap(), 16);
+        assert_eq!(
+            refused.err().unwrap().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        let served = server.join().unwrap();
+        let first = served[0].unwrap();
+        assert_eq!((first.keys, first.found), (30_005, 10_002));
+        assert_eq!(served[1], Err(std::io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::path;
+use std::time;
+
+use redb::ReadableTable;
+use serde_derive::{Deserialize, Serialize};
+
+// Kept in the output directory across scans. Unlike everything else there, a fresh scan doesn't clear it out.
+pub const HISTORY_NAME: &str = "history.redb";
+
+// The file cache: the chunks of each file by its path
+pub const FILES: redb::TableDefinition<&str, &[u8]> = redb::TableDefinition::new("files");
+// Named settings, such as the chunk settings the file cache was made with
+pub const SETTINGS: redb::TableDefinition<&str, &str> = redb::TableDefinition::new("settings");
+// The statistics of every merge, by when it finished in milliseconds since the Unix epoch
+const RUNS: redb::TableDefinition<u64, &[u8]> = redb::TableDefinition::new("runs");
+
+// One merge of the output directory and what it found
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    // When the merge finished, in milliseconds since the Unix epoch
+    pub finished: u64,
+    pub files: u64,
+    pub statistics: crate::merge::Statistics,
+}
+
+// Opens the database in the output directory, creating it the first time. Only one process can have it open at once.
+pub fn open(out_dir: &path::Path) -> io::Result<redb::Database> {
+    redb::Database::create(out_dir.join(HISTORY_NAME)).map_err(io::Error::other)
+}
+
+// Adds a merge to the history
+pub fn record_run(db: &redb::Database, run: &Run) -> io::Result<()> {
+    let value = bincode::serialize(run).map_err(io::Error::other)?;
+    let transaction = db.begin_write().map_err(io::Error::other)?;
+    let mut runs = transaction.open_table(RUNS).map_err(io::Error::other)?;
+    runs.insert(run.finished, value.as_slice())
+        .map_err(io::Error::other)?;
+    drop(runs);
+    transaction.commit().map_err(io::Error::other)
+}
+
+// Every merge in the history, oldest first
+pub fn runs(db: &redb::Database) -> io::Result<Vec<Run>> {
+    let transaction = db.begin_read().map_err(io::Error::other)?;
+    let table = match transaction.open_table(RUNS) {
+        Ok(table) => table,
+        Err(redb::TableError::TableDoesNotExist(_)) => return Ok(vec![]),
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    let mut runs = vec![];
+    for entry in table.iter().map_err(io::Error::other)? {
+        let (_, value) = entry.map_err(io::Error::other)?;
+        runs.push(bincode::deserialize(value.value()).map_err(io::Error::other)?);
+    }
+    Ok(runs)
+}
+
+// The time now, in milliseconds since the Unix epoch
+pub fn now_ms() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// Milliseconds since the Unix epoch as a UTC date and time
+pub fn format_time(ms: u64) -> String {
+    let secs = ms / 1000;
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_runs() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_history_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let db = crate::history::open(&dir).unwrap();
+        assert_eq!(crate::history::runs(&db).unwrap(), vec![]);
+        let run = |finished, unique_chunk_bytes| crate::history::Run {
+            finished,
+            files: 2,
+            statistics: crate::merge::Statistics {
+                unique_chunk_bytes,
+                ..Default::default()
+            },
+        };
+        crate::history::record_run(&db, &run(2000, 20)).unwrap();
+        crate::history::record_run(&db, &run(1000, 10)).unwrap();
+        drop(db);
+
+        // The history outlives the process that wrote it, and is in the order the merges finished
+        let db = crate::history::open(&dir).unwrap();
+        assert_eq!(
+            crate::history::runs(&db).unwrap(),
+            vec![run(1000, 10), run(2000, 20)]
+        );
+
+        assert_eq!(crate::history::format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(
+            crate::history::format_time(1_700_000_000_123),
+            "2023-11-14 22:13:20"
+        );
+
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+// HyperLogLog estimates the number of distinct items in a stream using a fixed amount of memory, no matter how many
+// items there are. Each item's hash picks one of 2^precision registers (using the top bits) and the register remembers
+// the longest run of leading zeros seen in the rest of the hash. Long runs of zeros are rare, so the longest run says
+// something about how many distinct hashes that register has seen; averaging across many registers makes the estimate
+// accurate. The standard error is 1.04 / sqrt(2^precision).
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    // Precision must be between 4 and 18. Memory use is 2^precision bytes.
+    pub fn new(precision: u8) -> HyperLogLog {
+        assert!(
+            (4..=18).contains(&precision),
+            "precision must be between 4 and 18"
+        );
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    // Adds an item by its hash. The hash must be uniformly distributed, like the chunk ids, which are already a
+    // cryptographic hash of the chunk contents.
+    pub fn insert(&mut self, hash: u64) {
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() as u8).min(64 - self.precision) + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    // Returns the estimated number of distinct items inserted so far.
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let raw = alpha * m * m / sum;
+
+        // The raw estimate is biased when only a few registers have been touched. Linear counting on the number of
+        // empty registers is much better in that range.
+        let empty = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && empty > 0 {
+            m * (m / empty as f64).ln()
+        } else {
+            raw
+        }
+    }
+
+    // The relative standard error of the estimate, e.g. 0.008 for +/- 0.8%
+    pub fn standard_error(&self) -> f64 {
+        1.04 / (self.registers.len() as f64).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_hyperloglog_estimate() {
+        let mut hll = crate::hll::HyperLogLog::new(14);
+
+        // Insert 100,000 distinct items, each of them three times
+        for _ in 0..3 {
+            for i in 0..100_000u64 {
+                hll.insert(crate::hll::tests::splitmix(i));
+            }
+        }
+
+        // Well within four standard errors
+        let error = (hll.estimate() - 100_000.0).abs() / 100_000.0;
+        assert!(error < 4.0 * hll.standard_error(), "error was {}", error);
+    }
+
+    fn splitmix(mut x: u64) -> u64 {
+        x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^ (x >> 31)
+    }
+}
@@ -0,0 +1,651 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Seek};
+use std::ops;
+
+// A disk image only stores the parts of the virtual machine's disk (the guest's disk) that the guest has written, in
+// clusters or grains placed wherever there was room when they were written, with tables saying where each one went.
+// Chunking the image file itself mixes those tables in with the data and lines the data up differently in every
+// image, so two images of the same disk look unrelated. Reading through the tables gives back the guest's disk, which
+// is what gets chunked instead.
+
+const SECTOR: u64 = 512;
+
+// The magic numbers at the start of a qcow2 or sparse VMDK image, and in the footer at the end of a VHD
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+const VMDK_MAGIC: &[u8] = b"KDMV";
+const VHD_COOKIE: &[u8] = b"conectix";
+const VHD_SPARSE_COOKIE: &[u8] = b"cxsparse";
+
+// The bits of a qcow2 L1 or L2 table entry that hold an offset in the image, and the flags in an L2 entry
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const QCOW2_COMPRESSED: u64 = 1 << 62;
+const QCOW2_ZERO: u64 = 1;
+// The incompatible features we can read: a dirty image (whose reference counts may be off, which doesn't matter here)
+// and the compression type (which only matters for compressed clusters, which aren't read anyway)
+const QCOW2_KNOWN_FEATURES: u64 = 1 | 1 << 3;
+
+// A VMDK grain table entry for a grain the guest never wrote, and for one it wrote zeros to
+const VMDK_UNALLOCATED: u32 = 0;
+const VMDK_ZERO: u32 = 1;
+const VMDK_COMPRESSED: u32 = 1 << 16;
+// The grain directory offset of a stream-optimized VMDK, whose directory is at the end of the file
+const VMDK_GD_AT_END: u64 = u64::MAX;
+
+// A VHD block allocation table entry for a block the guest never wrote
+const VHD_UNALLOCATED: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Qcow2,
+    Vmdk,
+    Vhd,
+}
+
+// Where a piece of the guest's disk is stored in the image file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Piece {
+    pub guest: u64,
+    pub host: u64,
+    pub len: u64,
+}
+
+// The guest's disk, as far as the image holds it. Everything outside the pieces reads as zeros, or for an image with a
+// backing file (or a differencing disk's parent), as whatever that file has there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub format: Format,
+    // How large the guest's disk is
+    pub len: u64,
+    // In order of where they are on the guest's disk, with pieces that are next to each other in both merged
+    pub pieces: Vec<Piece>,
+}
+
+impl Image {
+    // The parts of the guest's disk the image holds data for, with neighbouring pieces joined up
+    pub fn extents(&self) -> Vec<ops::Range<u64>> {
+        let mut extents: Vec<ops::Range<u64>> = vec![];
+        for piece in self.pieces.iter() {
+            match extents.last_mut() {
+                Some(last) if last.end == piece.guest => last.end += piece.len,
+                _ => extents.push(piece.guest..piece.guest + piece.len),
+            }
+        }
+        extents
+    }
+
+    fn new(format: Format, len: u64, mut pieces: Vec<Piece>) -> Image {
+        pieces.sort_by_key(|p| p.guest);
+        let mut merged: Vec<Piece> = Vec::with_capacity(pieces.len());
+        for piece in pieces {
+            match merged.last_mut() {
+                Some(last)
+                    if last.guest + last.len == piece.guest
+                        && last.host + last.len == piece.host =>
+                {
+                    last.len += piece.len
+                }
+                _ => merged.push(piece),
+            }
+        }
+        Image {
+            format,
+            len,
+            pieces: merged,
+        }
+    }
+}
+
+// Reads the tables of a qcow2, sparse VMDK or VHD image. Returns None if the file isn't one of those, and an error with
+// the kind Unsupported for images that are, but that can't be read this way (i.e. compressed or encrypted ones).
+pub fn open(file: &mut fs::File) -> io::Result<Option<Image>> {
+    let file_len = file.metadata()?.len();
+    if file_len < SECTOR {
+        return Ok(None);
+    }
+    let head = read_at(file, 0, SECTOR as usize)?;
+    if head.starts_with(QCOW2_MAGIC) {
+        return qcow2(file, file_len).map(Some);
+    }
+    if head.starts_with(VMDK_MAGIC) {
+        return vmdk(file, file_len, &head).map(Some);
+    }
+    let footer = read_at(file, file_len - SECTOR, SECTOR as usize)?;
+    if footer.starts_with(VHD_COOKIE) {
+        return vhd(file, file_len, &footer).map(Some);
+    }
+    Ok(None)
+}
+
+// qcow2 (QEMU): a two-level table maps every cluster of the guest's disk to one in the image
+fn qcow2(file: &mut fs::File, file_len: u64) -> io::Result<Image> {
+    let header = read_at(file, 0, 72)?;
+    let version = be_u32(&header[4..]);
+    let cluster_bits = be_u32(&header[20..]);
+    let len = be_u64(&header[24..]);
+    let l1_entries = be_u32(&header[36..]) as u64;
+    let l1_offset = be_u64(&header[40..]);
+    if !(2..=3).contains(&version) {
+        return Err(unsupported(format!("qcow2 version {}", version)));
+    }
+    if be_u32(&header[32..]) != 0 {
+        return Err(unsupported("encrypted qcow2 images".to_string()));
+    }
+    if version >= 3 {
+        let features = be_u64(&read_at(file, 72, 8)?);
+        if features & !QCOW2_KNOWN_FEATURES != 0 {
+            return Err(unsupported(format!(
+                "qcow2 incompatible features {:#x}",
+                features
+            )));
+        }
+    }
+    if !(9..=21).contains(&cluster_bits) {
+        return Err(invalid(format!(
+            "a qcow2 cluster size of 2^{}",
+            cluster_bits
+        )));
+    }
+    let cluster = 1u64 << cluster_bits;
+    let l2_entries = cluster / 8;
+    check_table(l1_offset, l1_entries * 8, file_len)?;
+
+    let l1 = read_at(file, l1_offset, (l1_entries * 8) as usize)?;
+    let mut pieces = vec![];
+    for (i, l1_entry) in l1.chunks_exact(8).map(be_u64).enumerate() {
+        let l2_offset = l1_entry & QCOW2_OFFSET_MASK;
+        if l2_offset == 0 {
+            continue;
+        }
+        check_table(l2_offset, cluster, file_len)?;
+        let l2 = read_at(file, l2_offset, cluster as usize)?;
+        for (j, l2_entry) in l2.chunks_exact(8).map(be_u64).enumerate() {
+            let guest = (i as u64 * l2_entries + j as u64) * cluster;
+            if guest >= len {
+                break;
+            }
+            if l2_entry & QCOW2_COMPRESSED != 0 {
+                return Err(unsupported("compressed qcow2 clusters".to_string()));
+            }
+            let host = l2_entry & QCOW2_OFFSET_MASK;
+            if host == 0 || l2_entry & QCOW2_ZERO != 0 {
+                continue;
+            }
+            pieces.push(piece(guest, host, cluster.min(len - guest), file_len)?);
+        }
+    }
+    Ok(Image::new(Format::Qcow2, len, pieces))
+}
+
+// Hosted sparse VMDK (VMware): a grain directory points to grain tables, which point to the grains of the guest's disk
+fn vmdk(file: &mut fs::File, file_len: u64, header: &[u8]) -> io::Result<Image> {
+    let flags = le_u32(&header[8..]);
+    let sectors = le_u64(&header[12..]);
+    let grain_sectors = le_u64(&header[20..]);
+    let gt_entries = le_u32(&header[44..]) as u64;
+    let gd_offset = le_u64(&header[56..]);
+    let compression = u16::from_le_bytes([header[77], header[78]]);
+    if flags & VMDK_COMPRESSED != 0 || compression != 0 {
+        return Err(unsupported(
+            "compressed (stream-optimized) VMDK images".to_string(),
+        ));
+    }
+    if gd_offset == VMDK_GD_AT_END {
+        return Err(unsupported(
+            "VMDK images with the grain directory at the end".to_string(),
+        ));
+    }
+    // Grains are a power of two sectors, and at most a few MiB in practice
+    if grain_sectors == 0
+        || grain_sectors > 1 << 16
+        || gt_entries == 0
+        || sectors > u64::MAX / SECTOR
+    {
+        return Err(invalid(format!(
+            "a VMDK capacity of {} sectors in grains of {}",
+            sectors, grain_sectors
+        )));
+    }
+    let len = sectors * SECTOR;
+    let grain = grain_sectors * SECTOR;
+    let gd_entries = len.div_ceil(grain * gt_entries);
+    let gd_offset = gd_offset.saturating_mul(SECTOR);
+    check_table(gd_offset, gd_entries * 4, file_len)?;
+
+    let gd = read_at(file, gd_offset, (gd_entries * 4) as usize)?;
+    let mut pieces = vec![];
+    for (i, gt_offset) in gd.chunks_exact(4).map(le_u32).enumerate() {
+        if gt_offset == 0 {
+            continue;
+        }
+        let gt_offset = gt_offset as u64 * SECTOR;
+        check_table(gt_offset, gt_entries * 4, file_len)?;
+        let gt = read_at(file, gt_offset, (gt_entries * 4) as usize)?;
+        for (j, grain_offset) in gt.chunks_exact(4).map(le_u32).enumerate() {
+            let guest = (i as u64 * gt_entries + j as u64) * grain;
+            if guest >= len {
+                break;
+            }
+            if grain_offset == VMDK_UNALLOCATED || grain_offset == VMDK_ZERO {
+                continue;
+            }
+            pieces.push(piece(
+                guest,
+                grain_offset as u64 * SECTOR,
+                grain.min(len - guest),
+                file_len,
+            )?);
+        }
+    }
+    Ok(Image::new(Format::Vmdk, len, pieces))
+}
+
+// VHD (Hyper-V and Virtual PC): a fixed disk is the guest's disk followed by a footer, and a dynamic one has a table
+// of where each block of the guest's disk is. Each block starts with a bitmap of the sectors in it the guest wrote; the
+// ones it didn't are zeros, and are read as they are.
+fn vhd(file: &mut fs::File, file_len: u64, footer: &[u8]) -> io::Result<Image> {
+    let header_offset = be_u64(&footer[16..]);
+    let len = be_u64(&footer[48..]);
+    match be_u32(&footer[60..]) {
+        // Fixed
+        2 => Ok(Image::new(
+            Format::Vhd,
+            len,
+            vec![piece(0, 0, len, file_len - SECTOR)?],
+        )),
+        // Dynamic
+        3 => {
+            check_table(header_offset, 2 * SECTOR, file_len)?;
+            let header = read_at(file, header_offset, 2 * SECTOR as usize)?;
+            if !header.starts_with(VHD_SPARSE_COOKIE) {
+                return Err(invalid("a dynamic VHD without its header".to_string()));
+            }
+            let bat_offset = be_u64(&header[16..]);
+            let bat_entries = be_u32(&header[28..]) as u64;
+            let block = be_u32(&header[32..]) as u64;
+            if block == 0 || !block.is_multiple_of(SECTOR) {
+                return Err(invalid(format!("a VHD block size of {}", block)));
+            }
+            let bitmap = (block / SECTOR).div_ceil(8).next_multiple_of(SECTOR);
+            check_table(bat_offset, bat_entries * 4, file_len)?;
+
+            let bat = read_at(file, bat_offset, (bat_entries * 4) as usize)?;
+            let mut pieces = vec![];
+            for (i, sector) in bat.chunks_exact(4).map(be_u32).enumerate() {
+                let guest = i as u64 * block;
+                if guest >= len {
+                    break;
+                }
+                if sector == VHD_UNALLOCATED {
+                    continue;
+                }
+                pieces.push(piece(
+                    guest,
+                    sector as u64 * SECTOR + bitmap,
+                    block.min(len - guest),
+                    file_len,
+                )?);
+            }
+            Ok(Image::new(Format::Vhd, len, pieces))
+        }
+        4 => Err(unsupported("differencing VHD disks".to_string())),
+        disk_type => Err(invalid(format!("a VHD disk type of {}", disk_type))),
+    }
+}
+
+// Reads the guest's disk out of an image, with zeros wherever the image holds nothing
+pub struct Reader {
+    file: fs::File,
+    image: Image,
+    position: u64,
+}
+
+impl Reader {
+    pub fn new(file: fs::File, image: Image) -> Reader {
+        Reader {
+            file,
+            image,
+            position: 0,
+        }
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.image.len {
+            return Ok(0);
+        }
+        let wanted = (buf.len() as u64).min(self.image.len - self.position);
+        // The first piece that ends after the position, which may not have started yet
+        let position = self.position;
+        let i = self
+            .image
+            .pieces
+            .partition_point(|p| p.guest + p.len <= position);
+        let read = match self.image.pieces.get(i) {
+            Some(p) if p.guest <= position => {
+                let len = wanted.min(p.guest + p.len - position) as usize;
+                self.file
+                    .seek(io::SeekFrom::Start(p.host + position - p.guest))?;
+                let read = self.file.read(&mut buf[..len])?;
+                if read == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the image is shorter than its tables say",
+                    ));
+                }
+                read
+            }
+            next => {
+                let len = wanted.min(next.map_or(u64::MAX, |p| p.guest - position)) as usize;
+                buf[..len].fill(0);
+                len
+            }
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for Reader {
+    fn seek(&mut self, from: io::SeekFrom) -> io::Result<u64> {
+        let position = match from {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            io::SeekFrom::End(offset) => self.image.len.checked_add_signed(offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't seek before the start of the disk",
+            )),
+        }
+    }
+}
+
+// A piece of the guest's disk, which has to be inside the image
+fn piece(guest: u64, host: u64, len: u64, file_len: u64) -> io::Result<Piece> {
+    if host.checked_add(len).is_none_or(|end| end > file_len) {
+        return Err(invalid(format!(
+            "data at {} that runs past the end of the image",
+            host
+        )));
+    }
+    Ok(Piece { guest, host, len })
+}
+
+// Tables are read into memory whole, so one that is past the end of the file is caught before it is allocated
+fn check_table(offset: u64, len: u64, file_len: u64) -> io::Result<()> {
+    if offset.checked_add(len).is_none_or(|end| end > file_len) {
+        return Err(invalid(format!(
+            "a table at {} that runs past the end of the image",
+            offset
+        )));
+    }
+    Ok(())
+}
+
+fn read_at(file: &mut fs::File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0; len];
+    file.seek(io::SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut array = [0u8; 8];
+    array.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(array)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut array = [0u8; 8];
+    array.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(array)
+}
+
+fn unsupported(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} can't be read", what),
+    )
+}
+
+fn invalid(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the image has {}", what),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections;
+    use std::fs;
+    use std::io::Read;
+
+    const CLUSTER: usize = 65536;
+
+    // Data that doesn't repeat
+    fn guest_data(len: usize) -> Vec<u8> {
+        let mut x = 7u64;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    // Stores the data the way qemu-img would, in 64KiB clusters, but with the clusters in reverse order and the ones
+    // that are all zeros left out
+    fn qcow2(data: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; 3 * CLUSTER];
+        image[0..4].copy_from_slice(b"QFI\xfb");
+        image[4..8].copy_from_slice(&3u32.to_be_bytes());
+        image[20..24].copy_from_slice(&16u32.to_be_bytes());
+        image[24..32].copy_from_slice(&(data.len() as u64).to_be_bytes());
+        image[36..40].copy_from_slice(&1u32.to_be_bytes());
+        image[40..48].copy_from_slice(&(CLUSTER as u64).to_be_bytes());
+        image[100..104].copy_from_slice(&104u32.to_be_bytes());
+        let l2 = (2 * CLUSTER) as u64 | 1 << 63;
+        image[CLUSTER..CLUSTER + 8].copy_from_slice(&l2.to_be_bytes());
+        for (i, cluster) in data.chunks(CLUSTER).enumerate().rev() {
+            if cluster.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let entry = 2 * CLUSTER + i * 8;
+            let host = image.len() as u64 | 1 << 63;
+            image[entry..entry + 8].copy_from_slice(&host.to_be_bytes());
+            image.extend_from_slice(cluster);
+            image.resize(image.len().next_multiple_of(CLUSTER), 0);
+        }
+        image
+    }
+
+    // A hosted sparse VMDK with 64KiB grains, the grain of zeros marked as such
+    fn vmdk(data: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; CLUSTER];
+        image[0..4].copy_from_slice(b"KDMV");
+        image[4..8].copy_from_slice(&1u32.to_le_bytes());
+        image[12..20].copy_from_slice(&(data.len() as u64 / 512).to_le_bytes());
+        image[20..28].copy_from_slice(&128u64.to_le_bytes());
+        image[44..48].copy_from_slice(&512u32.to_le_bytes());
+        image[56..64].copy_from_slice(&1u64.to_le_bytes());
+        image[512..516].copy_from_slice(&2u32.to_le_bytes());
+        for (i, grain) in data.chunks(CLUSTER).enumerate() {
+            let entry = 1024 + i * 4;
+            let sector = if grain.iter().all(|&b| b == 0) {
+                1
+            } else {
+                let sector = image.len() as u32 / 512;
+                image.extend_from_slice(grain);
+                sector
+            };
+            image[entry..entry + 4].copy_from_slice(&sector.to_le_bytes());
+        }
+        image
+    }
+
+    // A dynamic VHD with 64KiB blocks, leaving the block of zeros unallocated
+    fn vhd(data: &[u8]) -> Vec<u8> {
+        let mut footer = vec![0u8; 512];
+        footer[0..8].copy_from_slice(b"conectix");
+        footer[16..24].copy_from_slice(&512u64.to_be_bytes());
+        footer[48..56].copy_from_slice(&(data.len() as u64).to_be_bytes());
+        footer[60..64].copy_from_slice(&3u32.to_be_bytes());
+        let mut image = footer.clone();
+        image.resize(4096, 0);
+        image[512..520].copy_from_slice(b"cxsparse");
+        image[528..536].copy_from_slice(&2048u64.to_be_bytes());
+        image[540..544].copy_from_slice(&(data.len().div_ceil(CLUSTER) as u32).to_be_bytes());
+        image[544..548].copy_from_slice(&(CLUSTER as u32).to_be_bytes());
+        image[2048..4096].fill(0xff);
+        for (i, block) in data.chunks(CLUSTER).enumerate() {
+            if block.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let entry = 2048 + i * 4;
+            let sector = image.len() as u32 / 512;
+            image[entry..entry + 4].copy_from_slice(&sector.to_be_bytes());
+            // The bitmap of the sectors written, padded to a sector
+            image.extend_from_slice(&[0xff; 512]);
+            image.extend_from_slice(block);
+        }
+        image.extend_from_slice(&footer);
+        image
+    }
+
+    #[test]
+    fn test_images() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_image_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // With a cluster of zeros, which none of the images store
+        let mut data = guest_data(5 * CLUSTER + 4096);
+        data[CLUSTER..2 * CLUSTER].fill(0);
+
+        for (name, image, format) in [
+            ("qcow2", qcow2(&data), crate::image::Format::Qcow2),
+            ("vmdk", vmdk(&data), crate::image::Format::Vmdk),
+            ("vhd", vhd(&data), crate::image::Format::Vhd),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, &image).unwrap();
+            let mut file = fs::File::open(&path).unwrap();
+            let image = crate::image::open(&mut file).unwrap().unwrap();
+            assert_eq!(image.format, format, "{}", name);
+            assert_eq!(image.len, data.len() as u64, "{}", name);
+            assert_eq!(
+                image.extents(),
+                vec![0..CLUSTER as u64, 2 * CLUSTER as u64..data.len() as u64],
+                "{}",
+                name
+            );
+
+            let mut guest = vec![];
+            crate::image::Reader::new(file, image)
+                .read_to_end(&mut guest)
+                .unwrap();
+            assert!(guest == data, "{}", name);
+        }
+
+        // A raw disk, or anything else, isn't an image
+        fs::write(dir.join("raw"), &data).unwrap();
+        let mut file = fs::File::open(dir.join("raw")).unwrap();
+        assert_eq!(crate::image::open(&mut file).unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_image_chunks_match_raw_disk() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_image_chunks_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = guest_data(20 * CLUSTER);
+        fs::write(dir.join("raw"), &data).unwrap();
+        fs::write(dir.join("qcow2"), qcow2(&data)).unwrap();
+
+        // The chunks of each file, by its name
+        let chunks = |disk_images| {
+            let mut chunks = collections::BTreeMap::new();
+            let mut found = vec![vec![], vec![]];
+            let scanned = crate::pipeline::run(
+                crate::pipeline::Input::Walk(vec![crate::pipeline::Root {
+                    dir: dir.clone(),
+                    exclude: ignore::gitignore::Gitignore::empty(),
+                }]),
+                &crate::pipeline::Options {
+                    chunking: crate::pipeline::Chunking::Variable(
+                        rabin::chunker::ChunkerBuilder::new(
+                            crate::MIN_CHUNK_SIZE,
+                            crate::MAX_CHUNK_SIZE,
+                        )
+                        .unwrap(),
+                    ),
+                    key_len: crate::KEY_LEN,
+                    threads: 2,
+                    hash_cpus: None,
+                    io_cpus: None,
+                    symlinks: crate::walk::Symlinks::Skip,
+                    detect_hard_links: true,
+                    streams: false,
+                    mmap: true,
+                    uring: None,
+                    direct_io: false,
+                    resume: vec![],
+                    compress_every: None,
+                    only: None,
+                    min_file_size: None,
+                    max_file_size: None,
+                    cache: None,
+                    sub_blocks: None,
+                    disk_images,
+                    anchors: vec![],
+                    compressed: crate::compressed::Policy::Chunk,
+                    archives: false,
+                },
+                &mut |event| {
+                    if let crate::pipeline::Event::Hashed(batch) = event {
+                        found[batch.file as usize]
+                            .extend(batch.chunks.iter().map(|c| (c.offset, c.size, c.key)));
+                    }
+                },
+            );
+            for (path, mut found) in scanned.paths.iter().zip(found) {
+                found.sort();
+                let name = std::path::Path::new(path).file_name().unwrap();
+                chunks.insert(name.to_string_lossy().into_owned(), found);
+            }
+            chunks
+        };
+
+        // The guest's disk in the image is chunked just like the raw disk, which the image file itself isn't
+        let images = chunks(true);
+        assert!(!images["raw"].is_empty());
+        assert_eq!(images["qcow2"], images["raw"]);
+        let files = chunks(false);
+        assert_eq!(files["raw"], images["raw"]);
+        assert_ne!(files["qcow2"], files["raw"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
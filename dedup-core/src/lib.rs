@@ -0,0 +1,59 @@
+// The chunking, indexing and merging behind test_chunks, for programs that want to measure deduplication themselves.
+// A scan walks directories (walk) and chunks and hashes every file it finds (pipeline), then sorts the chunk ids into
+// run files (run) that are committed to the output directory through a write-ahead log (txn, wal). Merging combines
+// the runs into one file of unique chunks along with the statistics the report is made from (merge).
+pub mod advise;
+pub mod affinity;
+pub mod aggregate;
+pub mod anchor;
+pub mod approximate;
+pub mod archive;
+pub mod baseline;
+pub mod cache;
+pub mod catalog;
+pub mod checkpoint;
+pub mod collisions;
+pub mod compare;
+pub mod compressed;
+pub mod decompress;
+pub mod dedup_archive;
+pub mod delta;
+pub mod differential;
+pub mod direct;
+pub mod entropy;
+pub mod files;
+pub mod filetype;
+pub mod have;
+pub mod history;
+pub mod hll;
+pub mod image;
+pub mod lock;
+pub mod memory;
+pub mod merge;
+pub mod multipart;
+pub mod oci;
+pub mod pipeline;
+pub mod popularity;
+pub mod reflink;
+pub mod run;
+pub mod signature;
+pub mod sizes;
+pub mod skipped;
+pub mod stability;
+pub mod stages;
+pub mod testdata;
+pub mod txn;
+pub mod uring;
+pub mod wal;
+pub mod walk;
+
+// Chunk ids are 144 bits of the chunk's SHA3 hash unless the scan is told otherwise, and can be the whole hash
+pub const KEY_LEN: usize = 18;
+pub const MAX_KEY_LEN: usize = 32;
+// These constants were calculated based on information provided in http://www.hpl.hp.com/techreports/2005/HPL-2005-30R1.pdf
+pub const MIN_CHUNK_SIZE: usize = 1856;
+pub const MAX_CHUNK_SIZE: usize = 11300;
+
+pub fn hex_key(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
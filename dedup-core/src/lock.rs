@@ -0,0 +1,334 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::sync::atomic;
+use std::time;
+
+const EXCLUSIVE_LOCK_NAME: &str = "lock.exclusive";
+const SHARED_LOCK_PREFIX: &str = "lock.shared.";
+// Lock files are written under a name of this form before they are put in place, and stale ones are moved to one
+// before they are removed. Neither looks like a lock.
+const NEW_LOCK_PREFIX: &str = "lock.new.";
+const STALE_LOCK_PREFIX: &str = "lock.stale.";
+
+// A lock taken on another machine (sharing the directory over NFS, say) that hasn't been refreshed in this long is
+// considered abandoned, since there is no way to ask whether the process that owns it is still running
+const STALE_AFTER: time::Duration = time::Duration::from_secs(30 * 60);
+// How often a lock that is held has to be refreshed
+pub const REFRESH_EVERY: time::Duration = time::Duration::from_secs(60);
+
+// Distinguishes the files of multiple locks taken by the same process
+static NEXT_LOCK_FILE: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockKind {
+    // Any number of readers may hold a shared lock at once, as long as nobody holds the exclusive lock
+    Shared,
+    // Only one writer may hold the exclusive lock, and only when there are no readers
+    Exclusive,
+}
+
+// A cooperative lock on an output directory, implemented with lock files so that it works across processes (and
+// machines, for shared filesystems). Each lock file records the owning host and process along with a timestamp that
+// is refreshed while the lock is held, so that locks left behind by a crashed process can be detected and removed.
+// The lock is released when it is dropped.
+pub struct Lock {
+    path: path::PathBuf,
+    last_refresh: time::Instant,
+}
+
+impl Lock {
+    // Takes the lock on 'dir', failing with ErrorKind::WouldBlock if a conflicting lock is held by a live process.
+    pub fn acquire(dir: &path::Path, kind: LockKind) -> io::Result<Lock> {
+        match kind {
+            LockKind::Exclusive => {
+                let path = dir.join(EXCLUSIVE_LOCK_NAME);
+                create_lock_file(&path)?;
+                let lock = Lock {
+                    path,
+                    last_refresh: time::Instant::now(),
+                };
+
+                // Readers may have snuck in before we created our file. If any of them are still alive we have to back
+                // off (dropping 'lock' removes our file).
+                if let Some(holder) = live_shared_lock(dir)? {
+                    return Err(held_by(&holder));
+                }
+                Ok(lock)
+            }
+            LockKind::Shared => {
+                let exclusive = dir.join(EXCLUSIVE_LOCK_NAME);
+                remove_if_stale(&exclusive)?;
+                if exclusive.exists() {
+                    return Err(held_by(&exclusive));
+                }
+
+                let path = dir.join(format!(
+                    "{}{}.{}",
+                    SHARED_LOCK_PREFIX,
+                    std::process::id(),
+                    NEXT_LOCK_FILE.fetch_add(1, atomic::Ordering::SeqCst)
+                ));
+                create_lock_file(&path)?;
+                let lock = Lock {
+                    path,
+                    last_refresh: time::Instant::now(),
+                };
+
+                // A writer may have taken the exclusive lock between our check and creating our file.
+                if exclusive.exists() {
+                    return Err(held_by(&exclusive));
+                }
+                Ok(lock)
+            }
+        }
+    }
+
+    // Updates the timestamp in the lock file so that other processes don't consider it stale. Cheap to call often:
+    // the file is only rewritten once a minute. Fails if the lock has been taken over by another process.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        if self.last_refresh.elapsed() < REFRESH_EVERY {
+            return Ok(());
+        }
+
+        if !is_ours(&self.path) {
+            return Err(io::Error::other(format!(
+                "the lock {:?} was taken over by another process",
+                self.path
+            )));
+        }
+        // Rewritten in place rather than truncated first, so that nobody ever reads an empty lock file. Only the
+        // timestamp changes, and it doesn't change length.
+        let mut file = fs::OpenOptions::new().write(true).open(&self.path)?;
+        let contents = lock_contents();
+        file.write_all(contents.as_bytes())?;
+        file.set_len(contents.len() as u64)?;
+        self.last_refresh = time::Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if is_ours(&self.path) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Atomically creates a lock file, replacing an existing one only if it is stale. The file is written in full under a
+// name of its own and then linked into place, which fails if the lock exists, so a lock file is never seen empty or
+// half written.
+fn create_lock_file(path: &path::Path) -> io::Result<()> {
+    remove_if_stale(path)?;
+    let new = unique_name(path, NEW_LOCK_PREFIX);
+    let mut file = fs::File::create(&new)?;
+    let linked = file
+        .write_all(lock_contents().as_bytes())
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::hard_link(&new, path));
+    let _ = fs::remove_file(&new);
+    match linked {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Err(held_by(path)),
+        linked => linked,
+    }
+}
+
+// Finds the first shared lock in the directory whose owner is still alive, cleaning up stale ones along the way.
+fn live_shared_lock(dir: &path::Path) -> io::Result<Option<path::PathBuf>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_shared = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with(SHARED_LOCK_PREFIX));
+        if is_shared {
+            remove_if_stale(&path)?;
+            if path.exists() {
+                return Ok(Some(path));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// The lock file holds three lines: the owning process id, the host it runs on, and when the lock was last refreshed
+// (in seconds since the epoch).
+fn lock_contents() -> String {
+    format!("{}\n{}\n{}\n", std::process::id(), hostname(), now_secs())
+}
+
+// Whether the lock file (still) belongs to this process
+fn is_ours(path: &path::Path) -> bool {
+    fs::read_to_string(path).is_ok_and(|contents| {
+        let mut lines = contents.lines();
+        lines.next() == Some(&std::process::id().to_string()) && lines.next() == Some(&hostname())
+    })
+}
+
+// A name in the lock's directory that no other lock file, and no other process, uses
+fn unique_name(path: &path::Path, prefix: &str) -> path::PathBuf {
+    path.with_file_name(format!(
+        "{}{}.{}",
+        prefix,
+        std::process::id(),
+        NEXT_LOCK_FILE.fetch_add(1, atomic::Ordering::SeqCst)
+    ))
+}
+
+// Removes the lock file if its owner is gone. Any number of processes may find the same lock stale at once, and one of
+// them may already have replaced it with a lock of its own, so the file is first moved out of the way (which only one
+// of them can do) and then checked to still be the stale lock that was read. If it isn't, it is put back.
+fn remove_if_stale(path: &path::Path) -> io::Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !is_stale(&contents) {
+        return Ok(());
+    }
+
+    let moved = unique_name(path, STALE_LOCK_PREFIX);
+    match fs::rename(path, &moved) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    if fs::read_to_string(&moved)? != contents {
+        // Linking doesn't replace a lock that was taken in the meantime; if one was, the lock we moved is lost, and
+        // its owner finds that out the next time it refreshes it
+        if let Err(e) = fs::hard_link(&moved, path) {
+            tracing::warn!(lock = ?path, "couldn't put back a lock that was moved: {}", e);
+        }
+    }
+    fs::remove_file(&moved)
+}
+
+// A lock is stale when its owner is known to have exited. Whether a process is running can only be asked on the same
+// machine, so a lock from another machine is only stale once it hasn't been refreshed for STALE_AFTER.
+fn is_stale(contents: &str) -> bool {
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|l| l.parse::<u32>().ok());
+    let host = lines.next();
+    let refreshed = lines.next().and_then(|l| l.parse::<u64>().ok());
+
+    // A lock file we can't parse was most likely left by a version that wrote it in place and crashed part way
+    let (pid, host, refreshed) = match (pid, host, refreshed) {
+        (Some(pid), Some(host), Some(refreshed)) => (pid, host, refreshed),
+        _ => return true,
+    };
+    let alive = if host == hostname() {
+        process_is_alive(pid)
+    } else {
+        None
+    };
+    match alive {
+        Some(alive) => !alive,
+        None => now_secs().saturating_sub(refreshed) > STALE_AFTER.as_secs(),
+    }
+}
+
+fn held_by(path: &path::Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::WouldBlock,
+        format!("the directory is locked by another process ({:?})", path),
+    )
+}
+
+fn now_secs() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    let result = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+// Signal 0 doesn't deliver anything, it just checks whether the process exists. EPERM means it exists but belongs to
+// somebody else.
+#[cfg(unix)]
+fn process_is_alive(pid: u32) -> Option<bool> {
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    Some(result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+// Without a portable way to check, the timestamp decides
+#[cfg(not(unix))]
+fn process_is_alive(_pid: u32) -> Option<bool> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::{Lock, LockKind};
+    use std::fs;
+
+    #[test]
+    fn test_shared_and_exclusive_locks() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_lock_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        {
+            let _exclusive = Lock::acquire(&dir, LockKind::Exclusive).unwrap();
+            assert!(Lock::acquire(&dir, LockKind::Exclusive).is_err());
+            assert!(Lock::acquire(&dir, LockKind::Shared).is_err());
+        }
+
+        {
+            let _first = Lock::acquire(&dir, LockKind::Shared).unwrap();
+            let _second = Lock::acquire(&dir, LockKind::Shared).unwrap();
+            assert!(Lock::acquire(&dir, LockKind::Exclusive).is_err());
+        }
+
+        // A lock left behind by a process that no longer exists is ignored. The pid is larger than any pid_max.
+        fs::write(
+            dir.join(crate::lock::EXCLUSIVE_LOCK_NAME),
+            format!(
+                "999999999\n{}\n{}\n",
+                crate::lock::hostname(),
+                crate::lock::now_secs()
+            ),
+        )
+        .unwrap();
+        Lock::acquire(&dir, LockKind::Exclusive).unwrap();
+
+        // A live owner on this machine keeps its lock however long ago it was refreshed, while a lock from another
+        // machine is only given up on once it is old
+        let exclusive = dir.join(crate::lock::EXCLUSIVE_LOCK_NAME);
+        let host = crate::lock::hostname();
+        fs::write(&exclusive, format!("{}\n{}\n0\n", std::process::id(), host)).unwrap();
+        assert!(Lock::acquire(&dir, LockKind::Exclusive).is_err());
+        let recent = crate::lock::now_secs();
+        fs::write(&exclusive, format!("1\n{}.elsewhere\n{}\n", host, recent)).unwrap();
+        assert!(Lock::acquire(&dir, LockKind::Shared).is_err());
+        fs::write(&exclusive, format!("1\n{}.elsewhere\n0\n", host)).unwrap();
+        let mut lock = Lock::acquire(&dir, LockKind::Exclusive).unwrap();
+
+        // A lock that was taken over can't be refreshed, and isn't removed by the process that lost it
+        fs::write(&exclusive, format!("1\n{}.elsewhere\n{}\n", host, recent)).unwrap();
+        lock.last_refresh -= crate::lock::REFRESH_EVERY;
+        assert!(lock.refresh().is_err());
+        drop(lock);
+        assert!(exclusive.exists());
+
+        // Nothing but the locks is left behind
+        fs::remove_file(&exclusive).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use std::fs;
+
+// A scan without --memory sorts in a quarter of the memory that is available when it starts. The rest is left for the
+// hashing pipeline, the page cache that makes reading files fast, and everything else running on the machine.
+const DEFAULT_FRACTION: u64 = 4;
+
+// How much memory a scan uses for sorting when it isn't told, or None if there is no way to tell on this platform
+pub fn default_budget() -> Option<u64> {
+    available().map(|bytes| bytes / DEFAULT_FRACTION)
+}
+
+// The memory that can be used without pushing anything else out. A container's memory limit counts too, since going
+// over it gets the scan killed no matter how much the machine has.
+pub fn available() -> Option<u64> {
+    let system = system_available()?;
+    Some(cgroup_available().map_or(system, |cgroup| cgroup.min(system)))
+}
+
+#[cfg(target_os = "linux")]
+fn system_available() -> Option<u64> {
+    parse_meminfo(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+// Other systems don't say how much of their memory is only being used as cache, so count all of it and let the
+// fraction leave room
+#[cfg(all(unix, not(target_os = "linux")))]
+fn system_available() -> Option<u64> {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if pages <= 0 || page_size <= 0 {
+        return None;
+    }
+    Some(pages as u64 * page_size as u64)
+}
+
+#[cfg(not(unix))]
+fn system_available() -> Option<u64> {
+    None
+}
+
+// MemAvailable is the kernel's estimate of what can be allocated without swapping, counting cache it would drop
+#[cfg(any(target_os = "linux", test))]
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+// What is left under the memory limit of the cgroup the scan runs in, for both versions of cgroups. A cgroup without a
+// limit reports "max" or a huge number, which is as good as none.
+fn cgroup_available() -> Option<u64> {
+    let read = |name: &str| -> Option<u64> { fs::read_to_string(name).ok()?.trim().parse().ok() };
+    let (limit, usage) = match read("/sys/fs/cgroup/memory.max") {
+        Some(limit) => (limit, read("/sys/fs/cgroup/memory.current")?),
+        None => (
+            read("/sys/fs/cgroup/memory/memory.limit_in_bytes")?,
+            read("/sys/fs/cgroup/memory/memory.usage_in_bytes")?,
+        ),
+    };
+    Some(limit.saturating_sub(usage))
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16303428 kB\nMemFree:         1214868 kB\nMemAvailable:    9461208 kB\n";
+        assert_eq!(crate::memory::parse_meminfo(meminfo), Some(9461208 * 1024));
+        assert_eq!(
+            crate::memory::parse_meminfo("MemTotal: 16303428 kB\n"),
+            None
+        );
+    }
+}
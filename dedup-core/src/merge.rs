@@ -0,0 +1,542 @@
+use std::cmp;
+use std::collections;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::time;
+
+use serde_derive::{Deserialize, Serialize};
+
+pub const MERGED_FILE_NAME: &str = "merged";
+
+// How many unique chunks to merge between progress messages
+const PROGRESS_CHUNKS: u64 = 1 << 20;
+const SUMMARY_FILE_NAME: &str = "summary";
+
+// Each run is read through a share of the memory budget, but never a buffer smaller than this, so merging thousands of
+// runs still reads in sensible blocks, or larger than this, past which bigger reads stop helping
+const MIN_RUN_BUFFER: usize = 64 * 1024;
+const MAX_RUN_BUFFER: usize = 16 * 1024 * 1024;
+
+// How many pairs of directories that share data to keep in the summary
+const MAX_OVERLAPS: usize = 100;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Statistics {
+    pub unique_chunks: u64,
+    pub duplicates: u64,
+    pub unique_chunk_bytes: u64,
+    pub duplicate_chunk_bytes: u64,
+    pub collisions: u64,
+    // Time spent by every scan that contributed runs, and by the merge itself
+    pub scan_ms: u64,
+    pub merge_ms: u64,
+    // How busy each stage of the scans was
+    pub scan_timings: crate::pipeline::Timings,
+    // The merge's own stages: opening the runs and reading the file lists, combining the runs into the merged file,
+    // and writing the file report and catalog
+    pub merge_open_ms: u64,
+    pub merge_combine: crate::pipeline::Phase,
+    pub merge_write_ms: u64,
+    // Hard links that weren't scanned because the file they link to already was
+    pub hard_links: u64,
+    pub hard_link_bytes: u64,
+    // Files that weren't scanned because they were smaller or larger than the file size range allowed
+    pub size_filtered_files: u64,
+    pub size_filtered_bytes: u64,
+    // Files that looked already compressed, which the scans either left out or chunked anyway
+    pub skipped_compressed_files: u64,
+    pub skipped_compressed_bytes: u64,
+    pub flagged_compressed_files: u64,
+    pub flagged_compressed_bytes: u64,
+    // Bytes in the holes of sparse files. The chunk bytes only count what is actually stored on disk.
+    pub hole_bytes: u64,
+    // Chunks that were already in the baseline of the scans that had one. They aren't in the runs, so they aren't
+    // counted as unique or duplicate chunks either.
+    pub baseline_chunks: u64,
+    pub baseline_bytes: u64,
+    // The unique chunks that were compressed, before and after compression. The scans may only have compressed a
+    // sample of them.
+    pub compression_sampled_bytes: u64,
+    pub compressed_bytes: u64,
+    // Bytes scanned in chunks that look already compressed or encrypted, duplicates included
+    pub high_entropy_bytes: u64,
+    // Files and directories the scans couldn't read, so their data is missing from everything else
+    pub skipped: u64,
+    // The pairs of directory groups that share the most data, most first
+    pub directory_overlap: Vec<Overlap>,
+    // The same for the directories the scans were given, which only differ from the groups when a scan had several
+    pub root_overlap: Vec<Overlap>,
+}
+
+// Two directory groups and the bytes of the unique chunks found in both of them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Overlap {
+    pub first: String,
+    pub second: String,
+    pub bytes: u64,
+}
+
+// === Sorting Algorithm ===
+// The keys will be inserted into an in-memory sorted array until the sorting memory buffer is full. It will then
+// write out that chunk of sorted data to a temp file and start with a new empty buffer.
+//
+// When chunking is complete, the sorted temp files will be merged into a single file and the calculations on
+// compression level, chunk size and collisions will be performed.
+//
+// When 'merging' we don't actually care about the contents except to see if there are duplicates and/or collisions.
+// Each unique chunk is written to the merged file once, with the number of times it occurred across every run. Its
+// bytes are credited to the first file (in scan order) that it was found in, and the per-file totals are written to the
+// file report. In the merged file, an entry's file is its position in the file report. The runs' read buffers share
+// 'memory' bytes between them. 'refresh' is called after every unique chunk, so that the caller can keep its lock on
+// the directory from going stale; the merge stops with its error if it fails.
+pub fn merge_runs(
+    out_dir: &path::Path,
+    committed: &crate::wal::Committed,
+    memory: u64,
+    refresh: &mut dyn FnMut() -> io::Result<()>,
+) -> io::Result<Statistics> {
+    let started = time::Instant::now();
+    let runs = &committed.runs;
+    let mut statistics = Statistics {
+        collisions: committed.totals.collisions,
+        scan_ms: committed.totals.elapsed_ms,
+        scan_timings: committed.totals.timings,
+        hard_links: committed.totals.hard_links,
+        hard_link_bytes: committed.totals.hard_link_bytes,
+        size_filtered_files: committed.totals.size_filtered_files,
+        size_filtered_bytes: committed.totals.size_filtered_bytes,
+        skipped_compressed_files: committed.totals.skipped_compressed_files,
+        skipped_compressed_bytes: committed.totals.skipped_compressed_bytes,
+        flagged_compressed_files: committed.totals.flagged_compressed_files,
+        flagged_compressed_bytes: committed.totals.flagged_compressed_bytes,
+        hole_bytes: committed.totals.hole_bytes,
+        baseline_chunks: committed.totals.baseline_chunks,
+        baseline_bytes: committed.totals.baseline_bytes,
+        high_entropy_bytes: committed.totals.high_entropy_bytes,
+        skipped: committed.totals.skipped,
+        ..Statistics::default()
+    };
+
+    let mut merge_files = vec![];
+    let mut merge_data: Vec<Option<crate::run::Entry>> = vec![];
+    let mut merge_scans = vec![];
+    let mut file_lists = vec![];
+    let buffer = run_buffer(memory, runs.len());
+    tracing::debug!(bytes = buffer, "run read buffers");
+    for (scan, scan_runs) in committed.scans.iter().enumerate() {
+        for &run in scan_runs.iter() {
+            let mut reader = crate::run::RunReader::with_buffer(
+                &crate::wal::run_file_name(out_dir, run),
+                buffer,
+            )?;
+            merge_data.push(reader.next_entry()?);
+            merge_files.push(reader);
+            merge_scans.push(scan);
+        }
+        file_lists.push(crate::files::read_records(&crate::wal::file_list_name(
+            out_dir,
+            scan as u32,
+        ))?);
+    }
+
+    // Keys of different lengths can't be compared, and chunks cut or hashed differently are never the same chunk, so
+    // every run has to have been scanned with the same settings. The merged file is marked with them too.
+    let params = merge_files.first().map_or(
+        crate::run::Params {
+            key_len: crate::KEY_LEN,
+            ..Default::default()
+        },
+        |r| *r.params(),
+    );
+    if let Some(other) = merge_files.iter().find(|r| *r.params() != params) {
+        let message = if other.key_len() != params.key_len {
+            "the runs were scanned with different --key-bits and can't be merged".to_string()
+        } else {
+            format!(
+                "the runs were scanned with different settings ({} versus {}) and can't be merged",
+                params,
+                other.params()
+            )
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let key_len = params.key_len;
+
+    // Each scan numbers its own directory groups
+    let scan_groups: Vec<crate::files::Groups> = file_lists
+        .iter()
+        .map(|list| crate::files::Groups::from_records(list))
+        .collect();
+    let mut overlap: collections::HashMap<(String, String), u64> = collections::HashMap::new();
+    // Every file in a group is in the same one of the directories its scan was given
+    let group_roots: Vec<collections::HashMap<String, String>> = file_lists
+        .iter()
+        .map(|list| {
+            list.iter()
+                .map(|r| (r.group.clone(), r.root.clone()))
+                .collect()
+        })
+        .collect();
+    let mut root_overlap: collections::HashMap<(String, String), u64> = collections::HashMap::new();
+
+    let mut file_offsets = vec![0];
+    for list in file_lists.iter() {
+        file_offsets.push(file_offsets.last().unwrap() + list.len() as u32);
+    }
+
+    // The merged file is written under a temporary name so a merge that is interrupted never replaces a good one
+    let merged_name = out_dir.join(MERGED_FILE_NAME);
+    let partial_name = merged_name.with_extension("partial");
+    let merged_file = fs::File::create(&partial_name)?;
+    let mut merged = io::BufWriter::new(&merged_file);
+    crate::run::write_header(&mut merged, &params)?;
+
+    // Collisions between runs are only found here, so the merge keeps its own record of them
+    let mut collisions = io::BufWriter::new(fs::File::create(
+        out_dir.join(crate::collisions::MERGE_COLLISIONS_NAME),
+    )?);
+
+    // The next entry of every run that has any left is in the heap, smallest key first. Runs with the same key come
+    // out in the order they were committed.
+    let mut heap = MergeHeap::new();
+    for (i, entry) in merge_data.iter().enumerate() {
+        if let Some(entry) = entry {
+            heap.push(cmp::Reverse((entry.key, i)));
+        }
+    }
+
+    statistics.merge_open_ms = started.elapsed().as_millis() as u64;
+    let combining = time::Instant::now();
+    tracing::info!(runs = runs.len(), scans = committed.scans.len(), "merging");
+    // Once the heap is empty, every run has been read to the end
+    while let Some(cmp::Reverse((_, smallest_index))) = heap.pop() {
+        let mut smallest = merge_data[smallest_index].unwrap();
+        let mut occurrences = smallest.count;
+        let mut first_file = (merge_scans[smallest_index], smallest.file, smallest.offset);
+        let mut found_in = vec![(merge_scans[smallest_index], smallest.groups)];
+
+        // Every other run whose next entry has the same key is a duplicate (or a collision) of the smallest. Each one
+        // moves on to its next entry.
+        while let Some(&cmp::Reverse((key, i))) = heap.peek() {
+            if key != smallest.key {
+                break;
+            }
+            heap.pop();
+            let current = merge_data[i].unwrap();
+            if !current.same_chunk(&smallest) {
+                statistics.collisions += 1;
+                let location =
+                    |scan: usize, entry: &crate::run::Entry| crate::collisions::Location {
+                        path: file_lists[scan][entry.file as usize].path.clone(),
+                        offset: entry.offset,
+                        size: entry.size,
+                        check: entry.check,
+                    };
+                crate::collisions::write(
+                    &mut collisions,
+                    "merge",
+                    &smallest.key[..key_len],
+                    &location(merge_scans[smallest_index], &smallest),
+                    &location(merge_scans[i], &current),
+                )?;
+            } else {
+                occurrences += current.count;
+                found_in.push((merge_scans[i], current.groups));
+                first_file = first_file.min((merge_scans[i], current.file, current.offset));
+            }
+            advance(i, &mut merge_files[i], &mut merge_data[i], &mut heap)?;
+        }
+        advance(
+            smallest_index,
+            &mut merge_files[smallest_index],
+            &mut merge_data[smallest_index],
+            &mut heap,
+        )?;
+
+        // Every occurrence after the first is a duplicate
+        statistics.unique_chunks += 1;
+        if statistics.unique_chunks.is_multiple_of(PROGRESS_CHUNKS) {
+            tracing::info!(chunks = statistics.unique_chunks, "merged so far");
+        }
+        refresh()?;
+        statistics.unique_chunk_bytes += smallest.size as u64;
+        statistics.duplicates += occurrences as u64 - 1;
+        statistics.duplicate_chunk_bytes += (occurrences as u64 - 1) * smallest.size as u64;
+
+        // Every directory group the chunk was found in shares its bytes with every other one
+        if found_in.len() > 1 || found_in[0].1.count_ones() > 1 {
+            let names: collections::BTreeSet<(usize, &str)> = found_in
+                .iter()
+                .flat_map(|&(scan, groups)| scan_groups[scan].names(groups).map(move |n| (scan, n)))
+                .collect();
+            add_pairs(
+                &mut overlap,
+                names.iter().map(|&(_, name)| name).collect(),
+                smallest.size as u64,
+            );
+            // Groups lumped together as "(other)" could be in any of the directories
+            add_pairs(
+                &mut root_overlap,
+                names
+                    .iter()
+                    .filter_map(|&(scan, name)| group_roots[scan].get(name).map(|r| r.as_str()))
+                    .collect(),
+                smallest.size as u64,
+            );
+        }
+
+        if smallest.compressed > 0 {
+            statistics.compression_sampled_bytes += smallest.size as u64;
+            statistics.compressed_bytes += smallest.compressed as u64;
+        }
+
+        let (scan, file, offset) = first_file;
+        file_lists[scan][file as usize].unique_bytes += smallest.size as u64;
+
+        smallest.count = occurrences;
+        smallest.file = file_offsets[scan] + file;
+        smallest.offset = offset;
+        // Group bits are numbered separately by each scan, so they mean nothing once scans are merged
+        smallest.groups = 0;
+        crate::run::write_entry(&mut merged, &smallest, key_len)?;
+    }
+
+    statistics.directory_overlap = top_overlaps(overlap);
+    statistics.root_overlap = top_overlaps(root_overlap);
+
+    merged.flush()?;
+    collisions.flush()?;
+    drop(merged);
+    merged_file.sync_all()?;
+    fs::rename(&partial_name, &merged_name)?;
+    statistics.merge_combine = crate::pipeline::Phase::new(
+        statistics.unique_chunk_bytes + statistics.duplicate_chunk_bytes,
+        combining.elapsed(),
+    );
+    let writing = time::Instant::now();
+
+    let file_report = fs::File::create(out_dir.join(crate::files::FILE_REPORT_NAME))?;
+    let files = file_lists.concat();
+    crate::files::write_records(&file_report, &files)?;
+    let mut catalog = io::BufWriter::new(fs::File::create(
+        out_dir.join(crate::catalog::CATALOG_NAME),
+    )?);
+    crate::catalog::write_catalog(&mut catalog, &files)?;
+    catalog.flush()?;
+    statistics.merge_write_ms = writing.elapsed().as_millis() as u64;
+    statistics.merge_ms = started.elapsed().as_millis() as u64;
+
+    let summary = bincode::serialize(&statistics).map_err(io::Error::other)?;
+    fs::write(out_dir.join(SUMMARY_FILE_NAME), summary)?;
+    Ok(statistics)
+}
+
+// Reads the statistics written by the last merge. Returns a NotFound error if nothing has been merged yet.
+pub fn read_summary(out_dir: &path::Path) -> io::Result<Statistics> {
+    let summary = fs::read(out_dir.join(SUMMARY_FILE_NAME))?;
+    bincode::deserialize(&summary).map_err(io::Error::other)
+}
+
+// How large a read buffer each of the runs gets out of the memory budget
+fn run_buffer(memory: u64, runs: usize) -> usize {
+    (memory / runs.max(1) as u64).clamp(MIN_RUN_BUFFER as u64, MAX_RUN_BUFFER as u64) as usize
+}
+
+// The key of the next entry of each run, and which run it is
+type MergeHeap = collections::BinaryHeap<cmp::Reverse<(crate::run::Key, usize)>>;
+
+// Reads the run's next entry, and puts it in the heap if there is one
+fn advance(
+    run: usize,
+    reader: &mut crate::run::RunReader,
+    next: &mut Option<crate::run::Entry>,
+    heap: &mut MergeHeap,
+) -> io::Result<()> {
+    *next = reader.next_entry()?;
+    if let Some(entry) = next {
+        heap.push(cmp::Reverse((entry.key, run)));
+    }
+    Ok(())
+}
+
+// Adds the bytes of a chunk to every pair of the places it was found in
+fn add_pairs(
+    overlap: &mut collections::HashMap<(String, String), u64>,
+    names: collections::BTreeSet<&str>,
+    bytes: u64,
+) {
+    for (i, first) in names.iter().enumerate() {
+        for second in names.iter().skip(i + 1) {
+            *overlap
+                .entry((first.to_string(), second.to_string()))
+                .or_insert(0) += bytes;
+        }
+    }
+}
+
+// The pairs that share the most bytes, most first
+fn top_overlaps(overlap: collections::HashMap<(String, String), u64>) -> Vec<Overlap> {
+    let mut overlaps: Vec<Overlap> = overlap
+        .into_iter()
+        .map(|((first, second), bytes)| Overlap {
+            first,
+            second,
+            bytes,
+        })
+        .collect();
+    overlaps.sort_by(|a, b| {
+        b.bytes
+            .cmp(&a.bytes)
+            .then_with(|| (&a.first, &a.second).cmp(&(&b.first, &b.second)))
+    });
+    overlaps.truncate(MAX_OVERLAPS);
+    overlaps
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_run_buffer() {
+        assert_eq!(crate::merge::run_buffer(1 << 30, 256), 4 << 20);
+        // Thousands of runs still get a reasonable buffer each, and a few runs don't get absurdly large ones
+        assert_eq!(crate::merge::run_buffer(1 << 30, 100_000), 64 * 1024);
+        assert_eq!(crate::merge::run_buffer(1 << 30, 1), 16 << 20);
+        assert_eq!(crate::merge::run_buffer(1 << 30, 0), 16 << 20);
+    }
+
+    #[test]
+    fn test_merge_runs() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_merge_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let entry = |key: u8, size: u32, count: u32, file: u32| crate::run::Entry {
+            key: crate::run::key_from(&[key; crate::KEY_LEN], crate::KEY_LEN),
+            size,
+            check: key as u32,
+            count,
+            file,
+            offset: 0,
+            compressed: 0,
+            groups: 1 << file,
+        };
+        let file = |path: &str, size: u64, chunks: u64, group: &str| crate::files::FileRecord {
+            path: path.to_string(),
+            size,
+            chunks,
+            unique_bytes: 0,
+            kind: String::new(),
+            group: group.to_string(),
+            root: format!("/{}", group),
+            ..crate::files::FileRecord::default()
+        };
+
+        // Chunk 2 shows up in both runs and both directories, and chunk 3 appears twice within the second run
+        let params = crate::run::Params::new(
+            &crate::pipeline::Chunking::Variable(
+                rabin::chunker::ChunkerBuilder::new(crate::MIN_CHUNK_SIZE, crate::MAX_CHUNK_SIZE)
+                    .unwrap(),
+            ),
+            crate::KEY_LEN,
+        );
+        let mut transaction = crate::txn::Transaction::begin(&dir).unwrap();
+        let runs = [
+            [entry(1, 100, 1, 0), entry(2, 200, 1, 1)],
+            [entry(2, 200, 1, 0), entry(3, 300, 2, 1)],
+        ];
+        for run in runs.iter() {
+            transaction
+                .write_run(|buffer| {
+                    crate::run::write_header(&mut *buffer, &params)?;
+                    for e in run.iter() {
+                        crate::run::write_entry(&mut *buffer, e, crate::KEY_LEN)?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+        }
+        transaction
+            .commit(
+                &[file("a", 300, 2, "x"), file("b", 800, 3, "y")],
+                crate::wal::ScanTotals {
+                    collisions: 1,
+                    elapsed_ms: 10,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let committed = crate::wal::read_committed(&dir).unwrap();
+        let statistics =
+            crate::merge::merge_runs(&dir, &committed, 1 << 20, &mut || Ok(())).unwrap();
+        assert_eq!(statistics.unique_chunks, 3);
+        assert_eq!(statistics.unique_chunk_bytes, 600);
+        assert_eq!(statistics.duplicates, 2);
+        assert_eq!(statistics.duplicate_chunk_bytes, 500);
+        assert_eq!(statistics.collisions, 1);
+        assert_eq!(statistics.scan_ms, 10);
+        assert_eq!(
+            statistics.directory_overlap,
+            vec![crate::merge::Overlap {
+                first: "x".to_string(),
+                second: "y".to_string(),
+                bytes: 200,
+            }]
+        );
+        assert_eq!(
+            statistics.root_overlap,
+            vec![crate::merge::Overlap {
+                first: "/x".to_string(),
+                second: "/y".to_string(),
+                bytes: 200,
+            }]
+        );
+        assert_eq!(crate::merge::read_summary(&dir).unwrap(), statistics);
+
+        // The merged file has one entry per chunk with the total number of occurrences, and no group bits
+        let mut merged =
+            crate::run::RunReader::open(&dir.join(crate::merge::MERGED_FILE_NAME)).unwrap();
+        let merged_entry = |key: u8, size: u32, count: u32, file: u32| crate::run::Entry {
+            groups: 0,
+            ..entry(key, size, count, file)
+        };
+        assert_eq!(
+            merged.next_entry().unwrap(),
+            Some(merged_entry(1, 100, 1, 0))
+        );
+        assert_eq!(
+            merged.next_entry().unwrap(),
+            Some(merged_entry(2, 200, 2, 0))
+        );
+        assert_eq!(
+            merged.next_entry().unwrap(),
+            Some(merged_entry(3, 300, 2, 1))
+        );
+        assert_eq!(merged.next_entry().unwrap(), None);
+
+        // Chunk 2 was found in both files but is credited to the first one
+        let files = crate::files::read_records(&dir.join(crate::files::FILE_REPORT_NAME)).unwrap();
+        assert_eq!(files[0].unique_bytes, 300);
+        assert_eq!(files[1].unique_bytes, 300);
+        assert_eq!(files[1].duplicate_bytes(), 500);
+
+        // A scan with other chunk sizes can't be merged with the first
+        let mut transaction = crate::txn::Transaction::begin(&dir).unwrap();
+        let fixed = crate::run::Params::new(&crate::pipeline::Chunking::Fixed, crate::KEY_LEN);
+        transaction
+            .write_run(|buffer| crate::run::write_header(&mut *buffer, &fixed))
+            .unwrap();
+        transaction
+            .commit(&[], crate::wal::ScanTotals::default())
+            .unwrap();
+        let committed = crate::wal::read_committed(&dir).unwrap();
+        let e = crate::merge::merge_runs(&dir, &committed, 1 << 20, &mut || Ok(())).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("fixed 4096 byte chunks"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
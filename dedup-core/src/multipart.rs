@@ -0,0 +1,223 @@
+use std::io;
+use std::ops;
+
+use sha3::Digest;
+
+// Groups a file's chunks into the parts of an S3 multipart upload. S3 wants every part but the last to be at least
+// 5 MiB, so each part has to hold many chunks. Filling parts up to a fixed size would make every part after an edit
+// different, just as fixed-size chunks do, so parts end at chunk boundaries chosen by the chunks' ids instead: once a
+// part is at least the minimum size, it ends after any chunk whose id falls below a threshold in proportion to the
+// chunk's length. An unchanged prefix of a file always makes the same parts, and the parts after an edit line up with
+// the old ones again a part or so later, so an uploader can copy the parts it uploaded before (i.e. with
+// UploadPartCopy) rather than send them again, and a resumed upload can tell which of its parts are already there.
+
+// The smallest and largest parts S3 takes, and the most parts one upload can have
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+pub const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+pub const MAX_PARTS: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Part {
+    // Parts are numbered from 1, as S3 numbers them
+    pub number: u32,
+    pub offset: u64,
+    pub size: u64,
+    // The part's chunks, as positions in the list it was planned from
+    pub chunks: ops::Range<usize>,
+    // The SHA3-256 hash of the ids of the part's chunks, which is the same for parts with the same bytes (chunked the
+    // same way) without reading them again
+    pub id: [u8; 32],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Planner {
+    pub min_size: u64,
+    // Parts only go past this when a single chunk does
+    pub max_size: u64,
+}
+
+impl Planner {
+    // Parts average about one and a half times the minimum size, and are never more than four times it
+    pub fn new(min_size: u64) -> Planner {
+        let min_size = min_size.max(1);
+        Planner {
+            min_size,
+            max_size: min_size.saturating_mul(4),
+        }
+    }
+
+    // The smallest parts that keep a file of this size within MAX_PARTS parts. The minimum is MIN_PART_SIZE doubled as
+    // often as it takes, so files whose sizes differ a little are almost always planned the same way.
+    pub fn for_size(size: u64) -> Planner {
+        let mut min_size = MIN_PART_SIZE;
+        while size.div_ceil(min_size) >= MAX_PARTS as u64 {
+            min_size *= 2;
+        }
+        Planner::new(min_size)
+    }
+
+    // Plans the parts of a file made of these chunks, in order: the length and id of each. 'key_len' is how many bytes
+    // of each id there are. Returns an InvalidInput error if the parts wouldn't fit in one upload.
+    pub fn plan(&self, chunks: &[(u64, crate::run::Key)], key_len: usize) -> io::Result<Vec<Part>> {
+        let mut parts = vec![];
+        let (mut start, mut offset, mut size) = (0, 0u64, 0u64);
+        let mut hasher = sha3::Sha3_256::default();
+        for (i, &(len, ref key)) in chunks.iter().enumerate() {
+            if size > 0 && size >= self.min_size && size + len > self.max_size {
+                parts.push(self.part(parts.len(), offset, size, start..i, &mut hasher));
+                (start, offset, size) = (i, offset + size, 0);
+            }
+            hasher.update(&key[..key_len]);
+            size += len;
+            if size >= self.min_size && self.ends_part(len, key) {
+                parts.push(self.part(parts.len(), offset, size, start..i + 1, &mut hasher));
+                (start, offset, size) = (i + 1, offset + size, 0);
+            }
+        }
+        if size > 0 || parts.is_empty() {
+            parts.push(self.part(parts.len(), offset, size, start..chunks.len(), &mut hasher));
+        }
+
+        if parts.len() > MAX_PARTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} parts of at least {} bytes is more than the {} an upload can have",
+                    parts.len(),
+                    self.min_size,
+                    MAX_PARTS
+                ),
+            ));
+        }
+        if let Some(part) = parts.iter().find(|p| p.size > MAX_PART_SIZE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "part {} is {} bytes, more than the {} a part can have",
+                    part.number, part.size, MAX_PART_SIZE
+                ),
+            ));
+        }
+        Ok(parts)
+    }
+
+    // Plans the parts of a signed file
+    pub fn plan_file(
+        &self,
+        file: &crate::signature::FileSignature,
+        key_len: usize,
+    ) -> io::Result<Vec<Part>> {
+        let chunks: Vec<(u64, crate::run::Key)> = (0..file.chunks.len())
+            .map(|i| (file.chunk_len(i), file.chunks[i].strong))
+            .collect();
+        self.plan(&chunks, key_len)
+    }
+
+    // Whether a part can end after this chunk. Each byte of the chunk gets the same chance of ending the part, 2 in
+    // min_size, so parts run on for half the minimum size past it on average whatever size the chunks are. Ids are
+    // a prefix of a hash, so their first bytes are as good as random, and read big-endian they are still a fair
+    // number when the id is shorter than 8 bytes.
+    fn ends_part(&self, len: u64, key: &crate::run::Key) -> bool {
+        let threshold = ((len as u128) << 65) / self.min_size as u128;
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&key[..8]);
+        (u64::from_be_bytes(id) as u128) < threshold
+    }
+
+    fn part(
+        &self,
+        index: usize,
+        offset: u64,
+        size: u64,
+        chunks: ops::Range<usize>,
+        hasher: &mut sha3::Sha3_256,
+    ) -> Part {
+        Part {
+            number: index as u32 + 1,
+            offset,
+            size,
+            chunks,
+            id: hasher.finalize_reset().into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha3::Digest;
+
+    // Chunks of 4 to 12 KiB with ids made from their seeds
+    fn chunks(seeds: std::ops::Range<u64>) -> Vec<(u64, crate::run::Key)> {
+        seeds
+            .map(|seed| {
+                let hash = sha3::Sha3_256::digest(seed.to_le_bytes());
+                let len = 4096 + (u64::from(hash[20]) << 5);
+                (len, crate::run::key_from(&hash, crate::KEY_LEN))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plan() {
+        let planner = crate::multipart::Planner::new(crate::multipart::MIN_PART_SIZE);
+        let original = chunks(0..30_000);
+        let total: u64 = original.iter().map(|c| c.0).sum();
+        let parts = planner.plan(&original, crate::KEY_LEN).unwrap();
+        let mut offset = 0;
+        for (i, part) in parts.iter().enumerate() {
+            assert_eq!(part.number as usize, i + 1);
+            assert_eq!(part.offset, offset);
+            assert_eq!(
+                part.size,
+                original[part.chunks.clone()]
+                    .iter()
+                    .map(|c| c.0)
+                    .sum::<u64>()
+            );
+            assert!(part.size <= planner.max_size);
+            assert!(part.size >= planner.min_size || i == parts.len() - 1);
+            offset += part.size;
+        }
+        assert_eq!(offset, total);
+        assert_eq!(parts.last().unwrap().chunks.end, original.len());
+        // About one and a half times the minimum on average
+        let average = total / parts.len() as u64;
+        assert!(average > planner.min_size * 5 / 4 && average < planner.min_size * 7 / 4);
+
+        // A chunk changed in the middle changes the part it is in and perhaps the one after, but every part before it
+        // is the same and the parts after it line up again
+        let mut edited = original.clone();
+        edited[15_000] = chunks(1_000_000..1_000_001)[0];
+        let edited_parts = planner.plan(&edited, crate::KEY_LEN).unwrap();
+        let changed = parts
+            .iter()
+            .position(|p| p.chunks.contains(&15_000))
+            .unwrap();
+        assert_eq!(edited_parts[..changed], parts[..changed]);
+        assert_ne!(edited_parts[changed].id, parts[changed].id);
+        let old: std::collections::HashSet<[u8; 32]> = parts.iter().map(|p| p.id).collect();
+        let reused = edited_parts.iter().filter(|p| old.contains(&p.id)).count();
+        assert!(reused >= parts.len() - 2, "{} of {}", reused, parts.len());
+
+        // Inserting chunks at the start shifts every offset, but most parts keep their ids
+        let mut shifted = chunks(2_000_000..2_000_010);
+        shifted.extend_from_slice(&original);
+        let shifted_parts = planner.plan(&shifted, crate::KEY_LEN).unwrap();
+        let reused = shifted_parts.iter().filter(|p| old.contains(&p.id)).count();
+        assert!(reused >= parts.len() - 2, "{} of {}", reused, parts.len());
+
+        // Short and empty files are one part
+        let short = planner.plan(&original[..10], crate::KEY_LEN).unwrap();
+        assert_eq!((short.len(), short[0].chunks.clone()), (1, 0..10));
+        let empty = planner.plan(&[], crate::KEY_LEN).unwrap();
+        assert_eq!((empty.len(), empty[0].size), (1, 0));
+
+        // Too many parts for one upload, unless the planner is chosen for the size
+        let tiny = crate::multipart::Planner::new(8192);
+        let error = tiny.plan(&original, crate::KEY_LEN).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(crate::multipart::Planner::for_size(total), planner);
+        let huge = crate::multipart::Planner::for_size(100 << 30);
+        assert_eq!(huge.min_size, crate::multipart::MIN_PART_SIZE * 4);
+    }
+}
@@ -0,0 +1,638 @@
+use std::collections;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read};
+use std::path;
+
+use serde_derive::{Deserialize, Serialize};
+
+// Container images are stacks of layers, each a tar file of the files it adds or changes, and registries share
+// storage between images a whole layer at a time. A layer that differs by one file from another is stored again in
+// full, and so is a base image rebuilt with the same files. Chunking the files in every layer shows how much more
+// would be shared if images were stored and distributed a chunk at a time instead.
+//
+// Images are read from an OCI image layout (a directory with an index.json and a blobs directory, as `skopeo copy` or
+// `docker buildx build --output type=oci` write) or from a tar file written by `docker save`, which also works for an
+// OCI layout packed into a tar file. Images in a registry have to be copied into a layout first, since nothing here
+// speaks the registry protocol. The files in every layer are read into memory one at a time, and the id of every
+// chunk is kept in memory along with where it was first seen.
+
+const DOCKER_MANIFEST_NAME: &str = "manifest.json";
+const INDEX_NAME: &str = "index.json";
+// The annotation an OCI layout names its images with
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+// Enough of the start of a layer to tell how it was compressed
+const HEAD_LEN: usize = 16;
+
+// The bytes of a layer's files, by where each of their chunks was first seen. Layers are read in the order the images
+// list them, each image in turn, and a layer that more than one image has is only read the first time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct Sharing {
+    // Chunks that hadn't been seen before
+    pub new: u64,
+    // Chunks seen earlier in the same layer
+    pub same_layer: u64,
+    // Chunks first seen in another layer of the same image
+    pub other_layer: u64,
+    // Chunks first seen in a layer of an image read earlier
+    pub other_image: u64,
+}
+
+impl Sharing {
+    // Every byte of the layer's files
+    pub fn total(&self) -> u64 {
+        self.new + self.same_layer + self.other_layer + self.other_image
+    }
+
+    fn add(&mut self, other: &Sharing) {
+        self.new += other.new;
+        self.same_layer += other.same_layer;
+        self.other_layer += other.other_layer;
+        self.other_image += other.other_image;
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Layer {
+    // The digest of the layer's tar file before compression (its diff id), which is the same however the layer was
+    // compressed. Layers whose image config doesn't list diff ids go by the name of their blob instead.
+    pub id: String,
+    // The first image with the layer, and how many images have it
+    pub image: String,
+    pub images: u64,
+    // The size of the layer as it is stored, usually compressed
+    pub blob_bytes: u64,
+    pub files: u64,
+    pub chunks: u64,
+    pub sharing: Sharing,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Image {
+    // The image's tag, or the digest of its manifest if it has none. An image built for several platforms is one
+    // image for each of them, with the platform after the name.
+    pub name: String,
+    // Where the image was read from
+    pub source: String,
+    // The image's layers, bottom first, as positions in Analysis::layers
+    pub layers: Vec<usize>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Analysis {
+    pub images: Vec<Image>,
+    // Every distinct layer, in the order they were read
+    pub layers: Vec<Layer>,
+    pub unique_chunks: u64,
+}
+
+impl Analysis {
+    // The bytes in the files of every image with nothing shared, as if each image were stored on its own
+    pub fn image_bytes(&self) -> u64 {
+        self.images
+            .iter()
+            .flat_map(|image| image.layers.iter())
+            .map(|&layer| self.layers[layer].sharing.total())
+            .sum()
+    }
+
+    // The bytes in the files of every distinct layer, which is what sharing whole layers (as registries do) stores
+    pub fn layer_bytes(&self) -> u64 {
+        self.layers.iter().map(|l| l.sharing.total()).sum()
+    }
+
+    // The bytes in every distinct layer as stored, usually compressed
+    pub fn blob_bytes(&self) -> u64 {
+        self.layers.iter().map(|l| l.blob_bytes).sum()
+    }
+
+    // Where the bytes of every distinct layer were first seen. Only the new ones would have to be stored if chunks were
+    // shared instead of layers.
+    pub fn sharing(&self) -> Sharing {
+        let mut sharing = Sharing::default();
+        for layer in self.layers.iter() {
+            sharing.add(&layer.sharing);
+        }
+        sharing
+    }
+}
+
+pub struct Analyzer {
+    chunking: crate::pipeline::Chunking,
+    key_len: usize,
+    // The positions (in Analysis::layers and Analysis::images) of the layer and image each chunk was first seen in
+    seen: collections::HashMap<crate::run::Key, (u32, u32)>,
+    // Where each layer is in Analysis::layers, by id
+    layers: collections::HashMap<String, usize>,
+    analysis: Analysis,
+}
+
+impl Analyzer {
+    pub fn new(chunking: crate::pipeline::Chunking, key_len: usize) -> Analyzer {
+        Analyzer {
+            chunking,
+            key_len,
+            seen: collections::HashMap::new(),
+            layers: collections::HashMap::new(),
+            analysis: Analysis::default(),
+        }
+    }
+
+    // Reads every image in the OCI image layout directory, or the tar file made by `docker save`, and returns how many
+    // there were. Images read before an error stay in the analysis.
+    pub fn add(&mut self, path: &path::Path) -> io::Result<usize> {
+        let source = if path.is_dir() {
+            Source::Directory(path)
+        } else {
+            Source::Tar(path)
+        };
+        let images = self.analysis.images.len();
+        if source.has(DOCKER_MANIFEST_NAME)? {
+            let manifests: Vec<DockerManifest> = source.read_json(DOCKER_MANIFEST_NAME)?;
+            for manifest in manifests {
+                let config: Config = source.read_json(&manifest.config)?;
+                let name = match manifest.repo_tags.and_then(|tags| tags.into_iter().next()) {
+                    Some(tag) => tag,
+                    None => manifest.config,
+                };
+                self.add_image(&source, name, &manifest.layers, &config.rootfs.diff_ids)?;
+            }
+        } else if source.has(INDEX_NAME)? {
+            let index: Manifest = source.read_json(INDEX_NAME)?;
+            for descriptor in index.manifests.iter() {
+                let name = descriptor
+                    .annotations
+                    .get(REF_NAME_ANNOTATION)
+                    .cloned()
+                    .unwrap_or_else(|| descriptor.digest.clone());
+                self.add_manifest(&source, name, descriptor, false)?;
+            }
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} isn't an OCI image layout or a tar file made by docker save",
+                    path.display()
+                ),
+            ));
+        }
+        Ok(self.analysis.images.len() - images)
+    }
+
+    pub fn analysis(&self) -> &Analysis {
+        &self.analysis
+    }
+
+    pub fn finish(self) -> Analysis {
+        self.analysis
+    }
+
+    // Reads the image a manifest describes, or each image an index (of the platforms an image was built for) lists
+    fn add_manifest(
+        &mut self,
+        source: &Source,
+        name: String,
+        descriptor: &Descriptor,
+        nested: bool,
+    ) -> io::Result<()> {
+        // Build tools list attestations alongside the images for each platform, which aren't images at all
+        if descriptor
+            .platform
+            .as_ref()
+            .is_some_and(|p| p.os == "unknown")
+        {
+            return Ok(());
+        }
+        let name = match (&descriptor.platform, nested) {
+            (Some(platform), true) => {
+                format!("{} ({}/{})", name, platform.os, platform.architecture)
+            }
+            _ => name,
+        };
+        let manifest: Manifest = source.read_json(&blob_name(&descriptor.digest)?)?;
+        if !manifest.manifests.is_empty() {
+            for inner in manifest.manifests.iter() {
+                self.add_manifest(source, name.clone(), inner, true)?;
+            }
+            return Ok(());
+        }
+        let config = match &manifest.config {
+            Some(config) => source.read_json(&blob_name(&config.digest)?)?,
+            None => Config::default(),
+        };
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| blob_name(&layer.digest))
+            .collect::<io::Result<Vec<String>>>()?;
+        self.add_image(source, name, &layers, &config.rootfs.diff_ids)
+    }
+
+    // Reads each of the image's layers that hasn't been read already. 'blobs' are the names of the layers' blobs in the
+    // source, and 'diff_ids' their ids from the image's config, if it has them.
+    fn add_image(
+        &mut self,
+        source: &Source,
+        name: String,
+        blobs: &[String],
+        diff_ids: &[String],
+    ) -> io::Result<()> {
+        let image = self.analysis.images.len();
+        self.analysis.images.push(Image {
+            name: name.clone(),
+            source: source.path().display().to_string(),
+            layers: vec![],
+        });
+        for (i, blob) in blobs.iter().enumerate() {
+            let id = match diff_ids.get(i) {
+                Some(id) if diff_ids.len() == blobs.len() => id.clone(),
+                _ => blob.clone(),
+            };
+            let layer = match self.layers.get(&id) {
+                Some(&layer) => {
+                    if !self.analysis.images[image].layers.contains(&layer) {
+                        self.analysis.layers[layer].images += 1;
+                    }
+                    layer
+                }
+                None => {
+                    let layer = self.analysis.layers.len();
+                    let mut read = Layer {
+                        id: id.clone(),
+                        image: name.clone(),
+                        images: 1,
+                        ..Layer::default()
+                    };
+                    source.read(blob, &mut |blob, blob_bytes| {
+                        read.blob_bytes = blob_bytes;
+                        self.read_layer(blob, &mut read, layer as u32, image as u32)
+                    })?;
+                    self.layers.insert(id, layer);
+                    self.analysis.layers.push(read);
+                    layer
+                }
+            };
+            self.analysis.images[image].layers.push(layer);
+        }
+        Ok(())
+    }
+
+    // Chunks every regular file in the layer, decompressing it first if it was compressed
+    fn read_layer(
+        &mut self,
+        blob: &mut dyn Read,
+        layer: &mut Layer,
+        position: u32,
+        image: u32,
+    ) -> io::Result<()> {
+        let mut blob = io::BufReader::new(blob);
+        let head = blob.fill_buf()?;
+        let codec = crate::compressed::detect_head(&head[..head.len().min(HEAD_LEN)])
+            .and_then(crate::decompress::Codec::from_format);
+        let data: Box<dyn Read + '_> = match codec {
+            Some(codec) => codec.reader(blob)?,
+            None => Box::new(blob),
+        };
+
+        let mut hasher = sha3::Sha3_256::default();
+        let mut archive = tar::Archive::new(data);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let mut data = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut data)?;
+            layer.files += 1;
+            for chunk in self.chunking.chunks(&data) {
+                let key = crate::pipeline::hash_key(&mut hasher, chunk, self.key_len);
+                let len = chunk.len() as u64;
+                layer.chunks += 1;
+                match self.seen.entry(key) {
+                    collections::hash_map::Entry::Vacant(vacant) => {
+                        vacant.insert((position, image));
+                        layer.sharing.new += len;
+                        self.analysis.unique_chunks += 1;
+                    }
+                    collections::hash_map::Entry::Occupied(seen) => match *seen.get() {
+                        (first, _) if first == position => layer.sharing.same_layer += len,
+                        (_, first) if first == image => layer.sharing.other_layer += len,
+                        _ => layer.sharing.other_image += len,
+                    },
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Where images are read from
+enum Source<'a> {
+    Directory(&'a path::Path),
+    Tar(&'a path::Path),
+}
+
+impl Source<'_> {
+    fn path(&self) -> &path::Path {
+        match self {
+            Source::Directory(path) | Source::Tar(path) => path,
+        }
+    }
+
+    // Whether there is a file by this name, relative to the top of the layout
+    fn has(&self, name: &str) -> io::Result<bool> {
+        match self.read(name, &mut |_, _| Ok(())) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Hands the file by this name, and its size, to 'read'. A tar file is searched from the start for each file, which
+    // only reads the headers of the files before it.
+    fn read(
+        &self,
+        name: &str,
+        read: &mut dyn FnMut(&mut dyn Read, u64) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let name = name.trim_start_matches("./");
+        match self {
+            Source::Directory(dir) => {
+                let file = fs::File::open(dir.join(name))?;
+                let len = file.metadata()?.len();
+                read(&mut io::BufReader::new(file), len)
+            }
+            Source::Tar(path) => {
+                let mut archive = tar::Archive::new(fs::File::open(path)?);
+                for entry in archive.entries_with_seek()? {
+                    let mut entry = entry?;
+                    if entry.path()?.to_string_lossy().trim_start_matches("./") == name {
+                        let len = entry.size();
+                        return read(&mut entry, len);
+                    }
+                }
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no {}", path.display(), name),
+                ))
+            }
+        }
+    }
+
+    fn read_json<T: serde::de::DeserializeOwned>(&self, name: &str) -> io::Result<T> {
+        let mut data = vec![];
+        self.read(name, &mut |file, _| file.read_to_end(&mut data).map(|_| ()))?;
+        serde_json::from_slice(&data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("can't read {} in {}: {}", name, self.path().display(), e),
+            )
+        })
+    }
+}
+
+// Where an OCI layout keeps the blob with this digest
+fn blob_name(digest: &str) -> io::Result<String> {
+    match digest.split_once(':') {
+        Some((algorithm, hex))
+            if !algorithm.is_empty()
+                && !hex.is_empty()
+                && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+                && hex.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            Ok(format!("blobs/{}/{}", algorithm, hex))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} isn't a digest", digest),
+        )),
+    }
+}
+
+// An image in the manifest.json of a `docker save` tar file. The config and layers are named by their paths in it.
+#[derive(Deserialize)]
+struct DockerManifest {
+    #[serde(rename = "Config")]
+    config: String,
+    #[serde(rename = "RepoTags", default)]
+    repo_tags: Option<Vec<String>>,
+    #[serde(rename = "Layers")]
+    layers: Vec<String>,
+}
+
+// An OCI index lists manifests, and a manifest lists an image's config and layers. Only the fields read here are given.
+#[derive(Default, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Descriptor {
+    digest: String,
+    #[serde(default)]
+    annotations: collections::HashMap<String, String>,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+#[derive(Default, Deserialize)]
+struct Config {
+    #[serde(default)]
+    rootfs: RootFs,
+}
+
+#[derive(Default, Deserialize)]
+struct RootFs {
+    #[serde(default)]
+    diff_ids: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    fn random(seed: u64, len: usize) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    // A layer holding these files, with a directory and a link that aren't chunked
+    fn layer(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        builder.append_data(&mut header, "usr/", &[][..]).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "usr/link", "bin").unwrap();
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn digest(n: u64) -> String {
+        format!("sha256:{:064x}", n)
+    }
+
+    #[test]
+    fn test_images() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_oci_{}", std::process::id()));
+        let base = (random(1, 200_000), random(2, 150_000));
+        let app = random(3, 100_000);
+        let extra = random(4, 80_000);
+        // The base layer, the first image's layer with a file from the base and one of its own, and the second image's
+        // layer with the first image's file and the same file twice
+        let layers = [
+            layer(&[("bin/a", &base.0), ("bin/b", &base.1)]),
+            gzip(&layer(&[("app/copy", &base.0), ("app/main", &app)])),
+            layer(&[("app/main", &app), ("x/1", &extra), ("x/2", &extra)]),
+        ];
+
+        // An OCI layout, with the layers' blobs named by made-up digests
+        let layout = dir.join("layout");
+        fs::create_dir_all(layout.join("blobs/sha256")).unwrap();
+        let blob = |n: u64| layout.join(format!("blobs/sha256/{:064x}", n));
+        for (i, data) in layers.iter().enumerate() {
+            fs::write(blob(10 + i as u64), data).unwrap();
+        }
+        let images = [(1, [0, 1]), (2, [0, 2])];
+        let mut index = vec![];
+        for (image, image_layers) in images.iter() {
+            let config = format!(
+                r#"{{"rootfs": {{"type": "layers", "diff_ids": ["{}", "{}"]}}}}"#,
+                digest(20 + image_layers[0]),
+                digest(20 + image_layers[1])
+            );
+            fs::write(blob(30 + image), &config).unwrap();
+            let manifest = format!(
+                r#"{{"schemaVersion": 2, "config": {{"digest": "{}", "size": 1}}, "layers": [{{"digest": "{}"}}, {{"digest": "{}"}}]}}"#,
+                digest(30 + image),
+                digest(10 + image_layers[0]),
+                digest(10 + image_layers[1])
+            );
+            fs::write(blob(40 + image), &manifest).unwrap();
+            index.push(format!(
+                r#"{{"digest": "{}", "annotations": {{"org.opencontainers.image.ref.name": "image{}"}}}}"#,
+                digest(40 + image),
+                image
+            ));
+        }
+        fs::write(
+            layout.join("index.json"),
+            format!(
+                r#"{{"schemaVersion": 2, "manifests": [{}]}}"#,
+                index.join(", ")
+            ),
+        )
+        .unwrap();
+
+        let chunking = crate::pipeline::Chunking::Variable(
+            rabin::chunker::ChunkerBuilder::new(1024, 8192).unwrap(),
+        );
+        let mut analyzer = crate::oci::Analyzer::new(chunking, crate::KEY_LEN);
+        assert_eq!(analyzer.add(&layout).unwrap(), 2);
+        let analysis = analyzer.finish();
+        let names: Vec<&str> = analysis.images.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, ["image1", "image2"]);
+        assert_eq!(analysis.images[1].layers, [0, 2]);
+        assert_eq!(analysis.layers.len(), 3);
+        assert_eq!(analysis.layers[0].id, digest(20));
+        assert_eq!(
+            (analysis.layers[0].images, analysis.layers[1].images),
+            (2, 1)
+        );
+        assert_eq!(analysis.layers[1].blob_bytes, layers[1].len() as u64);
+        assert_eq!(analysis.layers[2].files, 3);
+
+        // The same file anywhere has the same chunks
+        let sharing: Vec<crate::oci::Sharing> = analysis.layers.iter().map(|l| l.sharing).collect();
+        assert_eq!(sharing[0].new, 350_000);
+        assert_eq!((sharing[1].new, sharing[1].other_layer), (100_000, 200_000));
+        assert_eq!(sharing[2].new, 80_000);
+        assert_eq!(
+            (sharing[2].same_layer, sharing[2].other_image),
+            (80_000, 100_000)
+        );
+        assert_eq!(analysis.image_bytes(), 350_000 * 2 + 300_000 + 260_000);
+        assert_eq!(analysis.layer_bytes(), 350_000 + 300_000 + 260_000);
+        assert_eq!(analysis.sharing().new, 530_000);
+
+        // The same images saved by docker, with the layers under paths of their own
+        let mut saved = tar::Builder::new(vec![]);
+        let mut add = |name: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            saved.append_data(&mut header, name, data).unwrap();
+        };
+        for (i, data) in layers.iter().enumerate() {
+            add(&format!("layer{}/layer.tar", i), data);
+        }
+        let mut manifest = vec![];
+        for (image, image_layers) in images.iter() {
+            add(
+                &format!("config{}.json", image),
+                &fs::read(blob(30 + image)).unwrap(),
+            );
+            manifest.push(format!(
+                r#"{{"Config": "config{}.json", "RepoTags": ["image{}:latest"], "Layers": ["layer{}/layer.tar", "layer{}/layer.tar"]}}"#,
+                image, image, image_layers[0], image_layers[1]
+            ));
+        }
+        add(
+            "manifest.json",
+            format!("[{}]", manifest.join(", ")).as_bytes(),
+        );
+        fs::write(dir.join("saved.tar"), saved.into_inner().unwrap()).unwrap();
+
+        let mut analyzer = crate::oci::Analyzer::new(chunking, crate::KEY_LEN);
+        assert_eq!(analyzer.add(&dir.join("saved.tar")).unwrap(), 2);
+        let saved = analyzer.finish();
+        assert_eq!(saved.images[0].name, "image1:latest");
+        assert_eq!(
+            saved.layers,
+            analysis
+                .layers
+                .iter()
+                .map(|l| crate::oci::Layer {
+                    image: format!("{}:latest", l.image),
+                    ..l.clone()
+                })
+                .collect::<Vec<_>>()
+        );
+
+        // Anything else is an error
+        let mut analyzer = crate::oci::Analyzer::new(chunking, crate::KEY_LEN);
+        let error = analyzer.add(&dir.join("layout/blobs")).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,2224 @@
+use std::cell;
+use std::collections;
+use std::fs;
+use std::io;
+use std::iter;
+use std::ops;
+use std::path;
+use std::sync;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+use serde_derive::{Deserialize, Serialize};
+
+// How many messages each stage can get ahead of the one after it. Bounding the channels keeps a fast stage (usually the
+// reader) from piling up mapped files faster than the rest of the pipeline can get through them.
+const CHANNEL_DEPTH: usize = 64;
+
+// Chunks are handed to the hashing threads in batches. Large files get split across several threads, and small files
+// don't cost a message per chunk.
+const BATCH_CHUNKS: usize = 256;
+
+// Files that are streamed rather than mapped are read this many bytes at a time. The kernel is asked to read this far
+// ahead of the chunker too, mapped or not.
+const STREAM_BUFFER_BYTES: usize = 4 * 1024 * 1024;
+
+// The size of every chunk made by the fixed-size algorithm
+pub const FIXED_CHUNK_SIZE: usize = 4096;
+
+// Chunks are compressed with zstd at its default level, which is what a backup tool would most likely use
+const ZSTD_LEVEL: i32 = 3;
+
+// A chunk that has been through the whole pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HashedChunk {
+    pub key: crate::run::Key,
+    pub check: u32,
+    pub size: u32,
+    // Where the chunk starts in its file
+    pub offset: u64,
+    // The size of the chunk compressed with zstd, or 0 if it wasn't in the sample
+    pub compressed: u32,
+    // Whether the chunk looks like it is already compressed or encrypted
+    pub high_entropy: bool,
+}
+
+// A piece of a chunk cut at the fixed block boundaries of its file (see Options::sub_blocks), told apart from other
+// pieces by the first 64 bits of its SHA3 hash
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubBlock {
+    pub fingerprint: u64,
+    pub size: u32,
+}
+
+// Some of the chunks of one file, in the order they appear in the file. Batches from different files (and different
+// batches of the same file) can arrive in any order.
+#[derive(Debug)]
+pub struct HashedBatch {
+    pub file: u32,
+    pub chunks: Vec<HashedChunk>,
+    // Chunks are numbered from zero in the order they appear in their file. This is the number of the first one.
+    pub first_chunk: u64,
+    // The pieces of every chunk in the batch, in order, when the scan was asked for them. Chunks that came from the
+    // cache don't have any.
+    pub sub_blocks: Vec<SubBlock>,
+}
+
+// What the pipeline hands to the consumer. A file is always Found before any of its batches arrive. Its batches can
+// arrive before or after it is Finished, which just means every batch has been sent.
+#[derive(Debug)]
+pub enum Event {
+    Found {
+        file: u32,
+        // Which of the directories being scanned the file is in
+        root: u32,
+        path: String,
+        metadata: crate::catalog::Metadata,
+        // What the cache needs to know whether the file changes, which is only read when there is a cache
+        identity: Option<crate::cache::Identity>,
+        // The format the file was decompressed from, if it was chunked decompressed
+        decompressed: Option<crate::decompress::Codec>,
+    },
+    Hashed(HashedBatch),
+    Finished {
+        file: u32,
+        chunks: u64,
+    },
+}
+
+// Which chunking algorithm to run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chunking {
+    // Every chunk is FIXED_CHUNK_SIZE bytes, except maybe the last one in a file
+    Fixed,
+    Variable(rabin::chunker::ChunkerBuilder),
+}
+
+impl Chunking {
+    pub(crate) fn max_chunk(&self) -> usize {
+        match self {
+            Chunking::Fixed => FIXED_CHUNK_SIZE,
+            Chunking::Variable(builder) => builder.max(),
+        }
+    }
+
+    // Runs the chunking algorithm on the data. Chunks are found lazily as the iterator is advanced.
+    pub(crate) fn chunks<'a>(&self, data: &'a [u8]) -> Box<dyn Iterator<Item = &'a [u8]> + 'a> {
+        match self {
+            Chunking::Fixed => Box::new(data.chunks(FIXED_CHUNK_SIZE)),
+            Chunking::Variable(builder) => Box::new(builder.build(data)),
+        }
+    }
+}
+
+// A directory to scan and the exclusions that apply in it
+#[derive(Clone)]
+pub struct Root {
+    pub dir: path::PathBuf,
+    // Files and directories that match are skipped. Paths are matched relative to the directory.
+    pub exclude: ignore::gitignore::Gitignore,
+}
+
+// Where the pipeline gets the files to scan
+pub enum Input {
+    // Walks each of the directories
+    Walk(Vec<Root>),
+    // Reads the paths of the files from a list, separated by NUL bytes (like the output of find -print0), instead of
+    // walking anything. 'name' is where the list came from.
+    List {
+        name: String,
+        list: Box<dyn io::BufRead + Send>,
+    },
+}
+
+impl Input {
+    // What each file's root number refers to: the directories that are walked, or the list
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            Input::Walk(roots) => roots
+                .iter()
+                .map(|r| r.dir.to_string_lossy().into_owned())
+                .collect(),
+            Input::List { name, .. } => vec![name.clone()],
+        }
+    }
+}
+
+// How the pipeline finds and chunks files
+pub struct Options {
+    pub chunking: Chunking,
+    // How many bytes of the SHA3 hash make up a chunk id
+    pub key_len: usize,
+    pub threads: usize,
+    // Each hashing thread is pinned to one of these CPUs in turn, if given. Linux only.
+    pub hash_cpus: Option<Vec<usize>>,
+    // The reading and boundary detection threads, which mostly wait on the disk, may only run on these CPUs if given.
+    // Keeping them apart from the hashing threads' CPUs means neither stage takes cycles from the other.
+    pub io_cpus: Option<Vec<usize>>,
+    pub symlinks: crate::walk::Symlinks,
+    pub detect_hard_links: bool,
+    // Scan the alternate data streams of files on NTFS too, as if each one were a file of its own
+    pub streams: bool,
+    // Files are mapped into memory unless this is false, in which case they are read through a buffer as they are
+    // chunked. Mapping is faster, but a mapped file that shrinks while it is being scanned kills the process.
+    pub mmap: bool,
+    // Files are read with io_uring, keeping this many reads in flight, instead of being mapped or read one buffer at a
+    // time. Linux only; the files are read the usual way if io_uring can't be set up.
+    pub uring: Option<usize>,
+    // Files are read around the page cache, so that a one-off scan doesn't push out what the machine's real work
+    // needs. They are read through a buffer, as they are without mmap.
+    pub direct_io: bool,
+    // The files an interrupted scan had already numbered, in order, and how far it got through each of them. They keep
+    // their numbers, files that were finished aren't read again and chunks that were already stored are skipped.
+    pub resume: Vec<(String, crate::checkpoint::Progress)>,
+    // Compresses one chunk in this many to measure how compressible the data is, or none if it is None. The sample is
+    // picked by chunk id, so every copy of a chunk is either compressed or not.
+    pub compress_every: Option<u32>,
+    // Only these files are scanned, if given, and everything else the walk finds is passed over
+    pub only: Option<collections::HashSet<path::PathBuf>>,
+    // Files smaller than the first size or larger than the second are passed over, if given
+    pub min_file_size: Option<u64>,
+    pub max_file_size: Option<u64>,
+    // Files that haven't changed since the cache was written aren't read again; their chunks come from the cache
+    // instead. The cache is rewritten with the files this scan found.
+    pub cache: Option<crate::cache::Cache>,
+    // Each chunk is also cut at every multiple of this many bytes from the start of its file, and the pieces are
+    // hashed too, so that the same scan can measure how the data would dedup in fixed blocks (as storage arrays do).
+    pub sub_blocks: Option<usize>,
+    // Disk images (qcow2, sparse VMDK and VHD) are chunked as the disk the virtual machine sees, by reading through
+    // the image's tables, rather than as the image file. Images are always read through a buffer.
+    pub disk_images: bool,
+    // Files of these kinds are cut at the edges of their records (i.e. the messages in an mbox file) as well as where
+    // the chunker cuts them, so the same record gets the same chunks wherever it is
+    pub anchors: Vec<crate::anchor::Kind>,
+    // What is done with files that look already compressed (see compressed::detect)
+    pub compressed: crate::compressed::Policy,
+    // The members of tar, zip and 7z archives are each chunked as a file of their own, rather than the archive as one
+    // file. Members are read into memory one at a time.
+    pub archives: bool,
+}
+
+impl Options {
+    // Whether a file of this many bytes is inside the file size range
+    pub fn size_in_range(&self, len: u64) -> bool {
+        self.min_file_size.is_none_or(|min| len >= min)
+            && self.max_file_size.is_none_or(|max| len <= max)
+    }
+}
+
+// What the pipeline found besides the chunks themselves
+#[derive(Debug, Default)]
+pub struct Scanned {
+    // The path of each file, indexed by the number its batches carry
+    pub paths: Vec<String>,
+    pub skipped_hard_links: crate::walk::HardLinks,
+    // Files that were passed over for being outside the file size range, and the bytes in them
+    pub size_filtered_files: u64,
+    pub size_filtered_bytes: u64,
+    // Files that look already compressed, and the bytes in them, which were passed over or chunked anyway depending
+    // on the compressed file policy
+    pub skipped_compressed_files: u64,
+    pub skipped_compressed_bytes: u64,
+    pub flagged_compressed_files: u64,
+    pub flagged_compressed_bytes: u64,
+    // Bytes in the holes of sparse files. They aren't stored on disk, so they aren't chunked either.
+    pub hole_bytes: u64,
+    // Files and directories that couldn't be read, or were only read part of the way
+    pub skipped: crate::skipped::Skipped,
+    pub timings: Timings,
+}
+
+// How long one stage was busy and how many bytes went through it. Time spent waiting on the stages before and after it
+// isn't counted, so the stage with the lowest throughput is the one holding the others back.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Phase {
+    pub bytes: u64,
+    pub micros: u64,
+}
+
+impl Phase {
+    pub fn new(bytes: u64, busy: time::Duration) -> Phase {
+        Phase {
+            bytes,
+            micros: busy.as_micros() as u64,
+        }
+    }
+
+    pub fn add(&mut self, other: &Phase) {
+        self.bytes += other.bytes;
+        self.micros += other.micros;
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.micros as f64 / 1_000_000.0
+    }
+
+    // Bytes per second, or None if the stage took no time that could be measured or isn't measured in bytes
+    pub fn throughput(&self) -> Option<f64> {
+        match (self.bytes, self.micros) {
+            (0, _) | (_, 0) => None,
+            (bytes, micros) => Some(bytes as f64 * 1_000_000.0 / micros as f64),
+        }
+    }
+}
+
+// Each stage of the pipeline. Mapped files are only read from disk as boundary detection first touches them, so with
+// mmap the read is little more than opening the files and the time the disk takes is part of boundary detection.
+// Hashing is summed across its threads. Files taken from the cache are only inserted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Timings {
+    pub read: Phase,
+    pub boundary: Phase,
+    pub hash: Phase,
+    pub insert: Phase,
+}
+
+impl Timings {
+    pub fn add(&mut self, other: &Timings) {
+        self.read.add(&other.read);
+        self.boundary.add(&other.boundary);
+        self.hash.add(&other.hash);
+        self.insert.add(&other.insert);
+    }
+}
+
+// The contents of a file, ready to be chunked
+pub(crate) enum Contents {
+    Mapped(memmap::Mmap),
+    Owned(Vec<u8>),
+}
+
+impl ops::Deref for Contents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Contents::Mapped(mmap) => mmap,
+            Contents::Owned(bytes) => bytes,
+        }
+    }
+}
+
+impl Contents {
+    // Starts reading the part of a mapped file that will be chunked next
+    fn will_need(&self, range: ops::Range<usize>) {
+        if let Contents::Mapped(mmap) = self {
+            crate::advise::will_need(mmap, range);
+        }
+    }
+}
+
+// The parts of a file that actually hold data
+type Extents = Vec<ops::Range<usize>>;
+
+struct FoundFile {
+    file: u32,
+    path: path::PathBuf,
+    source: Source,
+    // The parts of the file that actually hold data. Each one is chunked on its own.
+    extents: Extents,
+    // The kind of records the file is made of, if it is cut at the edges of them too
+    anchors: Option<crate::anchor::Kind>,
+    // The chunks an interrupted scan already stored
+    stored: crate::checkpoint::Progress,
+    // Everything logged about the file, on any thread, is logged in this span
+    span: tracing::Span,
+}
+
+enum Source {
+    // The whole file is already in memory
+    Memory(sync::Arc<Contents>),
+    // The file is read a piece at a time while it is chunked
+    Stream(fs::File),
+    // The file is read a piece at a time through something that makes sense of it, such as the disk inside an image
+    // or the data inside a compressed file, while it is chunked
+    Reader(Box<dyn Stream + Send>),
+}
+
+struct ChunkBatch {
+    file: u32,
+    data: sync::Arc<Contents>,
+    // Where the data starts in the file
+    base: u64,
+    first_chunk: u64,
+    ranges: Vec<ops::Range<usize>>,
+    span: tracing::Span,
+}
+
+// Gathers the chunks found in a file into batches for the hashing threads, numbering them as it goes and leaving out
+// the ones that are already stored. A batch only ever holds consecutive chunks from one piece of data.
+struct Batcher<'a> {
+    sender: &'a mpsc::SyncSender<ChunkBatch>,
+    file: u32,
+    stored: &'a crate::checkpoint::Progress,
+    span: &'a tracing::Span,
+    data: sync::Arc<Contents>,
+    base: u64,
+    next_chunk: u64,
+    first_chunk: u64,
+    ranges: Vec<ops::Range<usize>>,
+    // How long sending batches waited for the hashing threads to catch up
+    blocked: time::Duration,
+    // Set once a batch can't be sent because the hashing threads have gone, after which nothing more is sent
+    stopped: bool,
+}
+
+impl<'a> Batcher<'a> {
+    fn new(
+        sender: &'a mpsc::SyncSender<ChunkBatch>,
+        file: u32,
+        stored: &'a crate::checkpoint::Progress,
+        span: &'a tracing::Span,
+    ) -> Batcher<'a> {
+        Batcher {
+            sender,
+            file,
+            stored,
+            span,
+            data: sync::Arc::new(Contents::Owned(vec![])),
+            base: 0,
+            next_chunk: 0,
+            first_chunk: 0,
+            ranges: Vec::with_capacity(BATCH_CHUNKS),
+            blocked: time::Duration::ZERO,
+            stopped: false,
+        }
+    }
+
+    // Chunks found from now on are ranges of this data, which starts at 'base' in the file
+    fn set_data(&mut self, data: sync::Arc<Contents>, base: u64) {
+        self.flush();
+        self.data = data;
+        self.base = base;
+    }
+
+    fn push(&mut self, range: ops::Range<usize>) {
+        let chunk = self.next_chunk;
+        self.next_chunk += 1;
+        if self.stored.is_stored(chunk) {
+            self.flush();
+            return;
+        }
+
+        if self.ranges.is_empty() {
+            self.first_chunk = chunk;
+        }
+        self.ranges.push(range);
+        if self.ranges.len() == BATCH_CHUNKS {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.ranges.is_empty() {
+            return;
+        }
+        let ranges = std::mem::replace(&mut self.ranges, Vec::with_capacity(BATCH_CHUNKS));
+        if self.stopped {
+            return;
+        }
+        let started = time::Instant::now();
+        let batch = ChunkBatch {
+            file: self.file,
+            data: self.data.clone(),
+            base: self.base,
+            first_chunk: self.first_chunk,
+            ranges,
+            span: self.span.clone(),
+        };
+        self.stopped = self.sender.send(batch).is_err();
+        self.blocked += started.elapsed();
+    }
+
+    // Sends whatever is left and returns how many chunks the file has, and how long sending waited. Returns None if the
+    // hashing threads have gone, so the file's chunks weren't all hashed.
+    fn finish(mut self) -> Option<(u64, time::Duration)> {
+        self.flush();
+        (!self.stopped).then_some((self.next_chunk, self.blocked))
+    }
+}
+
+// Chunks and hashes every file in the directories and calls 'consume' with the results on the calling thread. The work is
+// split into stages that run on their own threads, connected by bounded channels:
+//
+//   reader -> boundary detection -> hashing (on 'threads' threads) -> consume
+//
+// Files are numbered in the order the reader finds them, after the ones being resumed. The directories are walked one
+// after the other, and a directory or hard-linked file that several of them lead to is only scanned the first time.
+// Each stage is a function of its own. One whose next stage has gone (because 'consume' or a thread panicked) stops
+// quietly, and the panic is what run ends with.
+pub fn run(input: Input, options: &Options, consume: &mut dyn FnMut(Event)) -> Scanned {
+    let walked: Vec<path::PathBuf> = match &input {
+        Input::Walk(roots) => roots.iter().map(|r| r.dir.clone()).collect(),
+        Input::List { .. } => vec![],
+    };
+    thread::scope(|scope| {
+        let (file_sender, file_receiver) = mpsc::sync_channel::<FoundFile>(CHANNEL_DEPTH);
+        let (batch_sender, batch_receiver) = mpsc::sync_channel::<ChunkBatch>(CHANNEL_DEPTH);
+        let (event_sender, event_receiver) = mpsc::sync_channel::<Event>(CHANNEL_DEPTH);
+
+        let reader_events = event_sender.clone();
+        let reader = scope.spawn(move || read_files(input, options, file_sender, reader_events));
+        let boundary_events = event_sender.clone();
+        let boundary = scope
+            .spawn(move || find_boundaries(options, file_receiver, batch_sender, boundary_events));
+        let batch_receiver = sync::Arc::new(sync::Mutex::new(batch_receiver));
+        let mut hashers = vec![];
+        for i in 0..options.threads.max(1) {
+            let batch_receiver = batch_receiver.clone();
+            let event_sender = event_sender.clone();
+            hashers
+                .push(scope.spawn(move || hash_batches(options, i, &batch_receiver, event_sender)));
+        }
+        drop(event_sender);
+
+        // Consuming happens right here, so it needs no synchronization of its own. The new cache is written as the
+        // events go by; the scan carries on without one if it can't be.
+        let mut recorder = options.cache.as_ref().and_then(|cache| {
+            cache
+                .record(walked)
+                .map_err(|e| tracing::warn!(error = %e, "can't write the cache"))
+                .ok()
+        });
+        let (mut inserted, mut inserting) = (0, time::Duration::ZERO);
+        for event in event_receiver {
+            if let Some(r) = recorder.as_mut() {
+                if let Err(e) = r.observe(&event) {
+                    tracing::warn!(error = %e, "can't write the cache");
+                    recorder = None;
+                }
+            }
+            if let Event::Hashed(batch) = &event {
+                inserted += batch.chunks.iter().map(|c| c.size as u64).sum::<u64>();
+            }
+            let started = time::Instant::now();
+            consume(event);
+            inserting += started.elapsed();
+        }
+        if let Some(Err(e)) = recorder.map(|r| r.finish()) {
+            tracing::warn!(error = %e, "can't write the cache");
+        }
+
+        let mut scanned = joined(reader);
+        let (skipped, read, boundary) = joined(boundary);
+        scanned.skipped.add(skipped);
+        scanned.timings.read.add(&read);
+        scanned.timings.boundary = boundary;
+        for hasher in hashers {
+            scanned.timings.hash.add(&joined(hasher));
+        }
+        scanned.timings.insert = Phase::new(inserted, inserting);
+        scanned
+    })
+}
+
+// The reading stage: walks the directories (or reads the list) and opens, and usually maps, each file for boundary
+// detection. Files that need no chunking (empty, cached or unreadable) go straight to the events. Stops early if either
+// stage after it has gone, since nothing it finds could be used.
+fn read_files(
+    input: Input,
+    options: &Options,
+    file_sender: mpsc::SyncSender<FoundFile>,
+    events: mpsc::SyncSender<Event>,
+) -> Scanned {
+    pin(options.io_cpus.as_deref(), "reader");
+    let mut paths: Vec<String> = options
+        .resume
+        .iter()
+        .map(|(path, _)| path.clone())
+        .collect();
+    let resumed: collections::HashMap<&str, usize> = options
+        .resume
+        .iter()
+        .enumerate()
+        .map(|(file, (path, _))| (path.as_str(), file))
+        .collect();
+    let mut hole_bytes = 0;
+    let mut size_filtered_files = 0;
+    let mut size_filtered_bytes = 0;
+    let mut skipped_compressed_files = 0;
+    let mut skipped_compressed_bytes = 0;
+    let mut flagged_compressed_files = 0;
+    let mut flagged_compressed_bytes = 0;
+    let mut read = Phase::default();
+    let mut skipped = crate::skipped::Skipped::default();
+    let mut walker =
+        crate::walk::Walker::new(options.symlinks, options.detect_hard_links, options.streams);
+    // Numbers a file the first time it is found, unless an interrupted scan numbered it already
+    let mut number = |path_string: &str| match resumed.get(path_string) {
+        Some(&file) => (file as u32, options.resume[file].1.clone()),
+        None => {
+            paths.push(path_string.to_string());
+            (
+                (paths.len() - 1) as u32,
+                crate::checkpoint::Progress::default(),
+            )
+        }
+    };
+    // Set once a later stage has gone, after which nothing more is read
+    let stopped = cell::Cell::new(false);
+    let send_event = |event: Event| {
+        let sent = events.send(event).is_ok();
+        stopped.set(stopped.get() || !sent);
+        sent
+    };
+    let send_file = |found: FoundFile| {
+        let sent = file_sender.send(found).is_ok();
+        stopped.set(stopped.get() || !sent);
+    };
+    let mut visit = |root: u32, path: &path::Path, found: crate::walk::Found| {
+        if stopped.get() {
+            return;
+        }
+        if let Some(ref only) = options.only {
+            if !only.contains(path) {
+                return;
+            }
+        }
+        if options.min_file_size.is_some() || options.max_file_size.is_some() {
+            let len = crate::catalog::Metadata::read(path, found).len;
+            if !options.size_in_range(len) {
+                size_filtered_files += 1;
+                size_filtered_bytes += len;
+                return;
+            }
+        }
+        if options.archives && found == crate::walk::Found::File {
+            if let Some(format) = crate::archive::detect(path) {
+                let started = time::Instant::now();
+                let (mut members, mut bytes) = (0, 0);
+                let expanded = crate::archive::members(path, format, &mut |member| {
+                    members += 1;
+                    if !options.size_in_range(member.metadata.len) {
+                        size_filtered_files += 1;
+                        size_filtered_bytes += member.metadata.len;
+                        return;
+                    }
+                    let (file, stored) = number(&member.path);
+                    if stored.is_complete() || stopped.get() {
+                        return;
+                    }
+                    let span = tracing::debug_span!("file", file, path = %member.path);
+                    let _entered = span.enter();
+                    let found = Event::Found {
+                        file,
+                        root,
+                        path: member.path.clone(),
+                        metadata: member.metadata,
+                        identity: None,
+                        decompressed: None,
+                    };
+                    if !send_event(found) {
+                        return;
+                    }
+                    let len = member.data.len();
+                    if 0 == len {
+                        send_event(Event::Finished { file, chunks: 0 });
+                        return;
+                    }
+                    bytes += len as u64;
+                    send_file(FoundFile {
+                        file,
+                        path: path::PathBuf::from(member.path),
+                        anchors: crate::anchor::detect_in(&options.anchors, &member.data[..]),
+                        source: Source::Memory(sync::Arc::new(Contents::Owned(member.data))),
+                        extents: std::iter::once(0..len).collect(),
+                        stored,
+                        span: span.clone(),
+                    });
+                });
+                read.add(&Phase::new(bytes, started.elapsed()));
+                match expanded {
+                    Ok(()) => return,
+                    // An archive that can't be read from the start, or has a member too large to read, is
+                    // chunked like any other file
+                    Err(e) if members == 0 => {
+                        tracing::info!(path = %path.display(), "chunking the archive as a file: {}", e);
+                    }
+                    Err(e) => {
+                        skipped.record(path, &e);
+                        return;
+                    }
+                }
+            }
+        }
+        let mut codec = None;
+        if options.compressed != crate::compressed::Policy::Chunk
+            && found == crate::walk::Found::File
+        {
+            if let Some(format) = crate::compressed::detect(path) {
+                tracing::debug!(path = %path.display(), format, "compressed");
+                if options.compressed == crate::compressed::Policy::Decompress {
+                    codec = crate::decompress::Codec::from_format(format);
+                }
+                let len = crate::catalog::Metadata::read(path, found).len;
+                if options.compressed == crate::compressed::Policy::Skip {
+                    skipped_compressed_files += 1;
+                    skipped_compressed_bytes += len;
+                    return;
+                }
+                if codec.is_none() {
+                    flagged_compressed_files += 1;
+                    flagged_compressed_bytes += len;
+                }
+            }
+        }
+        let path_string = path.to_string_lossy().into_owned();
+        let (file, stored) = number(&path_string);
+        if stored.is_complete() {
+            // Still worth opening to count the holes, since only this walk's totals are kept
+            if let Ok(Some((_, len, extents))) = open_file(path, false) {
+                hole_bytes += (len - extents.iter().map(|e| e.len()).sum::<usize>()) as u64;
+            }
+            return;
+        }
+        let span = tracing::debug_span!("file", file, path = %path.display());
+        let _entered = span.enter();
+        let identity = options
+            .cache
+            .as_ref()
+            .and_then(|_| crate::cache::Identity::read(path, found));
+        // A file that was partly stored before the scan was interrupted is read, so the numbers of the chunks
+        // that are left line up with what was stored
+        let cached = match (&options.cache, identity) {
+            (Some(cache), Some(identity)) if stored == crate::checkpoint::Progress::default() => {
+                cache.get(&path_string, &identity)
+            }
+            _ => None,
+        };
+        // Decompressing the file to find its length is also how a file that only looks compressed is found out,
+        // before it is recorded as decompressed
+        let mut decompressed = None;
+        if let (Some(c), None) = (codec, &cached) {
+            match open_decompressed(path, c) {
+                Ok(opened) => decompressed = Some(opened),
+                Err(e) => {
+                    tracing::warn!(error = %e, "can't decompress the file, so it is chunked as it is");
+                    codec = None;
+                }
+            }
+        }
+        let found_event = Event::Found {
+            file,
+            root,
+            path: path_string,
+            metadata: crate::catalog::Metadata::read(path, found),
+            identity,
+            decompressed: codec,
+        };
+        if !send_event(found_event) {
+            return;
+        }
+
+        if let (Some(chunks), Some(identity)) = (cached, identity) {
+            tracing::debug!(chunks = chunks.len(), "cached");
+            hole_bytes += identity
+                .len
+                .saturating_sub(chunks.iter().map(|c| c.size as u64).sum());
+            for (i, batch) in chunks.chunks(BATCH_CHUNKS).enumerate() {
+                let hashed = Event::Hashed(HashedBatch {
+                    file,
+                    chunks: batch.to_vec(),
+                    first_chunk: (i * BATCH_CHUNKS) as u64,
+                    sub_blocks: vec![],
+                });
+                if !send_event(hashed) {
+                    return;
+                }
+            }
+            send_event(Event::Finished {
+                file,
+                chunks: chunks.len() as u64,
+            });
+            return;
+        }
+
+        let started = time::Instant::now();
+        let opened = match decompressed {
+            Some(opened) => Ok(opened),
+            None => open_source(path, found, options),
+        };
+        match opened {
+            Ok(Some((source, len, extents))) => {
+                let data_bytes: usize = extents.iter().map(|e| e.len()).sum();
+                hole_bytes += (len - data_bytes) as u64;
+                // Streamed files are read as they are chunked, which counts towards reading there
+                if matches!(source, Source::Memory(_)) {
+                    read.add(&Phase::new(data_bytes as u64, started.elapsed()));
+                }
+                send_file(FoundFile {
+                    file,
+                    path: path.to_path_buf(),
+                    source,
+                    extents,
+                    anchors: match codec {
+                        Some(codec) => crate::decompress::Reader::open(path, codec)
+                            .ok()
+                            .and_then(|reader| crate::anchor::detect_in(&options.anchors, reader)),
+                        None => crate::anchor::detect(&options.anchors, path),
+                    },
+                    stored,
+                    span: span.clone(),
+                });
+            }
+            // Empty files have no chunks
+            Ok(None) => {
+                send_event(Event::Finished { file, chunks: 0 });
+            }
+            Err(e) => {
+                skipped.record(path, &e);
+                send_event(Event::Finished { file, chunks: 0 });
+            }
+        }
+    };
+    match input {
+        Input::Walk(roots) => {
+            for (root, r) in roots.iter().enumerate() {
+                walker.walk(&r.dir, &r.exclude, &mut |path, found| {
+                    visit(root as u32, path, found)
+                });
+            }
+        }
+        Input::List { name, list } => {
+            for path in io::BufRead::split(list, 0) {
+                if stopped.get() {
+                    break;
+                }
+                match path {
+                    Ok(path) if path.is_empty() => {}
+                    Ok(path) => {
+                        walker.file(&list_path(path), &mut |path, found| visit(0, path, found))
+                    }
+                    // Whatever is left of the list is lost
+                    Err(e) => {
+                        skipped.record(path::Path::new(&name), &e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    skipped.add(walker.skipped());
+    Scanned {
+        paths,
+        skipped_hard_links: walker.skipped_hard_links(),
+        size_filtered_files,
+        size_filtered_bytes,
+        skipped_compressed_files,
+        skipped_compressed_bytes,
+        flagged_compressed_files,
+        flagged_compressed_bytes,
+        hole_bytes,
+        skipped,
+        timings: Timings {
+            read,
+            ..Default::default()
+        },
+    }
+}
+
+// The boundary detection stage: finds where each chunk of a file starts and ends, without looking at the chunk
+// contents again, and passes them on in batches for hashing. Returns the files that couldn't be read, and the time
+// spent reading streamed files and finding boundaries.
+fn find_boundaries(
+    options: &Options,
+    files: mpsc::Receiver<FoundFile>,
+    batch_sender: mpsc::SyncSender<ChunkBatch>,
+    events: mpsc::SyncSender<Event>,
+) -> (crate::skipped::Skipped, Phase, Phase) {
+    pin(options.io_cpus.as_deref(), "boundary detection");
+    let chunking = options.chunking;
+    let mut skipped = crate::skipped::Skipped::default();
+    let mut ring = options.uring.and_then(|depth| {
+        crate::uring::Ring::new(depth)
+            .map_err(|e| tracing::warn!(error = %e, "can't set up io_uring, so files are read the usual way"))
+            .ok()
+    });
+    let mut direct = options.direct_io.then(crate::direct::Reader::new);
+    let (mut read, mut boundary) = (Phase::default(), Phase::default());
+    for found in files {
+        let _entered = found.span.enter();
+        let started = time::Instant::now();
+        let mut reading = time::Duration::ZERO;
+        let streamed = matches!(found.source, Source::Stream(_) | Source::Reader(_));
+        let mut batcher = Batcher::new(&batch_sender, found.file, &found.stored, &found.span);
+        match found.source {
+            Source::Memory(data) => {
+                batcher.set_data(data.clone(), 0);
+                for extent in found.extents.iter() {
+                    let mut start = extent.start;
+                    // Keep at least half a buffer's worth of the file being read ahead of the chunker
+                    let mut advised = extent.start;
+                    let anchors: Vec<usize> = found.anchors.map_or(vec![], |kind| {
+                        crate::anchor::find(kind, &data[extent.clone()], extent.start as u64)
+                            .into_iter()
+                            .map(|a| a as usize)
+                            .collect()
+                    });
+                    for piece in crate::anchor::split(extent.clone(), &anchors) {
+                        for c in chunking.chunks(&data[piece]) {
+                            if start + STREAM_BUFFER_BYTES / 2 >= advised && advised < extent.end {
+                                let next = (advised + STREAM_BUFFER_BYTES).min(extent.end);
+                                data.will_need(advised..next);
+                                advised = next;
+                            }
+                            batcher.push(start..start + c.len());
+                            start += c.len();
+                        }
+                    }
+                }
+            }
+            Source::Stream(opened) => {
+                // The chunks found before the error are kept, but the rest of the file is missing
+                let streamed = match ring.as_mut() {
+                    Some(ring) => ring_chunks(
+                        ring,
+                        &opened,
+                        &found.extents,
+                        chunking,
+                        found.anchors,
+                        &mut batcher,
+                        &mut reading,
+                    ),
+                    None => stream_chunks(
+                        &opened,
+                        &found.extents,
+                        chunking,
+                        found.anchors,
+                        direct.as_mut(),
+                        &mut batcher,
+                        &mut reading,
+                    ),
+                };
+                if let Err(e) = streamed {
+                    skipped.record(&found.path, &e);
+                }
+            }
+            Source::Reader(reader) => {
+                let streamed = stream_chunks(
+                    reader,
+                    &found.extents,
+                    chunking,
+                    found.anchors,
+                    None,
+                    &mut batcher,
+                    &mut reading,
+                );
+                if let Err(e) = streamed {
+                    skipped.record(&found.path, &e);
+                }
+            }
+        }
+        // The hashing threads only go if the scan is stopping
+        let (chunks, blocked) = match batcher.finish() {
+            Some(finished) => finished,
+            None => break,
+        };
+        let data_bytes = found.extents.iter().map(|e| e.len() as u64).sum();
+        if streamed {
+            read.add(&Phase::new(data_bytes, reading));
+        }
+        boundary.add(&Phase::new(
+            data_bytes,
+            started.elapsed().saturating_sub(reading + blocked),
+        ));
+        tracing::debug!(chunks, "chunked");
+        let finished = Event::Finished {
+            file: found.file,
+            chunks,
+        };
+        if events.send(finished).is_err() {
+            break;
+        }
+    }
+    (skipped, read, boundary)
+}
+
+// One of the hashing threads, the expensive part, which take batches from the boundary detection stage in turn. Returns
+// how many bytes this one hashed and how long it spent at it.
+fn hash_batches(
+    options: &Options,
+    thread: usize,
+    batches: &sync::Mutex<mpsc::Receiver<ChunkBatch>>,
+    events: mpsc::SyncSender<Event>,
+) -> Phase {
+    if let Some(cpus) = options.hash_cpus.as_deref().filter(|cpus| !cpus.is_empty()) {
+        pin(Some(&cpus[thread % cpus.len()..][..1]), "hashing");
+    }
+    let mut hasher = sha3::Sha3_256::default();
+    // Compressing is only a sample for the report, so the scan carries on without it if zstd fails
+    let mut compressor = options.compress_every.and_then(|every| {
+        zstd::bulk::Compressor::new(ZSTD_LEVEL)
+            .map_err(|e| tracing::warn!(error = %e, "can't start compressing chunks"))
+            .ok()
+            .map(|compressor| (compressor, every))
+    });
+    let (mut bytes, mut busy) = (0, time::Duration::ZERO);
+
+    loop {
+        // Only hold the lock long enough to take the next batch. The lock is only poisoned if another hashing thread
+        // panicked, which stops the scan anyway.
+        let batch = match batches.lock().map(|batches| batches.recv()) {
+            Ok(Ok(batch)) => batch,
+            _ => break,
+        };
+
+        let _entered = batch.span.enter();
+        tracing::trace!(
+            first_chunk = batch.first_chunk,
+            chunks = batch.ranges.len(),
+            "hashing"
+        );
+        let started = time::Instant::now();
+        let chunks: Vec<HashedChunk> = batch
+            .ranges
+            .iter()
+            .map(|range| {
+                let c = &batch.data[range.clone()];
+                let key = hash_key(&mut hasher, c, options.key_len);
+                let compressed = match compressor.as_mut() {
+                    Some((compressor, every)) if in_sample(&key, *every) => {
+                        compressor.compress(c).map_or(0, |z| z.len() as u32)
+                    }
+                    _ => 0,
+                };
+                HashedChunk {
+                    key,
+                    check: sha2_check(c),
+                    size: c.len() as u32,
+                    offset: batch.base + range.start as u64,
+                    compressed,
+                    high_entropy: crate::entropy::is_high_entropy(c),
+                }
+            })
+            .collect();
+        let mut sub_blocks = vec![];
+        if let Some(block) = options.sub_blocks {
+            for range in batch.ranges.iter() {
+                let offset = batch.base + range.start as u64;
+                for piece in split_blocks(&batch.data[range.clone()], offset, block) {
+                    let key = hash_key(&mut hasher, piece, 8);
+                    let mut prefix = [0u8; 8];
+                    prefix.copy_from_slice(&key[0..8]);
+                    sub_blocks.push(SubBlock {
+                        fingerprint: u64::from_be_bytes(prefix),
+                        size: piece.len() as u32,
+                    });
+                }
+            }
+        }
+        busy += started.elapsed();
+        bytes += chunks.iter().map(|c| c.size as u64).sum::<u64>();
+        // Nothing is left to hash for if whatever consumes the events has gone
+        let hashed = Event::Hashed(HashedBatch {
+            file: batch.file,
+            chunks,
+            first_chunk: batch.first_chunk,
+            sub_blocks,
+        });
+        if events.send(hashed).is_err() {
+            break;
+        }
+    }
+    Phase::new(bytes, busy)
+}
+
+// Waits for a stage to finish. A stage that panicked is a bug, so its panic carries on here as it was.
+fn joined<T>(stage: thread::ScopedJoinHandle<T>) -> T {
+    stage
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+// Paths are just bytes on Unix, so any path can be in a list. Elsewhere they have to be UTF-8.
+#[cfg(unix)]
+fn list_path(bytes: Vec<u8>) -> path::PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    path::PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn list_path(bytes: Vec<u8>) -> path::PathBuf {
+    path::PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// Pins the calling thread to the CPUs, if there are any. The scan carries on wherever the thread ends up if it can't be.
+fn pin(cpus: Option<&[usize]>, stage: &str) {
+    if let Some(cpus) = cpus {
+        if let Err(e) = crate::affinity::pin(cpus) {
+            tracing::warn!(error = %e, ?cpus, stage, "can't pin the thread to its CPUs");
+        }
+    }
+}
+
+// Gets what was found ready to be chunked, along with its length and the parts of it that hold data. Returns None if
+// there is nothing to chunk.
+fn open_source(
+    path: &path::Path,
+    found: crate::walk::Found,
+    options: &Options,
+) -> io::Result<Option<(Source, usize, Extents)>> {
+    match found {
+        crate::walk::Found::File => {
+            if options.disk_images {
+                if let Some(image) = open_image(path)? {
+                    return Ok(Some(image));
+                }
+            }
+            let (opened, len, extents) = match open_file(path, options.direct_io)? {
+                Some(opened) => opened,
+                None => return Ok(None),
+            };
+            let source = if options.mmap && options.uring.is_none() && !options.direct_io {
+                let mmap = unsafe { memmap::Mmap::map(&opened)? };
+                crate::advise::sequential(&mmap);
+                Source::Memory(sync::Arc::new(Contents::Mapped(mmap)))
+            } else {
+                // The page cache isn't used for direct IO, so there is nothing to advise it about
+                if !options.direct_io {
+                    crate::advise::file_sequential(&opened);
+                }
+                Source::Stream(opened)
+            };
+            Ok(Some((source, len, extents)))
+        }
+        crate::walk::Found::Symlink => {
+            let bytes = fs::read_link(path)?
+                .to_string_lossy()
+                .into_owned()
+                .into_bytes();
+            let len = bytes.len();
+            let source = Source::Memory(sync::Arc::new(Contents::Owned(bytes)));
+            Ok(Some((source, len, std::iter::once(0..len).collect())))
+        }
+    }
+}
+
+// Reads the tables of the disk image at the path, and gets the disk inside it ready to be chunked with its length and
+// the parts of it the image holds. Returns None if the file isn't an image, or is one that can't be read this way, so
+// that the file itself is chunked instead.
+fn open_image(path: &path::Path) -> io::Result<Option<(Source, usize, Extents)>> {
+    let mut file = fs::File::open(path)?;
+    let image = match crate::image::open(&mut file) {
+        Ok(Some(image)) => image,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            tracing::warn!(error = %e, "can't read the disk image, so the image file is chunked as it is");
+            return Ok(None);
+        }
+    };
+    tracing::debug!(format = ?image.format, len = image.len, "disk image");
+    let len = image.len as usize;
+    let extents = image
+        .extents()
+        .into_iter()
+        .map(|e| e.start as usize..e.end as usize)
+        .collect();
+    Ok(Some((
+        Source::Reader(Box::new(crate::image::Reader::new(file, image))),
+        len,
+        extents,
+    )))
+}
+
+// Gets the data inside a compressed file ready to be chunked, with its length. Returns None if there is none.
+fn open_decompressed(
+    path: &path::Path,
+    codec: crate::decompress::Codec,
+) -> io::Result<Option<(Source, usize, Extents)>> {
+    let len = crate::decompress::decompressed_len(path, codec)? as usize;
+    tracing::debug!(?codec, len, "decompressed");
+    if 0 == len {
+        return Ok(None);
+    }
+    let reader = crate::decompress::Reader::open(path, codec)?;
+    Ok(Some((
+        Source::Reader(Box::new(reader)),
+        len,
+        std::iter::once(0..len).collect(),
+    )))
+}
+
+// Opens the file and finds its length and the parts of it that hold data, or returns None if it is empty. A file opened
+// for direct IO has to be read with crate::direct::Reader.
+fn open_file(path: &path::Path, direct: bool) -> io::Result<Option<(fs::File, usize, Extents)>> {
+    let file = if direct {
+        crate::direct::open(path)?
+    } else {
+        fs::OpenOptions::new().read(true).open(path)?
+    };
+
+    // Can't mmap zero-length files, and there's nothing to chunk in them anyway
+    let len = file.metadata()?.len() as usize;
+    if 0 == len {
+        return Ok(None);
+    }
+
+    let extents = data_extents(&file, len);
+    Ok(Some((file, len, extents)))
+}
+
+// What a file is streamed from: the file itself, the disk inside an image, or the data inside a compressed file
+trait Stream: io::Read + io::Seek {
+    // The file being read, if it is read as it is, for direct IO and for asking the kernel to read ahead
+    fn file(&self) -> Option<&fs::File>;
+}
+
+impl Stream for &fs::File {
+    fn file(&self) -> Option<&fs::File> {
+        Some(self)
+    }
+}
+
+impl Stream for crate::image::Reader {
+    fn file(&self) -> Option<&fs::File> {
+        None
+    }
+}
+
+impl Stream for crate::decompress::Reader {
+    fn file(&self) -> Option<&fs::File> {
+        None
+    }
+}
+
+impl<S: Stream + ?Sized> Stream for Box<S> {
+    fn file(&self) -> Option<&fs::File> {
+        (**self).file()
+    }
+}
+
+// The anchors found so far in an extent that is being streamed, for a file that has them
+struct StreamAnchors {
+    finder: Option<crate::anchor::Finder>,
+    found: Vec<u64>,
+}
+
+impl StreamAnchors {
+    fn new(kind: Option<crate::anchor::Kind>, start: usize) -> StreamAnchors {
+        StreamAnchors {
+            finder: kind.map(|kind| crate::anchor::Finder::new(kind, start as u64)),
+            found: vec![],
+        }
+    }
+
+    // Looks for anchors in the bytes just read, which come right after the ones before
+    fn feed(&mut self, data: &[u8]) {
+        if let Some(finder) = self.finder.as_mut() {
+            finder.feed(data, &mut self.found);
+        }
+    }
+
+    // The chunks at the front of a streaming buffer, which starts at 'buffer_start' in the file, that can be cut
+    // already. The anchors at or before the last of them are no longer needed.
+    fn ranges(
+        &mut self,
+        chunking: &Chunking,
+        buffer: &[u8],
+        buffer_start: u64,
+        ended: bool,
+    ) -> Vec<ops::Range<usize>> {
+        let anchors: Vec<usize> = self
+            .found
+            .iter()
+            .map(|&a| (a - buffer_start) as usize)
+            .collect();
+        let known = match (&self.finder, ended) {
+            (_, true) => None,
+            (Some(finder), false) => Some(finder.known().saturating_sub(buffer_start) as usize),
+            (None, false) => Some(buffer.len()),
+        };
+        let ranges = anchored_ranges(chunking, buffer, &anchors, known);
+        let cut = buffer_start + ranges.last().map_or(0, |r| r.end) as u64;
+        self.found.retain(|&a| a > cut);
+        ranges
+    }
+}
+
+// Reads each extent of the file (or of the disk in an image) through a buffer and chunks it as it goes, sending the
+// chunks in each buffer as soon as they are found. The chunks are exactly the ones that chunking the whole extent at
+// once would find: where a chunk ends only depends on the bytes (and anchors) up to the largest chunk size past its
+// start, so a chunk isn't cut until that many bytes have been read or the extent has ended.
+fn stream_chunks<S: Stream>(
+    mut opened: S,
+    extents: &[ops::Range<usize>],
+    chunking: Chunking,
+    anchors: Option<crate::anchor::Kind>,
+    mut direct: Option<&mut crate::direct::Reader>,
+    batcher: &mut Batcher,
+    reading: &mut time::Duration,
+) -> io::Result<()> {
+    let max_chunk = chunking.max_chunk();
+
+    for extent in extents.iter() {
+        opened.seek(io::SeekFrom::Start(extent.start as u64))?;
+        let mut unread = extent.len();
+        let mut buffer = vec![];
+        let mut buffer_start = extent.start as u64;
+        let mut found = StreamAnchors::new(anchors, extent.start);
+        loop {
+            // Top the buffer up, keeping whatever was left over from the last one at the front
+            let read_from = buffer.len();
+            let read_len = stream_read_len(read_from, unread, max_chunk, STREAM_BUFFER_BYTES);
+            buffer.resize(read_from + read_len, 0);
+            let started = time::Instant::now();
+            match (direct.as_mut(), opened.file()) {
+                (Some(direct), Some(file)) => direct.read_exact_at(
+                    file,
+                    buffer_start + read_from as u64,
+                    &mut buffer[read_from..],
+                )?,
+                _ => opened.read_exact(&mut buffer[read_from..])?,
+            }
+            *reading += started.elapsed();
+            unread -= read_len;
+            found.feed(&buffer[read_from..]);
+            // Have the next buffer read in while this one is chunked
+            if let (None, Some(file), true) = (direct.as_ref(), opened.file(), unread > 0) {
+                let next = extent.end - unread;
+                crate::advise::file_will_need(file, next..next + unread.min(STREAM_BUFFER_BYTES));
+            }
+
+            let ranges = found.ranges(&chunking, &buffer, buffer_start, unread == 0);
+            let start = ranges.last().map_or(0, |r| r.end);
+            let leftover = buffer[start..].to_vec();
+            let data = sync::Arc::new(Contents::Owned(std::mem::replace(&mut buffer, leftover)));
+            batcher.set_data(data, buffer_start);
+            for range in ranges {
+                batcher.push(range);
+            }
+            buffer_start += start as u64;
+            if unread == 0 {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Chunks each extent of the file as io_uring reads it, with several pieces of it in flight at once. The pieces arrive
+// in order and are cut into chunks the same way stream_chunks cuts its buffers, so the chunks are the same too.
+fn ring_chunks(
+    ring: &mut crate::uring::Ring,
+    opened: &fs::File,
+    extents: &[ops::Range<usize>],
+    chunking: Chunking,
+    anchors: Option<crate::anchor::Kind>,
+    batcher: &mut Batcher,
+    reading: &mut time::Duration,
+) -> io::Result<()> {
+    for extent in extents.iter() {
+        let mut buffer = vec![];
+        let mut buffer_start = extent.start as u64;
+        let mut unread = extent.len();
+        let mut found = StreamAnchors::new(anchors, extent.start);
+        ring.read(opened, extent.clone(), reading, &mut |piece| {
+            unread -= piece.len();
+            found.feed(&piece);
+            if buffer.is_empty() {
+                buffer = piece;
+            } else {
+                buffer.extend_from_slice(&piece);
+            }
+            let ranges = found.ranges(&chunking, &buffer, buffer_start, unread == 0);
+            let start = match ranges.last() {
+                Some(last) => last.end,
+                None => return,
+            };
+            let leftover = buffer[start..].to_vec();
+            let data = sync::Arc::new(Contents::Owned(std::mem::replace(&mut buffer, leftover)));
+            batcher.set_data(data, buffer_start);
+            for range in ranges {
+                batcher.push(range);
+            }
+            buffer_start += start as u64;
+        })?;
+    }
+    Ok(())
+}
+
+// How much to read into a streaming buffer that already holds 'buffered' bytes: enough to fill it, and always enough
+// for the largest chunk, but no more than is left of the extent
+pub(crate) fn stream_read_len(
+    buffered: usize,
+    unread: usize,
+    max_chunk: usize,
+    buffer_bytes: usize,
+) -> usize {
+    (buffer_bytes.max(buffered + max_chunk) - buffered).min(unread)
+}
+
+// The chunks at the front of a streaming buffer that can be cut already. Until the extent has 'ended', a chunk is only
+// cut once the buffer holds the largest chunk size past its start; the rest wait for the next read.
+pub(crate) fn stream_ranges(
+    chunking: &Chunking,
+    buffer: &[u8],
+    ended: bool,
+) -> Vec<ops::Range<usize>> {
+    anchored_ranges(chunking, buffer, &[], (!ended).then_some(buffer.len()))
+}
+
+// Like stream_ranges, but the buffer is also cut at each of the anchors (offsets in the buffer, in order). The anchors
+// have all been found up to 'known', or to the end of the buffer if it is None because the extent has ended, and a
+// chunk is only cut once everything up to the largest chunk size past its start is known.
+fn anchored_ranges(
+    chunking: &Chunking,
+    buffer: &[u8],
+    anchors: &[usize],
+    known: Option<usize>,
+) -> Vec<ops::Range<usize>> {
+    let max_chunk = chunking.max_chunk();
+    let mut ranges = vec![];
+    let mut start = 0;
+    for piece in crate::anchor::split(0..buffer.len(), anchors) {
+        for c in chunking.chunks(&buffer[piece]) {
+            if known.is_some_and(|known| start + max_chunk > known) {
+                return ranges;
+            }
+            ranges.push(start..start + c.len());
+            start += c.len();
+        }
+    }
+    ranges
+}
+
+// Asks the filesystem where the data in a sparse file is so the holes can be skipped. Reading a hole just returns
+// zeros, and a VM image or preallocated database file can be mostly holes. Filesystems that don't keep track of holes
+// report the whole file as data.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn data_extents(file: &fs::File, len: usize) -> Vec<ops::Range<usize>> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut extents = vec![];
+    let mut offset = 0;
+    while offset < len {
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            // There is no more data after the offset, only a hole that runs to the end of the file
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return std::iter::once(0..len).collect();
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return std::iter::once(0..len).collect();
+        }
+
+        // The file may have changed size since it was mapped
+        let start = (start as usize).min(len);
+        let end = (end as usize).min(len);
+        if start < end {
+            extents.push(start..end);
+        }
+        offset = end.max(offset + 1);
+    }
+    extents
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn data_extents(_file: &fs::File, len: usize) -> Vec<ops::Range<usize>> {
+    iter::once(0..len).collect()
+}
+
+// Whether the chunk is one of the one in 'every' that are compressed. Ids are hashes, so any bits of them will do.
+fn in_sample(key: &crate::run::Key, every: u32) -> bool {
+    u32::from_le_bytes([key[0], key[1], key[2], key[3]]).is_multiple_of(every)
+}
+
+// Cuts a chunk that starts 'offset' bytes into its file at every multiple of 'block' bytes from the start of the file.
+// The pieces in the middle are whole blocks; the first and last may be parts of one.
+pub(crate) fn split_blocks(
+    chunk: &[u8],
+    offset: u64,
+    block: usize,
+) -> impl Iterator<Item = &[u8]> + '_ {
+    let first = (block - (offset % block as u64) as usize) % block;
+    let (head, rest) = chunk.split_at(first.min(chunk.len()));
+    iter::once(head)
+        .filter(|head| !head.is_empty())
+        .chain(rest.chunks(block))
+}
+
+// Hashes the chunk with SHA3 and keeps as many bytes of the hash as the id is supposed to have
+pub fn hash_key(hasher: &mut sha3::Sha3_256, chunk: &[u8], key_len: usize) -> crate::run::Key {
+    use sha3::digest::{FixedOutputReset, Update};
+
+    hasher.update(chunk);
+    crate::run::key_from(&hasher.finalize_fixed_reset(), key_len)
+}
+
+// SHA2 and SHA3 are completely different algorithms. It is extremely unlikely that and particular piece of data will
+// have even just these four bytes of SHA2 match another piece of data with the same SHA3 hash.
+fn sha2_check(chunk: &[u8]) -> u32 {
+    let hash = rabin::hash_chunk_sha256(chunk);
+
+    (hash[0] as u32) << 24 | (hash[1] as u32) << 16 | (hash[2] as u32) << 8 | (hash[3] as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections;
+    use std::fs;
+
+    // The defaults the tests start from
+    fn options() -> crate::pipeline::Options {
+        crate::pipeline::Options {
+            chunking: crate::pipeline::Chunking::Variable(
+                rabin::chunker::ChunkerBuilder::new(crate::MIN_CHUNK_SIZE, crate::MAX_CHUNK_SIZE)
+                    .unwrap(),
+            ),
+            threads: 1,
+            hash_cpus: None,
+            io_cpus: None,
+            symlinks: crate::walk::Symlinks::Skip,
+            detect_hard_links: true,
+            streams: false,
+            key_len: crate::KEY_LEN,
+            mmap: true,
+            uring: None,
+            direct_io: false,
+            resume: vec![],
+            compress_every: None,
+            only: None,
+            min_file_size: None,
+            max_file_size: None,
+            cache: None,
+            sub_blocks: None,
+            disk_images: false,
+            anchors: vec![],
+            compressed: crate::compressed::Policy::Chunk,
+            archives: false,
+        }
+    }
+
+    // Scans the directories with nothing excluded
+    fn walk(dirs: &[&std::path::Path]) -> crate::pipeline::Input {
+        crate::pipeline::Input::Walk(
+            dirs.iter()
+                .map(|dir| crate::pipeline::Root {
+                    dir: dir.to_path_buf(),
+                    exclude: ignore::gitignore::Gitignore::empty(),
+                })
+                .collect(),
+        )
+    }
+
+    // Pseudo-random data that chunks the same way every time
+    fn random_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect()
+    }
+
+    // A chunk the pipeline found as (file, chunk number, key, check, size, offset)
+    type Found = (u32, u64, crate::run::Key, u32, u32, u64);
+
+    // Every chunk the pipeline finds, sorted
+    fn collect(
+        dir: &std::path::Path,
+        options: &crate::pipeline::Options,
+    ) -> (crate::pipeline::Scanned, Vec<Found>) {
+        let mut chunks = vec![];
+        let scanned = crate::pipeline::run(walk(&[dir]), options, &mut |event| {
+            if let crate::pipeline::Event::Hashed(batch) = event {
+                for (i, c) in batch.chunks.iter().enumerate() {
+                    let number = batch.first_chunk + i as u64;
+                    chunks.push((batch.file, number, c.key, c.check, c.size, c.offset));
+                }
+            }
+        });
+        chunks.sort();
+        (scanned, chunks)
+    }
+
+    #[test]
+    fn test_thread_count_does_not_change_results() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_pipeline_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+
+        // Enough pseudo-random data to fill several batches, plus an empty file that has no chunks at all
+        let data = random_data(4_000_000, 1);
+        fs::write(dir.join("a"), &data).unwrap();
+        fs::write(dir.join("sub").join("b"), &data[1000..]).unwrap();
+        fs::write(dir.join("empty"), b"").unwrap();
+
+        let (scanned, serial) = collect(&dir, &options());
+        let (_, parallel) = collect(
+            &dir,
+            &crate::pipeline::Options {
+                threads: 4,
+                ..options()
+            },
+        );
+        assert_eq!(scanned.paths.len(), 3);
+        assert!(serial.len() > crate::pipeline::BATCH_CHUNKS);
+        assert_eq!(serial, parallel);
+
+        // Nor does where the threads run, or whether they could be pinned at all
+        let (_, pinned) = collect(
+            &dir,
+            &crate::pipeline::Options {
+                threads: 3,
+                hash_cpus: Some(vec![0, 1]),
+                io_cpus: Some(vec![0]),
+                ..options()
+            },
+        );
+        assert_eq!(serial, pinned);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stages_stop_when_the_next_has_gone() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_pipeline_stop_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["a", "b", "c"].iter() {
+            fs::write(dir.join(name), random_data(10_000, 2)).unwrap();
+        }
+
+        // The reader stops at the first file it can't hand on, rather than panicking or reading the rest
+        let (file_sender, _) = std::sync::mpsc::sync_channel(crate::pipeline::CHANNEL_DEPTH);
+        let (event_sender, _) = std::sync::mpsc::sync_channel(crate::pipeline::CHANNEL_DEPTH);
+        let scanned =
+            crate::pipeline::read_files(walk(&[&dir]), &options(), file_sender, event_sender);
+        assert_eq!(scanned.paths.len(), 1);
+
+        // As does boundary detection when there's nothing left to hash its batches
+        let (batch_sender, _) = std::sync::mpsc::sync_channel(1);
+        let stored = crate::checkpoint::Progress::default();
+        let span = tracing::Span::none();
+        let mut batcher = crate::pipeline::Batcher::new(&batch_sender, 0, &stored, &span);
+        batcher.push(0..10);
+        assert!(batcher.finish().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_several_roots() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_roots_{}", std::process::id()));
+        fs::create_dir_all(dir.join("first/inner")).unwrap();
+        fs::create_dir_all(dir.join("second")).unwrap();
+        fs::write(dir.join("first/a"), b"a").unwrap();
+        fs::write(dir.join("first/inner/b"), b"b").unwrap();
+        fs::write(dir.join("second/c"), b"c").unwrap();
+
+        // The inner directory was already scanned as part of the first one, so it isn't scanned again
+        let mut found = vec![];
+        let scanned = crate::pipeline::run(
+            walk(&[
+                &dir.join("first"),
+                &dir.join("second"),
+                &dir.join("first/inner"),
+            ]),
+            &options(),
+            &mut |event| {
+                if let crate::pipeline::Event::Found { root, path, .. } = event {
+                    let path = std::path::Path::new(&path).strip_prefix(&dir).unwrap();
+                    found.push((root, path.to_string_lossy().into_owned()));
+                }
+            },
+        );
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                (0, "first/a".to_string()),
+                (0, "first/inner/b".to_string()),
+                (1, "second/c".to_string())
+            ]
+        );
+        assert_eq!(scanned.paths.len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_list() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_list_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), b"a").unwrap();
+        fs::write(dir.join("with\nnewline"), b"b").unwrap();
+        fs::write(dir.join("sub/c"), b"c").unwrap();
+
+        // Only what is listed is scanned, and directories in the list aren't entered
+        let mut list = vec![];
+        for name in &["with\nnewline", "missing", "sub", "a"] {
+            list.extend_from_slice(dir.join(name).to_string_lossy().as_bytes());
+            list.push(0);
+        }
+        let input = crate::pipeline::Input::List {
+            name: "-".to_string(),
+            list: Box::new(std::io::Cursor::new(list)),
+        };
+        let scanned = crate::pipeline::run(input, &options(), &mut |_| {});
+        let dir = dir.to_string_lossy();
+        assert_eq!(
+            scanned.paths,
+            vec![format!("{}/with\nnewline", dir), format!("{}/a", dir)]
+        );
+        assert_eq!(scanned.skipped.reasons["vanished"], 1);
+
+        fs::remove_dir_all(&*dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_size_range() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_sizes_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("tiny"), [1u8; 10]).unwrap();
+        fs::write(dir.join("medium"), random_data(5000, 1)).unwrap();
+        fs::write(dir.join("large"), random_data(50000, 2)).unwrap();
+
+        // Both ends of the range are inclusive
+        let range = crate::pipeline::Options {
+            min_file_size: Some(5000),
+            max_file_size: Some(20000),
+            ..options()
+        };
+        let (scanned, chunks) = collect(&dir, &range);
+        assert_eq!(scanned.paths, vec![dir.join("medium").to_string_lossy()]);
+        assert_eq!(chunks.iter().map(|c| c.4 as u64).sum::<u64>(), 5000);
+        assert_eq!(scanned.size_filtered_files, 2);
+        assert_eq!(scanned.size_filtered_bytes, 50010);
+
+        let range = crate::pipeline::Options {
+            min_file_size: Some(11),
+            ..options()
+        };
+        let (scanned, _) = collect(&dir, &range);
+        assert_eq!(scanned.paths.len(), 2);
+        assert_eq!(scanned.size_filtered_files, 1);
+        assert_eq!(scanned.size_filtered_bytes, 10);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_policy() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_compressed_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut gzip = b"\x1f\x8b\x08\x00".to_vec();
+        gzip.extend_from_slice(&[7u8; 996]);
+        fs::write(dir.join("log.gz"), &gzip).unwrap();
+        fs::write(dir.join("encrypted"), random_data(20000, 1)).unwrap();
+        fs::write(
+            dir.join("text"),
+            "plain text, nothing to see here\n".repeat(200),
+        )
+        .unwrap();
+
+        let (scanned, chunks) = collect(&dir, &options());
+        assert_eq!(scanned.paths.len(), 3);
+        assert_eq!(scanned.flagged_compressed_files, 0);
+
+        let skip = crate::pipeline::Options {
+            compressed: crate::compressed::Policy::Skip,
+            ..options()
+        };
+        let (scanned, skipped_chunks) = collect(&dir, &skip);
+        assert_eq!(scanned.paths, vec![dir.join("text").to_string_lossy()]);
+        assert_eq!(scanned.skipped_compressed_files, 2);
+        assert_eq!(scanned.skipped_compressed_bytes, 21000);
+        assert_eq!(skipped_chunks.iter().map(|c| c.4 as u64).sum::<u64>(), 6400);
+
+        // Flagged files are still chunked
+        let flag = crate::pipeline::Options {
+            compressed: crate::compressed::Policy::Flag,
+            ..options()
+        };
+        let (scanned, flagged_chunks) = collect(&dir, &flag);
+        assert_eq!(scanned.paths.len(), 3);
+        assert_eq!(scanned.flagged_compressed_files, 2);
+        assert_eq!(scanned.flagged_compressed_bytes, 21000);
+        assert_eq!(scanned.skipped_compressed_files, 0);
+        assert_eq!(flagged_chunks.len(), chunks.len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archives() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_archives_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = random_data(200_000, 1);
+        fs::write(dir.join("lib.so"), &data).unwrap();
+        // The member lands at an offset in the tar that the chunker wouldn't cut at by itself
+        let mut tar = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1000);
+        header.set_cksum();
+        tar.append_data(&mut header, "readme", &random_data(1000, 2)[..])
+            .unwrap();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, "app/lib.so", &data[..])
+            .unwrap();
+        fs::write(dir.join("app.tar"), tar.into_inner().unwrap()).unwrap();
+
+        let archives = crate::pipeline::Options {
+            archives: true,
+            ..options()
+        };
+        let (scanned, chunks) = collect(&dir, &archives);
+        let mut paths = scanned.paths.clone();
+        paths.sort();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        assert_eq!(
+            paths,
+            vec![
+                path("app.tar/app/lib.so"),
+                path("app.tar/readme"),
+                path("lib.so")
+            ]
+        );
+        let keys = |name: &str| {
+            let file = scanned.paths.iter().position(|p| *p == path(name)).unwrap() as u32;
+            chunks
+                .iter()
+                .filter(|c| c.0 == file)
+                .map(|c| c.2)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("app.tar/app/lib.so"), keys("lib.so"));
+
+        // The archive as one file shares next to nothing with the file inside it
+        let (scanned, chunks) = collect(&dir, &options());
+        assert_eq!(scanned.paths.len(), 2);
+        let file = |name: &str| scanned.paths.iter().position(|p| *p == path(name)).unwrap() as u32;
+        let outside: std::collections::HashSet<_> = chunks
+            .iter()
+            .filter(|c| c.0 == file("lib.so"))
+            .map(|c| c.2)
+            .collect();
+        let shared = chunks
+            .iter()
+            .filter(|c| c.0 == file("app.tar") && outside.contains(&c.2))
+            .count();
+        assert!(shared < outside.len() - 1);
+
+        // An archive that was cut short in its first member is chunked as a file rather than lost
+        let whole = fs::read(dir.join("app.tar")).unwrap();
+        fs::write(dir.join("cut.tar"), &whole[..1000]).unwrap();
+        let (scanned, _) = collect(&dir, &archives);
+        assert!(scanned.paths.contains(&path("cut.tar")));
+        assert_eq!(scanned.skipped.count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_decompress() {
+        use std::io::Write;
+
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_decompressed_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Sixteen letters, so the original doesn't look compressed itself
+        let data: Vec<u8> = random_data(300_000, 1)
+            .iter()
+            .map(|b| b'a' + b % 16)
+            .collect();
+        fs::write(dir.join("log"), &data).unwrap();
+        for (name, level) in [("fast.gz", 1), ("best.gz", 9)] {
+            let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::new(level));
+            gzip.write_all(&data).unwrap();
+            fs::write(dir.join(name), gzip.finish().unwrap()).unwrap();
+        }
+        fs::write(dir.join("log.zst"), zstd::encode_all(&data[..], 3).unwrap()).unwrap();
+        let chunks_of = |scanned: &crate::pipeline::Scanned, chunks: &[Found], name: &str| {
+            let file = scanned
+                .paths
+                .iter()
+                .position(|p| p.as_str() == dir.join(name).to_string_lossy())
+                .unwrap() as u32;
+            chunks
+                .iter()
+                .filter(|c| c.0 == file)
+                .map(|c| (c.2, c.4, c.5))
+                .collect::<Vec<_>>()
+        };
+
+        let decompress = crate::pipeline::Options {
+            compressed: crate::compressed::Policy::Decompress,
+            ..options()
+        };
+        let (scanned, chunks) = collect(&dir, &decompress);
+        assert_eq!(scanned.flagged_compressed_files, 0);
+        let original = chunks_of(&scanned, &chunks, "log");
+        assert!(original.len() > 10);
+        for name in ["fast.gz", "best.gz", "log.zst"] {
+            assert_eq!(chunks_of(&scanned, &chunks, name), original);
+        }
+
+        // Without decompressing, the compressed copies have nothing in common
+        let (scanned, chunks) = collect(&dir, &options());
+        assert_ne!(
+            chunks_of(&scanned, &chunks, "fast.gz")[0].0,
+            chunks_of(&scanned, &chunks, "best.gz")[0].0
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_sparse_file() {
+        use std::io::{Seek, Write};
+
+        let dir = std::env::temp_dir().join(format!("test_chunks_sparse_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Data at the start and the end with a large hole between
+        let mut file = fs::File::create(dir.join("sparse")).unwrap();
+        file.write_all(&[1u8; 65536]).unwrap();
+        file.seek(std::io::SeekFrom::Start(64 * 1024 * 1024))
+            .unwrap();
+        file.write_all(&[2u8; 65536]).unwrap();
+        drop(file);
+
+        let options = crate::pipeline::Options {
+            chunking: crate::pipeline::Chunking::Fixed,
+            ..options()
+        };
+        let (scanned, chunks) = collect(&dir, &options);
+        let bytes: u64 = chunks.iter().map(|c| c.4 as u64).sum();
+
+        // Every byte is either chunked or in a hole, whether or not the filesystem keeps track of holes
+        assert_eq!(bytes + scanned.hole_bytes, 64 * 1024 * 1024 + 65536);
+        assert!(bytes >= 2 * 65536);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_streaming_matches_mmap() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_streaming_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Large enough to need several stream buffers
+        let data = random_data(10_000_000, 7);
+        fs::write(dir.join("a"), &data).unwrap();
+        fs::write(dir.join("b"), &data[..5000]).unwrap();
+
+        let chunkings = [options().chunking, crate::pipeline::Chunking::Fixed];
+        for &chunking in chunkings.iter() {
+            let (mapped_scan, mapped) = collect(
+                &dir,
+                &crate::pipeline::Options {
+                    chunking,
+                    threads: 2,
+                    ..options()
+                },
+            );
+            let (streamed_scan, streamed) = collect(
+                &dir,
+                &crate::pipeline::Options {
+                    chunking,
+                    threads: 2,
+                    mmap: false,
+                    ..options()
+                },
+            );
+            let (ring_scan, ring) = collect(
+                &dir,
+                &crate::pipeline::Options {
+                    chunking,
+                    threads: 2,
+                    uring: Some(4),
+                    ..options()
+                },
+            );
+            let (direct_scan, direct) = collect(
+                &dir,
+                &crate::pipeline::Options {
+                    chunking,
+                    threads: 2,
+                    direct_io: true,
+                    ..options()
+                },
+            );
+            let bytes: u64 = streamed.iter().map(|c| c.4 as u64).sum();
+            assert_eq!(bytes, 10_005_000);
+            assert_eq!(mapped, streamed);
+            assert_eq!(mapped, ring);
+            assert_eq!(mapped, direct);
+
+            // Every stage sees every byte, whether the reading is done up front or as the file is chunked
+            for scanned in [mapped_scan, streamed_scan, ring_scan, direct_scan].iter() {
+                let t = &scanned.timings;
+                for phase in [t.read, t.boundary, t.hash, t.insert].iter() {
+                    assert_eq!(phase.bytes, bytes);
+                }
+                assert!(t.boundary.micros > 0 && t.hash.micros > 0);
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mbox_anchors() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_mbox_{}", std::process::id()));
+        fs::create_dir_all(dir.join("maildir")).unwrap();
+
+        // Messages of all sizes, enough to need several stream buffers, with three of them in a Maildir too
+        let mut mbox = vec![];
+        let data = random_data(5_000_000, 8);
+        let mut start = 0;
+        for i in 0..400 {
+            let len = 100 + (i * 7919) % 20_000;
+            // Every line of a message ends in a newline, the last one included
+            let mut message = data[start..start + len].to_vec();
+            message.push(b'\n');
+            start += len;
+            mbox.extend_from_slice(
+                format!("\nFrom sender{} Mon Jan  1 00:00:00 2024\n", i).as_bytes(),
+            );
+            mbox.extend_from_slice(&message);
+            if i % 150 == 1 {
+                fs::write(dir.join("maildir").join(i.to_string()), &message).unwrap();
+            }
+        }
+        fs::write(dir.join("mbox"), &mbox[1..]).unwrap();
+
+        let anchored = || crate::pipeline::Options {
+            threads: 2,
+            anchors: vec![crate::anchor::Kind::Mbox],
+            ..options()
+        };
+        let (_, mapped) = collect(&dir, &anchored());
+        for options in [
+            crate::pipeline::Options {
+                mmap: false,
+                ..anchored()
+            },
+            crate::pipeline::Options {
+                uring: Some(4),
+                ..anchored()
+            },
+            crate::pipeline::Options {
+                direct_io: true,
+                ..anchored()
+            },
+        ] {
+            assert_eq!(collect(&dir, &options).1, mapped);
+        }
+
+        // Every chunk of the messages in the Maildir is in the mbox, which isn't so without anchors
+        let keys = |chunks: &[Found], file| {
+            chunks
+                .iter()
+                .filter(|c| c.0 == file)
+                .map(|c| c.2)
+                .collect::<collections::HashSet<_>>()
+        };
+        let (scanned, plain) = collect(&dir, &options());
+        let mbox_file = scanned
+            .paths
+            .iter()
+            .position(|p| p.ends_with("mbox"))
+            .unwrap() as u32;
+        let messages: collections::HashSet<_> = (0..4)
+            .filter(|&f| f != mbox_file)
+            .flat_map(|f| keys(&mapped, f))
+            .collect();
+        assert!(messages.is_subset(&keys(&mapped, mbox_file)));
+        assert!(!messages.is_subset(&keys(&plain, mbox_file)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_resume_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), random_data(2_000_000, 5)).unwrap();
+        fs::write(dir.join("b"), random_data(100_000, 6)).unwrap();
+        let (scanned, all) = collect(&dir, &options());
+
+        // Pretend an interrupted scan finished one file and stored some of the other's chunks
+        let mut finished = crate::checkpoint::Progress::default();
+        let mut partial = crate::checkpoint::Progress::default();
+        let finished_file = 0;
+        let finished_chunks = all.iter().filter(|c| c.0 == finished_file).count() as u64;
+        finished.add(0..finished_chunks);
+        finished.finish(finished_chunks);
+        partial.add(0..10);
+        partial.add(20..30);
+        let resume = vec![
+            (scanned.paths[0].clone(), finished),
+            (scanned.paths[1].clone(), partial),
+        ];
+
+        for &mmap in [true, false].iter() {
+            let options = crate::pipeline::Options {
+                resume: resume.clone(),
+                mmap,
+                ..options()
+            };
+            let (resumed, chunks) = collect(&dir, &options);
+            assert_eq!(resumed.paths, scanned.paths);
+
+            // Only the chunks that weren't stored are found again, with the same numbers as before
+            let expected: Vec<_> = all
+                .iter()
+                .filter(|c| c.0 == 1 && !(0..10).contains(&c.1) && !(20..30).contains(&c.1))
+                .cloned()
+                .collect();
+            assert_eq!(chunks, expected);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_cached_{}", std::process::id()));
+        let files = dir.join("files");
+        fs::create_dir_all(&files).unwrap();
+        fs::write(files.join("a"), random_data(2_000_000, 7)).unwrap();
+        fs::write(files.join("b"), random_data(100_000, 8)).unwrap();
+        let cached = || crate::pipeline::Options {
+            cache: Some(
+                crate::cache::Cache::open(&dir, crate::cache::settings(&options())).unwrap(),
+            ),
+            ..options()
+        };
+        let (scanned, all) = collect(&files, &cached());
+        let b = scanned.paths.iter().position(|p| p.ends_with('b')).unwrap() as u32;
+        let of_file = |chunks: &[Found], file| -> Vec<Found> {
+            chunks.iter().filter(|c| c.0 == file).cloned().collect()
+        };
+
+        // Other data with the same length and modification time is taken to be the same file, which shows it wasn't
+        // read again
+        let a = fs::File::open(files.join("a")).unwrap();
+        let modified = a.metadata().unwrap().modified().unwrap();
+        fs::write(files.join("a"), random_data(2_000_000, 9)).unwrap();
+        a.set_modified(modified).unwrap();
+        let (_, chunks) = collect(&files, &cached());
+        assert_eq!(chunks, all);
+
+        // A file that really changed is read again
+        fs::write(files.join("b"), random_data(100_000, 10)).unwrap();
+        let (_, chunks) = collect(&files, &cached());
+        assert_eq!(of_file(&chunks, 1 - b), of_file(&all, 1 - b));
+        assert_ne!(of_file(&chunks, b)[0].2, of_file(&all, b)[0].2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compression_sample() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_compression_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Half random and half zeros, so some chunks compress and some don't
+        let mut data = random_data(500_000, 3);
+        data.resize(1_000_000, 0);
+        fs::write(dir.join("a"), &data).unwrap();
+
+        let compressed_sizes = |compress_every| {
+            let mut sizes = vec![];
+            crate::pipeline::run(
+                walk(&[&dir]),
+                &crate::pipeline::Options {
+                    compress_every,
+                    ..options()
+                },
+                &mut |event| {
+                    if let crate::pipeline::Event::Hashed(batch) = event {
+                        sizes.extend(
+                            batch
+                                .chunks
+                                .iter()
+                                .map(|c| (c.offset, c.size, c.compressed)),
+                        );
+                    }
+                },
+            );
+            sizes.sort();
+            sizes
+        };
+
+        let none = compressed_sizes(None);
+        let all = compressed_sizes(Some(1));
+        let sample = compressed_sizes(Some(4));
+        assert!(none.iter().all(|c| c.2 == 0));
+        assert!(all.iter().all(|c| c.2 > 0));
+        assert!(all.iter().any(|c| c.2 < c.1 / 10));
+
+        // A sampled chunk is compressed exactly as it would be without sampling
+        let sampled = sample.iter().filter(|c| c.2 > 0).count();
+        assert!(sampled > 0 && sampled < sample.len());
+        for (s, a) in sample.iter().zip(all.iter()) {
+            assert!(s.2 == 0 || s == a);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sub_blocks() {
+        // Cut at the multiples of the block size in the file, not in the chunk
+        let pieces: Vec<usize> = crate::pipeline::split_blocks(&[0; 10_000], 1000, 4096)
+            .map(|p| p.len())
+            .collect();
+        assert_eq!(pieces, vec![3096, 4096, 2808]);
+        let pieces: Vec<usize> = crate::pipeline::split_blocks(&[0; 100], 4096, 4096)
+            .map(|p| p.len())
+            .collect();
+        assert_eq!(pieces, vec![100]);
+
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_sub_blocks_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = random_data(1_000_000, 4);
+        fs::write(dir.join("a"), &data).unwrap();
+        fs::write(dir.join("copy"), &data).unwrap();
+
+        let mut chunk_bytes = 0u64;
+        let mut block_bytes = 0u64;
+        let mut blocks = 0;
+        let mut unique = std::collections::HashSet::new();
+        crate::pipeline::run(
+            walk(&[&dir]),
+            &crate::pipeline::Options {
+                sub_blocks: Some(4096),
+                ..options()
+            },
+            &mut |event| {
+                if let crate::pipeline::Event::Hashed(batch) = event {
+                    chunk_bytes += batch.chunks.iter().map(|c| c.size as u64).sum::<u64>();
+                    block_bytes += batch.sub_blocks.iter().map(|b| b.size as u64).sum::<u64>();
+                    assert!(batch.sub_blocks.iter().all(|b| b.size <= 4096));
+                    blocks += batch.sub_blocks.len();
+                    unique.extend(batch.sub_blocks.iter().map(|b| b.fingerprint));
+                }
+            },
+        );
+        assert_eq!(chunk_bytes, 2_000_000);
+        assert_eq!(block_bytes, chunk_bytes);
+        // At least a block for each 4096 bytes of each file, and the copy's are all the same as the first file's
+        assert!(blocks >= 2 * 245);
+        assert_eq!(unique.len() * 2, blocks);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
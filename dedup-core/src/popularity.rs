@@ -0,0 +1,109 @@
+use std::cmp;
+use std::collections;
+
+// Tracks how many times each unique chunk occurred so we can tell whether the savings come from a few very hot chunks
+// or from broad similarity across the data. Keeping a count for every chunk would defeat the point of the memory
+// budget, so this only keeps a histogram (occurrences -> number of chunks and bytes saved) plus the few most
+// duplicated chunks.
+pub struct Popularity {
+    histogram: collections::BTreeMap<u32, Bucket>,
+    top: collections::BinaryHeap<cmp::Reverse<(u32, crate::run::Key, u32)>>,
+    top_k: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    chunks: u64,
+    saved_bytes: u64,
+}
+
+impl Popularity {
+    pub fn new(top_k: usize) -> Popularity {
+        Popularity {
+            histogram: collections::BTreeMap::new(),
+            top: collections::BinaryHeap::new(),
+            top_k,
+        }
+    }
+
+    // Records a unique chunk that occurred 'occurrences' times in total. Every occurrence after the first is a
+    // duplicate that didn't need to be stored.
+    pub fn record(&mut self, key: crate::run::Key, occurrences: u32, size: u32) {
+        let bucket = self.histogram.entry(occurrences).or_default();
+        bucket.chunks += 1;
+        bucket.saved_bytes += (occurrences as u64 - 1) * size as u64;
+
+        if occurrences > 1 {
+            self.top.push(cmp::Reverse((occurrences, key, size)));
+            if self.top.len() > self.top_k {
+                self.top.pop();
+            }
+        }
+    }
+
+    // Returns the most duplicated chunks as (key, occurrences, size), most duplicated first.
+    pub fn most_duplicated(&self) -> Vec<(crate::run::Key, u32, u32)> {
+        let mut top: Vec<_> = self
+            .top
+            .iter()
+            .map(|cmp::Reverse((occurrences, key, size))| (*key, *occurrences, *size))
+            .collect();
+        top.sort_by_key(|t| cmp::Reverse(t.1));
+        top
+    }
+
+    // Returns how many unique chunks occurred more than once.
+    pub fn duplicated_chunks(&self) -> u64 {
+        self.histogram
+            .range(2..)
+            .map(|(_, bucket)| bucket.chunks)
+            .sum()
+    }
+
+    // Returns the fraction (0.0 to 1.0) of all duplicate bytes that came from the most duplicated 'fraction' of unique
+    // chunks. Chunks are ranked by their number of occurrences; within the last bucket that is only partly included,
+    // the savings are split evenly between its chunks.
+    pub fn savings_from_top(&self, fraction: f64) -> f64 {
+        let total_chunks: u64 = self.histogram.values().map(|b| b.chunks).sum();
+        let total_saved: u64 = self.histogram.values().map(|b| b.saved_bytes).sum();
+        if total_saved == 0 {
+            return 0.0;
+        }
+
+        let mut remaining = (total_chunks as f64 * fraction).ceil() as u64;
+        let mut saved = 0.0;
+        for bucket in self.histogram.values().rev() {
+            if remaining == 0 {
+                break;
+            }
+            let taken = remaining.min(bucket.chunks);
+            saved += bucket.saved_bytes as f64 * taken as f64 / bucket.chunks as f64;
+            remaining -= taken;
+        }
+        saved / total_saved as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_popularity() {
+        let mut popularity = crate::popularity::Popularity::new(2);
+
+        // 98 chunks that only occur once, one that occurs 11 times and one that occurs 3 times
+        for i in 0..98u8 {
+            popularity.record([i; crate::MAX_KEY_LEN], 1, 100);
+        }
+        popularity.record([200; crate::MAX_KEY_LEN], 11, 100);
+        popularity.record([201; crate::MAX_KEY_LEN], 3, 100);
+
+        assert_eq!(popularity.duplicated_chunks(), 2);
+        let top = popularity.most_duplicated();
+        assert_eq!(top[0].1, 11);
+        assert_eq!(top[1].1, 3);
+
+        // The top 1% is the single hottest chunk, which saved 1000 of the 1200 duplicate bytes
+        let share = popularity.savings_from_top(0.01);
+        assert!((share - 1000.0 / 1200.0).abs() < 0.0001);
+    }
+}
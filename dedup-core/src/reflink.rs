@@ -0,0 +1,681 @@
+use std::collections;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path;
+
+// Turns whole files that a scan found to be duplicates into clones that share their storage, on filesystems that
+// support it (Btrfs, XFS, bcachefs, OCFS2 and others on Linux, and ReFS on Windows). A clone looks and behaves like a
+// copy, and is copied on write when either file changes, so the files stay independent. Duplicate chunks inside files
+// that differ are shared a range at a time: on Linux with FIDEDUPERANGE, which has the kernel compare the bytes before
+// it shares them, and on Windows with FSCTL_DUPLICATE_EXTENTS_TO_FILE after comparing them here.
+
+// Linux's FIDEDUPERANGE ioctl, which libc doesn't define: _IOWR(0x94, 54, struct file_dedupe_range)
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const FIDEDUPERANGE: u32 = 0xC018_9436;
+
+// The status FIDEDUPERANGE gives when the two ranges don't hold the same bytes
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+// The most one FIDEDUPERANGE call is asked to share. Btrfs shares no more than 16MiB a call, so longer ranges are
+// shared a piece at a time.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAX_DEDUPE_LEN: u64 = 16 * 1024 * 1024;
+
+// The most one FSCTL_DUPLICATE_EXTENTS_TO_FILE call is asked to clone. It has to be under 4GiB, and a multiple of
+// every cluster size.
+#[cfg(windows)]
+const MAX_CLONE_LEN: u64 = 1024 * 1024 * 1024;
+
+// The cluster size of volumes that don't say what theirs is, which is NTFS's default
+#[cfg(windows)]
+const DEFAULT_CLUSTER_SIZE: u64 = 4096;
+
+// struct file_dedupe_range with the one struct file_dedupe_range_info that follows it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[repr(C)]
+struct DedupeRequest {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+// Bytes in one file that hold the same data as bytes in another file, or earlier in the same one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedRange {
+    // Where the data was first found, as a position in the scan's file list and an offset in that file
+    pub source: u32,
+    pub source_offset: u64,
+    // The later copy, which is made to share the first copy's storage
+    pub dest: u32,
+    pub dest_offset: u64,
+    pub len: u64,
+}
+
+impl SharedRange {
+    // The part of the range the kernel can share, given the filesystem's block size: whole blocks, plus the end of the
+    // last block if the range runs to the end of both files. Blocks are shared whole, so only data that starts at the
+    // same place within a block in both files can be shared; None if none of it can.
+    pub fn aligned(&self, block: u64, source_size: u64, dest_size: u64) -> Option<SharedRange> {
+        let skip = (block - self.dest_offset % block) % block;
+        if skip >= self.len || !(self.source_offset + skip).is_multiple_of(block) {
+            return None;
+        }
+        let (source_offset, dest_offset) = (self.source_offset + skip, self.dest_offset + skip);
+        let mut len = self.len - skip;
+        if source_offset + len != source_size || dest_offset + len != dest_size {
+            len -= len % block;
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(SharedRange {
+            source_offset,
+            dest_offset,
+            len,
+            ..*self
+        })
+    }
+}
+
+// Groups the files in a scan's file list whose chunks are all the same: the same size, number of chunks and
+// fingerprint. Each group is in the order of the list, so the first file is the one that was found first. Empty files
+// are left out; there is nothing to share.
+pub fn duplicate_files(files: &[crate::files::FileRecord]) -> Vec<Vec<usize>> {
+    let mut groups: collections::HashMap<(u64, u64, u64), Vec<usize>> = collections::HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        if file.size > 0 {
+            groups
+                .entry((file.size, file.chunks, file.fingerprint))
+                .or_default()
+                .push(i);
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    groups.sort();
+    groups
+}
+
+// Chunks a file the way the scan did and finds the chunks that the merged file says were first found somewhere else:
+// in an earlier file, or earlier in this one. Neighbouring chunks whose first copies are neighbours too are joined
+// into one range. 'file' is the file's position in the scan's file list.
+pub fn duplicate_ranges(
+    merged: &crate::run::RunIndex,
+    chunking: &crate::pipeline::Chunking,
+    file: u32,
+    path: &path::Path,
+) -> io::Result<Vec<SharedRange>> {
+    let data = crate::signature::map(path)?;
+    let mut hasher = sha3::Sha3_256::default();
+    let mut ranges: Vec<SharedRange> = vec![];
+    let mut offset = 0u64;
+    for chunk in chunking.chunks(&data) {
+        let len = chunk.len() as u64;
+        let key = crate::pipeline::hash_key(&mut hasher, chunk, merged.key_len());
+        match merged.find(&key) {
+            Some(entry)
+                if entry.count > 1
+                    && entry.size as u64 == len
+                    && (entry.file, entry.offset) != (file, offset) =>
+            {
+                match ranges.last_mut() {
+                    Some(last)
+                        if last.source == entry.file
+                            && last.source_offset + last.len == entry.offset
+                            && last.dest_offset + last.len == offset =>
+                    {
+                        last.len += len
+                    }
+                    _ => ranges.push(SharedRange {
+                        source: entry.file,
+                        source_offset: entry.offset,
+                        dest: file,
+                        dest_offset: offset,
+                        len,
+                    }),
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(ranges)
+}
+
+// Whether two files hold exactly the same bytes now. Matching chunk ids make duplicates all but certain, but a file
+// can change after it was scanned, and sharing storage between files that differ would lose data.
+pub fn same_contents(a: &path::Path, b: &path::Path) -> io::Result<bool> {
+    let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+    let (mut a_buffer, mut b_buffer) = (vec![0u8; 1024 * 1024], vec![0u8; 1024 * 1024]);
+    loop {
+        let read = read_full(&mut a, &mut a_buffer)?;
+        if read_full(&mut b, &mut b_buffer[..read.max(1)])? != read
+            || a_buffer[..read] != b_buffer[..read]
+        {
+            return Ok(false);
+        }
+        if read == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+// Whether the two paths are the same file, through a hard link, in which case they already share their storage
+pub fn same_file(a: &path::Path, b: &path::Path) -> io::Result<bool> {
+    let (a_metadata, b_metadata) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(
+        match (
+            crate::walk::file_id(a, &a_metadata),
+            crate::walk::file_id(b, &b_metadata),
+        ) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        },
+    )
+}
+
+// Makes 'dest' a clone of 'source' with the FICLONE ioctl (FSCTL_DUPLICATE_EXTENTS_TO_FILE on Windows), keeping
+// dest's inode, owner, permissions and links. The whole of dest is replaced, so the two must already hold the same
+// bytes (see same_contents). Fails with Unsupported where the filesystem can't clone, and with the error the system
+// gives (i.e. across filesystems) otherwise.
+pub fn clone_file(source: &path::Path, dest: &path::Path) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::io::AsRawFd;
+        let from = fs::File::open(source)?;
+        let to = fs::OpenOptions::new().write(true).open(dest)?;
+        // Safety: both descriptors stay open for the whole call
+        let result = unsafe { libc::ioctl(to.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) };
+        if result != 0 {
+            return Err(unsupported(
+                io::Error::last_os_error(),
+                "the filesystem can't clone files",
+            ));
+        }
+        Ok(())
+    }
+    #[cfg(windows)]
+    {
+        let from = fs::File::open(source)?;
+        let to = fs::OpenOptions::new().read(true).write(true).open(dest)?;
+        let len = from.metadata()?.len();
+        if to.metadata()?.len() != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the files are different sizes",
+            ));
+        }
+        duplicate_extents(&from, 0, &to, 0, len)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+    {
+        let _ = (source, dest);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "files can only be cloned on Linux and Windows",
+        ))
+    }
+}
+
+// Asks the kernel to make 'len' bytes of 'dest' at 'dest_offset' share the storage of the same bytes of 'source'. The
+// kernel locks both ranges and compares them itself, and only shares them if they still hold the same bytes, so
+// this is safe even on files that changed since the scan or are in use. Windows has no such call, so there the ranges
+// are compared first and then cloned, and a write to dest in between would be lost. Offsets must be multiples of
+// block_size (see SharedRange::aligned). Returns how many bytes were shared. Fails with InvalidData if the ranges
+// differ, and with Unsupported where the filesystem can't share them.
+pub fn dedupe_range(
+    source: &fs::File,
+    source_offset: u64,
+    dest: &fs::File,
+    dest_offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::io::AsRawFd;
+        let mut shared = 0;
+        while shared < len {
+            let mut request = DedupeRequest {
+                src_offset: source_offset + shared,
+                src_length: (len - shared).min(MAX_DEDUPE_LEN),
+                dest_count: 1,
+                reserved1: 0,
+                reserved2: 0,
+                dest_fd: dest.as_raw_fd() as i64,
+                dest_offset: dest_offset + shared,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            };
+            // Safety: the request is the struct the ioctl expects, and both descriptors stay open for the whole call
+            let result = unsafe {
+                libc::ioctl(
+                    source.as_raw_fd(),
+                    FIDEDUPERANGE as libc::Ioctl,
+                    &mut request as *mut DedupeRequest,
+                )
+            };
+            if result != 0 {
+                return Err(unsupported(
+                    io::Error::last_os_error(),
+                    "the filesystem can't share ranges of files",
+                ));
+            }
+            match request.status {
+                FILE_DEDUPE_RANGE_DIFFERS => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the ranges no longer hold the same bytes",
+                    ))
+                }
+                status if status < 0 => {
+                    return Err(unsupported(
+                        io::Error::from_raw_os_error(-status),
+                        "the filesystem can't share ranges of files",
+                    ))
+                }
+                // The kernel may share less than it was asked to, but never nothing unless it can't go on
+                _ if request.bytes_deduped == 0 => break,
+                _ => shared += request.bytes_deduped,
+            }
+        }
+        Ok(shared)
+    }
+    #[cfg(windows)]
+    {
+        if !same_range(source, source_offset, dest, dest_offset, len)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the ranges no longer hold the same bytes",
+            ));
+        }
+        duplicate_extents(source, source_offset, dest, dest_offset, len)?;
+        Ok(len)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+    {
+        let _ = (source, source_offset, dest, dest_offset, len);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "ranges of files can only be shared on Linux and Windows",
+        ))
+    }
+}
+
+// The size of the blocks the filesystem the file is on shares between files, which ranges have to be aligned to: the
+// block size on Unix, and the cluster size on Windows
+pub fn block_size(file: &fs::File) -> io::Result<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(file.metadata()?.blksize().max(1))
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::Ioctl::{
+            FSCTL_GET_INTEGRITY_INFORMATION, FSCTL_GET_INTEGRITY_INFORMATION_BUFFER,
+        };
+        use windows_sys::Win32::System::IO::DeviceIoControl;
+        // Only ReFS, the filesystem that can clone, answers this
+        let mut information = FSCTL_GET_INTEGRITY_INFORMATION_BUFFER::default();
+        let mut returned = 0u32;
+        let ok = unsafe {
+            DeviceIoControl(
+                file.as_raw_handle() as _,
+                FSCTL_GET_INTEGRITY_INFORMATION,
+                std::ptr::null(),
+                0,
+                &mut information as *mut FSCTL_GET_INTEGRITY_INFORMATION_BUFFER as *mut _,
+                std::mem::size_of::<FSCTL_GET_INTEGRITY_INFORMATION_BUFFER>() as u32,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 || information.ClusterSizeInBytes == 0 {
+            return Ok(DEFAULT_CLUSTER_SIZE);
+        }
+        Ok(information.ClusterSizeInBytes as u64)
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = file;
+        Ok(4096)
+    }
+}
+
+// Makes 'len' bytes of 'dest' at 'dest_offset' share the clusters of 'source' at 'source_offset' with
+// FSCTL_DUPLICATE_EXTENTS_TO_FILE. Offsets must be multiples of the cluster size. A length that isn't is rounded up,
+// which is only allowed where the range runs to the end of both files.
+#[cfg(windows)]
+fn duplicate_extents(
+    source: &fs::File,
+    source_offset: u64,
+    dest: &fs::File,
+    dest_offset: u64,
+    len: u64,
+) -> io::Result<()> {
+    use std::os::windows::fs::MetadataExt;
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetVolumeInformationByHandleW, FILE_ATTRIBUTE_SPARSE_FILE,
+    };
+    use windows_sys::Win32::System::Ioctl::{
+        DUPLICATE_EXTENTS_DATA, FSCTL_DUPLICATE_EXTENTS_TO_FILE, FSCTL_SET_SPARSE,
+    };
+    use windows_sys::Win32::System::SystemServices::FILE_SUPPORTS_BLOCK_REFCOUNTING;
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    // Volumes that can't clone say so, rather than failing part of the way through
+    let mut flags = 0u32;
+    let ok = unsafe {
+        GetVolumeInformationByHandleW(
+            dest.as_raw_handle() as _,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut flags,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if flags & FILE_SUPPORTS_BLOCK_REFCOUNTING == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the volume can't clone blocks (ReFS can)",
+        ));
+    }
+
+    let mut returned = 0u32;
+    // Clusters can only be cloned from a sparse file into another sparse file
+    if source.metadata()?.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0
+        && dest.metadata()?.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE == 0
+    {
+        let ok = unsafe {
+            DeviceIoControl(
+                dest.as_raw_handle() as _,
+                FSCTL_SET_SPARSE,
+                std::ptr::null(),
+                0,
+                std::ptr::null_mut(),
+                0,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let cluster = block_size(dest)?;
+    let len = len.div_ceil(cluster) * cluster;
+    let mut cloned = 0;
+    while cloned < len {
+        let count = (len - cloned).min(MAX_CLONE_LEN);
+        let data = DUPLICATE_EXTENTS_DATA {
+            FileHandle: source.as_raw_handle() as _,
+            SourceFileOffset: (source_offset + cloned) as i64,
+            TargetFileOffset: (dest_offset + cloned) as i64,
+            ByteCount: count as i64,
+        };
+        // Safety: the request is the struct the control code expects, and both handles stay open for the whole call
+        let ok = unsafe {
+            DeviceIoControl(
+                dest.as_raw_handle() as _,
+                FSCTL_DUPLICATE_EXTENTS_TO_FILE,
+                &data as *const DUPLICATE_EXTENTS_DATA as *const _,
+                std::mem::size_of::<DUPLICATE_EXTENTS_DATA>() as u32,
+                std::ptr::null_mut(),
+                0,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        cloned += count;
+    }
+    Ok(())
+}
+
+// Whether two ranges of files hold the same bytes now
+#[cfg(windows)]
+fn same_range(
+    source: &fs::File,
+    source_offset: u64,
+    dest: &fs::File,
+    dest_offset: u64,
+    len: u64,
+) -> io::Result<bool> {
+    use std::os::windows::fs::FileExt;
+    let (mut a, mut b) = (vec![0u8; 1024 * 1024], vec![0u8; 1024 * 1024]);
+    let mut compared = 0;
+    while compared < len {
+        let count = (len - compared).min(a.len() as u64) as usize;
+        for (file, offset, buffer) in [
+            (source, source_offset + compared, &mut a),
+            (dest, dest_offset + compared, &mut b),
+        ] {
+            let mut filled = 0;
+            while filled < count {
+                match file.seek_read(&mut buffer[filled..count], offset + filled as u64)? {
+                    0 => return Ok(false),
+                    read => filled += read,
+                }
+            }
+        }
+        if a[..count] != b[..count] {
+            return Ok(false);
+        }
+        compared += count as u64;
+    }
+    Ok(true)
+}
+
+// The errors a filesystem gives for an ioctl it doesn't support, as Unsupported
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn unsupported(e: io::Error, message: &str) -> io::Error {
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL) => {
+            io::Error::new(io::ErrorKind::Unsupported, message)
+        }
+        _ => e,
+    }
+}
+
+// Fills as much of the buffer as the file has left, returning how much that was
+fn read_full(file: &mut fs::File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_duplicate_files() {
+        let key = |byte: u8| crate::run::key_from(&[byte; crate::KEY_LEN], crate::KEY_LEN);
+        let file = |chunks: &[u8]| {
+            let mut record = crate::files::FileRecord {
+                size: chunks.len() as u64 * 100,
+                chunks: chunks.len() as u64,
+                ..Default::default()
+            };
+            for (i, &c) in chunks.iter().enumerate() {
+                record.add_chunk(i as u64, &key(c));
+            }
+            record
+        };
+        // The same chunks in a different order aren't the same file, and empty files are never duplicates
+        let files = [
+            file(&[1, 2, 3]),
+            file(&[3, 2, 1]),
+            file(&[]),
+            file(&[1, 2, 3]),
+            file(&[]),
+            file(&[3, 2, 1]),
+            file(&[1, 2, 3]),
+        ];
+        assert_eq!(
+            crate::reflink::duplicate_files(&files),
+            vec![vec![0, 3, 6], vec![1, 5]]
+        );
+
+        let dir = std::env::temp_dir().join(format!("test_chunks_reflink_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 249) as u8).collect();
+        fs::write(dir.join("a"), &data).unwrap();
+        fs::write(dir.join("b"), &data).unwrap();
+        let mut changed = data.clone();
+        changed[2_500_000] ^= 1;
+        fs::write(dir.join("c"), &changed).unwrap();
+        fs::hard_link(dir.join("a"), dir.join("link")).unwrap();
+        assert!(crate::reflink::same_contents(&dir.join("a"), &dir.join("b")).unwrap());
+        assert!(!crate::reflink::same_contents(&dir.join("a"), &dir.join("c")).unwrap());
+        assert!(crate::reflink::same_file(&dir.join("a"), &dir.join("link")).unwrap());
+        assert!(!crate::reflink::same_file(&dir.join("a"), &dir.join("b")).unwrap());
+
+        // Whether the clone can be made depends on the filesystem the tests run on, but the data never changes
+        match crate::reflink::clone_file(&dir.join("a"), &dir.join("b")) {
+            Ok(()) => {}
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported, "{}", e),
+        }
+        assert_eq!(fs::read(dir.join("b")).unwrap(), data);
+
+        // Only data at the same place within a block can share it
+        let range = |source_offset, dest_offset, len| crate::reflink::SharedRange {
+            source: 0,
+            source_offset,
+            dest: 1,
+            dest_offset,
+            len,
+        };
+        assert_eq!(
+            range(5000, 9096, 10000).aligned(4096, 1 << 20, 1 << 20),
+            Some(range(8192, 12288, 4096))
+        );
+        assert_eq!(
+            range(5000, 9000, 10000).aligned(4096, 1 << 20, 1 << 20),
+            None
+        );
+        assert_eq!(range(0, 4096, 4000).aligned(4096, 1 << 20, 1 << 20), None);
+        // The last block of both files can be shared even though it isn't full
+        assert_eq!(
+            range(0, 4096, 5000).aligned(4096, 5000, 9096),
+            Some(range(0, 4096, 5000))
+        );
+
+        let (a, c) = (
+            fs::File::open(dir.join("a")).unwrap(),
+            fs::File::open(dir.join("c")).unwrap(),
+        );
+        match crate::reflink::dedupe_range(&a, 0, &c, 0, 1 << 20) {
+            Ok(shared) => assert_eq!(shared, 1 << 20),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::Unsupported, "{}", e),
+        }
+        match crate::reflink::dedupe_range(&a, 2_490_368, &c, 2_490_368, 16384) {
+            Ok(_) => panic!("ranges that differ were shared"),
+            Err(e) => assert!(
+                matches!(
+                    e.kind(),
+                    std::io::ErrorKind::Unsupported | std::io::ErrorKind::InvalidData
+                ),
+                "{}",
+                e
+            ),
+        }
+        assert_eq!(fs::read(dir.join("c")).unwrap(), changed);
+
+        // A merged file for two files, where the second holds the first after a chunk of its own and then repeats
+        // its own chunk
+        let chunking = crate::pipeline::Chunking::Fixed;
+        let size = crate::pipeline::FIXED_CHUNK_SIZE;
+        let first: Vec<u8> = (0..3 * size).map(|i| (i % 251) as u8).collect();
+        let mut second = vec![7u8; size];
+        second.extend_from_slice(&first);
+        second.extend_from_slice(&[7u8; 10]);
+        second.extend(vec![7u8; size - 10]);
+        fs::write(dir.join("first"), &first).unwrap();
+        fs::write(dir.join("second"), &second).unwrap();
+        let mut entries: Vec<crate::run::Entry> = vec![];
+        let mut hasher = sha3::Sha3_256::default();
+        for (file, data) in [&first, &second].iter().enumerate() {
+            for (i, chunk) in chunking.chunks(data).enumerate() {
+                let key = crate::pipeline::hash_key(&mut hasher, chunk, crate::KEY_LEN);
+                match entries.iter_mut().find(|e| e.key == key) {
+                    Some(entry) => entry.count += 1,
+                    None => entries.push(crate::run::Entry {
+                        key,
+                        size: chunk.len() as u32,
+                        count: 1,
+                        file: file as u32,
+                        offset: (i * size) as u64,
+                        ..Default::default()
+                    }),
+                }
+            }
+        }
+        entries.sort_by_key(|e| e.key);
+        let mut merged = fs::File::create(dir.join("merged")).unwrap();
+        let params = crate::run::Params::new(&chunking, crate::KEY_LEN);
+        crate::run::write_header(&mut merged, &params).unwrap();
+        for entry in entries.iter() {
+            crate::run::write_entry(&mut merged, entry, crate::KEY_LEN).unwrap();
+        }
+        drop(merged);
+        let merged = crate::run::RunIndex::open(&dir.join("merged")).unwrap();
+        assert_eq!(merged.params(), &params);
+        assert_eq!(
+            crate::reflink::duplicate_ranges(&merged, &chunking, 0, &dir.join("first")).unwrap(),
+            vec![]
+        );
+        let size = size as u64;
+        assert_eq!(
+            crate::reflink::duplicate_ranges(&merged, &chunking, 1, &dir.join("second")).unwrap(),
+            vec![
+                crate::reflink::SharedRange {
+                    source: 0,
+                    source_offset: 0,
+                    dest: 1,
+                    dest_offset: size,
+                    len: 3 * size
+                },
+                crate::reflink::SharedRange {
+                    source: 1,
+                    source_offset: 0,
+                    dest: 1,
+                    dest_offset: 4 * size,
+                    len: size
+                }
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+use std::cmp;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::path;
+
+// Every run file starts with these bytes followed by the format version, so that a file from an older (or newer) build
+// is rejected instead of being misread
+const RUN_MAGIC: [u8; 4] = *b"DRUN";
+
+// Version 1 files had no header and a 16 bit chunk size. Version 2 added the header and widened the size to 32 bits.
+// Version 3 added the key length to the header, and only stores that many bytes of each key. Version 4 added the
+// offset, version 5 the compressed size and version 6 the directory groups. Version 7 added the chunking and hash
+// settings to the header, and version 8 the alignment.
+const FORMAT_VERSION: u16 = 8;
+
+// The magic, the version, the key length, the chunking, the minimum and maximum chunk sizes, the mask bits, the hash
+// and the alignment
+const HEADER_LEN: usize = 22;
+
+// How the chunks were cut, as it is stored in the header
+pub const CHUNKING_FIXED: u8 = 0;
+pub const CHUNKING_RABIN: u8 = 1;
+
+// How chunk ids are made from the chunks' data, as it is stored in the header
+pub const HASH_SHA3_256: u8 = 1;
+
+// Everything in an entry after the key: the size, check, count, file, offset, compressed size and directory groups
+const ENTRY_FIELDS_LEN: usize = 36;
+
+// How many bytes of a run are read at a time, unless the reader is given a buffer size
+const DEFAULT_READ_BUFFER: usize = 64 * 1024;
+
+// Chunk ids are a prefix of the chunk's SHA3 hash, as long as the scan was told to make them. In memory every key has
+// room for the whole hash, and the bytes past the end of the id are zero.
+pub type Key = [u8; crate::MAX_KEY_LEN];
+
+// Makes a key from the first 'key_len' bytes of a hash
+pub fn key_from(hash: &[u8], key_len: usize) -> Key {
+    let mut key = [0u8; crate::MAX_KEY_LEN];
+    key[..key_len].copy_from_slice(&hash[..key_len]);
+    key
+}
+
+// A run file is a header followed by a sequence of entries sorted by key with no key repeated. Scans write one run each
+// time the memtree fills up, and the merge combines every committed run into a single run (the merged file) holding
+// every unique chunk.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Entry {
+    pub key: Key,
+    pub size: u32,
+    pub check: u32,
+    pub count: u32,
+    // The first file the chunk was found in, as a position in the scan's file list, and where in that file it starts
+    pub file: u32,
+    pub offset: u64,
+    // How large the chunk is once compressed, or 0 if the scan didn't compress it
+    pub compressed: u32,
+    // The directory groups (see files::Groups) of every file the chunk was found in, one bit each
+    pub groups: u64,
+}
+
+// The settings a run was made with. Runs made with different settings have different chunks (or different ids for
+// the same chunks), so merging them would give statistics that look right but aren't.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    pub key_len: usize,
+    pub chunking: u8,
+    pub min_chunk: u32,
+    pub max_chunk: u32,
+    // Bits in the primary bitmask, which is what sets the average chunk size. Zero for fixed size chunks.
+    pub mask_bits: u8,
+    pub hash: u8,
+    // The block size chunks were cut at multiples of, or zero if they weren't aligned
+    pub alignment: u32,
+}
+
+impl Params {
+    pub fn new(chunking: &crate::pipeline::Chunking, key_len: usize) -> Params {
+        let (chunking, min_chunk, max_chunk, mask_bits, alignment) = match chunking {
+            crate::pipeline::Chunking::Fixed => {
+                let size = crate::pipeline::FIXED_CHUNK_SIZE as u32;
+                (CHUNKING_FIXED, size, size, 0, 0)
+            }
+            crate::pipeline::Chunking::Variable(builder) => (
+                CHUNKING_RABIN,
+                builder.min() as u32,
+                builder.max() as u32,
+                builder.mask_bits() as u8,
+                builder.alignment().unwrap_or(0) as u32,
+            ),
+        };
+        Params {
+            key_len,
+            chunking,
+            min_chunk,
+            max_chunk,
+            mask_bits,
+            hash: HASH_SHA3_256,
+            alignment,
+        }
+    }
+}
+
+impl Params {
+    // How to chunk data the same way the run's chunks were cut, or None if the settings aren't ones this build knows
+    pub fn chunking(&self) -> Option<crate::pipeline::Chunking> {
+        match self.chunking {
+            CHUNKING_FIXED if self.max_chunk as usize == crate::pipeline::FIXED_CHUNK_SIZE => {
+                Some(crate::pipeline::Chunking::Fixed)
+            }
+            CHUNKING_RABIN => rabin::chunker::ChunkerBuilder::new(
+                self.min_chunk as usize,
+                self.max_chunk as usize,
+            )
+            .and_then(|builder| builder.with_mask_bits(self.mask_bits as u32))
+            .and_then(|builder| match self.alignment {
+                0 => Ok(builder),
+                alignment => builder.with_alignment(alignment as usize),
+            })
+            .ok()
+            .map(crate::pipeline::Chunking::Variable),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Params {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.chunking {
+            CHUNKING_FIXED => write!(f, "fixed {} byte chunks", self.max_chunk)?,
+            CHUNKING_RABIN => write!(
+                f,
+                "Rabin chunks of {} to {} bytes with {} mask bits",
+                self.min_chunk, self.max_chunk, self.mask_bits
+            )?,
+            other => write!(f, "chunking {}", other)?,
+        }
+        if self.alignment > 0 {
+            write!(f, " aligned to {} bytes", self.alignment)?;
+        }
+        match self.hash {
+            HASH_SHA3_256 => write!(f, " and {} bit SHA3-256 ids", self.key_len * 8),
+            other => write!(f, " and {} bit ids from hash {}", self.key_len * 8, other),
+        }
+    }
+}
+
+impl Entry {
+    // Two entries describe the same chunk if the key, size and check all match. The count and file don't matter.
+    pub fn same_chunk(&self, other: &Entry) -> bool {
+        self.key == other.key && self.size == other.size && self.check == other.check
+    }
+}
+
+pub struct RunReader {
+    reader: io::BufReader<fs::File>,
+    params: Params,
+    key_len: usize,
+}
+
+impl RunReader {
+    // Opens the run and checks its header
+    pub fn open(path: &path::Path) -> io::Result<RunReader> {
+        RunReader::with_buffer(path, DEFAULT_READ_BUFFER)
+    }
+
+    // The same as open, reading the file 'buffer' bytes at a time
+    pub fn with_buffer(path: &path::Path, buffer: usize) -> io::Result<RunReader> {
+        let mut reader = io::BufReader::with_capacity(buffer, fs::File::open(path)?);
+        let mut header = [0u8; HEADER_LEN];
+        let header = match reader.read_exact(&mut header) {
+            Ok(()) => &header[..],
+            Err(_) => &[],
+        };
+        let params = check_header(header, path)?;
+        Ok(RunReader {
+            reader,
+            params,
+            key_len: params.key_len,
+        })
+    }
+
+    // How many bytes of each key the run stores
+    pub fn key_len(&self) -> usize {
+        self.key_len
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    // Returns the next entry, or None at the end of the file. A file that ends part of the way through an entry is an
+    // error rather than the end of the run.
+    pub fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        let entry_len = self.key_len + ENTRY_FIELDS_LEN;
+        let buffered = self.reader.fill_buf()?;
+        if buffered.is_empty() {
+            return Ok(None);
+        }
+        // Entries are decoded straight out of the buffer, and only copied out when one straddles the end of it
+        if buffered.len() >= entry_len {
+            let entry = decode_entry(&buffered[..entry_len], self.key_len);
+            self.reader.consume(entry_len);
+            return Ok(Some(entry));
+        }
+
+        let mut bytes = [0u8; crate::MAX_KEY_LEN + ENTRY_FIELDS_LEN];
+        let bytes = &mut bytes[..self.key_len + ENTRY_FIELDS_LEN];
+        self.reader.read_exact(bytes)?;
+        Ok(Some(decode_entry(bytes, self.key_len)))
+    }
+}
+
+// A run opened to look keys up in rather than to read in order. The file is mapped and binary searched in place, so
+// even a merged file much larger than memory can be used.
+pub struct RunIndex {
+    data: memmap::Mmap,
+    params: Params,
+    key_len: usize,
+}
+
+impl RunIndex {
+    pub fn open(path: &path::Path) -> io::Result<RunIndex> {
+        let file = fs::File::open(path)?;
+        let data = unsafe { memmap::Mmap::map(&file)? };
+        let params = check_header(&data[..HEADER_LEN.min(data.len())], path)?;
+        let key_len = params.key_len;
+        if !(data.len() - HEADER_LEN).is_multiple_of(key_len + ENTRY_FIELDS_LEN) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} ends part of the way through an entry", path),
+            ));
+        }
+        Ok(RunIndex {
+            data,
+            params,
+            key_len,
+        })
+    }
+
+    pub fn key_len(&self) -> usize {
+        self.key_len
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    // Finds the entry with the key, if the run has one
+    pub fn find(&self, key: &Key) -> Option<Entry> {
+        let entry_len = self.key_len + ENTRY_FIELDS_LEN;
+        let entries = &self.data[HEADER_LEN..];
+        let (mut low, mut high) = (0, entries.len() / entry_len);
+        while low < high {
+            let middle = (low + high) / 2;
+            let bytes = &entries[middle * entry_len..(middle + 1) * entry_len];
+            match bytes[..self.key_len].cmp(&key[..self.key_len]) {
+                cmp::Ordering::Less => low = middle + 1,
+                cmp::Ordering::Greater => high = middle,
+                cmp::Ordering::Equal => return Some(decode_entry(bytes, self.key_len)),
+            }
+        }
+        None
+    }
+}
+
+// Checks the header of a run file and returns the settings it was made with
+fn check_header(header: &[u8], path: &path::Path) -> io::Result<Params> {
+    if header.len() < HEADER_LEN || header[0..4] != RUN_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} is not a run file, or was written by an older version; scan again",
+                path
+            ),
+        ));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} is run file format version {}, but only version {} can be read",
+                path, version, FORMAT_VERSION
+            ),
+        ));
+    }
+    let key_len = header[6] as usize;
+    if key_len == 0 || key_len > crate::MAX_KEY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} has {} byte keys", path, key_len),
+        ));
+    }
+    let field =
+        |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+    Ok(Params {
+        key_len,
+        chunking: header[7],
+        min_chunk: field(8),
+        max_chunk: field(12),
+        mask_bits: header[16],
+        hash: header[17],
+        alignment: field(18),
+    })
+}
+
+fn decode_entry(bytes: &[u8], key_len: usize) -> Entry {
+    let (key, fields) = bytes.split_at(key_len);
+    let field =
+        |i: usize| u32::from_le_bytes([fields[i], fields[i + 1], fields[i + 2], fields[i + 3]]);
+    Entry {
+        key: key_from(key, key_len),
+        size: field(0),
+        check: field(4),
+        count: field(8),
+        file: field(12),
+        offset: u64::from_le_bytes([
+            fields[16], fields[17], fields[18], fields[19], fields[20], fields[21], fields[22],
+            fields[23],
+        ]),
+        compressed: field(24),
+        groups: u64::from_le_bytes([
+            fields[28], fields[29], fields[30], fields[31], fields[32], fields[33], fields[34],
+            fields[35],
+        ]),
+    }
+}
+
+// Must be written before the first entry of every run. Every entry in the run is written with the key length in the
+// settings.
+pub fn write_header<W: io::Write>(mut out: W, params: &Params) -> io::Result<()> {
+    out.write_all(&RUN_MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    out.write_all(&[params.key_len as u8, params.chunking])?;
+    out.write_all(&params.min_chunk.to_le_bytes())?;
+    out.write_all(&params.max_chunk.to_le_bytes())?;
+    out.write_all(&[params.mask_bits, params.hash])?;
+    out.write_all(&params.alignment.to_le_bytes())
+}
+
+pub fn write_entry<W: io::Write>(mut out: W, entry: &Entry, key_len: usize) -> io::Result<()> {
+    out.write_all(&entry.key[..key_len])?;
+    out.write_all(&entry.size.to_le_bytes())?;
+    out.write_all(&entry.check.to_le_bytes())?;
+    out.write_all(&entry.count.to_le_bytes())?;
+    out.write_all(&entry.file.to_le_bytes())?;
+    out.write_all(&entry.offset.to_le_bytes())?;
+    out.write_all(&entry.compressed.to_le_bytes())?;
+    out.write_all(&entry.groups.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_key_lengths() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_run_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let hash: Vec<u8> = (1..=32).collect();
+        for &key_len in [14, 16, 18, 20, 32].iter() {
+            let entry = crate::run::Entry {
+                key: crate::run::key_from(&hash, key_len),
+                size: 70000,
+                check: 2,
+                count: 3,
+                file: 4,
+                offset: 5_000_000_000,
+                compressed: 6,
+                groups: 1 << 63,
+            };
+            let name = dir.join(format!("run_{}", key_len));
+            let mut out = fs::File::create(&name).unwrap();
+            let params = crate::run::Params {
+                key_len,
+                chunking: crate::run::CHUNKING_RABIN,
+                min_chunk: 2048,
+                max_chunk: 65536,
+                mask_bits: 13,
+                hash: crate::run::HASH_SHA3_256,
+                alignment: 4096,
+            };
+            crate::run::write_header(&mut out, &params).unwrap();
+            crate::run::write_entry(&mut out, &entry, key_len).unwrap();
+            out.flush().unwrap();
+
+            // Only the key's own bytes are stored
+            let stored = fs::metadata(&name).unwrap().len() as usize;
+            assert_eq!(
+                stored,
+                crate::run::HEADER_LEN + key_len + crate::run::ENTRY_FIELDS_LEN
+            );
+
+            let mut reader = crate::run::RunReader::open(&name).unwrap();
+            assert_eq!(reader.key_len(), key_len);
+            assert_eq!(reader.params(), &params);
+            assert_eq!(reader.next_entry().unwrap(), Some(entry));
+            assert_eq!(reader.next_entry().unwrap(), None);
+
+            // An entry that doesn't fit in the buffer is read all the same
+            let mut reader = crate::run::RunReader::with_buffer(&name, 16).unwrap();
+            assert_eq!(reader.next_entry().unwrap(), Some(entry));
+            assert_eq!(reader.next_entry().unwrap(), None);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_index() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_run_index_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Every even key from 0 to 198
+        let entry = |key: u8| crate::run::Entry {
+            key: crate::run::key_from(&[key; crate::KEY_LEN], crate::KEY_LEN),
+            size: key as u32,
+            ..Default::default()
+        };
+        let name = dir.join("run");
+        let mut out = fs::File::create(&name).unwrap();
+        let params = crate::run::Params {
+            key_len: crate::KEY_LEN,
+            ..Default::default()
+        };
+        crate::run::write_header(&mut out, &params).unwrap();
+        for key in (0..200).step_by(2) {
+            crate::run::write_entry(&mut out, &entry(key), crate::KEY_LEN).unwrap();
+        }
+        out.flush().unwrap();
+
+        let index = crate::run::RunIndex::open(&name).unwrap();
+        assert_eq!(index.key_len(), crate::KEY_LEN);
+        for key in 0..=200 {
+            let expected = if key % 2 == 0 && key < 200 {
+                Some(entry(key))
+            } else {
+                None
+            };
+            assert_eq!(index.find(&entry(key).key), expected);
+        }
+
+        // A run that was cut off part of the way through an entry is rejected
+        let bytes = fs::read(&name).unwrap();
+        fs::write(&name, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(crate::run::RunIndex::open(&name).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,360 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path;
+
+// A signature describes the chunks of a file, or of every file in a tree, compactly enough to send somewhere else:
+// where each chunk starts, a cheap weak hash of it and its id. Whoever has a new version of the data can chunk it the
+// same way and look its chunks up in the signature, and only has to send the ones the signature doesn't have (see
+// delta). The layout, all little-endian, is:
+//
+//    header   the magic, the format version, the key length, the chunking and hash settings (as in a run file's
+//             header) and the number of files
+//    files    each file's path (a u32 length and UTF-8 bytes, relative to what was signed and separated by '/'), size
+//             and number of chunks, followed by each chunk's offset (u64), weak hash (u32) and the key_len bytes of its id
+const MAGIC: [u8; 4] = *b"DSIG";
+const FORMAT_VERSION: u16 = 2;
+
+// What is known about each chunk. Its size is the distance to the next chunk's offset, or to the end of the file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkSignature {
+    pub offset: u64,
+    pub weak: u32,
+    pub strong: crate::run::Key,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileSignature {
+    pub path: String,
+    pub size: u64,
+    pub chunks: Vec<ChunkSignature>,
+}
+
+impl FileSignature {
+    // Chunks the data and describes each chunk
+    pub fn new(
+        path: String,
+        data: &[u8],
+        chunking: &crate::pipeline::Chunking,
+        key_len: usize,
+    ) -> FileSignature {
+        let mut hasher = sha3::Sha3_256::default();
+        let mut chunks = vec![];
+        let mut offset = 0;
+        for chunk in chunking.chunks(data) {
+            chunks.push(ChunkSignature {
+                offset,
+                weak: weak_hash(chunk),
+                strong: crate::pipeline::hash_key(&mut hasher, chunk, key_len),
+            });
+            offset += chunk.len() as u64;
+        }
+        FileSignature {
+            path,
+            size: data.len() as u64,
+            chunks,
+        }
+    }
+
+    // How many bytes the chunk at this position in the list holds
+    pub fn chunk_len(&self, i: usize) -> u64 {
+        let end = self.chunks.get(i + 1).map_or(self.size, |c| c.offset);
+        end - self.chunks[i].offset
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    // How the chunks were cut and their ids made. Data can only be compared with the signature when it is chunked
+    // and hashed the same way.
+    pub params: crate::run::Params,
+    pub files: Vec<FileSignature>,
+}
+
+impl Signature {
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let p = &self.params;
+        out.write_all(&MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        out.write_all(&[p.key_len as u8, p.chunking])?;
+        out.write_all(&p.min_chunk.to_le_bytes())?;
+        out.write_all(&p.max_chunk.to_le_bytes())?;
+        out.write_all(&[p.mask_bits, p.hash])?;
+        out.write_all(&p.alignment.to_le_bytes())?;
+        out.write_all(&(self.files.len() as u32).to_le_bytes())?;
+        for file in self.files.iter() {
+            out.write_all(&(file.path.len() as u32).to_le_bytes())?;
+            out.write_all(file.path.as_bytes())?;
+            out.write_all(&file.size.to_le_bytes())?;
+            out.write_all(&(file.chunks.len() as u32).to_le_bytes())?;
+            for c in file.chunks.iter() {
+                out.write_all(&c.offset.to_le_bytes())?;
+                out.write_all(&c.weak.to_le_bytes())?;
+                out.write_all(&c.strong[..p.key_len])?;
+            }
+        }
+        out.flush()
+    }
+
+    pub fn read<R: Read>(mut input: R) -> io::Result<Signature> {
+        let mut header = [0u8; 22];
+        input.read_exact(&mut header)?;
+        if header[0..4] != MAGIC {
+            return Err(invalid("this isn't a signature file".to_string()));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!(
+                "the signature is format version {}, but only version {} can be read",
+                version, FORMAT_VERSION
+            )));
+        }
+        let key_len = header[6] as usize;
+        if key_len == 0 || key_len > crate::MAX_KEY_LEN {
+            return Err(invalid(format!("the signature has {} byte ids", key_len)));
+        }
+        let field =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        let params = crate::run::Params {
+            key_len,
+            chunking: header[7],
+            min_chunk: field(8),
+            max_chunk: field(12),
+            mask_bits: header[16],
+            hash: header[17],
+            alignment: field(18),
+        };
+
+        let mut files = vec![];
+        for _ in 0..read_u32(&mut input)? {
+            let mut path = vec![0u8; read_u32(&mut input)? as usize];
+            input.read_exact(&mut path)?;
+            let path = String::from_utf8(path)
+                .map_err(|_| invalid("a path in the signature isn't UTF-8".to_string()))?;
+            let size = read_u64(&mut input)?;
+            let count = read_u32(&mut input)?;
+            let mut chunks = Vec::with_capacity(count.min(1 << 20) as usize);
+            for _ in 0..count {
+                let offset = read_u64(&mut input)?;
+                let weak = read_u32(&mut input)?;
+                let mut strong = [0u8; crate::MAX_KEY_LEN];
+                input.read_exact(&mut strong[..key_len])?;
+                if offset > size
+                    || chunks
+                        .last()
+                        .is_some_and(|c: &ChunkSignature| c.offset >= offset)
+                {
+                    return Err(invalid(format!(
+                        "the chunks of {:?} are out of order",
+                        path
+                    )));
+                }
+                chunks.push(ChunkSignature {
+                    offset,
+                    weak,
+                    strong,
+                });
+            }
+            files.push(FileSignature { path, size, chunks });
+        }
+        Ok(Signature { params, files })
+    }
+
+    // How many chunks the signature describes, in all of its files
+    pub fn chunks(&self) -> usize {
+        self.files.iter().map(|f| f.chunks.len()).sum()
+    }
+}
+
+// Signs a file, or every file in a directory tree in the order of their paths (see files). Files that can't be read are
+// skipped and recorded.
+pub fn sign(
+    source: &path::Path,
+    options: &crate::pipeline::Options,
+) -> (Signature, crate::skipped::Skipped) {
+    let (paths, mut skipped) = files(source, options.symlinks);
+    let mut signed = vec![];
+    for (name, path) in paths {
+        match map(&path) {
+            Ok(data) => signed.push(FileSignature::new(
+                name,
+                &data,
+                &options.chunking,
+                options.key_len,
+            )),
+            Err(e) => skipped.record(&path, &e),
+        }
+    }
+    let params = crate::run::Params::new(&options.chunking, options.key_len);
+    let signature = Signature {
+        params,
+        files: signed,
+    };
+    (signature, skipped)
+}
+
+// The files in a directory tree sorted by path, or a file on its own, with the names they go by in signatures and
+// deltas: their paths relative to the directory, or the file's name
+pub(crate) fn files(
+    source: &path::Path,
+    symlinks: crate::walk::Symlinks,
+) -> (Vec<(String, path::PathBuf)>, crate::skipped::Skipped) {
+    let mut walker = crate::walk::Walker::new(symlinks, false, false);
+    let mut paths = vec![];
+    let mut found = |path: &path::Path, found: crate::walk::Found| {
+        if found == crate::walk::Found::File {
+            paths.push(path.to_path_buf());
+        }
+    };
+    if source.is_dir() {
+        walker.walk(source, &ignore::gitignore::Gitignore::empty(), &mut found);
+    } else {
+        walker.file(source, &mut found);
+    }
+    paths.sort();
+
+    let named = paths
+        .into_iter()
+        .map(|path| {
+            let name = match path.strip_prefix(source) {
+                Ok(relative) if relative != path::Path::new("") => relative_name(relative),
+                _ => path
+                    .file_name()
+                    .map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+            };
+            (name, path)
+        })
+        .collect();
+    (named, walker.skipped())
+}
+
+// The checksum rsync uses as its weak hash: the sum of the bytes and the sum of the running sums, 16 bits of each.
+// It is far cheaper than the chunk id, so a chunk whose weak hash isn't in the signature needn't be hashed again.
+pub fn weak_hash(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (0u32, 0u32);
+    for &byte in data {
+        a = a.wrapping_add(byte as u32);
+        b = b.wrapping_add(a);
+    }
+    (a & 0xffff) | (b << 16)
+}
+
+// Paths in a signature are the same on every system
+fn relative_name(relative: &path::Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// The contents of a file, mapped into memory unless it is empty (which can't be mapped)
+pub(crate) fn map(path: &path::Path) -> io::Result<crate::pipeline::Contents> {
+    let file = fs::File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(crate::pipeline::Contents::Owned(vec![]));
+    }
+    let mmap = unsafe { memmap::Mmap::map(&file)? };
+    crate::advise::sequential(&mmap);
+    Ok(crate::pipeline::Contents::Mapped(mmap))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(crate::signature::weak_hash(b"abc"), 294 | (586 << 16));
+
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_signature_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let mut x = 1u64;
+        let data: Vec<u8> = (0..200_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        fs::write(dir.join("a"), &data).unwrap();
+        fs::write(dir.join("sub").join("b"), &data[500..]).unwrap();
+        fs::write(dir.join("empty"), b"").unwrap();
+
+        let options = crate::pipeline::Options {
+            chunking: crate::pipeline::Chunking::Variable(
+                rabin::chunker::ChunkerBuilder::new(crate::MIN_CHUNK_SIZE, crate::MAX_CHUNK_SIZE)
+                    .unwrap(),
+            ),
+            key_len: crate::KEY_LEN,
+            threads: 1,
+            hash_cpus: None,
+            io_cpus: None,
+            symlinks: crate::walk::Symlinks::Skip,
+            detect_hard_links: true,
+            streams: false,
+            mmap: true,
+            uring: None,
+            direct_io: false,
+            resume: vec![],
+            compress_every: None,
+            only: None,
+            min_file_size: None,
+            max_file_size: None,
+            cache: None,
+            sub_blocks: None,
+            disk_images: false,
+            anchors: vec![],
+            compressed: crate::compressed::Policy::Chunk,
+            archives: false,
+        };
+        let (signature, skipped) = crate::signature::sign(&dir, &options);
+        assert_eq!(skipped.count(), 0);
+        let paths: Vec<&str> = signature.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["a", "empty", "sub/b"]);
+        let a = &signature.files[0];
+        assert!(a.chunks.len() > 10);
+        let sizes: u64 = (0..a.chunks.len()).map(|i| a.chunk_len(i)).sum();
+        assert_eq!(sizes, 200_000);
+        assert!(signature.files[1].chunks.is_empty());
+        // After the first few chunks the two files are chunked the same way
+        let b = &signature.files[2];
+        assert_eq!(
+            a.chunks.last().unwrap().strong,
+            b.chunks.last().unwrap().strong
+        );
+
+        // A file on its own is signed under its name
+        let (single, _) = crate::signature::sign(&dir.join("a"), &options);
+        assert_eq!(single.files, &signature.files[..1]);
+
+        let mut bytes = vec![];
+        signature.write(&mut bytes).unwrap();
+        assert_eq!(
+            crate::signature::Signature::read(&bytes[..]).unwrap(),
+            signature
+        );
+        assert!(crate::signature::Signature::read(&bytes[..bytes.len() - 1]).is_err());
+        assert!(crate::signature::Signature::read(&b"DRUN"[..]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
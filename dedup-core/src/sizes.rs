@@ -0,0 +1,105 @@
+use std::collections;
+
+use serde_derive::Serialize;
+
+// How many equally wide ranges of sizes the histogram is split into
+const BUCKETS: u32 = 20;
+
+// Counts how many chunks of each size were made. The minimum and maximum chunk sizes, and the secondary boundary used
+// when no primary one turns up before the maximum, all shape the distribution: chunks pile up just under the maximum
+// and exactly at it, and the only chunks under the minimum are the ends of files. Every size is counted exactly and
+// only split into ranges at the end, since the largest size isn't known until then.
+#[derive(Debug, Default)]
+pub struct SizeHistogram {
+    sizes: collections::BTreeMap<u32, Bucket>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    chunks: u64,
+    bytes: u64,
+}
+
+// The chunks whose size was in min..=max
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SizeRange {
+    pub min: u32,
+    pub max: u32,
+    pub chunks: u64,
+    pub bytes: u64,
+}
+
+impl SizeHistogram {
+    // Records a unique chunk that occurred 'occurrences' times in total, so the histogram covers every chunk made
+    pub fn record(&mut self, size: u32, occurrences: u32) {
+        let bucket = self.sizes.entry(size).or_default();
+        bucket.chunks += occurrences as u64;
+        bucket.bytes += occurrences as u64 * size as u64;
+    }
+
+    // The chunks that were exactly the largest size seen, which are usually the ones cut short at the maximum
+    pub fn largest(&self) -> SizeRange {
+        match self.sizes.iter().next_back() {
+            Some((&size, bucket)) => SizeRange {
+                min: size,
+                max: size,
+                chunks: bucket.chunks,
+                bytes: bucket.bytes,
+            },
+            None => SizeRange::default(),
+        }
+    }
+
+    // Splits the sizes from 0 to the largest seen into equally wide ranges. Ranges before the first chunk are left out.
+    pub fn ranges(&self) -> Vec<SizeRange> {
+        let largest = match self.sizes.keys().next_back() {
+            Some(&largest) => largest,
+            None => return vec![],
+        };
+        let width = largest / BUCKETS + 1;
+
+        let mut ranges: Vec<SizeRange> = (0..BUCKETS)
+            .map(|i| SizeRange {
+                min: i * width,
+                max: (i * width + width - 1).min(largest),
+                chunks: 0,
+                bytes: 0,
+            })
+            .collect();
+        for (&size, bucket) in self.sizes.iter() {
+            let range = &mut ranges[(size / width) as usize];
+            range.chunks += bucket.chunks;
+            range.bytes += bucket.bytes;
+        }
+
+        let first = ranges.iter().position(|r| r.chunks > 0).unwrap_or(0);
+        ranges.drain(..first);
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_size_histogram() {
+        let mut histogram = crate::sizes::SizeHistogram::default();
+        assert!(histogram.ranges().is_empty());
+
+        histogram.record(1000, 2);
+        histogram.record(1050, 1);
+        histogram.record(1999, 1);
+        histogram.record(1999, 3);
+
+        // 20 ranges 100 wide cover 0 to 1999, and the ten below 1000 are dropped
+        let ranges = histogram.ranges();
+        assert_eq!(ranges.len(), 10);
+        assert_eq!((ranges[0].min, ranges[0].max), (1000, 1099));
+        assert_eq!((ranges[0].chunks, ranges[0].bytes), (3, 3050));
+        assert_eq!((ranges[9].min, ranges[9].max), (1900, 1999));
+        assert_eq!((ranges[9].chunks, ranges[9].bytes), (4, 7996));
+        assert_eq!(ranges.iter().map(|r| r.chunks).sum::<u64>(), 7);
+
+        let largest = histogram.largest();
+        assert_eq!((largest.min, largest.chunks), (1999, 4));
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections;
+use std::io;
+use std::path;
+
+// How many of the skipped paths to show along with the totals
+const EXAMPLES: usize = 10;
+
+// The files and directories a scan couldn't read, and why. Anything skipped is missing from the statistics, so the
+// scan says how much was left out instead of quietly carrying on.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Skipped {
+    // How many were skipped for each reason
+    pub reasons: collections::BTreeMap<&'static str, u64>,
+    // The first few that were skipped, with the error for each
+    pub examples: Vec<(String, String)>,
+}
+
+impl Skipped {
+    pub fn record(&mut self, path: &path::Path, error: &io::Error) {
+        tracing::info!(path = %path.display(), error = %error, "skipped");
+        *self.reasons.entry(reason(error)).or_insert(0) += 1;
+        if self.examples.len() < EXAMPLES {
+            self.examples
+                .push((path.to_string_lossy().into_owned(), error.to_string()));
+        }
+    }
+
+    pub fn add(&mut self, other: Skipped) {
+        for (reason, count) in other.reasons {
+            *self.reasons.entry(reason).or_insert(0) += count;
+        }
+        let room = EXAMPLES - self.examples.len().min(EXAMPLES);
+        self.examples.extend(other.examples.into_iter().take(room));
+    }
+
+    pub fn count(&self) -> u64 {
+        self.reasons.values().sum()
+    }
+}
+
+// Sorts errors into the few reasons that are worth telling apart
+fn reason(error: &io::Error) -> &'static str {
+    if is_name_too_long(error) {
+        return "path too long";
+    }
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => "permission denied",
+        // Deleted between being found and being read
+        io::ErrorKind::NotFound => "vanished",
+        // Shrank while it was being read
+        io::ErrorKind::UnexpectedEof => "changed while reading",
+        _ => "other errors",
+    }
+}
+
+#[cfg(unix)]
+fn is_name_too_long(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ENAMETOOLONG)
+}
+
+// ERROR_FILENAME_EXCED_RANGE
+#[cfg(windows)]
+fn is_name_too_long(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(206)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_name_too_long(_error: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path;
+
+    #[test]
+    fn test_skipped() {
+        let mut skipped = crate::skipped::Skipped::default();
+        skipped.record(
+            path::Path::new("a"),
+            &io::Error::from(io::ErrorKind::PermissionDenied),
+        );
+        skipped.record(
+            path::Path::new("b"),
+            &io::Error::from(io::ErrorKind::NotFound),
+        );
+
+        let mut other = crate::skipped::Skipped::default();
+        for i in 0..20 {
+            other.record(
+                path::Path::new(&i.to_string()),
+                &io::Error::from(io::ErrorKind::PermissionDenied),
+            );
+        }
+        other.record(
+            path::Path::new("long"),
+            &io::Error::from_raw_os_error(libc::ENAMETOOLONG),
+        );
+        skipped.add(other);
+
+        assert_eq!(skipped.count(), 23);
+        assert_eq!(skipped.reasons["permission denied"], 21);
+        assert_eq!(skipped.reasons["vanished"], 1);
+        assert_eq!(skipped.reasons["path too long"], 1);
+        assert_eq!(skipped.examples.len(), 10);
+        assert_eq!(skipped.examples[0].0, "a");
+        assert_eq!(skipped.examples[2].0, "0");
+    }
+}
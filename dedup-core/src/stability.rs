@@ -0,0 +1,185 @@
+use std::collections;
+
+// The kinds of edit the stability test makes to a file
+pub const EDITS: &[&str] = &["insert", "delete", "overwrite"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edit {
+    // Adds random bytes at the offset, moving everything after it
+    Insert,
+    // Removes bytes at the offset, moving everything after it
+    Delete,
+    // Changes bytes at the offset in place
+    Overwrite,
+}
+
+impl Edit {
+    pub fn from_name(name: &str) -> Option<Edit> {
+        match name {
+            "insert" => Some(Edit::Insert),
+            "delete" => Some(Edit::Delete),
+            "overwrite" => Some(Edit::Overwrite),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Edit::Insert => "insert",
+            Edit::Delete => "delete",
+            Edit::Overwrite => "overwrite",
+        }
+    }
+}
+
+// A xorshift generator, so the same seed makes the same edits (and the same test data) every time
+pub struct Random(u64);
+
+impl Random {
+    // xorshift never leaves 0, so a seed of 0 is taken as 1
+    pub fn new(seed: u64) -> Random {
+        Random(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n.max(1) as u64) as usize
+    }
+
+    // A number from 0 up to but not including 1
+    pub fn fraction(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Makes 'edits' edits of 'bytes' bytes each at random offsets in the data, one after the other. Overwritten bytes
+// always change.
+pub fn perturb(data: &[u8], edit: Edit, bytes: usize, edits: usize, seed: u64) -> Vec<u8> {
+    let mut random = Random::new(seed);
+    let mut edited = data.to_vec();
+    for _ in 0..edits {
+        let offset = random.below(edited.len() + 1);
+        match edit {
+            Edit::Insert => {
+                let inserted: Vec<u8> = (0..bytes).map(|_| random.next_u64() as u8).collect();
+                edited.splice(offset..offset, inserted);
+            }
+            Edit::Delete => {
+                edited.drain(offset..(offset + bytes).min(edited.len()));
+            }
+            Edit::Overwrite => {
+                let end = (offset + bytes).min(edited.len());
+                for b in edited[offset..end].iter_mut() {
+                    *b ^= 1 + random.below(255) as u8;
+                }
+            }
+        }
+    }
+    edited
+}
+
+// How much of an edited file could be stored by reusing the chunks of the original
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stability {
+    pub chunks: u64,
+    pub bytes: u64,
+    // The chunks of the edited file that the original has too
+    pub reused_chunks: u64,
+    pub reused_bytes: u64,
+}
+
+impl Stability {
+    pub fn add(&mut self, other: &Stability) {
+        self.chunks += other.chunks;
+        self.bytes += other.bytes;
+        self.reused_chunks += other.reused_chunks;
+        self.reused_bytes += other.reused_bytes;
+    }
+
+    // The percent of the edited file's bytes that are in chunks the original has
+    pub fn reused_percent(&self) -> f64 {
+        (self.reused_bytes * 100) as f64 / self.bytes.max(1) as f64
+    }
+}
+
+// Chunks both versions of the file and counts the chunks of the edited one that the original already has
+pub fn measure(original: &[u8], edited: &[u8], chunking: &crate::pipeline::Chunking) -> Stability {
+    let known: collections::HashSet<[u8; 32]> = chunking
+        .chunks(original)
+        .map(rabin::hash_chunk_sha256)
+        .collect();
+    let mut stability = Stability::default();
+    for c in chunking.chunks(edited) {
+        stability.chunks += 1;
+        stability.bytes += c.len() as u64;
+        if known.contains(&rabin::hash_chunk_sha256(c)) {
+            stability.reused_chunks += 1;
+            stability.reused_bytes += c.len() as u64;
+        }
+    }
+    stability
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stability::Edit;
+
+    #[test]
+    fn test_stability() {
+        let mut x = 5u64;
+        let data: Vec<u8> = (0..500_000)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect();
+
+        // The edits are the same for the same seed, and do what they say
+        let inserted = crate::stability::perturb(&data, Edit::Insert, 10, 3, 7);
+        assert_eq!(
+            inserted,
+            crate::stability::perturb(&data, Edit::Insert, 10, 3, 7)
+        );
+        assert_eq!(inserted.len(), data.len() + 30);
+        assert_eq!(
+            crate::stability::perturb(&data, Edit::Delete, 10, 3, 7).len(),
+            data.len() - 30
+        );
+        let overwritten = crate::stability::perturb(&data, Edit::Overwrite, 10, 1, 7);
+        assert_eq!(overwritten.len(), data.len());
+        assert_eq!(
+            data.iter()
+                .zip(overwritten.iter())
+                .filter(|(a, b)| a != b)
+                .count(),
+            10
+        );
+
+        let variable = crate::pipeline::Chunking::Variable(
+            rabin::chunker::ChunkerBuilder::new(crate::MIN_CHUNK_SIZE, crate::MAX_CHUNK_SIZE)
+                .unwrap(),
+        );
+        let unchanged = crate::stability::measure(&data, &data, &variable);
+        assert_eq!(unchanged.reused_bytes, data.len() as u64);
+        assert_eq!(unchanged.reused_percent(), 100.0);
+
+        // Variable-size chunks only lose the few around each edit, but every fixed-size chunk after an insert moves
+        let variable = crate::stability::measure(&data, &inserted, &variable);
+        assert_eq!(variable.bytes, inserted.len() as u64);
+        assert!(variable.reused_percent() > 90.0, "{:?}", variable);
+        let fixed = crate::stability::measure(&data, &inserted, &crate::pipeline::Chunking::Fixed);
+        assert!(
+            fixed.reused_percent() < variable.reused_percent(),
+            "{:?}",
+            fixed
+        );
+    }
+}
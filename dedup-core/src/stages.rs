@@ -0,0 +1,298 @@
+use std::sync;
+use std::sync::atomic;
+use std::sync::mpsc;
+use std::thread;
+
+// The pieces a staged pipeline like the scan's (read, then chunk, then hash, then store) is built from, for programs
+// that want the same shape without copying pipeline.rs. Each stage runs on threads of its own in a thread scope and
+// passes what it makes to the next through a channel that holds a limited number of items, so a slow stage holds up
+// the ones before it instead of letting them fill memory. The first error any stage returns stops every stage, and is
+// what the sink returns.
+
+// Where a stage puts what it makes, for the next stage to pick up. Waits for room if the channel is full.
+pub struct Output<T> {
+    sender: mpsc::SyncSender<T>,
+    stopped: sync::Arc<atomic::AtomicBool>,
+}
+
+impl<T> Output<T> {
+    // Hands the item on. Returns false if the pipeline has stopped, because a stage failed or the stages after this
+    // one are gone, in which case whatever is making items should stop too.
+    pub fn send(&self, item: T) -> bool {
+        !self.stopped() && self.sender.send(item).is_ok()
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(atomic::Ordering::Relaxed)
+    }
+}
+
+pub struct Stages<'scope, 'env, E> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+    stopped: sync::Arc<atomic::AtomicBool>,
+    // The first error any stage returned
+    error: sync::Arc<sync::Mutex<Option<E>>>,
+}
+
+impl<'scope, 'env, E: Send + 'scope> Stages<'scope, 'env, E> {
+    pub fn new(scope: &'scope thread::Scope<'scope, 'env>) -> Stages<'scope, 'env, E> {
+        Stages {
+            scope,
+            stopped: sync::Arc::new(atomic::AtomicBool::new(false)),
+            error: sync::Arc::new(sync::Mutex::new(None)),
+        }
+    }
+
+    // Whether a stage has failed, or the sink has stopped early
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(atomic::Ordering::Relaxed)
+    }
+
+    // Starts the first stage, which makes items out of nothing the pipeline gives it (i.e. walks directories and
+    // reads files) on a thread of its own. At most 'bound' items wait for the next stage.
+    pub fn source<T, F>(&self, bound: usize, produce: F) -> mpsc::Receiver<T>
+    where
+        T: Send + 'scope,
+        F: FnOnce(&Output<T>) -> Result<(), E> + Send + 'scope,
+    {
+        let (output, receiver) = self.output(bound);
+        let failed = self.failed();
+        self.scope.spawn(move || {
+            if let Err(e) = produce(&output) {
+                failed(e);
+            }
+        });
+        receiver
+    }
+
+    // Starts a stage that takes each item from the stage before and makes any number of items for the next one, on
+    // 'workers' threads. With more than one, the items come out in whatever order the threads finish them; put a
+    // number on them if the order matters. At most 'bound' items wait for the next stage.
+    pub fn stage<T, U, F>(
+        &self,
+        input: mpsc::Receiver<T>,
+        workers: usize,
+        bound: usize,
+        f: F,
+    ) -> mpsc::Receiver<U>
+    where
+        T: Send + 'scope,
+        U: Send + 'scope,
+        F: Fn(T, &Output<U>) -> Result<(), E> + Send + Sync + 'scope,
+    {
+        let (output, receiver) = self.output(bound);
+        let input = sync::Arc::new(sync::Mutex::new(input));
+        let f = sync::Arc::new(f);
+        for _ in 0..workers.max(1) {
+            let output = Output {
+                sender: output.sender.clone(),
+                stopped: output.stopped.clone(),
+            };
+            let input = input.clone();
+            let f = f.clone();
+            let failed = self.failed();
+            self.scope.spawn(move || loop {
+                if output.stopped() {
+                    return;
+                }
+                // The lock is only held while waiting for the next item, not while working on it
+                let item = match input.lock().unwrap().recv() {
+                    Ok(item) => item,
+                    Err(_) => return,
+                };
+                if let Err(e) = f(item, &output) {
+                    failed(e);
+                    return;
+                }
+            });
+        }
+        receiver
+    }
+
+    // Takes each item from the last stage on this thread until there are no more, and returns the first error any
+    // stage (this one included) returned. Every stage is stopped by the time it returns, though their threads are only
+    // joined at the end of the scope.
+    pub fn sink<T, F>(self, input: mpsc::Receiver<T>, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(T) -> Result<(), E>,
+    {
+        for item in input.iter() {
+            if self.stopped() {
+                break;
+            }
+            if let Err(e) = f(item) {
+                self.failed()(e);
+                break;
+            }
+        }
+        // The stages before have nowhere to send anything now, so they stop the next time they try
+        drop(input);
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn output<T>(&self, bound: usize) -> (Output<T>, mpsc::Receiver<T>) {
+        let (sender, receiver) = mpsc::sync_channel(bound);
+        let output = Output {
+            sender,
+            stopped: self.stopped.clone(),
+        };
+        (output, receiver)
+    }
+
+    // Records a stage's error, unless another stage failed first, and stops the rest
+    fn failed(&self) -> impl Fn(E) + Send + 'scope {
+        let stopped = self.stopped.clone();
+        let error = self.error.clone();
+        move |e| {
+            let mut error = error.lock().unwrap();
+            if error.is_none() {
+                *error = Some(e);
+            }
+            stopped.store(true, atomic::Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic;
+
+    #[test]
+    fn test_read_chunk_hash_sink() {
+        // Pseudo-random files, and a copy of the first
+        let mut files: Vec<Vec<u8>> = (1..=4u64)
+            .map(|seed| {
+                let mut x = seed;
+                (0..300_000)
+                    .map(|_| {
+                        x = x
+                            .wrapping_mul(6364136223846793005)
+                            .wrapping_add(1442695040888963407);
+                        (x >> 56) as u8
+                    })
+                    .collect()
+            })
+            .collect();
+        files.push(files[0].clone());
+
+        let mut hasher = sha3::Sha3_256::default();
+        let mut expected: Vec<(usize, crate::run::Key)> = vec![];
+        for (file, data) in files.iter().enumerate() {
+            let chunker =
+                rabin::chunker::Chunker::new(data, crate::MIN_CHUNK_SIZE, crate::MAX_CHUNK_SIZE)
+                    .unwrap();
+            for chunk in chunker {
+                expected.push((
+                    file,
+                    crate::pipeline::hash_key(&mut hasher, chunk, crate::KEY_LEN),
+                ));
+            }
+        }
+        expected.sort();
+
+        let mut hashed = vec![];
+        let result: Result<(), String> = std::thread::scope(|scope| {
+            let stages = crate::stages::Stages::new(scope);
+            let read = stages.source(2, |output| {
+                for (file, data) in files.iter().enumerate() {
+                    if !output.send((file, data.clone())) {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+            let chunked = stages.stage(read, 1, 64, |(file, data): (usize, Vec<u8>), output| {
+                let chunker = rabin::chunker::Chunker::new(
+                    &data,
+                    crate::MIN_CHUNK_SIZE,
+                    crate::MAX_CHUNK_SIZE,
+                )
+                .unwrap();
+                for chunk in chunker {
+                    if !output.send((file, chunk.to_vec())) {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+            let keys = stages.stage(chunked, 4, 64, |(file, chunk): (usize, Vec<u8>), output| {
+                let mut hasher = sha3::Sha3_256::default();
+                output.send((
+                    file,
+                    crate::pipeline::hash_key(&mut hasher, &chunk, crate::KEY_LEN),
+                ));
+                Ok(())
+            });
+            stages.sink(keys, |key| {
+                hashed.push(key);
+                Ok(())
+            })
+        });
+        assert_eq!(result, Ok(()));
+        hashed.sort();
+        assert_eq!(hashed, expected);
+    }
+
+    #[test]
+    fn test_backpressure_and_errors() {
+        // A source that could go on forever only gets as far ahead of a slow sink as the channels hold
+        let made = atomic::AtomicUsize::new(0);
+        let result: Result<(), String> = std::thread::scope(|scope| {
+            let stages = crate::stages::Stages::new(scope);
+            let numbers = stages.source(4, |output| {
+                for i in 0.. {
+                    made.fetch_add(1, atomic::Ordering::SeqCst);
+                    if !output.send(i) {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+            let doubled = stages.stage(numbers, 1, 4, |i: u64, output| {
+                output.send(i * 2);
+                Ok(())
+            });
+            let mut taken = 0;
+            stages.sink(doubled, |i| {
+                taken += 1;
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                // Four waiting in each channel, one with each thread and one more the source made before it found out
+                assert!(made.load(atomic::Ordering::SeqCst) <= taken + 12);
+                if taken == 50 {
+                    return Err(format!("stopped at {}", i));
+                }
+                Ok(())
+            })
+        });
+        assert_eq!(result, Err("stopped at 98".to_string()));
+        assert!(made.load(atomic::Ordering::SeqCst) < 70);
+
+        // An error part way through a stage stops the source, and is what the sink returns
+        let made = atomic::AtomicUsize::new(0);
+        let result: Result<(), String> = std::thread::scope(|scope| {
+            let stages = crate::stages::Stages::new(scope);
+            let numbers = stages.source(4, |output| {
+                for i in 0..1_000_000u64 {
+                    made.fetch_add(1, atomic::Ordering::SeqCst);
+                    if !output.send(i) {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+            let checked = stages.stage(numbers, 2, 4, |i: u64, output| {
+                if i == 100 {
+                    return Err("can't read 100".to_string());
+                }
+                output.send(i);
+                Ok(())
+            });
+            stages.sink(checked, |_| Ok(()))
+        });
+        assert_eq!(result, Err("can't read 100".to_string()));
+        assert!(made.load(atomic::Ordering::SeqCst) < 1000);
+    }
+}
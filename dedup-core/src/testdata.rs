@@ -0,0 +1,267 @@
+use std::fs;
+use std::io;
+use std::path;
+
+// Makes directory trees of made-up files to chunk, with a known amount of duplication, so that chunking settings can
+// be compared on the same data anywhere and tests have data whose answer is known. The same settings and seed always
+// make the same files, byte for byte.
+
+// The shapes the file sizes can be drawn from, by the names from_name takes
+pub const SIZE_DISTRIBUTIONS: &[&str] = &["fixed", "uniform", "log-uniform"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeDistribution {
+    // Every file is the minimum size
+    Fixed,
+    // Any size between the minimum and maximum is as likely as any other
+    Uniform,
+    // Sizes are spread evenly over the powers of two between the minimum and maximum, so there are many small files
+    // and a few large ones, as in most real trees
+    LogUniform,
+}
+
+impl SizeDistribution {
+    pub fn from_name(name: &str) -> Option<SizeDistribution> {
+        match name {
+            "fixed" => Some(SizeDistribution::Fixed),
+            "uniform" => Some(SizeDistribution::Uniform),
+            "log-uniform" => Some(SizeDistribution::LogUniform),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub files: usize,
+    pub min_size: u64,
+    pub max_size: u64,
+    pub sizes: SizeDistribution,
+    // The fraction of files (0 to 1) that are copies of a file made earlier instead of new data
+    pub duplicates: f64,
+    // How many edits are made to each copy for every MiB in it, each inserting, deleting or overwriting up to
+    // MAX_EDIT_BYTES bytes somewhere. 0 leaves the copies identical.
+    pub edit_rate: f64,
+    // The fraction of each new file (0 to 1) that is text made of a few words, which compresses well. The rest is
+    // random bytes, which don't compress at all.
+    pub compressibility: f64,
+    pub files_per_directory: usize,
+    pub seed: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            files: 1000,
+            min_size: 4 * 1024,
+            max_size: 4 * 1024 * 1024,
+            sizes: SizeDistribution::LogUniform,
+            duplicates: 0.3,
+            edit_rate: 1.0,
+            compressibility: 0.2,
+            files_per_directory: 100,
+            seed: 1,
+        }
+    }
+}
+
+// What was made
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Generated {
+    pub files: u64,
+    pub bytes: u64,
+    // The files that are copies of another, and the bytes in them
+    pub copies: u64,
+    pub copied_bytes: u64,
+    pub edits: u64,
+}
+
+// The most bytes one edit to a copy changes
+pub const MAX_EDIT_BYTES: usize = 64;
+
+// New data is made in blocks of this many bytes, each one text or random bytes
+const BLOCK_SIZE: usize = 4096;
+
+// What compressible blocks are made of
+const WORDS: &str = "the chunk of data is stored once and every other copy points at it backup file directory a hash \
+                     rolling window boundary deduplication when changed bytes in middle shifts everything after to";
+
+// Writes the files under 'dir', as 'dir'/<directory number>/<file number>, creating the directories as needed
+pub fn generate(dir: &path::Path, settings: &Settings) -> io::Result<Generated> {
+    let mut random = crate::stability::Random::new(settings.seed);
+    let mut generated = Generated::default();
+    // The seed and size of every file that was made from new data, which is all it takes to make it again
+    let mut originals: Vec<(u64, u64)> = vec![];
+    for i in 0..settings.files {
+        let copy = !originals.is_empty() && random.fraction() < settings.duplicates;
+        let data = if copy {
+            let (seed, size) = originals[random.below(originals.len())];
+            let mut data = new_data(seed, size as usize, settings.compressibility);
+            // A whole number of edits on average, with the fraction left over as the chance of one more
+            let expected = settings.edit_rate * size as f64 / (1024.0 * 1024.0);
+            let edits = expected as u64 + (random.fraction() < expected.fract()) as u64;
+            for _ in 0..edits {
+                let edit = crate::stability::Edit::from_name(
+                    crate::stability::EDITS[random.below(crate::stability::EDITS.len())],
+                )
+                .unwrap();
+                let bytes = 1 + random.below(MAX_EDIT_BYTES);
+                data = crate::stability::perturb(&data, edit, bytes, 1, random.next_u64());
+            }
+            generated.copies += 1;
+            generated.copied_bytes += data.len() as u64;
+            generated.edits += edits;
+            data
+        } else {
+            let seed = random.next_u64();
+            let size = file_size(&mut random, settings);
+            originals.push((seed, size));
+            new_data(seed, size as usize, settings.compressibility)
+        };
+
+        let directory = dir.join(format!("{:04}", i / settings.files_per_directory.max(1)));
+        fs::create_dir_all(&directory)?;
+        fs::write(directory.join(format!("{:08}", i)), &data)?;
+        generated.files += 1;
+        generated.bytes += data.len() as u64;
+    }
+    Ok(generated)
+}
+
+fn file_size(random: &mut crate::stability::Random, settings: &Settings) -> u64 {
+    let (min, max) = (settings.min_size, settings.max_size.max(settings.min_size));
+    match settings.sizes {
+        SizeDistribution::Fixed => min,
+        SizeDistribution::Uniform => min + random.next_u64() % (max - min + 1),
+        SizeDistribution::LogUniform => {
+            let (low, high) = ((min.max(1) as f64).ln(), (max.max(1) as f64).ln());
+            let size = (low + random.fraction() * (high - low)).exp().round() as u64;
+            size.clamp(min, max)
+        }
+    }
+}
+
+// The contents of a new file, made from its own seed so a copy can be made again without keeping the original
+fn new_data(seed: u64, size: usize, compressibility: f64) -> Vec<u8> {
+    let mut random = crate::stability::Random::new(scramble(seed));
+    let words: Vec<&str> = WORDS.split_whitespace().collect();
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let end = (data.len() + BLOCK_SIZE).min(size);
+        if random.fraction() < compressibility {
+            while data.len() < end {
+                let word = words[random.below(words.len())];
+                data.extend_from_slice(word.as_bytes());
+                data.push(if random.below(12) == 0 { b'\n' } else { b' ' });
+            }
+            data.truncate(end);
+        } else {
+            while data.len() < end {
+                data.extend_from_slice(&random.next_u64().to_le_bytes());
+            }
+            data.truncate(end);
+        }
+    }
+    data
+}
+
+// The seeds come from the same xorshift sequence that makes them, so using one as it is would start the file's data
+// just a little further along that sequence, where another file's data already is. The splitmix64 finalizer moves it
+// somewhere unrelated.
+fn scramble(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections;
+    use std::fs;
+
+    // Every file under the directory with its contents, by its path relative to the directory
+    fn read_tree(dir: &std::path::Path) -> collections::BTreeMap<String, Vec<u8>> {
+        let mut files = collections::BTreeMap::new();
+        for directory in fs::read_dir(dir).unwrap() {
+            let directory = directory.unwrap().path();
+            for file in fs::read_dir(&directory).unwrap() {
+                let file = file.unwrap().path();
+                let name = file
+                    .strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                files.insert(name, fs::read(&file).unwrap());
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_generate() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_testdata_{}", std::process::id()));
+        let settings = crate::testdata::Settings {
+            files: 200,
+            min_size: 1000,
+            max_size: 100_000,
+            duplicates: 0.5,
+            edit_rate: 0.0,
+            compressibility: 0.0,
+            files_per_directory: 50,
+            ..crate::testdata::Settings::default()
+        };
+
+        // The same seed makes the same tree
+        let generated = crate::testdata::generate(&dir.join("a"), &settings).unwrap();
+        crate::testdata::generate(&dir.join("b"), &settings).unwrap();
+        let files = read_tree(&dir.join("a"));
+        assert_eq!(files, read_tree(&dir.join("b")));
+        assert_eq!(files.len(), 200);
+        assert!(files.contains_key("0003/00000199"));
+        assert_eq!(generated.files, 200);
+        assert_eq!(
+            generated.bytes,
+            files.values().map(|d| d.len() as u64).sum::<u64>()
+        );
+        assert!(files.values().all(|d| (1000..=100_000).contains(&d.len())));
+
+        // Without edits every copy is identical to a file made earlier
+        let distinct: collections::HashSet<&Vec<u8>> = files.values().collect();
+        assert_eq!(distinct.len() as u64, generated.files - generated.copies);
+        assert!(generated.copies > 70 && generated.copies < 130);
+        assert_eq!(generated.edits, 0);
+
+        // Edited copies share most of their chunks with their originals, and compressible data compresses
+        let edited = crate::testdata::Settings {
+            edit_rate: 100.0,
+            compressibility: 1.0,
+            seed: 2,
+            ..settings
+        };
+        let generated = crate::testdata::generate(&dir.join("c"), &edited).unwrap();
+        let files = read_tree(&dir.join("c"));
+        assert!(generated.edits > generated.copies);
+        let distinct: collections::HashSet<&Vec<u8>> = files.values().collect();
+        assert!((distinct.len() as u64) > generated.files - generated.copies);
+        let chunking = crate::pipeline::Chunking::Variable(
+            rabin::chunker::ChunkerBuilder::new(256, 4096).unwrap(),
+        );
+        let (mut total, mut unique) = (0, collections::HashSet::new());
+        for data in files.values() {
+            for chunk in chunking.chunks(data) {
+                total += 1;
+                unique.insert(rabin::hash_chunk_sha256(chunk));
+            }
+        }
+        assert!(
+            unique.len() * 10 < total * 8,
+            "{} of {}",
+            unique.len(),
+            total
+        );
+        let all: Vec<u8> = files.values().flatten().copied().collect();
+        assert!(zstd::encode_all(&all[..], 3).unwrap().len() < all.len() / 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
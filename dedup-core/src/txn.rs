@@ -0,0 +1,270 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::thread;
+use std::time;
+
+const STAGING_DIR_NAME: &str = "staging";
+const CHECKPOINT_FILE_NAME: &str = "checkpoint";
+
+// How long to wait between attempts to take the commit lock. Commits are short (a handful of renames and two log
+// records) so contention clears quickly.
+const COMMIT_RETRY: time::Duration = time::Duration::from_millis(100);
+
+// An ingest session. Run files are written to a private staging directory while the scan is in progress, where they
+// are invisible to everyone else, and then committed to the output directory's write-ahead log in one atomic batch.
+// Any number of transactions can stage into the same output directory at the same time; only the commit itself is
+// serialized. A transaction that is dropped without being committed throws away everything it staged, unless it has
+// written a checkpoint, in which case it is left for a later session to resume.
+pub struct Transaction {
+    dir: path::PathBuf,
+    staging: path::PathBuf,
+    staged: Vec<path::PathBuf>,
+    // Held for as long as the session is alive, so nobody resumes it out from under us
+    lock: crate::lock::Lock,
+    resumable: bool,
+}
+
+impl Transaction {
+    pub fn begin(dir: &path::Path) -> io::Result<Transaction> {
+        let nanos = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let staging =
+            dir.join(STAGING_DIR_NAME)
+                .join(format!("txn_{}_{}", std::process::id(), nanos));
+        fs::create_dir_all(&staging)?;
+        let lock = crate::lock::Lock::acquire(&staging, crate::lock::LockKind::Exclusive)?;
+
+        Ok(Transaction {
+            dir: dir.to_path_buf(),
+            staging,
+            staged: vec![],
+            lock,
+            resumable: false,
+        })
+    }
+
+    // Takes over a session that was checkpointed by a scan that never committed. Fails with ErrorKind::WouldBlock if
+    // the session is still alive. Runs staged after the checkpoint are thrown away, since the checkpoint doesn't know
+    // what is in them.
+    pub fn resume(
+        dir: &path::Path,
+        staging: &path::Path,
+    ) -> io::Result<(Transaction, crate::checkpoint::Checkpoint)> {
+        let lock = crate::lock::Lock::acquire(staging, crate::lock::LockKind::Exclusive)?;
+        let checkpoint: crate::checkpoint::Checkpoint =
+            bincode::deserialize(&fs::read(staging.join(CHECKPOINT_FILE_NAME))?)
+                .map_err(io::Error::other)?;
+
+        for entry in fs::read_dir(staging)? {
+            let path = entry?.path();
+            let run = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_prefix("run_"))
+                .and_then(|n| n.parse::<usize>().ok());
+            if run.is_some_and(|run| run >= checkpoint.runs) {
+                fs::remove_file(&path)?;
+            }
+        }
+        let staged: Vec<_> = (0..checkpoint.runs)
+            .map(|run| staging.join(format!("run_{}", run)))
+            .collect();
+        if let Some(missing) = staged.iter().find(|path| !path.exists()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the checkpointed run {:?} is missing", missing),
+            ));
+        }
+
+        let transaction = Transaction {
+            dir: dir.to_path_buf(),
+            staging: staging.to_path_buf(),
+            staged,
+            lock,
+            resumable: true,
+        };
+        Ok((transaction, checkpoint))
+    }
+
+    // Writes one run file into the staging area and syncs it so it is ready to be committed.
+    pub fn write_run<F>(&mut self, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut io::BufWriter<&fs::File>) -> io::Result<()>,
+    {
+        let staged_name = self.staging.join(format!("run_{}", self.staged.len()));
+        let staged_file = fs::File::create(&staged_name)?;
+        {
+            let mut buffer = io::BufWriter::new(&staged_file);
+            write(&mut buffer)?;
+            buffer.flush()?;
+        }
+        staged_file.sync_all()?;
+
+        self.staged.push(staged_name);
+        Ok(())
+    }
+
+    pub fn staged_runs(&self) -> usize {
+        self.staged.len()
+    }
+
+    // Records how far the session has got. Once a checkpoint has been written, the session survives being dropped (or
+    // killed) without committing, and can be resumed later.
+    pub fn checkpoint(&mut self, checkpoint: &crate::checkpoint::Checkpoint) -> io::Result<()> {
+        let checkpoint_name = self.staging.join(CHECKPOINT_FILE_NAME);
+        let partial_name = checkpoint_name.with_extension("partial");
+        let bytes = bincode::serialize(checkpoint).map_err(io::Error::other)?;
+        let partial = fs::File::create(&partial_name)?;
+        (&partial).write_all(&bytes)?;
+        partial.sync_all()?;
+        fs::rename(&partial_name, &checkpoint_name)?;
+        self.resumable = true;
+        Ok(())
+    }
+
+    // Keeps the session from looking abandoned. Cheap to call often.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        self.lock.refresh()
+    }
+
+    // Makes every staged run part of the index at once, along with the list of files the runs refer to and the scan's
+    // totals, and returns the run ids they were assigned.
+    pub fn commit(
+        mut self,
+        files: &[crate::files::FileRecord],
+        totals: crate::wal::ScanTotals,
+    ) -> io::Result<Vec<u32>> {
+        let file_list = self.staging.join("files");
+        let file_list_file = fs::File::create(&file_list)?;
+        crate::files::write_records(&file_list_file, files)?;
+        file_list_file.sync_all()?;
+
+        // The staging directory doubles as the domain of the commit lock, so that committing doesn't conflict with
+        // the shared locks that concurrent sessions hold on the output directory itself.
+        let staging_root = self.dir.join(STAGING_DIR_NAME);
+        let _commit_lock = loop {
+            match crate::lock::Lock::acquire(&staging_root, crate::lock::LockKind::Exclusive) {
+                Ok(lock) => break lock,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(COMMIT_RETRY),
+                Err(e) => return Err(e),
+            }
+        };
+
+        let mut wal = crate::wal::Wal::open(&self.dir)?;
+        let runs = wal.commit_runs(&self.staged, &file_list, totals)?;
+        self.staged.clear();
+        self.resumable = false;
+        Ok(runs)
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.resumable {
+            let _ = fs::remove_dir_all(&self.staging);
+        }
+    }
+}
+
+// Lists the sessions in the staging area that have written a checkpoint, newest checkpoint first. Some of them may
+// still be alive.
+pub fn checkpointed(dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+    let entries = match fs::read_dir(dir.join(STAGING_DIR_NAME)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut sessions = vec![];
+    for entry in entries {
+        let staging = entry?.path();
+        if let Ok(metadata) = fs::metadata(staging.join(CHECKPOINT_FILE_NAME)) {
+            sessions.push((metadata.modified()?, staging));
+        }
+    }
+    sessions.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    Ok(sessions.into_iter().map(|(_, staging)| staging).collect())
+}
+
+// Throws away whatever was left in the staging area by sessions that crashed before committing. Only safe to call
+// while holding the exclusive lock on the output directory.
+pub fn clear_staging(dir: &path::Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir.join(STAGING_DIR_NAME)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_concurrent_transactions() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_txn_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Two sessions stage at the same time; each one's runs only appear when it commits
+        let mut first = crate::txn::Transaction::begin(&dir).unwrap();
+        let mut second = crate::txn::Transaction::begin(&dir).unwrap();
+        first.write_run(|_| Ok(())).unwrap();
+        second.write_run(|_| Ok(())).unwrap();
+        second.write_run(|_| Ok(())).unwrap();
+
+        assert_eq!(second.commit(&[], Default::default()).unwrap(), vec![0, 1]);
+        assert_eq!(first.commit(&[], Default::default()).unwrap(), vec![2]);
+
+        // A session that is abandoned leaves nothing behind
+        let mut abandoned = crate::txn::Transaction::begin(&dir).unwrap();
+        abandoned.write_run(|_| Ok(())).unwrap();
+        drop(abandoned);
+
+        let committed = crate::wal::read_committed(&dir).unwrap();
+        assert_eq!(committed.runs, vec![0, 1, 2]);
+        assert!(!crate::wal::run_file_name(&dir, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_txn_resume_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // A session that checkpointed after its first run, staged another and then died
+        let mut interrupted = crate::txn::Transaction::begin(&dir).unwrap();
+        interrupted.write_run(|_| Ok(())).unwrap();
+        let checkpoint = crate::checkpoint::Checkpoint {
+            directories: vec!["scanned".to_string()],
+            runs: interrupted.staged_runs(),
+            collisions: 3,
+            ..Default::default()
+        };
+        interrupted.checkpoint(&checkpoint).unwrap();
+        interrupted.write_run(|_| Ok(())).unwrap();
+        drop(interrupted);
+
+        // Only the runs the checkpoint knows about are kept
+        let sessions = crate::txn::checkpointed(&dir).unwrap();
+        assert_eq!(sessions.len(), 1);
+        let (mut resumed, restored) = crate::txn::Transaction::resume(&dir, &sessions[0]).unwrap();
+        assert_eq!(restored, checkpoint);
+        assert_eq!(resumed.staged_runs(), 1);
+
+        // Nobody else can take over a session while it is alive
+        let e = crate::txn::Transaction::resume(&dir, &sessions[0])
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::WouldBlock);
+
+        resumed.write_run(|_| Ok(())).unwrap();
+        assert_eq!(resumed.commit(&[], Default::default()).unwrap(), vec![0, 1]);
+        assert!(crate::txn::checkpointed(&dir).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs;
+use std::io;
+use std::ops;
+use std::time;
+
+// How much each read asks for. Several of them are in flight at once, so a file is read well ahead of its chunking.
+pub const PIECE_BYTES: usize = 1024 * 1024;
+
+// Reads files with io_uring, keeping up to 'depth' reads in flight so that fast NVMe drives and network storage are
+// kept busy. Synchronous reads and mmap only ever ask the disk for one thing at a time.
+#[cfg(target_os = "linux")]
+pub struct Ring {
+    ring: io_uring::IoUring,
+    depth: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl Ring {
+    // Fails if the kernel doesn't have io_uring (before 5.1) or doesn't allow it, as some containers don't
+    pub fn new(depth: usize) -> io::Result<Ring> {
+        let depth = depth.max(1);
+        Ok(Ring {
+            ring: io_uring::IoUring::new(depth.next_power_of_two() as u32)?,
+            depth,
+        })
+    }
+
+    // Reads the range of the file one piece after another, passing each piece on in order as soon as it and every
+    // piece before it have arrived. Time spent waiting on the reads is added to 'waiting'.
+    pub fn read(
+        &mut self,
+        file: &fs::File,
+        extent: ops::Range<usize>,
+        waiting: &mut time::Duration,
+        on_piece: &mut dyn FnMut(Vec<u8>),
+    ) -> io::Result<()> {
+        use std::collections::{BTreeMap, HashMap};
+        use std::os::unix::io::AsRawFd;
+
+        let fd = io_uring::types::Fd(file.as_raw_fd());
+        let pieces = extent.len().div_ceil(PIECE_BYTES);
+        // The reads in flight by piece number, with how much of each has been filled. The buffers are on the heap, so
+        // they stay where the kernel is writing to them however the map changes.
+        let mut in_flight: HashMap<u64, (Vec<u8>, usize)> = HashMap::new();
+        let mut arrived: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+        let (mut next_read, mut next_piece) = (0u64, 0u64);
+        let mut failure: Option<io::Error> = None;
+
+        loop {
+            while failure.is_none() && (next_read as usize) < pieces && in_flight.len() < self.depth
+            {
+                let start = extent.start + next_read as usize * PIECE_BYTES;
+                let len = PIECE_BYTES.min(extent.end - start);
+                in_flight.insert(next_read, (vec![0; len], 0));
+                self.submit(fd, &mut in_flight, next_read, start)?;
+                next_read += 1;
+            }
+            // Once something has failed, the reads still in flight have to finish before their buffers can go
+            if in_flight.is_empty() {
+                break;
+            }
+
+            let started = time::Instant::now();
+            loop {
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        // The kernel may still write to the buffers in flight, so they can never be freed
+                        std::mem::forget(in_flight);
+                        return Err(e);
+                    }
+                }
+            }
+            *waiting += started.elapsed();
+
+            let completed: Vec<(u64, i32)> = self
+                .ring
+                .completion()
+                .map(|c| (c.user_data(), c.result()))
+                .collect();
+            for (piece, result) in completed {
+                let (len, filled) = match in_flight.get_mut(&piece) {
+                    Some((buffer, filled)) if result > 0 => {
+                        *filled += result as usize;
+                        (buffer.len(), *filled)
+                    }
+                    Some(_) => {
+                        in_flight.remove(&piece);
+                        // A read that returns nothing means the file got shorter
+                        failure.get_or_insert(match result {
+                            0 => io::Error::from(io::ErrorKind::UnexpectedEof),
+                            _ => io::Error::from_raw_os_error(-result),
+                        });
+                        continue;
+                    }
+                    None => continue,
+                };
+                if filled < len && failure.is_none() {
+                    // Reads can come back short, i.e. from network filesystems, so the rest is asked for again
+                    let start = extent.start + piece as usize * PIECE_BYTES + filled;
+                    self.submit(fd, &mut in_flight, piece, start)?;
+                } else if let Some((buffer, _)) = in_flight.remove(&piece) {
+                    arrived.insert(piece, buffer);
+                }
+            }
+            if failure.is_some() {
+                continue;
+            }
+            while let Some(buffer) = arrived.remove(&next_piece) {
+                on_piece(buffer);
+                next_piece += 1;
+            }
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Asks for the unfilled part of the piece's buffer, starting at 'offset' in the file
+    fn submit(
+        &mut self,
+        fd: io_uring::types::Fd,
+        in_flight: &mut std::collections::HashMap<u64, (Vec<u8>, usize)>,
+        piece: u64,
+        offset: usize,
+    ) -> io::Result<()> {
+        let (buffer, filled) = in_flight.get_mut(&piece).unwrap();
+        let unfilled = &mut buffer[*filled..];
+        let read = io_uring::opcode::Read::new(fd, unfilled.as_mut_ptr(), unfilled.len() as u32)
+            .offset(offset as u64)
+            .build()
+            .user_data(piece);
+        // Safety: the buffer stays in 'in_flight' until the read has completed. There are never more reads in flight
+        // than the queue has entries, so the push can't fail.
+        unsafe { self.ring.submission().push(&read) }
+            .map_err(|_| io::Error::other("the io_uring submission queue is full"))
+    }
+}
+
+// io_uring is Linux only, so elsewhere files are always read the usual way
+#[cfg(not(target_os = "linux"))]
+pub struct Ring;
+
+#[cfg(not(target_os = "linux"))]
+impl Ring {
+    pub fn new(_depth: usize) -> io::Result<Ring> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "io_uring is only available on Linux",
+        ))
+    }
+
+    pub fn read(
+        &mut self,
+        _file: &fs::File,
+        _extent: ops::Range<usize>,
+        _waiting: &mut time::Duration,
+        _on_piece: &mut dyn FnMut(Vec<u8>),
+    ) -> io::Result<()> {
+        unreachable!()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_ring() {
+        let mut ring = match crate::uring::Ring::new(3) {
+            Ok(ring) => ring,
+            // Not every kernel or container allows io_uring, and the scan falls back to reading the usual way then
+            Err(e) => {
+                eprintln!("skipping the io_uring test: {}", e);
+                return;
+            }
+        };
+        let dir = std::env::temp_dir().join(format!("test_chunks_uring_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..(crate::uring::PIECE_BYTES * 7 + 1234) as u32)
+            .map(|i| ((i * 13) >> 3) as u8)
+            .collect();
+        fs::write(dir.join("a"), &data).unwrap();
+        let file = fs::File::open(dir.join("a")).unwrap();
+
+        // The pieces come in order however the reads complete
+        let mut read = vec![];
+        let mut waiting = std::time::Duration::ZERO;
+        ring.read(&file, 1000..data.len(), &mut waiting, &mut |piece| {
+            assert!(piece.len() <= crate::uring::PIECE_BYTES);
+            read.extend_from_slice(&piece)
+        })
+        .unwrap();
+        assert_eq!(read, &data[1000..]);
+
+        // Asking for more than the file has fails
+        let result = ring.read(&file, 0..data.len() + 10, &mut waiting, &mut |_| {});
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+
+use serde_derive::{Deserialize, Serialize};
+
+const WAL_FILE_NAME: &str = "wal";
+// Committed run files and file lists are kept here until a merge has read them
+const RUNS_DIR_NAME: &str = "runs";
+
+// Every mutation of the on-disk index (moving sorted run files into place) is bracketed by a Begin and a Commit record
+// in the write-ahead log. Run files only count as part of the index once their Commit record has been synced to disk,
+// so a crash at any point leaves the output directory in a state that can be recovered by replaying the log. Both
+// records list every run in the batch, which makes committing several runs at once atomic. Each batch comes from one
+// scan, which also commits the list of files it scanned and its totals. A Merged record marks everything committed
+// before it as merged.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Record {
+    Begin(Vec<u32>),
+    Commit { runs: Vec<u32>, totals: ScanTotals },
+    Merged,
+}
+
+// What a scan knows about itself that can't be recovered from its run files later
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanTotals {
+    // Collisions found while building the runs. Colliding chunks never make it into a run for the merge to see.
+    pub collisions: u64,
+    // How long the scan took
+    pub elapsed_ms: u64,
+    // Links to files that had already been scanned under another name, which were left out
+    pub hard_links: u64,
+    pub hard_link_bytes: u64,
+    // Files outside the file size range, which were left out
+    pub size_filtered_files: u64,
+    pub size_filtered_bytes: u64,
+    // Files that look already compressed, which were left out or chunked anyway (see compressed::Policy)
+    pub skipped_compressed_files: u64,
+    pub skipped_compressed_bytes: u64,
+    pub flagged_compressed_files: u64,
+    pub flagged_compressed_bytes: u64,
+    // Bytes in the holes of sparse files, which were skipped rather than chunked
+    pub hole_bytes: u64,
+    // Chunks a scan against a baseline left out of its runs, because the baseline already had them
+    pub baseline_chunks: u64,
+    pub baseline_bytes: u64,
+    // Bytes scanned in chunks that look already compressed or encrypted
+    pub high_entropy_bytes: u64,
+    // Files and directories that couldn't be read, or were only read part of the way
+    pub skipped: u64,
+    // How busy each stage of the pipeline was. A scan that was resumed only has the stages' time since it resumed.
+    pub timings: crate::pipeline::Timings,
+}
+
+impl ScanTotals {
+    fn add(&mut self, other: &ScanTotals) {
+        self.collisions += other.collisions;
+        self.elapsed_ms += other.elapsed_ms;
+        self.hard_links += other.hard_links;
+        self.hard_link_bytes += other.hard_link_bytes;
+        self.size_filtered_files += other.size_filtered_files;
+        self.size_filtered_bytes += other.size_filtered_bytes;
+        self.skipped_compressed_files += other.skipped_compressed_files;
+        self.skipped_compressed_bytes += other.skipped_compressed_bytes;
+        self.flagged_compressed_files += other.flagged_compressed_files;
+        self.flagged_compressed_bytes += other.flagged_compressed_bytes;
+        self.hole_bytes += other.hole_bytes;
+        self.baseline_chunks += other.baseline_chunks;
+        self.baseline_bytes += other.baseline_bytes;
+        self.high_entropy_bytes += other.high_entropy_bytes;
+        self.skipped += other.skipped;
+        self.timings.add(&other.timings);
+    }
+}
+
+pub struct Wal {
+    dir: path::PathBuf,
+    file: fs::File,
+    committed: Committed,
+}
+
+// Everything that has been durably committed to the index
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Committed {
+    // The ids of the committed run files, in the order they were committed
+    pub runs: Vec<u32>,
+    // The runs committed by each scan, in the order the scans were committed. A scan's position in this list is also
+    // the id of its file list.
+    pub scans: Vec<Vec<u32>>,
+    // The sum of the totals of the scans that committed them
+    pub totals: ScanTotals,
+    // Whether a merge has read everything committed so far. The runs may have been removed by the merge since.
+    pub merged: bool,
+}
+
+impl Wal {
+    // Opens (or creates) the log in the specified directory and replays it. Runs that were begun but never committed
+    // are removed from disk, and a torn record at the end of the log is truncated away. The caller must hold a lock
+    // that keeps anyone else from committing while the log is replayed.
+    pub fn open(dir: &path::Path) -> io::Result<Wal> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(WAL_FILE_NAME))?;
+        fs::create_dir_all(dir.join(RUNS_DIR_NAME))?;
+
+        let (committed, pending, valid_len) = replay(&file);
+        file.set_len(valid_len)?;
+
+        // Anything still pending was interrupted mid-commit and cannot be trusted
+        if !pending.is_empty() {
+            remove_if_exists(&file_list_name(dir, committed.scans.len() as u32))?;
+        }
+        for run in pending {
+            remove_if_exists(&run_file_name(dir, run))?;
+        }
+
+        let mut wal = Wal {
+            dir: dir.to_path_buf(),
+            file,
+            committed,
+        };
+        wal.sync()?;
+        Ok(wal)
+    }
+
+    pub fn committed(&self) -> &Committed {
+        &self.committed
+    }
+
+    // Discards the history of the log, and the runs it committed, so that a new scan can start numbering runs from zero.
+    // The log is emptied first, so a crash part of the way through only leaves files that nothing refers to.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.committed = Committed::default();
+        self.file.sync_all()?;
+        let runs_dir = self.dir.join(RUNS_DIR_NAME);
+        match fs::remove_dir_all(&runs_dir) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+        fs::create_dir_all(&runs_dir)
+    }
+
+    // Records that a merge has read everything committed so far
+    pub fn mark_merged(&mut self) -> io::Result<()> {
+        self.append(&Record::Merged)?;
+        self.committed.merged = true;
+        Ok(())
+    }
+
+    // Moves completed run files (and the list of files the scan read) into the index as a single atomic batch and
+    // returns the ids they were given. The staged files must already be synced to disk; they are renamed into place
+    // between the Begin and Commit records.
+    pub fn commit_runs(
+        &mut self,
+        staged: &[path::PathBuf],
+        file_list: &path::Path,
+        totals: ScanTotals,
+    ) -> io::Result<Vec<u32>> {
+        let first = self.committed.runs.iter().max().map_or(0, |&r| r + 1);
+        let runs: Vec<u32> = (first..first + staged.len() as u32).collect();
+
+        self.append(&Record::Begin(runs.clone()))?;
+        for (staged_file, &run) in staged.iter().zip(runs.iter()) {
+            fs::rename(staged_file, run_file_name(&self.dir, run))?;
+        }
+        let scan = self.committed.scans.len() as u32;
+        fs::rename(file_list, file_list_name(&self.dir, scan))?;
+        self.sync()?;
+
+        self.append(&Record::Commit {
+            runs: runs.clone(),
+            totals,
+        })?;
+        self.committed.runs.extend_from_slice(&runs);
+        self.committed.scans.push(runs.clone());
+        self.committed.totals.add(&totals);
+        self.committed.merged = false;
+        Ok(runs)
+    }
+
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let bytes = bincode::serialize(record).map_err(io::Error::other)?;
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()
+    }
+
+    // Makes sure renames and deletes in the directory are durable (not supported on all platforms).
+    fn sync(&mut self) -> io::Result<()> {
+        #[cfg(unix)]
+        fs::File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+// Reads what has been committed without recovering from any interrupted commit, which makes it safe to call while only
+// holding a shared lock. Runs that are still being committed by someone else are simply not included.
+pub fn read_committed(dir: &path::Path) -> io::Result<Committed> {
+    match fs::File::open(dir.join(WAL_FILE_NAME)) {
+        Ok(file) => Ok(replay(&file).0),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Committed::default()),
+        Err(e) => Err(e),
+    }
+}
+
+// Reads records until we run out or hit one that was only partially written. Returns what was committed, the runs that
+// were begun but not committed, and the length of the log up to the end of the last complete record.
+fn replay(file: &fs::File) -> (Committed, Vec<u32>, u64) {
+    let mut committed = Committed::default();
+    let mut pending = vec![];
+    let mut valid_len = 0;
+    let mut reader = io::BufReader::new(file);
+    while let Ok(record) = bincode::deserialize_from::<_, Record>(&mut reader) {
+        valid_len += bincode::serialized_size(&record).unwrap();
+        match record {
+            Record::Begin(runs) => pending.extend(runs),
+            Record::Commit { runs, totals } => {
+                pending.retain(|p| !runs.contains(p));
+                committed.runs.extend_from_slice(&runs);
+                committed.scans.push(runs);
+                committed.totals.add(&totals);
+                committed.merged = false;
+            }
+            Record::Merged => committed.merged = true,
+        }
+    }
+    (committed, pending, valid_len)
+}
+
+// The name of the file that holds the sorted entries of a particular run
+pub fn run_file_name(dir: &path::Path, run: u32) -> path::PathBuf {
+    dir.join(RUNS_DIR_NAME).join(format!("mem_{}", run))
+}
+
+// The name of the file that lists the files a particular scan read
+pub fn file_list_name(dir: &path::Path, scan: u32) -> path::PathBuf {
+    dir.join(RUNS_DIR_NAME).join(format!("files_{}", scan))
+}
+
+fn remove_if_exists(file: &path::Path) -> io::Result<()> {
+    match fs::remove_file(file) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_recover_interrupted_commit() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_wal_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // Commit one run, then simulate a crash part of the way through committing two more
+        {
+            let mut wal = crate::wal::Wal::open(&dir).unwrap();
+            wal.reset().unwrap();
+            fs::write(dir.join("staged"), b"run").unwrap();
+            fs::write(dir.join("staged_files"), b"files").unwrap();
+            assert_eq!(
+                wal.commit_runs(
+                    &[dir.join("staged")],
+                    &dir.join("staged_files"),
+                    crate::wal::ScanTotals {
+                        collisions: 3,
+                        elapsed_ms: 10,
+                        ..Default::default()
+                    }
+                )
+                .unwrap(),
+                vec![0]
+            );
+            wal.append(&crate::wal::Record::Begin(vec![1, 2])).unwrap();
+            fs::write(crate::wal::run_file_name(&dir, 1), b"torn").unwrap();
+        }
+
+        // Only the committed run survives recovery
+        assert_eq!(crate::wal::read_committed(&dir).unwrap().runs, vec![0]);
+        let wal = crate::wal::Wal::open(&dir).unwrap();
+        assert_eq!(wal.committed().runs, vec![0]);
+        assert_eq!(wal.committed().scans, vec![vec![0]]);
+        assert!(crate::wal::file_list_name(&dir, 0).exists());
+        assert_eq!(wal.committed().totals.collisions, 3);
+        assert!(crate::wal::run_file_name(&dir, 0).exists());
+        assert!(!crate::wal::run_file_name(&dir, 1).exists());
+        assert!(!wal.committed().merged);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merged() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_wal_merged_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let commit = |wal: &mut crate::wal::Wal| {
+            fs::write(dir.join("staged"), b"run").unwrap();
+            fs::write(dir.join("staged_files"), b"files").unwrap();
+            wal.commit_runs(
+                &[dir.join("staged")],
+                &dir.join("staged_files"),
+                crate::wal::ScanTotals::default(),
+            )
+            .unwrap()
+        };
+
+        // A merge covers what was committed before it, and not what is appended after
+        let mut wal = crate::wal::Wal::open(&dir).unwrap();
+        assert_eq!(commit(&mut wal), vec![0]);
+        wal.mark_merged().unwrap();
+        assert!(crate::wal::read_committed(&dir).unwrap().merged);
+        assert_eq!(commit(&mut wal), vec![1]);
+        assert!(!crate::wal::read_committed(&dir).unwrap().merged);
+
+        // Removing the runs after a merge still leaves a record that there was one
+        wal.reset().unwrap();
+        assert!(!crate::wal::run_file_name(&dir, 0).exists());
+        assert!(!crate::wal::file_list_name(&dir, 1).exists());
+        wal.mark_merged().unwrap();
+        drop(wal);
+        let committed = crate::wal::read_committed(&dir).unwrap();
+        assert!(committed.merged);
+        assert!(committed.scans.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,545 @@
+use std::collections;
+use std::fs;
+use std::iter;
+use std::path;
+
+// Directories can carry their own exclusion rules, with the same syntax as a .gitignore file
+const IGNORE_FILE_NAME: &str = ".dedupignore";
+
+// What to do with symbolic links
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Symlinks {
+    // Leave them out of the scan
+    Skip,
+    // Scan whatever they point to as if it were in the link's place
+    Follow,
+    // Scan the link itself, whose contents are the path it points to (like tar and most backup tools do)
+    Record,
+}
+
+// Something the walk found that should be scanned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Found {
+    File,
+    Symlink,
+}
+
+// The extra links to files that were already scanned through another path
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HardLinks {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+// Walks directory trees, deciding what is part of the scan. Directories and hard-linked files are only scanned once
+// even when several of the trees walked lead to them.
+pub struct Walker {
+    // The exclusions of the tree being walked
+    exclude: ignore::gitignore::Gitignore,
+    symlinks: Symlinks,
+    // The (device, inode) of every file with more than one link that has been found so far, or None if hard links are
+    // scanned every time they are found
+    linked_files: Option<collections::HashSet<(u64, u64)>>,
+    skipped_hard_links: HardLinks,
+    // Directories and entries that couldn't be read
+    skipped: crate::skipped::Skipped,
+    // The ignore files of the directories we are currently inside, outermost first
+    ignore_files: Vec<ignore::gitignore::Gitignore>,
+    // The (device, inode) of every directory entered so far
+    visited: collections::HashSet<(u64, u64)>,
+    // Whether to scan the alternate data streams of files as well as their contents (NTFS only)
+    streams: bool,
+}
+
+impl Walker {
+    pub fn new(symlinks: Symlinks, detect_hard_links: bool, streams: bool) -> Walker {
+        Walker {
+            exclude: ignore::gitignore::Gitignore::empty(),
+            symlinks,
+            linked_files: if detect_hard_links {
+                Some(collections::HashSet::new())
+            } else {
+                None
+            },
+            skipped_hard_links: HardLinks::default(),
+            skipped: crate::skipped::Skipped::default(),
+            ignore_files: vec![],
+            visited: collections::HashSet::new(),
+            streams,
+        }
+    }
+
+    // Call the specified callback function once for each file, recursing into sub-directories. Anything that is
+    // excluded is skipped, including everything inside an excluded directory. Special files (devices, sockets and
+    // pipes) are always skipped. Directories and entries that can't be read are skipped and recorded.
+    pub fn walk(
+        &mut self,
+        dir: &path::Path,
+        exclude: &ignore::gitignore::Gitignore,
+        callback: &mut dyn FnMut(&path::Path, Found),
+    ) {
+        self.exclude = exclude.clone();
+        self.walk_dir(dir, callback);
+    }
+
+    fn walk_dir(&mut self, dir: &path::Path, callback: &mut dyn FnMut(&path::Path, Found)) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.skipped.record(dir, &e);
+                return;
+            }
+        };
+
+        // Entering a directory a second time (through a symlink or junction, or around a loop of them) would count
+        // everything in it again, or never finish
+        if let Some(id) = fs::metadata(dir).ok().and_then(|m| file_id(dir, &m)) {
+            if !self.visited.insert(id) {
+                return;
+            }
+        }
+
+        // An ignore file applies to everything below the directory it is in
+        let ignore_file = dir.join(IGNORE_FILE_NAME);
+        let has_ignore_file = ignore_file.is_file();
+        if has_ignore_file {
+            let mut builder = ignore::gitignore::GitignoreBuilder::new(dir);
+            if let Some(e) = builder.add(&ignore_file) {
+                tracing::warn!("{}", e);
+            }
+            self.ignore_files.push(
+                builder
+                    .build()
+                    .unwrap_or_else(|_| ignore::gitignore::Gitignore::empty()),
+            );
+        }
+
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.skipped.record(dir, &e);
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    self.skipped.record(&path, &e);
+                    continue;
+                }
+            };
+
+            let found = match self.classify(&path, file_type) {
+                Some(found) => found,
+                None => continue,
+            };
+            if self.is_excluded(&path, found.is_none()) {
+                continue;
+            }
+            match found {
+                None => self.walk_dir(&path, callback),
+                Some(found) => self.found(&path, found, callback),
+            }
+        }
+
+        if has_ignore_file {
+            self.ignore_files.pop();
+        }
+    }
+
+    // Calls the callback for one file from a list of them, instead of walking a directory. Links, hard links and
+    // special files are treated as they would be in a walk, but nothing is excluded and directories aren't entered:
+    // whatever made the list already chose what to scan.
+    pub fn file(&mut self, path: &path::Path, callback: &mut dyn FnMut(&path::Path, Found)) {
+        let file_type = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata.file_type(),
+            Err(e) => {
+                self.skipped.record(path, &e);
+                return;
+            }
+        };
+        if let Some(Some(found)) = self.classify(path, file_type) {
+            self.found(path, found, callback);
+        }
+    }
+
+    // Decides what to do with something a directory holds: None skips it, Some(None) is a directory to walk into and
+    // anything else is to be scanned.
+    fn classify(&self, path: &path::Path, file_type: fs::FileType) -> Option<Option<Found>> {
+        // Windows reports junctions and other reparse points that stand in for another path as symbolic links
+        if file_type.is_symlink() {
+            match self.symlinks {
+                Symlinks::Skip => None,
+                Symlinks::Record => Some(Some(Found::Symlink)),
+                Symlinks::Follow => match fs::metadata(path) {
+                    Ok(ref metadata) if metadata.is_dir() => Some(None),
+                    Ok(ref metadata) if metadata.is_file() => Some(Some(Found::File)),
+                    // Dangling, or pointing at something special
+                    _ => None,
+                },
+            }
+        } else if file_type.is_dir() {
+            Some(None)
+        } else if file_type.is_file() {
+            Some(Some(Found::File))
+        } else {
+            None
+        }
+    }
+
+    // Passes on something to scan, unless it is another link to a file that was already found
+    fn found(
+        &mut self,
+        path: &path::Path,
+        found: Found,
+        callback: &mut dyn FnMut(&path::Path, Found),
+    ) {
+        match found {
+            Found::File if self.is_another_link(path) => {}
+            Found::File if self.streams => {
+                callback(path, Found::File);
+                for stream in alternate_streams(path) {
+                    callback(&stream, Found::File);
+                }
+            }
+            found => callback(path, found),
+        }
+    }
+
+    // Hard links are just more names for the same file. Only the first one found is scanned; the rest would make the
+    // data look duplicated when there is only one copy of it on disk.
+    fn is_another_link(&mut self, path: &path::Path) -> bool {
+        let linked_files = match self.linked_files {
+            Some(ref mut linked_files) => linked_files,
+            None => return false,
+        };
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        if link_count(path, &metadata) < 2 {
+            return false;
+        }
+
+        match file_id(path, &metadata) {
+            Some(id) if !linked_files.insert(id) => {
+                self.skipped_hard_links.count += 1;
+                self.skipped_hard_links.bytes += metadata.len();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn skipped_hard_links(&self) -> HardLinks {
+        self.skipped_hard_links
+    }
+
+    pub fn skipped(&self) -> crate::skipped::Skipped {
+        self.skipped.clone()
+    }
+
+    // The command line patterns have the final say, then the ignore file closest to the path. Within each of them the
+    // last matching pattern wins, so a '!pattern' can bring back something an earlier pattern excluded.
+    fn is_excluded(&self, path: &path::Path, is_dir: bool) -> bool {
+        for matcher in iter::once(&self.exclude).chain(self.ignore_files.iter().rev()) {
+            match matcher.matched(path, is_dir) {
+                ignore::Match::None => {}
+                matched => return matched.is_ignore(),
+            }
+        }
+        false
+    }
+}
+
+// Builds the exclusion matcher from gitignore-style patterns. 'skip' lists other paths (like the output directory) to
+// leave out; any that aren't inside the scanned directory are ignored.
+pub fn exclusions(
+    dir: &path::Path,
+    patterns: &[&str],
+    skip: &[&path::Path],
+) -> Result<ignore::gitignore::Gitignore, ignore::Error> {
+    let mut builder = ignore::gitignore::GitignoreBuilder::new(dir);
+    for pattern in patterns {
+        builder.add_line(None, pattern)?;
+    }
+
+    let root = dir.canonicalize().map_err(ignore::Error::from)?;
+    for path in skip {
+        if let Ok(path) = path.canonicalize() {
+            if let Ok(relative) = path.strip_prefix(&root) {
+                if relative.as_os_str().is_empty() {
+                    continue;
+                }
+                builder.add_line(None, &format!("/{}", relative.to_string_lossy()))?;
+            }
+        }
+    }
+    builder.build()
+}
+
+// Deep trees on Windows easily go past the 260 character limit on paths, unless the paths are in the extended-length
+// form (\\?\C:\...) that the limit doesn't apply to. Every path the walk finds is built from the directory it starts
+// in, so it only has to start from an extended-length path. Other platforms have no such limit.
+#[cfg(windows)]
+pub fn long_path(dir: &path::Path) -> path::PathBuf {
+    // canonicalize always returns the extended-length form
+    dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf())
+}
+
+#[cfg(not(windows))]
+pub fn long_path(dir: &path::Path) -> path::PathBuf {
+    dir.to_path_buf()
+}
+
+// The (device, inode) pair that identifies a file no matter which path it was reached by. Windows has the volume serial
+// number and file index instead, which have to be read from an open handle. Not available on every platform.
+#[cfg(unix)]
+pub fn file_id(_path: &path::Path, metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(windows)]
+pub fn file_id(path: &path::Path, _metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    let handle = winapi_util::Handle::from_path_any(path).ok()?;
+    let information = winapi_util::file::information(&handle).ok()?;
+    Some((information.volume_serial_number(), information.file_index()))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn file_id(_path: &path::Path, _metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// The number of hard links to the file
+#[cfg(unix)]
+fn link_count(_path: &path::Path, metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(windows)]
+fn link_count(path: &path::Path, _metadata: &fs::Metadata) -> u64 {
+    winapi_util::Handle::from_path_any(path)
+        .and_then(|handle| winapi_util::file::information(&handle))
+        .map_or(1, |information| information.number_of_links())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn link_count(_path: &path::Path, _metadata: &fs::Metadata) -> u64 {
+    1
+}
+
+// The paths of a file's alternate data streams (file:name), which can be opened and read like any other file. Every
+// file has an unnamed main stream holding its usual contents; that one isn't included. Only NTFS has them, so the list
+// is empty anywhere else.
+#[cfg(windows)]
+fn alternate_streams(path: &path::Path) -> Vec<path::PathBuf> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Foundation::INVALID_HANDLE_VALUE;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FindClose, FindFirstStreamW, FindNextStreamW, FindStreamInfoStandard,
+        WIN32_FIND_STREAM_DATA,
+    };
+
+    let wide: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect();
+    let mut data = WIN32_FIND_STREAM_DATA::default();
+    let handle = unsafe {
+        FindFirstStreamW(
+            wide.as_ptr(),
+            FindStreamInfoStandard,
+            &mut data as *mut WIN32_FIND_STREAM_DATA as *mut _,
+            0,
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return vec![];
+    }
+
+    let mut streams = vec![];
+    loop {
+        // Names look like ":name:$DATA", and the main stream is "::$DATA"
+        let len = data
+            .cStreamName
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(data.cStreamName.len());
+        let name = String::from_utf16_lossy(&data.cStreamName[..len]);
+        if let Some(stream) = name.strip_suffix(":$DATA") {
+            if stream != ":" {
+                let mut stream_path = path.as_os_str().to_owned();
+                stream_path.push(stream);
+                streams.push(path::PathBuf::from(stream_path));
+            }
+        }
+        if unsafe { FindNextStreamW(handle, &mut data as *mut WIN32_FIND_STREAM_DATA as *mut _) }
+            == 0
+        {
+            break;
+        }
+    }
+    unsafe { FindClose(handle) };
+    streams
+}
+
+#[cfg(not(windows))]
+fn alternate_streams(_path: &path::Path) -> Vec<path::PathBuf> {
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path;
+
+    fn walk(
+        dir: &path::Path,
+        exclude: &ignore::gitignore::Gitignore,
+        symlinks: crate::walk::Symlinks,
+    ) -> Vec<(String, crate::walk::Found)> {
+        let mut found = vec![];
+        crate::walk::Walker::new(symlinks, true, false).walk(dir, exclude, &mut |path, kind| {
+            let relative = path.strip_prefix(dir).unwrap();
+            found.push((relative.to_string_lossy().into_owned(), kind));
+        });
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        found
+    }
+
+    fn names(found: &[(String, crate::walk::Found)]) -> Vec<&str> {
+        found.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_exclude() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_exclude_{}", std::process::id()));
+        for sub in &["node_modules/pkg", "src", "out"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in &[
+            "node_modules/pkg/index.js",
+            "src/main.rs",
+            "src/main.o",
+            "src/keep.o",
+            "out/mem_0",
+        ] {
+            fs::write(dir.join(file), file.as_bytes()).unwrap();
+        }
+
+        let exclude = crate::walk::exclusions(
+            &dir,
+            &["node_modules/", "*.o", "!keep.o"],
+            &[&dir.join("out")],
+        )
+        .unwrap();
+        let found = walk(&dir, &exclude, crate::walk::Symlinks::Skip);
+        assert_eq!(names(&found), vec!["src/keep.o", "src/main.rs"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_files() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_ignore_{}", std::process::id()));
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::write(dir.join(".dedupignore"), "*.tmp\ncache/\n").unwrap();
+        fs::write(dir.join("a/b/.dedupignore"), "!keep.tmp\n").unwrap();
+        for file in &["x.tmp", "a/y.tmp", "a/b/keep.tmp", "a/b/data", "a/cache"] {
+            fs::write(dir.join(file), file.as_bytes()).unwrap();
+        }
+        fs::create_dir_all(dir.join("a/b/cache")).unwrap();
+        fs::write(dir.join("a/b/cache/z"), b"z").unwrap();
+
+        // 'cache/' only matches directories, and the command line can still exclude what the files allow
+        let exclude = crate::walk::exclusions(&dir, &["data"], &[]).unwrap();
+        let found = walk(&dir, &exclude, crate::walk::Symlinks::Skip);
+        assert_eq!(
+            names(&found),
+            vec![
+                ".dedupignore",
+                "a/b/.dedupignore",
+                "a/b/keep.tmp",
+                "a/cache"
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(format!("test_chunks_symlinks_{}", std::process::id()));
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("real/file"), b"data").unwrap();
+        symlink(dir.join("real/file"), dir.join("file_link")).unwrap();
+        symlink(dir.join("real"), dir.join("dir_link")).unwrap();
+        symlink(&dir, dir.join("real/loop")).unwrap();
+        symlink(dir.join("missing"), dir.join("dangling")).unwrap();
+
+        let exclude = ignore::gitignore::Gitignore::empty();
+        let found = walk(&dir, &exclude, crate::walk::Symlinks::Skip);
+        assert_eq!(names(&found), vec!["real/file"]);
+
+        // The linked directory is the same one as 'real', so only one of them is entered (whichever comes first), and
+        // the loop leads back to the start
+        let found = walk(&dir, &exclude, crate::walk::Symlinks::Follow);
+        let names = names(&found);
+        assert!(names == ["file_link", "real/file"] || names == ["dir_link/file", "file_link"]);
+
+        let found = walk(&dir, &exclude, crate::walk::Symlinks::Record);
+        assert_eq!(
+            super::tests::names(&found),
+            vec![
+                "dangling",
+                "dir_link",
+                "file_link",
+                "real/file",
+                "real/loop"
+            ]
+        );
+        assert_eq!(found[0].1, crate::walk::Found::Symlink);
+        assert_eq!(found[3].1, crate::walk::Found::File);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_hard_links_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"data").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+        fs::hard_link(dir.join("a"), dir.join("c")).unwrap();
+        fs::write(dir.join("d"), b"other").unwrap();
+
+        let exclude = ignore::gitignore::Gitignore::empty();
+        let mut walker = crate::walk::Walker::new(crate::walk::Symlinks::Skip, true, false);
+        let mut found = 0;
+        walker.walk(&dir, &exclude, &mut |_, _| found += 1);
+        assert_eq!(found, 2);
+        assert_eq!(
+            walker.skipped_hard_links(),
+            crate::walk::HardLinks { count: 2, bytes: 8 }
+        );
+
+        // With detection turned off every link is scanned
+        let mut walker = crate::walk::Walker::new(crate::walk::Symlinks::Skip, false, false);
+        let mut found = 0;
+        walker.walk(&dir, &exclude, &mut |_, _| found += 1);
+        assert_eq!(found, 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
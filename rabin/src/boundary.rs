@@ -0,0 +1,195 @@
+// What the chunker can do after a byte: carry on, remember the spot in case no primary boundary turns up before the
+// maximum chunk size, or cut there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boundary {
+    None,
+    Secondary,
+    Primary,
+}
+
+// Decides where chunks end. The chunker hashes the bytes of each chunk up to the minimum size without asking, then asks
+// about each byte after that until it is told to cut or reaches the maximum size. A predicate can keep state of its own
+// across a chunk (i.e. a second rolling hash, or the largest hash seen so far), which it starts afresh in start_chunk.
+//
+// The chunker is generic over its predicate, so the default one costs nothing over the chunker checking the masks
+// itself.
+pub trait BoundaryPredicate {
+    // Called at the start of each chunk that is long enough to be checked, with the chunk's bytes up to the minimum
+    // size. Only the last window of them affects the rolling hash of the bytes that follow.
+    fn start_chunk(&mut self, _minimum: &[u8]) {}
+
+    // 'hash' is the rolling hash of the window that ends with 'byte', which is 'offset' bytes into the chunk
+    fn check(&mut self, hash: u64, byte: u8, offset: usize) -> Boundary;
+}
+
+// The default predicate: a primary boundary wherever the hash has every bit of the primary mask set, and a secondary
+// one wherever it has every bit of the secondary mask set. Using '&' to check has a significant performance bump over
+// '%', at the cost of the divisor having to be a power of 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Masks {
+    pub primary: u64,
+    pub secondary: u64,
+}
+
+impl BoundaryPredicate for Masks {
+    #[inline]
+    fn check(&mut self, hash: u64, _byte: u8, _offset: usize) -> Boundary {
+        if hash & self.primary == self.primary {
+            Boundary::Primary
+        } else if hash & self.secondary == self.secondary {
+            Boundary::Secondary
+        } else {
+            Boundary::None
+        }
+    }
+}
+
+// Boundaries where the default rolling hash and a second one over the same window, with a different polynomial, both
+// have every bit of their half of the mask set. Each hash only checks about half as many bits as Masks would, so
+// boundaries turn up as often, but a run of data that happens to keep matching one polynomial's mask (as low-entropy
+// data can) is unlikely to keep matching the other's too. Chunk sizes vary less and boundaries bunch up less, for the
+// cost of a second hash of every byte that is checked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DualMasks {
+    pub primary: [u64; 2],
+    pub secondary: [u64; 2],
+    second: crate::rolling_hash::RollingHash<crate::rolling_hash::SecondTables>,
+}
+
+impl DualMasks {
+    // Splits the bits of a primary mask of 'bits' bits (and a secondary one of one bit less) between the two hashes
+    pub fn new(bits: u32) -> DualMasks {
+        let split = |bits: u32| {
+            let first = bits.div_ceil(2);
+            [(1u64 << first) - 1, (1u64 << (bits - first)) - 1]
+        };
+        DualMasks {
+            primary: split(bits),
+            secondary: split(bits - 1),
+            second: crate::rolling_hash::RollingHash::with_tables(
+                crate::rolling_hash::SecondTables,
+            ),
+        }
+    }
+}
+
+impl BoundaryPredicate for DualMasks {
+    fn start_chunk(&mut self, minimum: &[u8]) {
+        self.second.reset();
+        self.second.hash_bytes(minimum);
+    }
+
+    #[inline]
+    fn check(&mut self, hash: u64, byte: u8, _offset: usize) -> Boundary {
+        self.second.hash_byte(byte);
+        let second = self.second.hash();
+        let matches =
+            |masks: [u64; 2]| hash & masks[0] == masks[0] && second & masks[1] == masks[1];
+        if matches(self.primary) {
+            Boundary::Primary
+        } else if matches(self.secondary) {
+            Boundary::Secondary
+        } else {
+            Boundary::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::boundary::{Boundary, BoundaryPredicate};
+
+    // Cuts before the first zero byte past the minimum, whatever the hash says
+    #[derive(Default)]
+    struct BeforeZero {
+        chunks: usize,
+    }
+
+    impl BoundaryPredicate for BeforeZero {
+        fn start_chunk(&mut self, minimum: &[u8]) {
+            assert_eq!(minimum.len(), 100);
+            self.chunks += 1;
+        }
+
+        fn check(&mut self, _hash: u64, byte: u8, offset: usize) -> Boundary {
+            assert!((100..1000).contains(&offset));
+            if byte == 0 {
+                Boundary::Primary
+            } else {
+                Boundary::None
+            }
+        }
+    }
+
+    #[test]
+    fn test_predicates() {
+        let mut x = 7u64;
+        let data: Vec<u8> = (0..500_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+
+        // The default masks given explicitly are the default chunker
+        let builder = crate::chunker::ChunkerBuilder::new(1856, 11300).unwrap();
+        let built: Vec<&[u8]> = builder.build(&data).collect();
+        let masked: Vec<&[u8]> = builder.build_with(&data, builder.masks()).collect();
+        assert_eq!(built, masked);
+
+        let builder = crate::chunker::ChunkerBuilder::new(100, 1000).unwrap();
+        let mut chunker = builder.build_with(&data, BeforeZero::default());
+        let chunks: Vec<&[u8]> = chunker.by_ref().collect();
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), data.len());
+        // Each chunk ends just before a zero, unless there wasn't one before the maximum size
+        let mut end = 0;
+        for chunk in &chunks[..chunks.len() - 1] {
+            end += chunk.len();
+            assert!(data[end] == 0 || chunk.len() == 1000);
+            assert!(chunk[100..].iter().all(|&b| b != 0));
+        }
+        // Only the last chunk can be too short to be checked
+        assert!(chunker.predicate().chunks >= chunks.len() - 1);
+    }
+
+    #[test]
+    fn test_dual_masks() {
+        let mut x = 11u64;
+        let random: Vec<u8> = (0..1_000_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let builder = crate::chunker::ChunkerBuilder::new(1856, 11300).unwrap();
+        assert_eq!(builder.dual_masks().primary, [63, 31]);
+        assert_eq!(builder.dual_masks().secondary, [31, 31]);
+
+        // About the same average as the single mask on random data, within the same limits
+        let single = builder.build(&random).count();
+        let dual: Vec<&[u8]> = builder.build_dual(&random).collect();
+        assert!(dual.len() * 10 > single * 8 && dual.len() * 10 < single * 12);
+        assert_eq!(dual.iter().map(|c| c.len()).sum::<usize>(), random.len());
+        assert!(dual[..dual.len() - 1]
+            .iter()
+            .all(|c| c.len() >= 1856 && c.len() <= 11300));
+        assert_ne!(builder.build(&random).next(), dual.first().copied());
+
+        // Text made of a few words never has the low 11 bits of the default hash all set, so every chunk is cut at the
+        // maximum size. Half as many bits of each hash are easy to find.
+        let words: Vec<u8> = random
+            .chunks(4)
+            .flat_map(|w| {
+                ["the ", "cat ", "sat ", "on  ", "mat\n", "and "][w[0] as usize % 6].bytes()
+            })
+            .collect();
+        let single: Vec<&[u8]> = builder.build(&words).collect();
+        assert!(single[..single.len() - 1].iter().all(|c| c.len() == 11300));
+        let dual: Vec<&[u8]> = builder.build_dual(&words).collect();
+        assert!(dual.len() > words.len() / 5000, "{} chunks", dual.len());
+    }
+}
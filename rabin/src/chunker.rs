@@ -4,24 +4,33 @@ const PRIMARY_BITMASK: u64 = 2047; // 2^11 - 1
 const SECONDARY_BITMASK: u64 = 1023; // 2^10 - 1
 
 // The Chunker takes a large number of bytes and breaks it into variably sized chunks based upon a two-divisor system
-// that picks consistent break-points for the same hash of data. See the README for more information.
-pub struct Chunker<'a> {
-    hasher: crate::rolling_hash::RollingHash,
+// that picks consistent break-points for the same hash of data. See the README for more information. The boundaries
+// come from a BoundaryPredicate, which is the two bitmasks unless the builder was given another, and the rolling hash
+// uses the default polynomial's tables unless it was given others.
+pub struct Chunker<
+    'a,
+    P: crate::boundary::BoundaryPredicate = crate::boundary::Masks,
+    T: crate::rolling_hash::Tables = crate::rolling_hash::DefaultTables,
+> {
+    hasher: crate::rolling_hash::RollingHash<T>,
     mem: &'a [u8],
     min: usize,
     max: usize,
+    predicate: P,
+    alignment: Option<usize>,
 }
 
 impl<'a> Chunker<'a> {
     // Creates a new Chunker where the chunk sizes will be at least 'min' (unless there aren't enough bytes left in the
     // data) and at most 'max'.
-    pub fn new(mem: &'a [u8], min: usize, max: usize) -> Chunker<'a> {
-        Chunker {
-            hasher: crate::rolling_hash::RollingHash::new(),
-            mem: mem,
-            min: min,
-            max: max,
-        }
+    pub fn new(mem: &'a [u8], min: usize, max: usize) -> crate::Result<Chunker<'a>> {
+        Ok(ChunkerBuilder::new(min, max)?.build(mem))
+    }
+}
+
+impl<'a, P: crate::boundary::BoundaryPredicate, T: crate::rolling_hash::Tables> Chunker<'a, P, T> {
+    pub fn predicate(&self) -> &P {
+        &self.predicate
     }
 
     // Removes the specified number of bytes from the list of bytes to chunk and returns them.
@@ -30,10 +39,169 @@ impl<'a> Chunker<'a> {
         self.mem = &self.mem[len..];
         chunk
     }
+
+    // Where to cut a chunk that the boundaries say should end at 'end'. Aligned chunks end at the next multiple of the
+    // alignment instead, as long as that isn't past the maximum (or the end of the data).
+    fn cut_at(&self, end: usize) -> usize {
+        match self.alignment {
+            None => end,
+            Some(alignment) => {
+                let rounded = end.div_ceil(alignment) * alignment;
+                rounded
+                    .min(self.max / alignment * alignment)
+                    .min(self.mem.len())
+            }
+        }
+    }
+}
+
+// Sets up a Chunker with something other than the default boundary conditions. A builder can make any number of
+// Chunkers with the same settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkerBuilder {
+    min: usize,
+    max: usize,
+    primary_mask: u64,
+    secondary_mask: u64,
+    alignment: Option<usize>,
+}
+
+impl ChunkerBuilder {
+    // Starts with the chunk sizes between 'min' and 'max' and the default bitmasks. A minimum of 0 or a maximum that
+    // isn't above the minimum would make empty chunks forever, so they are refused.
+    pub fn new(min: usize, max: usize) -> crate::Result<ChunkerBuilder> {
+        if min == 0 || max <= min {
+            return Err(crate::Error::ChunkSizes { min, max });
+        }
+        Ok(ChunkerBuilder {
+            min,
+            max,
+            primary_mask: PRIMARY_BITMASK,
+            secondary_mask: SECONDARY_BITMASK,
+            alignment: None,
+        })
+    }
+
+    // Picks the bitmasks so that chunks average roughly 'average' bytes. A primary boundary with 'n' bits in its mask
+    // turns up on average every 2^n bytes after the minimum chunk size, so 'n' is chosen to make min + 2^n as close to
+    // the average as possible. The secondary mask always has one bit less. Chunks cut at the maximum size pull the
+    // real average down a little when the maximum isn't well above the average. The average has to be above the
+    // minimum and no larger than the maximum.
+    pub fn average(self, average: usize) -> crate::Result<ChunkerBuilder> {
+        if average <= self.min || average > self.max {
+            return Err(crate::Error::AverageChunkSize {
+                average,
+                min: self.min,
+                max: self.max,
+            });
+        }
+        let beyond_min = average.saturating_sub(self.min).max(2) as f64;
+        let bits = (beyond_min.log2().round() as u32).clamp(2, 63);
+        self.with_mask_bits(bits)
+    }
+
+    // Sets the primary bitmask to check 'bits' bits (and the secondary one bit less) directly, which is how settings
+    // recorded as mask_bits() are restored exactly
+    pub fn with_mask_bits(mut self, bits: u32) -> crate::Result<ChunkerBuilder> {
+        if !(2..=63).contains(&bits) {
+            return Err(crate::Error::MaskBits(bits));
+        }
+        self.primary_mask = (1u64 << bits) - 1;
+        self.secondary_mask = (1u64 << (bits - 1)) - 1;
+        Ok(self)
+    }
+
+    // Only cuts chunks at multiples of 'alignment' bytes from the start of the data, like a filesystem that dedups
+    // blocks of that size would see it. Each boundary is moved on to the next multiple, so the cuts still depend on
+    // the content but every chunk is a whole number of blocks. The maximum chunk size has to be at least one block.
+    pub fn with_alignment(mut self, alignment: usize) -> crate::Result<ChunkerBuilder> {
+        if alignment == 0 || alignment > self.max {
+            return Err(crate::Error::Alignment {
+                alignment,
+                max: self.max,
+            });
+        }
+        self.alignment = Some(alignment);
+        Ok(self)
+    }
+
+    pub fn min(&self) -> usize {
+        self.min
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    // How many bits the primary bitmask checks for
+    pub fn mask_bits(&self) -> u32 {
+        self.primary_mask.count_ones()
+    }
+
+    pub fn alignment(&self) -> Option<usize> {
+        self.alignment
+    }
+
+    // The default boundary predicate for these settings
+    pub fn masks(&self) -> crate::boundary::Masks {
+        crate::boundary::Masks {
+            primary: self.primary_mask,
+            secondary: self.secondary_mask,
+        }
+    }
+
+    // The dual polynomial boundary predicate for these settings, with the bits of each mask split between the hashes
+    pub fn dual_masks(&self) -> crate::boundary::DualMasks {
+        crate::boundary::DualMasks::new(self.mask_bits())
+    }
+
+    pub fn build<'a>(&self, mem: &'a [u8]) -> Chunker<'a> {
+        self.build_with(mem, self.masks())
+    }
+
+    // Makes a Chunker that only cuts where two rolling hashes with different polynomials both match their masks. See
+    // DualMasks; the chunks are the same average size, but not cut in the same places as build's.
+    pub fn build_dual<'a>(&self, mem: &'a [u8]) -> Chunker<'a, crate::boundary::DualMasks> {
+        self.build_with(mem, self.dual_masks())
+    }
+
+    // Makes a Chunker that asks 'predicate' where the boundaries are instead of checking the bitmasks. The sizes and
+    // alignment still apply.
+    pub fn build_with<'a, P: crate::boundary::BoundaryPredicate>(
+        &self,
+        mem: &'a [u8],
+        predicate: P,
+    ) -> Chunker<'a, P> {
+        self.build_with_tables(mem, predicate, crate::rolling_hash::DefaultTables)
+    }
+
+    // Makes a Chunker whose rolling hash uses another polynomial's tables, such as ones from
+    // RollingHashTables::generate. The chunks are cut in entirely different places from the default polynomial's.
+    pub fn build_with_tables<
+        'a,
+        P: crate::boundary::BoundaryPredicate,
+        T: crate::rolling_hash::Tables,
+    >(
+        &self,
+        mem: &'a [u8],
+        predicate: P,
+        tables: T,
+    ) -> Chunker<'a, P, T> {
+        Chunker {
+            hasher: crate::rolling_hash::RollingHash::with_tables(tables),
+            mem,
+            min: self.min,
+            max: self.max,
+            predicate,
+            alignment: self.alignment,
+        }
+    }
 }
 
 // Chunks are discovered using this iterator, which will return Some(chunk_bytes) until all bytes have been chunked.
-impl<'a> Iterator for Chunker<'a> {
+impl<'a, P: crate::boundary::BoundaryPredicate, T: crate::rolling_hash::Tables> Iterator
+    for Chunker<'a, P, T>
+{
     type Item = &'a [u8];
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -55,6 +223,7 @@ impl<'a> Iterator for Chunker<'a> {
         // smart enough to skip calculations up to the rolling window size.
         self.hasher.reset();
         self.hasher.hash_bytes(&self.mem[0..self.min]);
+        self.predicate.start_chunk(&self.mem[0..self.min]);
 
         // Add one byte at a time to the hasher until we find a primary breaking point. If we don't find one by the max
         // size we'll need to use the secondary point if we can find it
@@ -69,16 +238,15 @@ impl<'a> Iterator for Chunker<'a> {
             self.hasher.hash_byte(self.mem[i]);
             let hash = self.hasher.hash();
 
-            // If we reached a primary boundary, this is where we make the chunk. Using '&' to check for a boundary has
-            // a significant performance bump over '%'. The problem is that the divisor has to be a power of 2
-            if hash & PRIMARY_BITMASK == PRIMARY_BITMASK {
-                return Some(self.pop_front_chunk(i));
-            }
-
-            // Check for secondary boundary. We simply store the index of the last secondary boundary we found in the
-            // hopes that we'll find a primary or another secondary.
-            if hash & SECONDARY_BITMASK == SECONDARY_BITMASK {
-                secondary = i;
+            match self.predicate.check(hash, self.mem[i], i) {
+                // If we reached a primary boundary, this is where we make the chunk
+                crate::boundary::Boundary::Primary => {
+                    return Some(self.pop_front_chunk(self.cut_at(i)))
+                }
+                // We simply store the index of the last secondary boundary we found in the hopes that we'll find a
+                // primary or another secondary.
+                crate::boundary::Boundary::Secondary => secondary = i,
+                crate::boundary::Boundary::None => {}
             }
         }
 
@@ -91,6 +259,106 @@ impl<'a> Iterator for Chunker<'a> {
             secondary = len;
         }
 
-        Some(self.pop_front_chunk(secondary))
+        Some(self.pop_front_chunk(self.cut_at(secondary)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunker::{Chunker, ChunkerBuilder};
+
+    #[test]
+    fn test_chunker_builder() {
+        let mut x = 3u64;
+        let data: Vec<u8> = (0..2_000_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+
+        // The builder's defaults are the same as the plain constructor's
+        let plain: Vec<&[u8]> = Chunker::new(&data, 1856, 11300).unwrap().collect();
+        let built: Vec<&[u8]> = ChunkerBuilder::new(1856, 11300)
+            .unwrap()
+            .build(&data)
+            .collect();
+        assert_eq!(plain, built);
+
+        // Asking for a larger average gives larger chunks that still respect the limits
+        let builder = ChunkerBuilder::new(1856, 65536)
+            .unwrap()
+            .average(16384)
+            .unwrap();
+        let chunks: Vec<&[u8]> = builder.build(&data).collect();
+        let average = data.len() / chunks.len();
+        assert!(average > 12000 && average < 20000, "average {}", average);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|c| c.len() >= 1856 && c.len() <= 65536));
+    }
+
+    #[test]
+    fn test_invalid_sizes() {
+        // Any of these would chunk forever without making progress
+        assert!(ChunkerBuilder::new(0, 11300).is_err());
+        assert!(ChunkerBuilder::new(1856, 1856).is_err());
+        assert!(Chunker::new(&[0u8; 100], 100, 10).is_err());
+
+        let builder = ChunkerBuilder::new(1856, 11300).unwrap();
+        assert!(builder.average(1856).is_err());
+        assert!(builder.average(11301).is_err());
+        assert!(builder.average(11300).is_ok());
+        match builder.average(1000) {
+            Err(crate::Error::AverageChunkSize { average, min, max }) => {
+                assert_eq!((average, min, max), (1000, 1856, 11300))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Recorded settings come back exactly
+        let averaged = builder.average(8192).unwrap();
+        assert_eq!(
+            builder.with_mask_bits(averaged.mask_bits()).unwrap(),
+            averaged
+        );
+        assert!(builder.with_mask_bits(1).is_err());
+        assert!(builder.with_mask_bits(64).is_err());
+        assert!(builder.with_alignment(0).is_err());
+        assert!(builder.with_alignment(11301).is_err());
+    }
+
+    #[test]
+    fn test_aligned_chunks() {
+        let mut x = 5u64;
+        let data: Vec<u8> = (0..1_000_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let builder = ChunkerBuilder::new(1856, 40000)
+            .unwrap()
+            .with_alignment(4096)
+            .unwrap();
+        let chunks: Vec<&[u8]> = builder.build(&data).collect();
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), data.len());
+        // Every chunk but the last is whole blocks, no more than the maximum
+        let (last, whole) = chunks.split_last().unwrap();
+        assert!(whole
+            .iter()
+            .all(|c| c.len() % 4096 == 0 && c.len() <= 36864));
+        assert_eq!(last.len() % 4096, data.len() % 4096);
+        // The first cut is the unaligned one moved on to the next block
+        let first = ChunkerBuilder::new(1856, 40000)
+            .unwrap()
+            .build(&data)
+            .next()
+            .unwrap();
+        assert_eq!(chunks[0].len(), first.len().div_ceil(4096) * 4096);
     }
 }
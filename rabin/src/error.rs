@@ -0,0 +1,29 @@
+// Everything that can go wrong in the library. Invalid settings are caught when a Chunker is set up, so nothing fails
+// part way through chunking.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(
+        "the maximum chunk size ({max}) must be larger than the minimum ({min}), which can't be 0"
+    )]
+    ChunkSizes { min: usize, max: usize },
+    #[error(
+        "the average chunk size ({average}) must be above the minimum ({min}) and no larger than the maximum ({max})"
+    )]
+    AverageChunkSize {
+        average: usize,
+        min: usize,
+        max: usize,
+    },
+    #[error("the bitmask must check between 2 and 63 bits, not {0}")]
+    MaskBits(u32),
+    #[error(
+        "the alignment ({alignment}) can't be 0 or larger than the maximum chunk size ({max})"
+    )]
+    Alignment { alignment: usize, max: usize },
+    #[error("{0:#x} can't be a rolling hash polynomial; its constant term (bit 0) must be set")]
+    Polynomial(u64),
+    #[error("the rolling hash can't use a window of {0} bytes")]
+    WindowSize(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
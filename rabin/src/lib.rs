@@ -1,72 +1,74 @@
+pub mod boundary;
 pub mod chunker;
+pub mod error;
+pub mod ordered;
+pub mod reference;
 pub mod rolling_hash;
+pub mod sketch;
 
-// This extension to SHA256 allows for using just part of the hash as an ID at the cost of increasing the chance of a
-// collision.
-pub trait ExtendableHashExt {
-    fn hash_chunk_112(&mut self, chunk: &[u8]) -> [u8; 14];
-    fn hash_chunk_128(&mut self, chunk: &[u8]) -> [u8; 16];
-    fn hash_chunk_144(&mut self, chunk: &[u8]) -> [u8; 18];
-    fn hash_chunk_160(&mut self, chunk: &[u8]) -> [u8; 20];
-}
+pub use crate::error::{Error, Result};
 
-// Each of these functions generates the full SHA256 value and then just uses part of the result as the hash.
-impl ExtendableHashExt for sha3::Sha3_256 {
-    fn hash_chunk_112(&mut self, chunk: &[u8]) -> [u8; 14] {
-        use sha3::Digest;
+use digest::typenum::Unsigned;
+use digest::{Digest, FixedOutputReset, Update};
 
-        self.input(chunk);
-        let out = self.result_reset();
+// Keeps just the first N bytes of a hash as an ID, at the cost of increasing the chance of a collision. Any hash with a
+// fixed output size works, and asking for more bytes than the hash makes fails to compile.
+#[derive(Debug, Clone)]
+pub struct Truncated<D, const N: usize> {
+    digest: D,
+}
 
-        let mut hash = [0u8; 14];
-        hash.copy_from_slice(&out[0..14]);
-        hash
+// The id lengths test_chunks has always offered, as 112 to 160 bits of a hash
+pub type Truncated112<D> = Truncated<D, 14>;
+pub type Truncated128<D> = Truncated<D, 16>;
+pub type Truncated144<D> = Truncated<D, 18>;
+pub type Truncated160<D> = Truncated<D, 20>;
+
+impl<D: Update + FixedOutputReset, const N: usize> Truncated<D, N> {
+    const FITS: () = assert!(
+        N <= <D::OutputSize as Unsigned>::USIZE,
+        "the hash's output is shorter than the truncated hash"
+    );
+
+    pub fn new(digest: D) -> Truncated<D, N> {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS;
+        Truncated { digest }
     }
 
-    fn hash_chunk_128(&mut self, chunk: &[u8]) -> [u8; 16] {
-        use sha3::Digest;
+    // Hashes the whole chunk and returns the first N bytes of the hash. The hash is reset afterwards, so the same
+    // Truncated can hash any number of chunks.
+    pub fn hash_chunk(&mut self, chunk: &[u8]) -> [u8; N] {
+        Update::update(&mut self.digest, chunk);
+        let out = self.digest.finalize_fixed_reset();
 
-        self.input(chunk);
-        let out = self.result_reset();
-
-        let mut hash = [0u8; 16];
-        hash.copy_from_slice(&out[0..16]);
+        let mut hash = [0u8; N];
+        hash.copy_from_slice(&out[..N]);
         hash
     }
 
-    fn hash_chunk_144(&mut self, chunk: &[u8]) -> [u8; 18] {
-        use sha3::Digest;
-
-        self.input(chunk);
-        let out = self.result_reset();
-
-        let mut hash = [0u8; 18];
-        hash.copy_from_slice(&out[0..18]);
-        hash
+    pub fn into_inner(self) -> D {
+        self.digest
     }
+}
 
-    fn hash_chunk_160(&mut self, chunk: &[u8]) -> [u8; 20] {
-        use sha3::Digest;
-
-        self.input(chunk);
-        let out = self.result_reset();
-
-        let mut hash = [0u8; 20];
-        hash.copy_from_slice(&out[0..20]);
-        hash
+impl<D: Default + Update + FixedOutputReset, const N: usize> Default for Truncated<D, N> {
+    fn default() -> Self {
+        Truncated::new(D::default())
     }
 }
 
-// This is a helper function to make calculating a version 2 SHA256 hash a one-liner
-pub fn hash_chunk_sha256(chunk: &[u8]) -> [u8; 32] {
-    use sha2::{Digest, Sha256};
+// Hashes a chunk in one go with any hash
+pub fn hash_chunk<D: Digest>(chunk: &[u8]) -> digest::Output<D> {
+    D::digest(chunk)
+}
 
-    let mut hasher = Sha256::new();
-    hasher.input(chunk);
+// A chunk's SHA256, the id chunks are known by when they are stored
+pub type ChunkId = [u8; 32];
 
-    let mut output = [0u8; 32];
-    output.copy_from_slice(hasher.result().as_slice());
-    output
+// This is a helper function to make calculating a version 2 SHA256 hash a one-liner
+pub fn hash_chunk_sha256(chunk: &[u8]) -> [u8; 32] {
+    hash_chunk::<sha2::Sha256>(chunk).into()
 }
 
 #[cfg(test)]
@@ -133,12 +135,12 @@ mod tests {
         }
 
         // Distribution should be +/- SIX_PERCENT in each bucket
-        for i in 0..256 {
+        for (i, &count) in buckets.iter().enumerate() {
             assert!(
-                buckets[i] >= LOWER_DISTRIBUTION && buckets[i] <= UPPER_DISTRIBUTION,
+                (LOWER_DISTRIBUTION..=UPPER_DISTRIBUTION).contains(&count),
                 "bucket {} had {} but should have been between {} and {}",
                 i,
-                buckets[i],
+                count,
                 LOWER_DISTRIBUTION,
                 UPPER_DISTRIBUTION
             );
@@ -147,9 +149,6 @@ mod tests {
 
     #[test]
     fn test_chunk_hash_random_distribution() {
-        use crate::ExtendableHashExt;
-        use sha3::{Digest, Sha3_256};
-
         const ITERATIONS: usize = 1024 * 16;
         const BYTES_PER_ITERATION: usize = 256;
         const OUTPUT_PER_ITERATION: usize = 18;
@@ -169,14 +168,13 @@ mod tests {
         let mut source = [0u8; BYTES_PER_ITERATION];
 
         // Hash a large amount of random data, putting the bottom u8 of the hash into 256 buckets
-        let mut hasher = Sha3_256::new();
+        let mut hasher = crate::Truncated144::<sha3::Sha3_256>::default();
         for _ in 0..ITERATIONS {
-            for i in 0..BYTES_PER_ITERATION {
-                source[i] = byte_iter.next().unwrap();
+            for b in source.iter_mut() {
+                *b = byte_iter.next().unwrap();
             }
 
-            hasher.reset();
-            let hash = hasher.hash_chunk_144(&source);
+            let hash = hasher.hash_chunk(&source);
             for &b in hash.iter() {
                 buckets[b as usize] += 1;
             }
@@ -185,15 +183,41 @@ mod tests {
         // Distribution should be +/- TEN_PERCENT in each bucket. The percentage is larger for this test than the
         // rolling hash because we have to do fewer iterations or the test takes too long. With more iterations, the
         // distribution should be better.
-        for i in 0..256 {
+        for (i, &count) in buckets.iter().enumerate() {
             assert!(
-                buckets[i] >= LOWER_DISTRIBUTION && buckets[i] <= UPPER_DISTRIBUTION,
+                (LOWER_DISTRIBUTION..=UPPER_DISTRIBUTION).contains(&count),
                 "bucket {} had {} but should have been between {} and {}",
                 i,
-                buckets[i],
+                count,
                 LOWER_DISTRIBUTION,
                 UPPER_DISTRIBUTION
             );
         }
     }
+
+    #[test]
+    fn test_truncated() {
+        let chunk = b"the same chunk hashed by two different algorithms";
+
+        // Every length is the start of the same full hash, whichever hash it is
+        let sha3 = crate::hash_chunk::<sha3::Sha3_256>(chunk);
+        let mut hasher = crate::Truncated112::<sha3::Sha3_256>::default();
+        assert_eq!(hasher.hash_chunk(chunk), sha3[..14]);
+        // Hashing resets it, so the second time is the same as the first
+        assert_eq!(hasher.hash_chunk(chunk), sha3[..14]);
+        assert_eq!(
+            crate::Truncated160::new(sha3::Sha3_256::default()).hash_chunk(chunk),
+            sha3[..20]
+        );
+
+        let sha256 = crate::hash_chunk_sha256(chunk);
+        assert_eq!(
+            crate::Truncated128::<sha2::Sha256>::default().hash_chunk(chunk),
+            sha256[..16]
+        );
+        assert_eq!(
+            crate::Truncated::<sha2::Sha512, 64>::default().hash_chunk(chunk)[..],
+            crate::hash_chunk::<sha2::Sha512>(chunk)[..]
+        );
+    }
 }
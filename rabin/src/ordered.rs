@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+// Chunking is cheap next to hashing every chunk with a strong hash, so the hashing is usually spread over a pool of
+// threads. The chunks come back from the pool in whatever order the threads finish them, though, and most consumers
+// need them in file order. HashInOrder hands chunks out to the pool and gives them back with their ids in the order
+// they went in. At most 'max_in_flight' chunks are handed out and not yet given back, so a consumer that stops asking
+// for chunks stops the chunker too, and a slow chunk only holds up as many others as that.
+
+// A chunk and where it starts in the data it was cut from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRef<'a> {
+    pub offset: u64,
+    pub data: &'a [u8],
+}
+
+// Numbers the chunks a chunker cuts with their offsets, assuming they are cut back to back from the start
+pub fn refs<'a, I>(chunks: I) -> impl Iterator<Item = ChunkRef<'a>>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    chunks.into_iter().scan(0u64, |offset, data| {
+        let chunk = ChunkRef {
+            offset: *offset,
+            data,
+        };
+        *offset += data.len() as u64;
+        Some(chunk)
+    })
+}
+
+pub struct HashInOrder<'a, I> {
+    chunks: I,
+    // Taken away once every chunk is handed out, which lets the threads finish
+    jobs: Option<mpsc::SyncSender<(u64, ChunkRef<'a>)>>,
+    results: mpsc::Receiver<(u64, ChunkRef<'a>, crate::ChunkId)>,
+    // Chunks that were hashed ahead of one that is still being hashed, by their number
+    pending: BTreeMap<u64, (ChunkRef<'a>, crate::ChunkId)>,
+    handed_out: u64,
+    given_back: u64,
+    max_in_flight: u64,
+}
+
+// Hashes the chunks with SHA256 on 'workers' threads in the scope, and yields each chunk with its id in the order the
+// chunks came in. The threads finish once the chunks run out or the iterator is dropped.
+pub fn hash_in_order<'scope, 'env, 'a, I>(
+    scope: &'scope thread::Scope<'scope, 'env>,
+    chunks: I,
+    workers: usize,
+    max_in_flight: usize,
+) -> HashInOrder<'a, I::IntoIter>
+where
+    'a: 'scope,
+    I: IntoIterator<Item = ChunkRef<'a>>,
+{
+    let max_in_flight = max_in_flight.max(1);
+    // Never fills up, since no more than max_in_flight chunks are ever handed out at once
+    let (jobs, job_receiver) = mpsc::sync_channel::<(u64, ChunkRef<'a>)>(max_in_flight);
+    let (result_sender, results) = mpsc::channel();
+    let job_receiver = Arc::new(Mutex::new(job_receiver));
+    for _ in 0..workers.max(1) {
+        let job_receiver = job_receiver.clone();
+        let result_sender = result_sender.clone();
+        scope.spawn(move || loop {
+            // The lock is only held while waiting for the next chunk, not while hashing it
+            let job = job_receiver.lock().unwrap().recv();
+            let (number, chunk) = match job {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            let id = crate::hash_chunk_sha256(chunk.data);
+            // Nobody wants the rest if the iterator was dropped
+            if result_sender.send((number, chunk, id)).is_err() {
+                return;
+            }
+        });
+    }
+
+    HashInOrder {
+        chunks: chunks.into_iter(),
+        jobs: Some(jobs),
+        results,
+        pending: BTreeMap::new(),
+        handed_out: 0,
+        given_back: 0,
+        max_in_flight: max_in_flight as u64,
+    }
+}
+
+impl<'a, I> HashInOrder<'a, I> {
+    // How many chunks have been handed to the threads and not yet given back
+    pub fn in_flight(&self) -> usize {
+        (self.handed_out - self.given_back) as usize
+    }
+}
+
+impl<'a, I: Iterator<Item = ChunkRef<'a>>> Iterator for HashInOrder<'a, I> {
+    type Item = (ChunkRef<'a>, crate::ChunkId);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.handed_out - self.given_back < self.max_in_flight {
+            let jobs = match self.jobs.as_ref() {
+                Some(jobs) => jobs,
+                None => break,
+            };
+            match self.chunks.next() {
+                Some(chunk) => {
+                    jobs.send((self.handed_out, chunk)).unwrap();
+                    self.handed_out += 1;
+                }
+                None => self.jobs = None,
+            }
+        }
+        if self.given_back == self.handed_out {
+            return None;
+        }
+
+        loop {
+            if let Some(hashed) = self.pending.remove(&self.given_back) {
+                self.given_back += 1;
+                return Some(hashed);
+            }
+            // Fails once every thread is gone, which only happens early if they all panicked. Hashing doesn't panic.
+            let (number, chunk, id) = self
+                .results
+                .recv()
+                .expect("a hashing thread stopped before hashing its chunk");
+            self.pending.insert(number, (chunk, id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut x = 1u64;
+        (0..len)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_in_order() {
+        let data = random_data(1_000_000);
+        let chunker = crate::chunker::Chunker::new(&data, 1856, 11300).unwrap();
+        let chunks: Vec<&[u8]> = chunker.collect();
+        let expected: Vec<(u64, usize, crate::ChunkId)> =
+            crate::ordered::refs(chunks.iter().copied())
+                .map(|c| (c.offset, c.data.len(), crate::hash_chunk_sha256(c.data)))
+                .collect();
+        assert!(expected.len() > 50);
+        assert_eq!(
+            expected.last().map(|c| c.0 as usize + c.1),
+            Some(data.len())
+        );
+
+        for (workers, max_in_flight) in [(1, 1), (4, 3), (8, 64)] {
+            let hashed: Vec<_> = std::thread::scope(|scope| {
+                crate::ordered::hash_in_order(
+                    scope,
+                    crate::ordered::refs(chunks.iter().copied()),
+                    workers,
+                    max_in_flight,
+                )
+                .map(|(c, id)| (c.offset, c.data.len(), id))
+                .collect()
+            });
+            assert_eq!(hashed, expected);
+        }
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let data = random_data(200_000);
+        let pulled = Cell::new(0);
+        let source =
+            crate::ordered::refs(data.chunks(1000)).inspect(|_| pulled.set(pulled.get() + 1));
+
+        std::thread::scope(|scope| {
+            let mut hashed = crate::ordered::hash_in_order(scope, source, 4, 8);
+            assert_eq!(pulled.get(), 0);
+            for taken in 1..=20 {
+                let (chunk, _) = hashed.next().unwrap();
+                assert_eq!(chunk.offset, (taken - 1) * 1000);
+                // Never more than the limit ahead of what was taken
+                assert!(pulled.get() as u64 <= taken + 8);
+                assert!(hashed.in_flight() <= 8);
+            }
+            // Dropping the iterator part way lets the threads finish
+        });
+        assert!(pulled.get() < 200);
+    }
+}
@@ -0,0 +1,102 @@
+// A deliberately plain chunker to check the real one against. The Chunker gets its speed from the rolling hash's
+// precomputed tables and from skipping ahead to the minimum chunk size; this one works out the Rabin fingerprint of
+// every window from scratch with bit-by-bit polynomial arithmetic and tests every position, so the two only agree if
+// both follow the rules in the README. It is far too slow for real data.
+
+// The same window and irreducible polynomial (x^64 + x^4 + x^3 + x + 1) that the rolling hash's tables are made
+// from. They are repeated here on purpose so that a change to one is caught by the other.
+const WINDOW_SIZE: usize = 16;
+const POLYNOMIAL: u64 = 0x1B;
+
+// Multiplies the fingerprint by x^8, reducing by the polynomial whenever a bit is shifted out of the top
+fn shift_byte(mut fingerprint: u64) -> u64 {
+    for _ in 0..8 {
+        let overflow = fingerprint & 0x8000_0000_0000_0000 != 0;
+        fingerprint <<= 1;
+        if overflow {
+            fingerprint ^= POLYNOMIAL;
+        }
+    }
+    fingerprint
+}
+
+// The fingerprint of the bytes in the window, oldest first
+pub fn fingerprint(window: &[u8]) -> u64 {
+    window.iter().fold(0, |f, &b| shift_byte(f) ^ b as u64)
+}
+
+// Where each chunk the builder's settings would make ends, as offsets into the data. The window never reaches back
+// past the start of the chunk it is in.
+pub fn boundaries(data: &[u8], builder: &crate::chunker::ChunkerBuilder) -> Vec<usize> {
+    let bits = builder.mask_bits();
+    let primary_mask = (1u64 << bits) - 1;
+    let secondary_mask = (1u64 << (bits - 1)) - 1;
+
+    let mut ends = vec![];
+    let mut start = 0;
+    while start < data.len() {
+        let len = data.len() - start;
+        let mut end = None;
+        let mut secondary = None;
+        // A chunk of 'size' bytes is cut before the byte at 'size', where that byte completed a primary boundary
+        for size in builder.min()..builder.max().min(len) {
+            let window =
+                &data[(start + size + 1).saturating_sub(WINDOW_SIZE).max(start)..=start + size];
+            let f = fingerprint(window);
+            if f & primary_mask == primary_mask {
+                end = Some(size);
+                break;
+            }
+            if f & secondary_mask == secondary_mask {
+                secondary = Some(size);
+            }
+        }
+
+        let size = end.or(secondary).unwrap_or(builder.max()).min(len);
+        ends.push(start + size);
+        start += size;
+    }
+    ends
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_matches_chunker() {
+        let mut x = 7u64;
+        let data: Vec<u8> = (0..300_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+
+        // The rolling hash agrees with working the fingerprint out from scratch
+        let mut rolling = crate::rolling_hash::RollingHash::new();
+        for (i, &b) in data[..100].iter().enumerate() {
+            rolling.hash_byte(b);
+            let window = &data[(i + 1).saturating_sub(16)..=i];
+            assert_eq!(rolling.hash(), crate::reference::fingerprint(window));
+        }
+
+        for builder in [
+            crate::chunker::ChunkerBuilder::new(1856, 11300).unwrap(),
+            crate::chunker::ChunkerBuilder::new(256, 4096)
+                .unwrap()
+                .average(1024)
+                .unwrap(),
+            // Smaller than the window, so the window is cut short at the start of every chunk
+            crate::chunker::ChunkerBuilder::new(4, 64).unwrap(),
+        ] {
+            let mut ends = vec![];
+            let mut end = 0;
+            for chunk in builder.build(&data) {
+                end += chunk.len();
+                ends.push(end);
+            }
+            assert_eq!(crate::reference::boundaries(&data, &builder), ends);
+        }
+    }
+}
@@ -1,5 +1,88 @@
-// This file includes all the necessary statics and consts to run the rolling hash
-include!(concat!(env!("OUT_DIR"), "/static_rolling_hash_autogen.rs"));
+use serde::ser::SerializeStruct;
+use serde_derive::{Deserialize, Serialize};
+
+// The window is 16 bytes unless the tables were made for another size. Larger windows are less likely to match a mask
+// by accident inside repetitive data (base64 and the like), where a 16 byte window can come round again and again.
+const WINDOW_SIZE: usize = 16;
+pub const MAX_WINDOW_SIZE: usize = 64;
+const BITS_PER_BYTE: usize = 8;
+
+// The rolling hash implementation in the file uses the Rabin fingerprinting method of irreducible polynomials over a
+// finited field. The Rabin fingerprint is NOT considered to be cryptographically secure, but it is a fast algorithm
+// that can be used to cut a file into chunks without leaking information on the contents of the file.
+
+// The default irreducible polynomial is x^64 + x^4 + x^3 + x + 1. This would normally require 65 bits to store, but in
+// this implementation we assume that bit 64 (0 indexed) is set and do not store it.  0x1B == 00011011 which is read as:
+//    bit 0: constant 1    (always set)
+//    bit 1: x             (set)
+//    bit 2: x^2           (not set)
+//    bit 3: x^3           (set)
+//    bit 4: x^4           (set)
+//    bit 5: x^5           (not set)
+//       etc...
+// The value for this polynomial is taken from "Table of Low-Weight Binary Irreducible Polynomials" published by Hewlett
+// Packard at: https://www.hpl.hp.com/techreports/98/HPL-98-135.pdf
+pub const DEFAULT_IRREDUCIBLE_POLYNOMIAL_64: u64 = 0x1B;
+
+// The second polynomial is x^64 + x^63 + x^61 + x^60 + 1, the reciprocal of the default one (the coefficients in the
+// reverse order), which is irreducible because the default one is. Hashing the same window with both gives two
+// fingerprints that are unrelated to each other.
+pub const SECOND_IRREDUCIBLE_POLYNOMIAL_64: u64 = 0xB000000000000001;
+
+// The tables are worked out when the crate is compiled, so any number of them for other polynomials and windows can be
+// constants too (i.e. `static MY_POP_TABLE: [u64; 256] = pop_table(MY_POLYNOMIAL, 32);`)
+static ROLLING_HASH_PUSH_TABLE: [u64; 256] = push_table(DEFAULT_IRREDUCIBLE_POLYNOMIAL_64);
+static ROLLING_HASH_POP_TABLE: [u64; 256] =
+    pop_table(DEFAULT_IRREDUCIBLE_POLYNOMIAL_64, WINDOW_SIZE);
+static SECOND_ROLLING_HASH_PUSH_TABLE: [u64; 256] = push_table(SECOND_IRREDUCIBLE_POLYNOMIAL_64);
+static SECOND_ROLLING_HASH_POP_TABLE: [u64; 256] =
+    pop_table(SECOND_IRREDUCIBLE_POLYNOMIAL_64, WINDOW_SIZE);
+
+// This is the basis of the Rabin fingerprint, where overflow when shifting results in dividing by a irreducible
+// polynomial and using the remainder.
+const fn shift_left_n_bits_with_mod_64(mut number: u64, n: usize, polynomial: u64) -> u64 {
+    let mut i = 0;
+    while i < n {
+        // We will need to mod the irreducible poly if shifting left would leave bit 65 set. Check bit 64 now to see if
+        // we need to do that
+        let needs_mod = number & 0x8000000000000000 == 0x8000000000000000;
+
+        // Do the shift
+        number <<= 1;
+
+        // In polynomial math of this nature, XOR is equivalent to mod
+        if needs_mod {
+            number ^= polynomial;
+        }
+        i += 1;
+    }
+
+    number
+}
+
+// The push table pre-computes what happens to every possible top byte of the hash when it gets modded as the hash is
+// shifted to make room for a new byte
+pub const fn push_table(polynomial: u64) -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = shift_left_n_bits_with_mod_64((i as u64) << 56, BITS_PER_BYTE, polynomial);
+        i += 1;
+    }
+    table
+}
+
+// The pop table pre-computes the same value for every byte that is about to leave a window of 'window_size' bytes,
+// which has been shifted once for every byte since it went in
+pub const fn pop_table(polynomial: u64, window_size: usize) -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = shift_left_n_bits_with_mod_64(i as u64, BITS_PER_BYTE * window_size, polynomial);
+        i += 1;
+    }
+    table
+}
 
 // A rolling hash is a hash function that operates over a windows of a certain number of bytes. The rolling nature comes
 // from the property that the hash of bytes [1..17] is the same as first hashing [0..16] and then pushing one more byte
@@ -7,23 +90,257 @@ include!(concat!(env!("OUT_DIR"), "/static_rolling_hash_autogen.rs"));
 // strong hash, it has the properties of near-random output (hashing any particular set of bytes will produce what looks
 // like a random number), but is repeatable (hashing two identical set of bytes will produce identical output).
 
+// The push and pop tables for one polynomial and window size. The hash is generic over them so that the tables it uses
+// (and the size of its window) are known at compile time and cost nothing to look up.
+pub trait Tables {
+    fn push_table(&self) -> &[u64; 256];
+    fn pop_table(&self) -> &[u64; 256];
+
+    // How many bytes the pop table was made for, from 1 to MAX_WINDOW_SIZE
+    fn window_size(&self) -> usize {
+        WINDOW_SIZE
+    }
+}
+
+// The tables for the default polynomial
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DefaultTables;
+
+impl Tables for DefaultTables {
+    #[inline]
+    fn push_table(&self) -> &[u64; 256] {
+        &ROLLING_HASH_PUSH_TABLE
+    }
+
+    #[inline]
+    fn pop_table(&self) -> &[u64; 256] {
+        &ROLLING_HASH_POP_TABLE
+    }
+}
+
+// The tables for the second polynomial, for a fingerprint of the same window that is unrelated to the default one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SecondTables;
+
+impl Tables for SecondTables {
+    #[inline]
+    fn push_table(&self) -> &[u64; 256] {
+        &SECOND_ROLLING_HASH_PUSH_TABLE
+    }
+
+    #[inline]
+    fn pop_table(&self) -> &[u64; 256] {
+        &SECOND_ROLLING_HASH_POP_TABLE
+    }
+}
+
+// The tables for the default polynomial with a window of another size, such as 32, 48 or 64 bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WindowTables<const WINDOW_SIZE: usize>;
+
+impl<const WINDOW_SIZE: usize> WindowTables<WINDOW_SIZE> {
+    const FITS: () = assert!(
+        WINDOW_SIZE >= 1 && WINDOW_SIZE <= MAX_WINDOW_SIZE,
+        "the rolling hash's window must be from 1 to 64 bytes"
+    );
+    const POP_TABLE: [u64; 256] = pop_table(DEFAULT_IRREDUCIBLE_POLYNOMIAL_64, WINDOW_SIZE);
+}
+
+impl<const WINDOW_SIZE: usize> Tables for WindowTables<WINDOW_SIZE> {
+    #[inline]
+    fn push_table(&self) -> &[u64; 256] {
+        &ROLLING_HASH_PUSH_TABLE
+    }
+
+    #[inline]
+    fn pop_table(&self) -> &[u64; 256] {
+        &Self::POP_TABLE
+    }
+
+    #[inline]
+    fn window_size(&self) -> usize {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::FITS;
+        WINDOW_SIZE
+    }
+}
+
+// Tables made when the program runs, for polynomials that aren't known before then (i.e. one for each tenant of a
+// server, so that one tenant's chunk boundaries say nothing about another's). They live on the heap and can be shared
+// between any number of hashes by reference or in an Arc.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollingHashTables {
+    polynomial: u64,
+    window_size: usize,
+    push: Box<[u64; 256]>,
+    pop: Box<[u64; 256]>,
+}
+
+impl RollingHashTables {
+    // Makes the tables for the polynomial, stored without its x^64 term as the default one is. Every irreducible
+    // polynomial has a constant term, so one without is refused; the rest aren't checked, and a reducible one still
+    // works, just with hashes that are spread less evenly. The window has to be one the hash supports.
+    pub fn generate(polynomial: u64, window_size: usize) -> crate::Result<RollingHashTables> {
+        if polynomial & 1 == 0 {
+            return Err(crate::Error::Polynomial(polynomial));
+        }
+        if !(1..=MAX_WINDOW_SIZE).contains(&window_size) {
+            return Err(crate::Error::WindowSize(window_size));
+        }
+        Ok(RollingHashTables {
+            polynomial,
+            window_size,
+            push: Box::new(push_table(polynomial)),
+            pop: Box::new(pop_table(polynomial, window_size)),
+        })
+    }
+
+    pub fn polynomial(&self) -> u64 {
+        self.polynomial
+    }
+
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+}
+
+impl Tables for RollingHashTables {
+    fn push_table(&self) -> &[u64; 256] {
+        &self.push
+    }
+
+    fn pop_table(&self) -> &[u64; 256] {
+        &self.pop
+    }
+
+    fn window_size(&self) -> usize {
+        self.window_size
+    }
+}
+
+impl<T: Tables + ?Sized> Tables for &T {
+    #[inline]
+    fn push_table(&self) -> &[u64; 256] {
+        (**self).push_table()
+    }
+
+    #[inline]
+    fn pop_table(&self) -> &[u64; 256] {
+        (**self).pop_table()
+    }
+
+    #[inline]
+    fn window_size(&self) -> usize {
+        (**self).window_size()
+    }
+}
+
+impl<T: Tables + ?Sized> Tables for std::sync::Arc<T> {
+    #[inline]
+    fn push_table(&self) -> &[u64; 256] {
+        (**self).push_table()
+    }
+
+    #[inline]
+    fn pop_table(&self) -> &[u64; 256] {
+        (**self).pop_table()
+    }
+
+    #[inline]
+    fn window_size(&self) -> usize {
+        (**self).window_size()
+    }
+}
+
 // The RollingHash struct keeps track of which bytes have recently been added to the hash so that the push and pop
-// tables will work correctly as bytes are added to the hash (which pushes the oldest byte off).
-pub struct RollingHash {
+// tables will work correctly as bytes are added to the hash (which pushes the oldest byte off). Its whole state can be
+// serialized so that hashing can stop part way through the data and carry on later, or on another machine. The tables
+// aren't part of the state; a hash has to be restored with the same ones it was saved with.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "SavedRollingHash", bound(deserialize = "T: Default"))]
+pub struct RollingHash<T: Tables = DefaultTables> {
     // The current hash value
     hash: u64,
-    // A list of the bytes that have been recently added. This list is circular with 'next' indexing the oldest byte
-    queue: [u8; WINDOW_SIZE],
-    // The index of the oldest byte in the queue. This must stay in the range [0..WINDOW_SIZE]
+    // A list of the bytes that have been recently added. This list is circular with 'next' indexing the oldest byte.
+    // Only the first window_size bytes are used.
+    queue: [u8; MAX_WINDOW_SIZE],
+    // The index of the oldest byte in the queue. This must stay in the range [0..window_size]
+    next: usize,
+    tables: T,
+}
+
+// Only the part of the queue that is in the window is saved, so a hash with the usual window is saved as it always was
+impl<T: Tables> serde::Serialize for RollingHash<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut saved = serializer.serialize_struct("RollingHash", 3)?;
+        saved.serialize_field("hash", &self.hash)?;
+        saved.serialize_field("queue", &self.queue[..self.tables.window_size()])?;
+        saved.serialize_field("next", &self.next)?;
+        saved.end()
+    }
+}
+
+// What a serialized RollingHash is read back as, so that the queue and its index can be checked before they are used
+#[derive(Deserialize)]
+struct SavedRollingHash {
+    hash: u64,
+    queue: Vec<u8>,
     next: usize,
 }
 
+impl<T: Tables + Default> std::convert::TryFrom<SavedRollingHash> for RollingHash<T> {
+    type Error = String;
+
+    fn try_from(saved: SavedRollingHash) -> Result<RollingHash<T>, String> {
+        let mut hash = RollingHash::with_tables(T::default());
+        let window_size = hash.tables.window_size();
+        if saved.queue.len() != window_size {
+            return Err(format!(
+                "the rolling hash's queue of {} bytes doesn't fit its window of {}",
+                saved.queue.len(),
+                window_size
+            ));
+        }
+        if saved.next >= window_size {
+            return Err(format!(
+                "the rolling hash's next byte {} is outside its window of {}",
+                saved.next, window_size
+            ));
+        }
+        hash.hash = saved.hash;
+        hash.queue[..window_size].copy_from_slice(&saved.queue);
+        hash.next = saved.next;
+        Ok(hash)
+    }
+}
+
+impl Default for RollingHash {
+    fn default() -> Self {
+        RollingHash::new()
+    }
+}
+
 impl RollingHash {
     pub fn new() -> RollingHash {
+        RollingHash::with_tables(DefaultTables)
+    }
+}
+
+impl<T: Tables> RollingHash<T> {
+    // Starts a hash that uses another polynomial's tables, or another window size. Tables can't be made for a window
+    // outside 1 to MAX_WINDOW_SIZE, so one that says it has such a window is a bug.
+    pub fn with_tables(tables: T) -> RollingHash<T> {
+        assert!(
+            (1..=MAX_WINDOW_SIZE).contains(&tables.window_size()),
+            "the rolling hash's window must be from 1 to {} bytes, not {}",
+            MAX_WINDOW_SIZE,
+            tables.window_size()
+        );
         RollingHash {
             hash: 0,
-            queue: [0; WINDOW_SIZE],
+            queue: [0; MAX_WINDOW_SIZE],
             next: 0,
+            tables,
         }
     }
 
@@ -35,38 +352,324 @@ impl RollingHash {
     // Resets the hash to it's default state
     pub fn reset(&mut self) {
         self.hash = 0;
-        self.queue = [0; WINDOW_SIZE];
+        self.queue = [0; MAX_WINDOW_SIZE];
     }
 
     // Adds a single byte to the hash.
     pub fn hash_byte(&mut self, b: u8) {
         // Concat the new byte onto the hash
         let high_byte = (self.hash >> 56) as usize;
-        self.hash = ((self.hash << 8) | (b as u64)) ^ ROLLING_HASH_PUSH_TABLE[high_byte];
+        self.hash = ((self.hash << 8) | (b as u64)) ^ self.tables.push_table()[high_byte];
 
         // Remove the old byte
         let old_byte = self.queue[self.next] as usize;
-        self.hash ^= ROLLING_HASH_POP_TABLE[old_byte];
+        self.hash ^= self.tables.pop_table()[old_byte];
 
-        // Update the circular byte queue. The next position will range from 0-15 and then wrap around.
-        // 'next & WINDOW_MASK' is equivilant to 'next % WINDOW_SIZE' as long as WINDOW_SIZE is a power of two.
-        // Profiling shows that AND is significantly faster than MOD and this code is in the hot path.
+        // Update the circular byte queue. The next position will range from 0 to one less than the window size and then
+        // wrap around. 'next & (window_size - 1)' is equivilant to 'next % window_size' as long as the window size is a
+        // power of two. Profiling shows that AND is significantly faster than MOD and this code is in the hot path.
+        // The window size of built-in tables is a constant, so only one of these is compiled for them.
         self.queue[self.next] = b;
-        self.next = (self.next + 1) & WINDOW_MASK;
+        let window_size = self.tables.window_size();
+        self.next = if window_size.is_power_of_two() {
+            (self.next + 1) & (window_size - 1)
+        } else if self.next + 1 == window_size {
+            0
+        } else {
+            self.next + 1
+        };
     }
 
     // Hashes the specified bytes. If there are a large number of bytes, hash_bytes will skip to the last window to save
-    // processing time.
+    // processing time: the hash is reset and only the last window of bytes is hashed. The hash comes out the same,
+    // since it only depends on the last window, but the rest of the state (where the queue starts) can be different
+    // from hashing the bytes one at a time, so two hashes that got to the same bytes different ways may not be equal.
+    // This is what the chunker wants when it skips ahead to the minimum chunk size.
     pub fn hash_bytes(&mut self, mut bytes: &[u8]) {
-        // If the additional bytes are longer than twice the window, its faster just to reset the hash and hash the 16
-        // bytes at the end
-        if bytes.len() > TWICE_WINDOW_SIZE {
+        // If the additional bytes are longer than twice the window, its faster just to reset the hash and hash the
+        // window's worth of bytes at the end
+        let window_size = self.tables.window_size();
+        if bytes.len() > 2 * window_size {
             self.reset();
-            bytes = &bytes[bytes.len() - WINDOW_SIZE..];
+            bytes = &bytes[bytes.len() - window_size..];
         }
 
         for &b in bytes {
             self.hash_byte(b);
         }
     }
+
+    // Hashes every one of the specified bytes in turn, exactly as calling hash_byte for each would, however many there
+    // are. Use this when the hash is an incremental hash of a stream rather than a way to find the hash of a window.
+    pub fn hash_all_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.hash_byte(b);
+        }
+    }
+}
+
+// Two rolling hashes of the same window with different polynomials, as one 128-bit fingerprint: the default hash in
+// the high 64 bits and the second in the low 64. 64 bits are plenty to find boundaries with, but too few to tell the
+// windows of a large data set apart, for those who use the fingerprint of the window that ends a chunk as a weak id
+// for it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RollingHash128 {
+    first: RollingHash,
+    second: RollingHash<SecondTables>,
+}
+
+impl Default for RollingHash128 {
+    fn default() -> Self {
+        RollingHash128::new()
+    }
+}
+
+impl RollingHash128 {
+    pub fn new() -> RollingHash128 {
+        RollingHash128 {
+            first: RollingHash::new(),
+            second: RollingHash::with_tables(SecondTables),
+        }
+    }
+
+    pub fn hash(&self) -> u128 {
+        ((self.first.hash() as u128) << 64) | self.second.hash() as u128
+    }
+
+    pub fn reset(&mut self) {
+        self.first.reset();
+        self.second.reset();
+    }
+
+    pub fn hash_byte(&mut self, b: u8) {
+        self.first.hash_byte(b);
+        self.second.hash_byte(b);
+    }
+
+    // Hashes the specified bytes, skipping to the last window if there are a large number of them as hash_bytes does
+    pub fn hash_bytes(&mut self, bytes: &[u8]) {
+        self.first.hash_bytes(bytes);
+        self.second.hash_bytes(bytes);
+    }
+
+    // Hashes every one of the specified bytes in turn, as hash_all_bytes does
+    pub fn hash_all_bytes(&mut self, bytes: &[u8]) {
+        self.first.hash_all_bytes(bytes);
+        self.second.hash_all_bytes(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rolling_hash::{RollingHash, RollingHash128};
+
+    #[test]
+    fn test_save_and_restore() {
+        let data: Vec<u8> = (0..100u32).map(|i| (i * 31) as u8).collect();
+        let mut hasher = RollingHash::new();
+        hasher.hash_bytes(&data[..5]);
+
+        // The restored hash carries on exactly where the saved one stopped
+        let saved = serde_json::to_string(&hasher).unwrap();
+        let mut restored: RollingHash = serde_json::from_str(&saved).unwrap();
+        assert_eq!(restored, hasher);
+        for &b in &data[5..] {
+            hasher.hash_byte(b);
+            restored.hash_byte(b);
+            assert_eq!(restored.hash(), hasher.hash());
+        }
+
+        // A queue index outside the window would panic on the next byte, so it isn't accepted
+        let damaged = saved.replace("\"next\":5", "\"next\":16");
+        assert_ne!(damaged, saved);
+        assert!(serde_json::from_str::<RollingHash>(&damaged).is_err());
+    }
+
+    #[test]
+    fn test_rolling_hash_128() {
+        let data: Vec<u8> = (0..200u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let mut rolling = RollingHash128::new();
+        let mut first = RollingHash::new();
+        for &b in &data {
+            rolling.hash_byte(b);
+            first.hash_byte(b);
+            assert_eq!((rolling.hash() >> 64) as u64, first.hash());
+        }
+        // The halves are hashed with different polynomials, which only differ once the hash has been reduced by them
+        assert_ne!(rolling.hash() as u64, first.hash());
+
+        // It rolls like the 64-bit hash, so the same window always has the same fingerprint
+        let mut hashed = RollingHash128::new();
+        hashed.hash_bytes(&data[184..]);
+        assert_eq!(hashed.hash(), rolling.hash());
+        hashed.reset();
+        hashed.hash_bytes(&data);
+        assert_eq!(hashed.hash(), rolling.hash());
+
+        let saved = serde_json::to_string(&rolling).unwrap();
+        assert_eq!(
+            serde_json::from_str::<RollingHash128>(&saved).unwrap(),
+            rolling
+        );
+    }
+
+    #[test]
+    fn test_const_tables() {
+        // Tables for other polynomials and windows can be constants alongside the default ones
+        const POP_48: [u64; 256] = crate::rolling_hash::pop_table(0x1B, 48);
+        const PUSH_SECOND: [u64; 256] =
+            crate::rolling_hash::push_table(crate::rolling_hash::SECOND_IRREDUCIBLE_POLYNOMIAL_64);
+        const POP_SECOND_32: [u64; 256] = crate::rolling_hash::pop_table(
+            crate::rolling_hash::SECOND_IRREDUCIBLE_POLYNOMIAL_64,
+            32,
+        );
+
+        // A byte shifted along by one push for every byte of the window ends up as its pop table entry
+        let push_zeros = |push: &[u64; 256], mut hash: u64, window: usize| {
+            for _ in 0..window {
+                hash = (hash << 8) ^ push[(hash >> 56) as usize];
+            }
+            hash
+        };
+        let push = crate::rolling_hash::push_table(0x1B);
+        for b in 0..256u64 {
+            assert_eq!(push_zeros(&push, b, 48), POP_48[b as usize]);
+            assert_eq!(push_zeros(&PUSH_SECOND, b, 32), POP_SECOND_32[b as usize]);
+        }
+        assert_ne!(push, PUSH_SECOND);
+    }
+
+    #[test]
+    fn test_runtime_tables() {
+        let mut x = 9u64;
+        let data: Vec<u8> = (0..300_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+
+        // Generated tables for the default polynomial hash the same as the built in ones
+        let default = crate::rolling_hash::RollingHashTables::generate(0x1B, 16).unwrap();
+        let mut built_in = RollingHash::new();
+        let mut generated = RollingHash::with_tables(&default);
+        for &b in &data[..1000] {
+            built_in.hash_byte(b);
+            generated.hash_byte(b);
+            assert_eq!(generated.hash(), built_in.hash());
+        }
+
+        // A tenant's own polynomial, shared between threads, cuts the data somewhere else
+        let builder = crate::chunker::ChunkerBuilder::new(1856, 11300).unwrap();
+        let tables = std::sync::Arc::new(
+            crate::rolling_hash::RollingHashTables::generate(0x800000000000000D, 16).unwrap(),
+        );
+        let ends = |chunks: &mut dyn Iterator<Item = &[u8]>| {
+            chunks
+                .scan(0, |end, c| {
+                    *end += c.len();
+                    Some(*end)
+                })
+                .collect::<Vec<usize>>()
+        };
+        let tenant = std::thread::scope(|scope| {
+            let tables = tables.clone();
+            let data = &data;
+            scope
+                .spawn(move || ends(&mut builder.build_with_tables(data, builder.masks(), tables)))
+                .join()
+                .unwrap()
+        });
+        let usual = ends(&mut builder.build(&data));
+        assert_eq!(tenant.last(), Some(&data.len()));
+        assert!(tenant.len() > 30);
+        assert!(tenant.iter().filter(|end| usual.contains(end)).count() < 3);
+
+        assert!(matches!(
+            crate::rolling_hash::RollingHashTables::generate(0x1A, 16),
+            Err(crate::Error::Polynomial(0x1A))
+        ));
+        assert!(matches!(
+            crate::rolling_hash::RollingHashTables::generate(0x1B, 65),
+            Err(crate::Error::WindowSize(65))
+        ));
+    }
+
+    #[test]
+    fn test_window_sizes() {
+        let data: Vec<u8> = (0..500u32).map(|i| (i * 37 + i / 7) as u8).collect();
+        for window in [32, 48, 64] {
+            let tables = crate::rolling_hash::RollingHashTables::generate(0x1B, window).unwrap();
+            let mut rolling = RollingHash::with_tables(&tables);
+            let mut hashed = RollingHash::with_tables(&tables);
+            for end in 1..=data.len() {
+                rolling.hash_byte(data[end - 1]);
+                // The hash only depends on the last window of bytes
+                hashed.reset();
+                for &b in &data[end.saturating_sub(window)..end] {
+                    hashed.hash_byte(b);
+                }
+                assert_eq!(rolling.hash(), hashed.hash(), "window {}", window);
+            }
+            hashed.hash_bytes(&data);
+            assert_eq!(hashed.hash(), rolling.hash());
+        }
+
+        // The constant tables for a window are the same as generated ones, and save only the window's bytes
+        let mut constant = RollingHash::with_tables(crate::rolling_hash::WindowTables::<48>);
+        let tables = crate::rolling_hash::RollingHashTables::generate(0x1B, 48).unwrap();
+        let mut generated = RollingHash::with_tables(&tables);
+        constant.hash_bytes(&data[..70]);
+        generated.hash_bytes(&data[..70]);
+        assert_eq!(constant.hash(), generated.hash());
+        let saved = serde_json::to_value(&constant).unwrap();
+        assert_eq!(saved["queue"].as_array().unwrap().len(), 48);
+        let restored: RollingHash<crate::rolling_hash::WindowTables<48>> =
+            serde_json::from_value(saved.clone()).unwrap();
+        assert_eq!(restored, constant);
+        // A hash saved with one window can't be restored with another
+        assert!(serde_json::from_value::<RollingHash>(saved).is_err());
+
+        // The chunker cuts with a wider window just as well
+        let builder = crate::chunker::ChunkerBuilder::new(100, 1000).unwrap();
+        let chunks: Vec<&[u8]> = builder
+            .build_with_tables(
+                &data,
+                builder.masks(),
+                crate::rolling_hash::WindowTables::<64>,
+            )
+            .collect();
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn test_hash_all_bytes() {
+        let data: Vec<u8> = (0..100u32).map(|i| (i * 13 + 5) as u8).collect();
+        let mut one_at_a_time = RollingHash::new();
+        one_at_a_time.hash_byte(7);
+        for &b in &data {
+            one_at_a_time.hash_byte(b);
+        }
+
+        // Every byte goes through the hash, so the whole state is the same as hashing them one at a time
+        let mut all = RollingHash::new();
+        all.hash_byte(7);
+        all.hash_all_bytes(&data);
+        assert_eq!(all, one_at_a_time);
+
+        // Skipping to the last window gets the same hash, but not the same state
+        let mut skipped = RollingHash::new();
+        skipped.hash_byte(7);
+        skipped.hash_bytes(&data);
+        assert_eq!(skipped.hash(), one_at_a_time.hash());
+        assert_ne!(skipped, one_at_a_time);
+
+        let mut all = RollingHash128::new();
+        all.hash_all_bytes(&data);
+        let mut skipped = RollingHash128::new();
+        skipped.hash_bytes(&data);
+        assert_eq!(all.hash(), skipped.hash());
+    }
 }
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// Resemblance detection finds chunks that are similar but not identical, using the "super-feature" form of minhash.
+//
+// Every window position in a chunk already has a Rabin fingerprint. Each feature takes a different linear transform of
+// those fingerprints and keeps the maximum, which acts as a random sample of one window out of the chunk. Two chunks
+// that share most of their windows are very likely to pick the same window (and therefore have the same feature),
+// while a small edit only changes the features whose maximum happened to land on the edited bytes.
+//
+// Comparing features one at a time is too noisy for an index, so groups of features are hashed together into
+// super-features. Two chunks that share even one super-feature are very likely to be near-duplicates.
+pub const FEATURES: usize = 12;
+pub const SUPER_FEATURES: usize = 3;
+const FEATURES_PER_SUPER_FEATURE: usize = FEATURES / SUPER_FEATURES;
+
+// Odd multipliers and arbitrary offsets for the linear transforms. Any values work as long as the multipliers are odd
+// (so that the transform is a permutation) and they differ from each other.
+const MULTIPLIERS: [u64; FEATURES] = [
+    0x9E37_79B9_7F4A_7C15,
+    0xBF58_476D_1CE4_E5B9,
+    0x94D0_49BB_1331_11EB,
+    0xD6E8_FEB8_6659_FD93,
+    0xA076_1D64_78BD_642F,
+    0xE703_7ED1_A0B4_28DB,
+    0x8EBC_6AF0_9C88_C6E3,
+    0x5899_65CC_7537_4CC3,
+    0x1D8E_4E27_C47D_124F,
+    0xC2B2_AE3D_27D4_EB4F,
+    0x1656_67B1_9E37_79F9,
+    0x27D4_EB2F_1656_67C5,
+];
+const OFFSETS: [u64; FEATURES] = [
+    0x0123_4567_89AB_CDEF,
+    0xFEDC_BA98_7654_3210,
+    0x0F1E_2D3C_4B5A_6978,
+    0x8796_A5B4_C3D2_E1F0,
+    0x1357_9BDF_0246_8ACE,
+    0xECA8_6420_FDB9_7531,
+    0x5A5A_5A5A_A5A5_A5A5,
+    0x3C3C_C3C3_3C3C_C3C3,
+    0x6996_9669_6996_9669,
+    0x0FF0_F00F_0FF0_F00F,
+    0x7777_1111_BBBB_DDDD,
+    0x2468_ACE0_1357_9BDF,
+];
+
+// A compact summary of a chunk's contents that can be compared with other sketches to estimate resemblance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sketch {
+    pub super_features: [u64; SUPER_FEATURES],
+}
+
+impl Sketch {
+    pub fn of(chunk: &[u8]) -> Sketch {
+        let mut features = [0u64; FEATURES];
+        let mut hasher = crate::rolling_hash::RollingHash::new();
+        for &b in chunk {
+            hasher.hash_byte(b);
+            let hash = hasher.hash();
+            for (i, feature) in features.iter_mut().enumerate() {
+                let transformed = hash.wrapping_mul(MULTIPLIERS[i]).wrapping_add(OFFSETS[i]);
+                if transformed > *feature {
+                    *feature = transformed;
+                }
+            }
+        }
+
+        let mut super_features = [0u64; SUPER_FEATURES];
+        for (i, group) in features.chunks(FEATURES_PER_SUPER_FEATURE).enumerate() {
+            super_features[i] = group.iter().fold(i as u64, |acc, &f| mix(acc ^ f));
+        }
+        Sketch { super_features }
+    }
+
+    // Returns how many super-features the two sketches have in common (0 to SUPER_FEATURES). Higher means more alike.
+    pub fn resemblance(&self, other: &Sketch) -> usize {
+        self.super_features
+            .iter()
+            .zip(other.super_features.iter())
+            .filter(|(a, b)| a == b)
+            .count()
+    }
+}
+
+// An index of sketches that can answer "which chunk that I've seen looks most like this one?". Each super-feature
+// position has its own table, so a lookup is SUPER_FEATURES hash lookups no matter how many chunks are indexed.
+pub struct ResemblanceIndex<T> {
+    tables: Vec<HashMap<u64, Vec<T>>>,
+}
+
+impl<T: Clone + Eq + Hash> Default for ResemblanceIndex<T> {
+    fn default() -> Self {
+        ResemblanceIndex::new()
+    }
+}
+
+impl<T: Clone + Eq + Hash> ResemblanceIndex<T> {
+    pub fn new() -> ResemblanceIndex<T> {
+        ResemblanceIndex {
+            tables: vec![HashMap::new(); SUPER_FEATURES],
+        }
+    }
+
+    // Adds a chunk (identified by 'id') to the index.
+    pub fn insert(&mut self, sketch: &Sketch, id: T) {
+        for (table, &super_feature) in self.tables.iter_mut().zip(sketch.super_features.iter()) {
+            table.entry(super_feature).or_default().push(id.clone());
+        }
+    }
+
+    // Finds the indexed chunk that shares the most super-features with 'sketch', returning it along with the number of
+    // super-features in common. Returns None if no indexed chunk shares any.
+    pub fn find_similar(&self, sketch: &Sketch) -> Option<(T, usize)> {
+        let mut counts: HashMap<&T, usize> = HashMap::new();
+        for (table, super_feature) in self.tables.iter().zip(sketch.super_features.iter()) {
+            if let Some(ids) = table.get(super_feature) {
+                for id in ids {
+                    *counts.entry(id).or_insert(0) += 1;
+                }
+            }
+        }
+
+        counts
+            .into_iter()
+            .max_by_key(|&(_, count)| count)
+            .map(|(id, count)| (id.clone(), count))
+    }
+}
+
+// The finalizer from SplitMix64. Spreads every input bit across the whole output so grouped features hash well.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::distributions::Distribution;
+
+    #[test]
+    fn test_find_similar_chunk() {
+        let mut rng = rand::thread_rng();
+        let original: Vec<u8> = rand::distributions::Standard
+            .sample_iter(&mut rng)
+            .take(8192)
+            .collect();
+        let unrelated: Vec<u8> = rand::distributions::Standard
+            .sample_iter(&mut rng)
+            .take(8192)
+            .collect();
+
+        // A lightly edited copy of the original
+        let mut edited = original.clone();
+        edited[4000] ^= 0xFF;
+        edited[4001] ^= 0xFF;
+
+        let mut index = crate::sketch::ResemblanceIndex::new();
+        index.insert(&crate::sketch::Sketch::of(&original), "original");
+        index.insert(&crate::sketch::Sketch::of(&unrelated), "unrelated");
+
+        let (id, count) = index
+            .find_similar(&crate::sketch::Sketch::of(&edited))
+            .unwrap();
+        assert_eq!(id, "original");
+        assert!(count >= 2);
+
+        let other: Vec<u8> = rand::distributions::Standard
+            .sample_iter(&mut rng)
+            .take(8192)
+            .collect();
+        assert_eq!(index.find_similar(&crate::sketch::Sketch::of(&other)), None);
+    }
+}
@@ -0,0 +1,112 @@
+use std::io;
+use std::io::Write;
+use std::path;
+
+// Combines the merged files of several output directories, oldest first, into the data they share and how the unique
+// data grew from one to the next. Every directory has to be merged first.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let dirs: Vec<&path::Path> = matches
+        .values_of("dirs")
+        .unwrap()
+        .map(path::Path::new)
+        .collect();
+    let mut locks = vec![];
+    for dir in dirs.iter() {
+        match crate::lock_dir(dir, dedup_core::lock::LockKind::Shared) {
+            Some(lock) => locks.push(lock),
+            None => return crate::EXIT_FATAL,
+        }
+    }
+
+    let mut aggregate = match dedup_core::aggregate::aggregate(&dirs) {
+        Ok(aggregate) => aggregate,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "ERROR: every output directory has to be merged first: {}",
+                e
+            );
+            return crate::EXIT_FATAL;
+        }
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    for (summary, dir) in aggregate.runs.iter_mut().zip(dirs.iter()) {
+        summary.merged = dedup_core::history::open(dir)
+            .and_then(|db| dedup_core::history::runs(&db))
+            .ok()
+            .and_then(|runs| runs.last().map(|run| run.finished));
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let written = match matches.value_of("format") {
+        Some("json") => serde_json::to_writer_pretty(&mut out, &aggregate)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(out)),
+        _ => write_text(&mut out, &aggregate),
+    };
+    if let Err(e) = written.and_then(|_| out.flush()) {
+        eprintln!("ERROR: can't write the aggregate: {}", e);
+        return crate::EXIT_FATAL;
+    }
+    crate::EXIT_SUCCESS
+}
+
+fn write_text(out: &mut dyn Write, aggregate: &dedup_core::aggregate::Aggregate) -> io::Result<()> {
+    writeln!(out, "{:>4} {:>20} directory", "run", "merged (UTC)")?;
+    for (i, summary) in aggregate.runs.iter().enumerate() {
+        writeln!(
+            out,
+            "{:>4} {:>20} {}",
+            i + 1,
+            summary
+                .merged
+                .map_or(String::new(), dedup_core::history::format_time),
+            summary.dir
+        )?;
+    }
+
+    // Each cell is the percent of the row's unique bytes that the column's run has too
+    writeln!(out)?;
+    writeln!(
+        out,
+        "Percent of each run's unique bytes found in the others:"
+    )?;
+    write!(out, "{:>4}", "")?;
+    for j in 0..aggregate.runs.len() {
+        write!(out, " {:>9}", j + 1)?;
+    }
+    writeln!(out)?;
+    for (i, row) in aggregate.overlap.iter().enumerate() {
+        write!(out, "{:>4}", i + 1)?;
+        for &bytes in row.iter() {
+            let percent = (bytes * 100) as f64 / row[i].max(1) as f64;
+            write!(out, " {:>8.4}%", percent)?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Growth of the unique chunks over the runs:")?;
+    writeln!(
+        out,
+        "{:>4} {:>16} {:>12} {:>16} {:>16} {:>16}",
+        "run", "unique bytes", "new chunks", "new bytes", "total chunks", "total bytes"
+    )?;
+    for (i, summary) in aggregate.runs.iter().enumerate() {
+        writeln!(
+            out,
+            "{:>4} {:>16} {:>12} {:>16} {:>16} {:>16}",
+            i + 1,
+            summary.unique_bytes,
+            summary.new_chunks,
+            summary.new_bytes,
+            summary.total_unique_chunks,
+            summary.total_unique_bytes
+        )?;
+    }
+    writeln!(out, "{} collisions", aggregate.collisions)?;
+    Ok(())
+}
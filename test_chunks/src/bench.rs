@@ -0,0 +1,171 @@
+use std::collections;
+use std::time;
+
+// The chunking algorithms the bench can compare, by the names --algorithms takes
+pub const ALGORITHMS: &[&str] = &["fixed", "rabin"];
+
+// What chunking the directories one way found, and how long it took
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Measured {
+    pub elapsed: time::Duration,
+    pub bytes: u64,
+    pub chunks: u64,
+    pub unique_bytes: u64,
+    pub unique_chunks: u64,
+}
+
+// Chunks the same directories with each algorithm in turn and prints a table comparing them. Nothing is written to
+// disk; chunks are told apart by the first 64 bits of their ids, which are kept in memory for each algorithm in turn.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let roots = match crate::scan::roots(matches) {
+        Some(roots) => roots,
+        None => return crate::EXIT_FATAL,
+    };
+    let mut options = match crate::scan::pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+    let variable = options.chunking;
+    let algorithms: Vec<&str> = matches
+        .values_of("algorithms")
+        .map_or(ALGORITHMS.to_vec(), |a| a.collect());
+
+    let mut results = vec![];
+    let mut skipped = dedup_core::skipped::Skipped::default();
+    for &name in algorithms.iter() {
+        options.chunking = match name {
+            "fixed" => dedup_core::pipeline::Chunking::Fixed,
+            "rabin" => variable,
+            _ => unreachable!(),
+        };
+        tracing::info!(algorithm = name, "benchmarking");
+        let (measured, skipped_now) =
+            measure(dedup_core::pipeline::Input::Walk(roots.clone()), &options);
+        // Every algorithm reads the same files, so they all skip the same ones
+        if results.is_empty() {
+            skipped = skipped_now;
+        }
+        results.push((name, measured));
+    }
+
+    println!(
+        "{:<10} {:>10} {:>12} {:>10} {:>16} {:>9} {:>8}",
+        "algorithm", "MB/s", "chunks", "avg chunk", "unique bytes", "unique", "ratio"
+    );
+    for (name, m) in results.iter() {
+        println!(
+            "{:<10} {:>10.1} {:>12} {:>10} {:>16} {:>8.4}% {:>7.3}x",
+            name,
+            m.bytes as f64 / 1_000_000.0 / m.elapsed.as_secs_f64().max(1e-9),
+            m.chunks,
+            m.bytes / m.chunks.max(1),
+            m.unique_bytes,
+            (m.unique_bytes * 100) as f64 / m.bytes.max(1) as f64,
+            m.bytes as f64 / m.unique_bytes.max(1) as f64
+        );
+    }
+    // The first pass reads the files from disk, and the rest most likely from the page cache
+    if results.len() > 1 {
+        println!("The algorithms ran in the order shown, so the first was the only one that may have waited on the disk");
+    }
+    crate::skipped::print(&skipped);
+    crate::EXIT_SUCCESS
+}
+
+// Chunks the files and counts the chunks and bytes, in total and without duplicates
+pub fn measure(
+    input: dedup_core::pipeline::Input,
+    options: &dedup_core::pipeline::Options,
+) -> (Measured, dedup_core::skipped::Skipped) {
+    let started = time::Instant::now();
+    let mut measured = Measured::default();
+    let mut seen = collections::HashSet::new();
+    let scanned = dedup_core::pipeline::run(input, options, &mut |event| {
+        let batch = match event {
+            dedup_core::pipeline::Event::Hashed(batch) => batch,
+            _ => return,
+        };
+        for c in batch.chunks {
+            let mut prefix = [0u8; 8];
+            prefix.copy_from_slice(&c.key[0..8]);
+            measured.chunks += 1;
+            measured.bytes += c.size as u64;
+            if seen.insert(u64::from_be_bytes(prefix)) {
+                measured.unique_chunks += 1;
+                measured.unique_bytes += c.size as u64;
+            }
+        }
+    });
+    measured.elapsed = started.elapsed();
+    (measured, scanned.skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_measure() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_bench_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Pseudo-random, so nothing within a file repeats
+        let mut x = 1u64;
+        let data: Vec<u8> = (0..1_000_000)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect();
+        fs::write(dir.join("a"), &data).unwrap();
+        fs::write(dir.join("copy"), &data).unwrap();
+
+        for chunking in [
+            dedup_core::pipeline::Chunking::Fixed,
+            dedup_core::pipeline::Chunking::Variable(
+                rabin::chunker::ChunkerBuilder::new(
+                    dedup_core::MIN_CHUNK_SIZE,
+                    dedup_core::MAX_CHUNK_SIZE,
+                )
+                .unwrap(),
+            ),
+        ] {
+            let options = dedup_core::pipeline::Options {
+                chunking,
+                key_len: dedup_core::KEY_LEN,
+                threads: 2,
+                hash_cpus: None,
+                io_cpus: None,
+                symlinks: dedup_core::walk::Symlinks::Skip,
+                detect_hard_links: true,
+                streams: false,
+                mmap: true,
+                uring: None,
+                direct_io: false,
+                resume: vec![],
+                compress_every: None,
+                only: None,
+                min_file_size: None,
+                max_file_size: None,
+                cache: None,
+                sub_blocks: None,
+                disk_images: false,
+                anchors: vec![],
+                compressed: dedup_core::compressed::Policy::Chunk,
+                archives: false,
+            };
+            let input = dedup_core::pipeline::Input::Walk(vec![dedup_core::pipeline::Root {
+                dir: dir.clone(),
+                exclude: ignore::gitignore::Gitignore::empty(),
+            }]);
+            let (measured, skipped) = crate::bench::measure(input, &options);
+            assert_eq!(skipped.count(), 0);
+            assert_eq!(measured.bytes, 2_000_000);
+            assert_eq!(measured.unique_bytes, 1_000_000);
+            assert_eq!(measured.chunks, measured.unique_chunks * 2);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use std::io;
+
+// Compares the merged files of two output directories, the way a backup of the later one would be stored in a
+// repository that already holds the earlier one. Both directories have to be merged first.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+    let (base_dir, _base_lock) =
+        match crate::open_dir(matches, "base", dedup_core::lock::LockKind::Shared) {
+            Some(output) => output,
+            None => return crate::EXIT_FATAL,
+        };
+
+    let comparison = match dedup_core::compare::compare(base_dir, out_dir) {
+        Ok(comparison) => comparison,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "ERROR: both output directories have to be merged first: {}",
+                e
+            );
+            return crate::EXIT_FATAL;
+        }
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+
+    // An empty scan has no bytes to take a percent of, and is 0% of everything
+    let percent = |part: u64, whole: u64| 100.0 * part as f64 / whole.max(1) as f64;
+    println!(
+        "{} chunks ({} bytes) in the later scan",
+        comparison.chunks, comparison.bytes
+    );
+    println!(
+        "{} unique chunks ({} bytes) once it is deduplicated on its own",
+        comparison.unique_chunks, comparison.unique_bytes
+    );
+    println!(
+        "{} of them ({} bytes {:0.4}%) were already in the earlier scan",
+        comparison.existing_chunks,
+        comparison.existing_bytes,
+        percent(comparison.existing_bytes, comparison.unique_bytes)
+    );
+    println!(
+        "{} new chunks ({} bytes {:0.4}% of the later scan) would have to be stored",
+        comparison.new_chunks(),
+        comparison.new_bytes(),
+        percent(comparison.new_bytes(), comparison.bytes)
+    );
+    println!("{} collisions", comparison.collisions);
+    crate::EXIT_SUCCESS
+}
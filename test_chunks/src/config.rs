@@ -0,0 +1,119 @@
+use std::ffi;
+use std::fs;
+
+// Reads the config file given with --config, if there was one, and returns the command line with its settings for the
+// subcommand appended. Parsing that again gives the same matches as if every setting had been typed out. Anything
+// already given on the command line is left out, so the command line always wins.
+//
+// The file has a table for each subcommand, keyed by the long names of its options:
+//
+//   [scan]
+//   memory = "2G"
+//   min-chunk = "4k"
+//   exclude = ["node_modules/", "*.o"]
+//   follow-symlinks = true
+pub fn args(matches: &clap::ArgMatches) -> Result<Option<Vec<ffi::OsString>>, String> {
+    let (subcommand, sub_matches) = match matches.subcommand() {
+        (name, Some(sub_matches)) => (name, sub_matches),
+        _ => return Ok(None),
+    };
+    let name = match sub_matches.value_of("config") {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let text = fs::read_to_string(name).map_err(|e| format!("can't read {}: {}", name, e))?;
+    let config: toml::Value = toml::from_str(&text).map_err(|e| format!("{}: {}", name, e))?;
+    let section = match config.get(subcommand) {
+        Some(toml::Value::Table(section)) => section,
+        Some(_) => return Err(format!("{}: [{}] must be a table", name, subcommand)),
+        None => return Ok(None),
+    };
+
+    let settings = section_args(section, &|arg| sub_matches.occurrences_of(arg) > 0)
+        .map_err(|e| format!("{}: {}", name, e))?;
+    let mut args: Vec<ffi::OsString> = std::env::args_os().collect();
+    args.extend(settings.into_iter().map(ffi::OsString::from));
+    Ok(Some(args))
+}
+
+// Turns one subcommand's settings into command line arguments, skipping the ones 'given' says are already there. A
+// true boolean is a flag on its own and a false one is left out, and a list repeats the option once per value.
+fn section_args(
+    section: &toml::value::Table,
+    given: &dyn Fn(&str) -> bool,
+) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    for (key, value) in section.iter() {
+        if given(key) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Boolean(true) => {
+                args.push(format!("--{}", key));
+                continue;
+            }
+            toml::Value::Boolean(false) => continue,
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            args.push(format!("--{}", key));
+            args.push(match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                _ => {
+                    return Err(format!(
+                        "'{}' must be a string, number, boolean or list of them",
+                        key
+                    ))
+                }
+            });
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+
+    #[test]
+    fn test_section_args() {
+        let config: toml::Value = toml::from_str(
+            r#"
+            [scan]
+            memory = "2G"
+            key-bits = 160
+            exclude = ["node_modules/", "*.o"]
+            follow-symlinks = true
+            no-mmap = false
+            output = "/out"
+            "#,
+        )
+        .unwrap();
+        let section = config["scan"].as_table().unwrap();
+
+        // The output directory was given on the command line, so the config's is left out
+        let args = crate::config::section_args(section, &|arg| arg == "output").unwrap();
+        assert_eq!(
+            args,
+            vec![
+                "--exclude",
+                "node_modules/",
+                "--exclude",
+                "*.o",
+                "--follow-symlinks",
+                "--key-bits",
+                "160",
+                "--memory",
+                "2G",
+            ]
+        );
+
+        let nested: toml::Value = toml::from_str("[scan]\nmemory = { size = 2 }\n").unwrap();
+        assert!(
+            crate::config::section_args(nested["scan"].as_table().unwrap(), &|_| false).is_err()
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs;
+use std::io;
+use std::path;
+
+// Compares a newer copy of the data with a signature of the older one and writes a delta holding only what the older
+// copy doesn't have
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let name = matches.value_of("signature").unwrap();
+    let signature = match fs::File::open(name)
+        .and_then(|file| dedup_core::signature::Signature::read(io::BufReader::new(file)))
+    {
+        Ok(signature) => signature,
+        Err(e) => {
+            eprintln!("ERROR: can't read the signature {:?}: {}", name, e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let source = path::Path::new(matches.value_of("source").unwrap());
+    if !source.exists() {
+        eprintln!("ERROR: {:?} doesn't exist", source);
+        return crate::EXIT_FATAL;
+    }
+    let symlinks = if matches.is_present("follow-symlinks") {
+        dedup_core::walk::Symlinks::Follow
+    } else {
+        dedup_core::walk::Symlinks::Skip
+    };
+
+    let (delta, skipped) = match dedup_core::delta::compute(&signature, source, symlinks) {
+        Ok(computed) => computed,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let out = matches.value_of("delta").unwrap();
+    let written = fs::File::create(out).and_then(|file| {
+        let mut writer = io::BufWriter::new(file);
+        delta.write(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+    });
+    if let Err(e) = written {
+        eprintln!("ERROR: can't write the delta to {:?}: {}", out, e);
+        return crate::EXIT_FATAL;
+    }
+
+    let copied = delta.copied_bytes();
+    let literal = delta.literal_bytes();
+    say!(
+        "{} files ({} bytes): {} bytes {:0.4}% copied from the signed files and {} bytes sent in {:?} ({} bytes)",
+        delta.files.len(),
+        copied + literal,
+        copied,
+        (copied * 100) as f64 / (copied + literal).max(1) as f64,
+        literal,
+        out,
+        fs::metadata(out).map_or(0, |m| m.len())
+    );
+    crate::skipped::print(&skipped);
+    crate::exit_status(0, skipped.count())
+}
+
+// Rebuilds the newer copy of the data from the older one and a delta
+pub fn apply(matches: &clap::ArgMatches) -> i32 {
+    let name = matches.value_of("delta").unwrap();
+    let delta = match fs::File::open(name)
+        .and_then(|file| dedup_core::delta::Delta::read(io::BufReader::new(file)))
+    {
+        Ok(delta) => delta,
+        Err(e) => {
+            eprintln!("ERROR: can't read the delta {:?}: {}", name, e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let base = path::Path::new(matches.value_of("base").unwrap());
+    let out = path::Path::new(matches.value_of("out").unwrap());
+    if let Err(e) = dedup_core::delta::apply(&delta, base, out) {
+        eprintln!("ERROR: can't rebuild {:?}: {}", out, e);
+        return crate::EXIT_FATAL;
+    }
+    say!(
+        "{} files ({} bytes) rebuilt in {:?}",
+        delta.files.len(),
+        delta.copied_bytes() + delta.literal_bytes(),
+        out
+    );
+    crate::EXIT_SUCCESS
+}
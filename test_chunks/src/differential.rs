@@ -0,0 +1,94 @@
+use std::fs;
+
+// Chunks every file in the directories with two implementations of the chunking and checks that they find exactly
+// the same chunks, printing where each file that doesn't first goes wrong. Exits with EXIT_FATAL if any file differs.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let roots = match crate::scan::roots(matches) {
+        Some(roots) => roots,
+        None => return crate::EXIT_FATAL,
+    };
+    let options = match crate::scan::pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+    let buffer_bytes = match crate::parse_memory_usage(matches.value_of("buffer").unwrap()) {
+        Ok(bytes) => bytes as usize,
+        Err(e) => {
+            eprintln!("ERROR: --buffer: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let names: Vec<&str> = matches.values_of("implementations").unwrap().collect();
+    let implementations: Vec<dedup_core::differential::Implementation> = names
+        .iter()
+        .map(|name| {
+            dedup_core::differential::Implementation::from_name(name, buffer_bytes).unwrap()
+        })
+        .collect();
+    let (first, second) = (implementations[0], implementations[1]);
+
+    let (mut files, mut bytes, mut chunks, mut diverged) = (0u64, 0u64, 0u64, 0u64);
+    let mut skipped = dedup_core::skipped::Skipped::default();
+    let mut walker =
+        dedup_core::walk::Walker::new(options.symlinks, options.detect_hard_links, options.streams);
+    for root in roots.iter() {
+        walker.walk(&root.dir, &root.exclude, &mut |path, _| {
+            let data = match fs::read(path) {
+                Ok(data) => data,
+                Err(e) => {
+                    skipped.record(path, &e);
+                    return;
+                }
+            };
+            tracing::debug!(path = %path.display(), "comparing");
+            let a = dedup_core::differential::boundaries(first, &data, &options.chunking);
+            let b = dedup_core::differential::boundaries(second, &data, &options.chunking);
+            files += 1;
+            bytes += data.len() as u64;
+            chunks += a.len() as u64;
+            if let Some(d) = dedup_core::differential::first_divergence(&data, &a, &b) {
+                diverged += 1;
+                let end = |end: Option<usize>| {
+                    end.map_or("no chunk".to_string(), |e| format!("offset {}", e))
+                };
+                println!(
+                    "{}: chunk {} ends at {} with {} but {} with {}",
+                    path.display(),
+                    d.chunk,
+                    end(d.first),
+                    first.name(),
+                    end(d.second),
+                    second.name()
+                );
+                let at = d
+                    .first
+                    .into_iter()
+                    .chain(d.second)
+                    .min()
+                    .unwrap_or(data.len())
+                    - d.context_start;
+                println!(
+                    "  bytes from offset {}: {} | {}",
+                    d.context_start,
+                    dedup_core::hex_key(&d.context[..at]),
+                    dedup_core::hex_key(&d.context[at..])
+                );
+            }
+        });
+    }
+
+    println!(
+        "{} files ({} bytes, {} chunks) compared with {} and {}: {} differed",
+        files,
+        bytes,
+        chunks,
+        first.name(),
+        second.name(),
+        diverged
+    );
+    crate::skipped::print(&skipped);
+    if diverged > 0 {
+        return crate::EXIT_FATAL;
+    }
+    crate::exit_status(0, skipped.count())
+}
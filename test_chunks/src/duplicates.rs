@@ -0,0 +1,294 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+
+use serde_derive::Serialize;
+
+// What can be done with one copy of a file that has duplicates
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    // The copy the others would become links or clones of: the first one the scan found that is still there
+    Kept,
+    // Can be replaced by a hard link to the kept copy, or made a clone of it
+    Candidate,
+    // Already a hard link to the kept copy, so it takes no space of its own
+    Linked,
+    // On another filesystem than the kept copy, which neither links nor clones can reach
+    OtherFilesystem,
+    // Gone, or can't be looked at, since the scan
+    Missing,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Copy {
+    pub path: String,
+    pub status: Status,
+}
+
+// Files whose chunks are all the same, and so hold the same bytes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Group {
+    pub size: u64,
+    // What linking or cloning the candidates would save
+    pub saved: u64,
+    pub copies: Vec<Copy>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Duplicates {
+    pub candidates: u64,
+    pub saved: u64,
+    pub groups: Vec<Group>,
+}
+
+// Lists the files the last merge found to be identical, and which of them could be hard links or clones of one copy,
+// largest savings first. Optionally writes a shell script that makes them so.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+    let files = match crate::open_merged(out_dir, |dir| {
+        dedup_core::files::read_records(&dir.join(dedup_core::files::FILE_REPORT_NAME))
+    }) {
+        Ok(files) => files,
+        Err(status) => return status,
+    };
+    let duplicates = find(&files);
+
+    if let Some(name) = matches.value_of("script") {
+        let hard_links = matches.value_of("script-action") == Some("hardlink");
+        if let Err(e) = write_script(path::Path::new(name), &duplicates, hard_links) {
+            eprintln!("ERROR: can't write the script to {:?}: {}", name, e);
+            return crate::EXIT_FATAL;
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let written = match matches.value_of("format") {
+        Some("json") => serde_json::to_writer_pretty(&mut out, &duplicates)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(out)),
+        _ => write_text(&mut out, &duplicates),
+    };
+    if let Err(e) = written.and_then(|_| out.flush()) {
+        eprintln!("ERROR: can't write the duplicates: {}", e);
+        return crate::EXIT_FATAL;
+    }
+    crate::EXIT_SUCCESS
+}
+
+// Groups the identical files and looks at each copy to see what can be done with it
+pub fn find(files: &[dedup_core::files::FileRecord]) -> Duplicates {
+    let mut duplicates = Duplicates::default();
+    for group in dedup_core::reflink::duplicate_files(files) {
+        // None for a copy that's missing, and Some(None) where the platform can't tell files apart
+        let ids: Vec<Option<Option<(u64, u64)>>> = group
+            .iter()
+            .map(|&i| {
+                let path = path::Path::new(&files[i].path);
+                fs::metadata(path)
+                    .ok()
+                    .map(|metadata| dedup_core::walk::file_id(path, &metadata))
+            })
+            .collect();
+        let kept = ids.iter().position(|id| id.is_some());
+        let copies: Vec<Copy> = group
+            .iter()
+            .enumerate()
+            .map(|(j, &i)| Copy {
+                path: files[i].path.clone(),
+                status: match (ids[j], kept.and_then(|k| ids[k])) {
+                    (None, _) => Status::Missing,
+                    _ if Some(j) == kept => Status::Kept,
+                    (Some(Some(id)), Some(Some(kept))) if id == kept => Status::Linked,
+                    (Some(Some((device, _))), Some(Some((kept, _)))) if device != kept => {
+                        Status::OtherFilesystem
+                    }
+                    _ => Status::Candidate,
+                },
+            })
+            .collect();
+        let size = files[group[0]].size;
+        let candidates = copies
+            .iter()
+            .filter(|c| c.status == Status::Candidate)
+            .count() as u64;
+        duplicates.candidates += candidates;
+        duplicates.saved += size * candidates;
+        duplicates.groups.push(Group {
+            size,
+            saved: size * candidates,
+            copies,
+        });
+    }
+    duplicates
+        .groups
+        .sort_by(|a, b| b.saved.cmp(&a.saved).then(b.size.cmp(&a.size)));
+    duplicates
+}
+
+fn write_text(out: &mut dyn Write, duplicates: &Duplicates) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} groups of identical files; {} duplicates ({} bytes) could be hard links or clones of the first copy",
+        duplicates.groups.len(),
+        duplicates.candidates,
+        duplicates.saved
+    )?;
+    for group in duplicates.groups.iter() {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{} copies of {} bytes, saving {} bytes:",
+            group.copies.len(),
+            group.size,
+            group.saved
+        )?;
+        for copy in group.copies.iter() {
+            let note = match copy.status {
+                Status::Kept => " (kept)",
+                Status::Candidate => "",
+                Status::Linked => " (already a hard link to the kept copy)",
+                Status::OtherFilesystem => " (on another filesystem)",
+                Status::Missing => " (missing)",
+            };
+            writeln!(out, "    {}{}", copy.path, note)?;
+        }
+    }
+    Ok(())
+}
+
+// Writes a shell script that replaces every candidate with a hard link to its group's kept copy, or makes it a clone
+// of the kept copy with cp --reflink=always. Each candidate is compared with the kept copy first, so files that
+// changed since the scan are left alone.
+pub fn write_script(
+    name: &path::Path,
+    duplicates: &Duplicates,
+    hard_links: bool,
+) -> io::Result<()> {
+    let mut script = io::BufWriter::new(fs::File::create(name)?);
+    writeln!(script, "#!/bin/sh")?;
+    writeln!(
+        script,
+        "# Written by test_chunks duplicate-files. Each duplicate is compared with the copy it is replaced by first."
+    )?;
+    for group in duplicates.groups.iter() {
+        let kept = match group.copies.iter().find(|c| c.status == Status::Kept) {
+            Some(kept) => quote(&kept.path),
+            None => continue,
+        };
+        for copy in group
+            .copies
+            .iter()
+            .filter(|c| c.status == Status::Candidate)
+        {
+            let copy = quote(&copy.path);
+            if hard_links {
+                writeln!(
+                    script,
+                    "cmp -s -- {} {} && ln -f -- {} {}",
+                    kept, copy, kept, copy
+                )?;
+            } else {
+                writeln!(
+                    script,
+                    "cmp -s -- {} {} && cp --reflink=always -- {} {}",
+                    kept, copy, kept, copy
+                )?;
+            }
+        }
+    }
+    script.flush()?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(name, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+// Quotes a path for the shell. Nothing is special inside single quotes, except a single quote itself.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_find() {
+        let dir =
+            std::env::temp_dir().join(format!("test_chunks_duplicates_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"same").unwrap();
+        fs::write(dir.join("it's"), b"same").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("link")).unwrap();
+        fs::write(dir.join("other"), b"else").unwrap();
+
+        let key = dedup_core::run::key_from(&[9; dedup_core::KEY_LEN], dedup_core::KEY_LEN);
+        let record = |name: &str| {
+            let mut record = dedup_core::files::FileRecord {
+                path: dir.join(name).to_string_lossy().into_owned(),
+                size: 4,
+                chunks: 1,
+                ..Default::default()
+            };
+            record.add_chunk(0, &key);
+            record
+        };
+        let files = [
+            record("gone"),
+            record("a"),
+            record("it's"),
+            record("link"),
+            dedup_core::files::FileRecord {
+                path: dir.join("other").to_string_lossy().into_owned(),
+                size: 4,
+                chunks: 1,
+                ..Default::default()
+            },
+        ];
+        let duplicates = crate::duplicates::find(&files);
+        assert_eq!(duplicates.groups.len(), 1);
+        assert_eq!(duplicates.candidates, 1);
+        assert_eq!(duplicates.saved, 4);
+        let statuses: Vec<crate::duplicates::Status> = duplicates.groups[0]
+            .copies
+            .iter()
+            .map(|c| c.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                crate::duplicates::Status::Missing,
+                crate::duplicates::Status::Kept,
+                crate::duplicates::Status::Candidate,
+                crate::duplicates::Status::Linked
+            ]
+        );
+
+        // The script only links the candidate, and quotes its name
+        let script = dir.join("script.sh");
+        crate::duplicates::write_script(&script, &duplicates, true).unwrap();
+        let text = fs::read_to_string(&script).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.contains("ln -f -- "));
+        assert!(text.contains("/it'\\''s' && ln"));
+        #[cfg(unix)]
+        {
+            let status = std::process::Command::new("sh")
+                .arg(&script)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert!(dedup_core::reflink::same_file(&dir.join("a"), &dir.join("it's")).unwrap());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
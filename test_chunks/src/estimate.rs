@@ -0,0 +1,298 @@
+use std::collections;
+use std::collections::hash_map;
+use std::hash::BuildHasher;
+use std::path;
+
+// How many standard errors either side of the estimate the confidence interval spans (95%)
+const CONFIDENCE_Z: f64 = 1.96;
+
+// The files in one size class: every power of two gets a class of its own
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Stratum {
+    // How many files the walk found, and how many of them were picked
+    files: u64,
+    sampled: u64,
+}
+
+// The files picked for the sample, out of everything the walk found
+struct Sample {
+    strata: collections::BTreeMap<u32, Stratum>,
+    // The class and length of each file picked
+    picked: collections::HashMap<path::PathBuf, (u32, u64)>,
+    // The length of every file found, picked or not
+    total_bytes: u64,
+}
+
+// A sampled file's contribution to the estimate
+#[derive(Debug, Default, Clone, Copy)]
+struct SampledFile {
+    stratum: u32,
+    bytes: f64,
+    // Each chunk's size divided by the number of times it turned up in the sample, so a chunk stored once is shared
+    // out between all its copies
+    unique: f64,
+}
+
+// The fraction of the data that would be unique, with the bounds of its confidence interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Estimate {
+    unique: f64,
+    low: f64,
+    high: f64,
+}
+
+// Chunks a random 'percent' of the files in the directories and extrapolates how much of all of them would be
+// unique. Small files and large ones dedup very differently, so the files are sampled separately from each power of
+// two of sizes, and every size found gets at least one file in the sample. Nothing is written to the output directory.
+// Returns the exit status.
+pub fn run(
+    roots: Vec<dedup_core::pipeline::Root>,
+    options: &mut dedup_core::pipeline::Options,
+    percent: f64,
+) -> i32 {
+    let Sample {
+        strata,
+        picked,
+        total_bytes,
+    } = pick(&roots, options, percent);
+    let sampled_files = picked.len();
+    let sampled_bytes: u64 = picked.values().map(|&(_, len)| len).sum();
+    let stratum_of: collections::HashMap<String, u32> = picked
+        .iter()
+        .map(|(path, &(stratum, _))| (path.to_string_lossy().into_owned(), stratum))
+        .collect();
+    options.only = Some(picked.into_keys().collect());
+
+    // Which file each chunk came from is kept until the end, when it is known how often each chunk turned up. The
+    // first 64 bits of a chunk id are plenty to tell chunks apart for an estimate.
+    let mut files: Vec<SampledFile> = vec![];
+    let mut chunks: Vec<(u32, u64, u32)> = vec![];
+    let mut counts: collections::HashMap<u64, u32> = collections::HashMap::new();
+    let scanned = dedup_core::pipeline::run(
+        dedup_core::pipeline::Input::Walk(roots),
+        options,
+        &mut |event| match event {
+            dedup_core::pipeline::Event::Found { file, path, .. } => {
+                if files.len() <= file as usize {
+                    files.resize(file as usize + 1, SampledFile::default());
+                }
+                files[file as usize].stratum = stratum_of[&path];
+            }
+            dedup_core::pipeline::Event::Hashed(batch) => {
+                for c in batch.chunks {
+                    let mut prefix = [0u8; 8];
+                    prefix.copy_from_slice(&c.key[0..8]);
+                    let prefix = u64::from_be_bytes(prefix);
+                    *counts.entry(prefix).or_insert(0) += 1;
+                    chunks.push((batch.file, prefix, c.size));
+                }
+            }
+            dedup_core::pipeline::Event::Finished { .. } => {}
+        },
+    );
+    for (file, prefix, size) in chunks {
+        let file = &mut files[file as usize];
+        file.bytes += size as f64;
+        file.unique += size as f64 / counts[&prefix] as f64;
+    }
+
+    say!(
+        "{} files ({} bytes) were sampled out of {} files ({} bytes)",
+        sampled_files,
+        sampled_bytes,
+        strata.values().map(|s| s.files).sum::<u64>(),
+        total_bytes
+    );
+    match extrapolate(&strata, &files) {
+        Some(estimate) => {
+            say!(
+                "~{:.0} bytes {:0.4}% would be unique (95% confidence: {:0.4}% to {:0.4}%)",
+                estimate.unique * total_bytes as f64,
+                estimate.unique * 100.0,
+                estimate.low * 100.0,
+                estimate.high * 100.0
+            );
+            say!("Duplicates of sampled data in files that weren't sampled can't be seen, so the real figure is usually lower, especially for small samples");
+        }
+        None => say!("nothing was sampled"),
+    }
+    crate::skipped::print(&scanned.skipped);
+    crate::exit_status(0, scanned.skipped.count())
+}
+
+// Picks a random 'percent' of the files in the directories the same way the estimate does
+pub fn sample(
+    roots: &[dedup_core::pipeline::Root],
+    options: &dedup_core::pipeline::Options,
+    percent: f64,
+) -> collections::HashSet<path::PathBuf> {
+    pick(roots, options, percent).picked.into_keys().collect()
+}
+
+// Walks the directories the way the scan would and picks the sample from each size class at random
+fn pick(
+    roots: &[dedup_core::pipeline::Root],
+    options: &dedup_core::pipeline::Options,
+    percent: f64,
+) -> Sample {
+    let mut found: collections::BTreeMap<u32, Vec<(path::PathBuf, u64)>> =
+        collections::BTreeMap::new();
+    let mut total_bytes = 0;
+    let mut walker =
+        dedup_core::walk::Walker::new(options.symlinks, options.detect_hard_links, options.streams);
+    for root in roots {
+        walker.walk(&root.dir, &root.exclude, &mut |path, kind| {
+            let len = dedup_core::catalog::Metadata::read(path, kind).len;
+            total_bytes += len;
+            found
+                .entry(stratum(len))
+                .or_default()
+                .push((path.to_path_buf(), len));
+        });
+    }
+
+    // Hashing the paths with a randomly seeded hasher shuffles them differently every time
+    let random = hash_map::RandomState::new();
+    let mut strata = collections::BTreeMap::new();
+    let mut picked = collections::HashMap::new();
+    for (class, mut files) in found {
+        let sampled = sample_size(files.len() as u64, percent);
+        files.sort_by_cached_key(|(path, _)| random.hash_one(path));
+        for (path, len) in files.iter().take(sampled as usize) {
+            picked.insert(path.clone(), (class, *len));
+        }
+        strata.insert(
+            class,
+            Stratum {
+                files: files.len() as u64,
+                sampled,
+            },
+        );
+    }
+    Sample {
+        strata,
+        picked,
+        total_bytes,
+    }
+}
+
+// Empty files get a class of their own, and the rest are grouped by the power of two at or below their length
+fn stratum(len: u64) -> u32 {
+    64 - len.leading_zeros()
+}
+
+// How many of a class's files to sample, which is never none of them
+fn sample_size(files: u64, percent: f64) -> u64 {
+    ((files as f64 * percent / 100.0).ceil() as u64).clamp(1, files)
+}
+
+// The stratified ratio estimate of the fraction that is unique. Each class's files stand in for the ones that weren't
+// picked, and the interval comes from how much the sampled files in each class disagree with each other, shrinking to
+// nothing for classes that were sampled completely.
+fn extrapolate(
+    strata: &collections::BTreeMap<u32, Stratum>,
+    files: &[SampledFile],
+) -> Option<Estimate> {
+    // Each sampled byte stands in for the bytes of every file in its class that wasn't picked
+    let weight = |f: &SampledFile| {
+        let s = &strata[&f.stratum];
+        s.files as f64 / s.sampled as f64
+    };
+    let bytes: f64 = files.iter().map(|f| weight(f) * f.bytes).sum();
+    if bytes == 0.0 {
+        return None;
+    }
+    let unique = files.iter().map(|f| weight(f) * f.unique).sum::<f64>() / bytes;
+
+    let mut variance = 0.0;
+    for (class, s) in strata.iter() {
+        let residuals: Vec<f64> = files
+            .iter()
+            .filter(|f| f.stratum == *class)
+            .map(|f| f.unique - unique * f.bytes)
+            .collect();
+        let n = residuals.len() as f64;
+        if n < 2.0 {
+            continue;
+        }
+        let mean = residuals.iter().sum::<f64>() / n;
+        let spread = residuals
+            .iter()
+            .map(|r| (r - mean) * (r - mean))
+            .sum::<f64>()
+            / (n - 1.0);
+        let population = s.files as f64;
+        variance += population * population * (1.0 - n / population) * spread / n;
+    }
+    let margin = CONFIDENCE_Z * variance.sqrt() / bytes;
+    Some(Estimate {
+        unique,
+        low: (unique - margin).max(0.0),
+        high: (unique + margin).min(1.0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections;
+
+    #[test]
+    fn test_sample_size() {
+        assert_eq!(crate::estimate::stratum(0), 0);
+        assert_eq!(crate::estimate::stratum(1), 1);
+        assert_eq!(crate::estimate::stratum(4095), 12);
+        assert_eq!(crate::estimate::stratum(4096), 13);
+
+        assert_eq!(crate::estimate::sample_size(1000, 1.0), 10);
+        assert_eq!(crate::estimate::sample_size(1001, 1.0), 11);
+        assert_eq!(crate::estimate::sample_size(3, 1.0), 1);
+        assert_eq!(crate::estimate::sample_size(3, 100.0), 3);
+    }
+
+    #[test]
+    fn test_extrapolate() {
+        let file = |stratum, bytes, unique| crate::estimate::SampledFile {
+            stratum,
+            bytes,
+            unique,
+        };
+        let mut strata = collections::BTreeMap::new();
+        strata.insert(
+            1,
+            crate::estimate::Stratum {
+                files: 2,
+                sampled: 2,
+            },
+        );
+        strata.insert(
+            2,
+            crate::estimate::Stratum {
+                files: 10,
+                sampled: 2,
+            },
+        );
+
+        // Class 1 was sampled completely and is all unique. Each file in class 2 stands for 5, and half of it is.
+        let files = [
+            file(1, 100.0, 100.0),
+            file(1, 100.0, 100.0),
+            file(2, 1000.0, 400.0),
+            file(2, 1000.0, 600.0),
+        ];
+        let estimate = crate::estimate::extrapolate(&strata, &files).unwrap();
+        assert!((estimate.unique - 5200.0 / 10200.0).abs() < 1e-9);
+        assert!(estimate.low < estimate.unique && estimate.unique < estimate.high);
+
+        // Files that agree exactly leave no doubt
+        let files = [
+            file(1, 100.0, 100.0),
+            file(1, 100.0, 100.0),
+            file(2, 1000.0, 500.0),
+            file(2, 1000.0, 500.0),
+        ];
+        let estimate = crate::estimate::extrapolate(&strata, &files).unwrap();
+        assert!((estimate.high - estimate.low).abs() < 1e-9);
+
+        assert_eq!(crate::estimate::extrapolate(&strata, &[]), None);
+    }
+}
@@ -0,0 +1,185 @@
+use std::fs;
+use std::io;
+use std::path;
+use std::sync;
+
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int64Type};
+
+// One row per unique chunk. The sizes and counts are unsigned in the merged file, but they are all stored as INT64
+// since that is what every tool that reads Parquet handles the same way.
+const CHUNK_SCHEMA: &str = "
+message chunk {
+    REQUIRED BYTE_ARRAY id (UTF8);
+    REQUIRED INT64 size;
+    REQUIRED INT64 occurrences;
+    REQUIRED BOOLEAN duplicate;
+    REQUIRED BYTE_ARRAY file (UTF8);
+    REQUIRED INT64 offset;
+    REQUIRED INT64 compressed;
+}";
+
+// How many rows are gathered in memory before they are written out as a row group
+const ROW_GROUP_ROWS: usize = 1 << 20;
+
+// Writes the chunks of the merged file to a Parquet file, so they can be queried with DuckDB, Spark, pandas and the
+// like. Each column is gathered separately until there are enough rows for a row group.
+pub struct ChunkWriter {
+    writer: parquet::file::writer::SerializedFileWriter<fs::File>,
+    key_len: usize,
+    ids: Vec<ByteArray>,
+    sizes: Vec<i64>,
+    occurrences: Vec<i64>,
+    duplicates: Vec<bool>,
+    files: Vec<ByteArray>,
+    offsets: Vec<i64>,
+    compressed: Vec<i64>,
+}
+
+impl ChunkWriter {
+    pub fn create(path: &path::Path, key_len: usize) -> io::Result<ChunkWriter> {
+        let schema =
+            parquet::schema::parser::parse_message_type(CHUNK_SCHEMA).map_err(io::Error::other)?;
+        let properties = parquet::file::properties::WriterProperties::builder()
+            .set_compression(parquet::basic::Compression::SNAPPY)
+            .build();
+        let writer = parquet::file::writer::SerializedFileWriter::new(
+            fs::File::create(path)?,
+            sync::Arc::new(schema),
+            sync::Arc::new(properties),
+        )
+        .map_err(io::Error::other)?;
+        Ok(ChunkWriter {
+            writer,
+            key_len,
+            ids: vec![],
+            sizes: vec![],
+            occurrences: vec![],
+            duplicates: vec![],
+            files: vec![],
+            offsets: vec![],
+            compressed: vec![],
+        })
+    }
+
+    // Adds a chunk of the merged file, along with the path of the first file it was found in
+    pub fn write(&mut self, entry: &dedup_core::run::Entry, file: &str) -> io::Result<()> {
+        self.ids.push(
+            dedup_core::hex_key(&entry.key[..self.key_len])
+                .into_bytes()
+                .into(),
+        );
+        self.sizes.push(entry.size as i64);
+        self.occurrences.push(entry.count as i64);
+        self.duplicates.push(entry.count > 1);
+        self.files.push(file.as_bytes().to_vec().into());
+        self.offsets.push(entry.offset as i64);
+        self.compressed.push(entry.compressed as i64);
+        if self.ids.len() >= ROW_GROUP_ROWS {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    // Writes whatever rows are left and the file's footer. The file isn't readable until this is done.
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.ids.is_empty() {
+            self.write_row_group()?;
+        }
+        self.writer.close().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn write_row_group(&mut self) -> io::Result<()> {
+        let mut row_group = self.writer.next_row_group().map_err(io::Error::other)?;
+        let mut column = 0;
+        while let Some(mut writer) = row_group.next_column().map_err(io::Error::other)? {
+            let written = match column {
+                0 => writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&self.ids, None, None),
+                1 => writer
+                    .typed::<Int64Type>()
+                    .write_batch(&self.sizes, None, None),
+                2 => writer
+                    .typed::<Int64Type>()
+                    .write_batch(&self.occurrences, None, None),
+                3 => writer
+                    .typed::<BoolType>()
+                    .write_batch(&self.duplicates, None, None),
+                4 => writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&self.files, None, None),
+                5 => writer
+                    .typed::<Int64Type>()
+                    .write_batch(&self.offsets, None, None),
+                _ => writer
+                    .typed::<Int64Type>()
+                    .write_batch(&self.compressed, None, None),
+            };
+            written.map_err(io::Error::other)?;
+            writer.close().map_err(io::Error::other)?;
+            column += 1;
+        }
+        row_group.close().map_err(io::Error::other)?;
+
+        self.ids.clear();
+        self.sizes.clear();
+        self.occurrences.clear();
+        self.duplicates.clear();
+        self.files.clear();
+        self.offsets.clear();
+        self.compressed.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use parquet::file::reader::FileReader;
+    use parquet::record::RowAccessor;
+
+    #[test]
+    fn test_chunk_writer() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_export_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let name = dir.join("chunks.parquet");
+
+        let entry = |key: u8, count: u32| dedup_core::run::Entry {
+            key: dedup_core::run::key_from(&[key; dedup_core::KEY_LEN], dedup_core::KEY_LEN),
+            size: 4096,
+            count,
+            offset: key as u64 * 4096,
+            ..Default::default()
+        };
+        let mut writer = crate::export::ChunkWriter::create(&name, dedup_core::KEY_LEN).unwrap();
+        writer.write(&entry(1, 1), "/data/a").unwrap();
+        writer.write(&entry(2, 3), "/data/b").unwrap();
+        writer.finish().unwrap();
+
+        let reader = parquet::file::serialized_reader::SerializedFileReader::new(
+            fs::File::open(&name).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let rows: Vec<parquet::record::Row> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        let row = &rows[1];
+        assert_eq!(
+            row.get_string(0).unwrap(),
+            &"02".repeat(dedup_core::KEY_LEN)
+        );
+        assert_eq!(row.get_long(1).unwrap(), 4096);
+        assert_eq!(row.get_long(2).unwrap(), 3);
+        assert!(row.get_bool(3).unwrap());
+        assert_eq!(row.get_string(4).unwrap(), "/data/b");
+        assert_eq!(row.get_long(5).unwrap(), 8192);
+        assert!(!rows[0].get_bool(3).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::net;
+use std::thread;
+use std::time;
+
+// How many of the merged file's chunks to read before asking about them
+const QUERY_CHUNKS: usize = 1 << 20;
+
+// Answers "which of these chunks do you have?" from a merged scan, standing in for a backup repository that a
+// deduplicating uploader would ask before sending anything. Each connection is answered on a thread of its own until
+// the process is killed.
+pub fn serve(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(opened) => opened,
+        None => return crate::EXIT_FATAL,
+    };
+    let store = match dedup_core::baseline::Baseline::open(out_dir) {
+        Ok(store) => store,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "ERROR: nothing has been merged in '{:?}' yet; run 'merge' first",
+                out_dir
+            );
+            return crate::EXIT_FATAL;
+        }
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let address = matches.value_of("listen").unwrap();
+    let listener = match net::TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("ERROR: can't listen on {}: {}", address, e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    say!(
+        "Answering for the chunks merged in {:?} on {}",
+        out_dir,
+        listener
+            .local_addr()
+            .map_or(address.to_string(), |a| a.to_string())
+    );
+
+    let store = &store;
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warning!("can't accept a connection: {}", e);
+                    continue;
+                }
+            };
+            scope.spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map_or_else(|_| "?".to_string(), |a| a.to_string());
+                match dedup_core::have::serve(stream, store) {
+                    Ok(served) => {
+                        tracing::info!(%peer, ?served, "answered");
+                        say!(
+                            "{} asked about {} chunks in {} batches, and {} were here",
+                            peer,
+                            served.keys,
+                            served.batches,
+                            served.found
+                        );
+                    }
+                    Err(e) => warning!("gave up on {}: {}", peer, e),
+                }
+            });
+        }
+    });
+    crate::EXIT_SUCCESS
+}
+
+// Asks a server which of the chunks merged in the output directory it already has, and how much would be left to send
+pub fn query(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(opened) => opened,
+        None => return crate::EXIT_FATAL,
+    };
+    let mut merged = match crate::open_merged(out_dir, |dir| {
+        dedup_core::run::RunReader::open(&dir.join(dedup_core::merge::MERGED_FILE_NAME))
+    }) {
+        Ok(merged) => merged,
+        Err(status) => return status,
+    };
+    let address = matches.value_of("server").unwrap();
+    let start = time::Instant::now();
+    let mut client = match net::TcpStream::connect(address)
+        .and_then(|stream| dedup_core::have::Client::connect(stream, merged.key_len()))
+    {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("ERROR: can't ask {}: {}", address, e);
+            return crate::EXIT_FATAL;
+        }
+    };
+
+    let (mut chunks, mut bytes, mut found, mut found_bytes) = (0u64, 0u64, 0u64, 0u64);
+    let mut entries = Vec::with_capacity(QUERY_CHUNKS);
+    loop {
+        entries.clear();
+        while entries.len() < QUERY_CHUNKS {
+            match merged.next_entry() {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("ERROR: can't read the merged file: {}", e);
+                    return crate::EXIT_FATAL;
+                }
+            }
+        }
+        if entries.is_empty() {
+            break;
+        }
+        let keys: Vec<dedup_core::run::Key> = entries.iter().map(|e| e.key).collect();
+        let has = match client.query(&keys) {
+            Ok(has) => has,
+            Err(e) => {
+                eprintln!("ERROR: lost {}: {}", address, e);
+                return crate::EXIT_FATAL;
+            }
+        };
+        for (entry, has) in entries.iter().zip(has) {
+            chunks += 1;
+            bytes += entry.size as u64;
+            if has {
+                found += 1;
+                found_bytes += entry.size as u64;
+            }
+        }
+    }
+    let batches = client.batches();
+    if let Err(e) = client.finish() {
+        warning!("couldn't say goodbye to {}: {}", address, e);
+    }
+
+    say!(
+        "{} of {} unique chunks ({} of {} bytes) are already on {}",
+        found,
+        chunks,
+        found_bytes,
+        bytes,
+        address
+    );
+    say!(
+        "{} chunks ({} bytes) would have to be sent",
+        chunks - found,
+        bytes - found_bytes
+    );
+    say!(
+        "Asked in {} batches in {:.3} seconds",
+        batches,
+        start.elapsed().as_secs_f64()
+    );
+    crate::EXIT_SUCCESS
+}
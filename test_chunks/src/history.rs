@@ -0,0 +1,51 @@
+// How many of the most recent merges the history subcommand shows
+const HISTORY_ROWS: usize = 20;
+
+// Prints how the deduplication of the output directory has changed from one merge to the next
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+    let runs =
+        match dedup_core::history::open(out_dir).and_then(|db| dedup_core::history::runs(&db)) {
+            Ok(runs) => runs,
+            Err(e) => {
+                eprintln!("ERROR: can't read the history in {:?}: {}", out_dir, e);
+                return crate::EXIT_FATAL;
+            }
+        };
+    if runs.is_empty() {
+        println!("nothing has been merged in {:?} yet", out_dir);
+        return crate::EXIT_SUCCESS;
+    }
+
+    println!(
+        "{:>20} {:>10} {:>16} {:>16} {:>9} {:>10}",
+        "merged (UTC)", "files", "total bytes", "unique bytes", "unique", "change"
+    );
+    let mut previous: Option<f64> = None;
+    let first = runs.len().saturating_sub(HISTORY_ROWS);
+    for (i, run) in runs.iter().enumerate() {
+        let s = &run.statistics;
+        let total = s.unique_chunk_bytes + s.duplicate_chunk_bytes + s.baseline_bytes;
+        let unique = (s.unique_chunk_bytes * 100) as f64 / total.max(1) as f64;
+        if i >= first {
+            let change = match previous {
+                Some(previous) => format!("{:+0.4}%", unique - previous),
+                None => String::new(),
+            };
+            println!(
+                "{:>20} {:>10} {:>16} {:>16} {:>8.4}% {:>10}",
+                dedup_core::history::format_time(run.finished),
+                run.files,
+                total,
+                s.unique_chunk_bytes,
+                unique,
+                change
+            );
+        }
+        previous = Some(unique);
+    }
+    crate::EXIT_SUCCESS
+}
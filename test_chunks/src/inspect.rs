@@ -0,0 +1,186 @@
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path;
+
+use serde_derive::Serialize;
+
+// The index of the sampled chunks in the inspection directory, beside a directory of raw chunks for each kind
+pub const SAMPLES_NAME: &str = "samples.json";
+const DUPLICATE_DIR: &str = "duplicate";
+const UNIQUE_DIR: &str = "unique";
+
+// What the index says about each chunk that was sampled
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Sample {
+    pub id: String,
+    pub duplicate: bool,
+    pub size: u32,
+    pub occurrences: u32,
+    // Where the chunk was first found
+    pub file: String,
+    pub offset: u64,
+    // The raw bytes, relative to the inspection directory, or None if the file couldn't be read
+    pub bytes: Option<String>,
+    // Whether the bytes read back are no longer the chunk that was scanned, because the file changed since
+    pub changed: bool,
+}
+
+// Picks up to 'count' duplicate and 'count' unique chunks as the merged file goes by. The merged file is in id order
+// and ids are hashes, so the first chunks of each kind are as good as a random sample of them.
+pub struct Sampler {
+    count: usize,
+    duplicates: Vec<dedup_core::run::Entry>,
+    unique: Vec<dedup_core::run::Entry>,
+}
+
+impl Sampler {
+    pub fn new(count: usize) -> Sampler {
+        Sampler {
+            count,
+            duplicates: vec![],
+            unique: vec![],
+        }
+    }
+
+    pub fn record(&mut self, entry: &dedup_core::run::Entry) {
+        let picked = if entry.count > 1 {
+            &mut self.duplicates
+        } else {
+            &mut self.unique
+        };
+        if picked.len() < self.count {
+            picked.push(*entry);
+        }
+    }
+
+    // Reads each sampled chunk back from the file it was found in and writes it to the inspection directory along
+    // with the index. Chunks whose files can't be read any more are still in the index, without bytes.
+    pub fn write(
+        &self,
+        dir: &path::Path,
+        files: &[dedup_core::files::FileRecord],
+        key_len: usize,
+    ) -> io::Result<Vec<Sample>> {
+        fs::create_dir_all(dir.join(DUPLICATE_DIR))?;
+        fs::create_dir_all(dir.join(UNIQUE_DIR))?;
+        let mut hasher = sha3::Sha3_256::default();
+        let mut samples = vec![];
+        for entry in self.duplicates.iter().chain(self.unique.iter()) {
+            let id = dedup_core::hex_key(&entry.key[..key_len]);
+            let duplicate = entry.count > 1;
+            // An entry's file is its position in the file report
+            let file = files
+                .get(entry.file as usize)
+                .map_or("", |f| f.path.as_str());
+            let (bytes, changed) = match read_chunk(path::Path::new(file), entry) {
+                Ok(data) => {
+                    let name = path::Path::new(if duplicate { DUPLICATE_DIR } else { UNIQUE_DIR })
+                        .join(format!("{}.bin", id));
+                    fs::write(dir.join(&name), &data)?;
+                    let key = dedup_core::pipeline::hash_key(&mut hasher, &data, key_len);
+                    (Some(name.to_string_lossy().into_owned()), key != entry.key)
+                }
+                Err(e) => {
+                    warning!("can't read chunk {} from {:?}: {}", id, file, e);
+                    (None, true)
+                }
+            };
+            samples.push(Sample {
+                id,
+                duplicate,
+                size: entry.size,
+                occurrences: entry.count,
+                file: file.to_string(),
+                offset: entry.offset,
+                bytes,
+                changed,
+            });
+        }
+        let mut index = io::BufWriter::new(fs::File::create(dir.join(SAMPLES_NAME))?);
+        serde_json::to_writer_pretty(&mut index, &samples)?;
+        writeln!(index)?;
+        index.flush()?;
+        Ok(samples)
+    }
+}
+
+fn read_chunk(file: &path::Path, entry: &dedup_core::run::Entry) -> io::Result<Vec<u8>> {
+    let mut f = fs::File::open(file)?;
+    f.seek(io::SeekFrom::Start(entry.offset))?;
+    let mut data = vec![0; entry.size as usize];
+    f.read_exact(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_sampler() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_inspect_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 7) as u8).collect();
+        fs::write(dir.join("data"), &data).unwrap();
+        let files = vec![
+            dedup_core::files::FileRecord {
+                path: dir.join("data").to_string_lossy().into_owned(),
+                ..Default::default()
+            },
+            dedup_core::files::FileRecord {
+                path: dir.join("missing").to_string_lossy().into_owned(),
+                ..Default::default()
+            },
+        ];
+
+        let mut hasher = sha3::Sha3_256::default();
+        let entry = |file: u32, offset: usize, size: usize, count: u32| dedup_core::run::Entry {
+            key: dedup_core::pipeline::hash_key(
+                &mut sha3::Sha3_256::default(),
+                &data[offset..offset + size],
+                dedup_core::KEY_LEN,
+            ),
+            size: size as u32,
+            count,
+            file,
+            offset: offset as u64,
+            ..Default::default()
+        };
+        let mut sampler = crate::inspect::Sampler::new(1);
+        sampler.record(&entry(0, 0, 100, 3));
+        // Only the first of each kind is kept
+        sampler.record(&entry(0, 200, 100, 2));
+        sampler.record(&entry(1, 0, 100, 1));
+        sampler.record(&entry(0, 100, 100, 1));
+
+        let inspect = dir.join("inspect");
+        let samples = sampler
+            .write(&inspect, &files, dedup_core::KEY_LEN)
+            .unwrap();
+        assert_eq!(samples.len(), 2);
+        assert!(samples[0].duplicate);
+        assert_eq!(samples[0].occurrences, 3);
+        assert!(!samples[0].changed);
+        let bytes = fs::read(inspect.join(samples[0].bytes.as_ref().unwrap())).unwrap();
+        assert_eq!(bytes, &data[0..100]);
+        // The file is gone, so there is nothing to show
+        assert!(!samples[1].duplicate);
+        assert_eq!(samples[1].bytes, None);
+        assert!(samples[1].changed);
+        assert!(inspect.join(crate::inspect::SAMPLES_NAME).exists());
+
+        // A file that changed since it was scanned still has bytes to look at, but they aren't the chunk
+        let mut changed = entry(0, 100, 100, 1);
+        changed.key = dedup_core::pipeline::hash_key(&mut hasher, b"other", dedup_core::KEY_LEN);
+        let mut sampler = crate::inspect::Sampler::new(1);
+        sampler.record(&changed);
+        let samples = sampler
+            .write(&inspect, &files, dedup_core::KEY_LEN)
+            .unwrap();
+        assert!(samples[0].bytes.is_some());
+        assert!(samples[0].changed);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
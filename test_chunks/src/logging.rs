@@ -0,0 +1,27 @@
+use std::io;
+use std::io::IsTerminal;
+
+// Diagnostics are logged through tracing to stderr, so they never get mixed into the results on stdout. Warnings are
+// shown by default (only errors with --quiet) and each -v shows another level of detail: what the scan and merge are doing (-v), every file and
+// checkpoint (-vv), and every batch of chunks (-vvv). With -vv and up, each file's span is logged when it closes along
+// with how long it was worked on, which shows where a slow scan spends its time.
+pub fn init(matches: &clap::ArgMatches) {
+    let level = match matches.occurrences_of("verbose") {
+        0 if matches.is_present("quiet") => tracing::Level::ERROR,
+        0 => tracing::Level::WARN,
+        1 => tracing::Level::INFO,
+        2 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_target(false)
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE);
+    if matches.is_present("log-json") {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
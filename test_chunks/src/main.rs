@@ -1,19 +1,75 @@
-use std::collections;
-use std::fs;
 use std::io;
 use std::path;
-use std::time;
+use std::process;
+use std::sync::atomic;
 
-use bincode;
-use clap;
-use regex;
-use serde_derive::{Deserialize, Serialize};
+use dedup_core::lock;
 
-pub const KEY_LEN: usize = 18;
-pub const ENTRY_LEN: usize = 24;
-// These constants were calculated based on information provided in http://www.hpl.hp.com/techreports/2005/HPL-2005-30R1.pdf
-pub const MIN_CHUNK_SIZE: usize = 1856;
-pub const MAX_CHUNK_SIZE: usize = 11300;
+// Prints a line of progress or results to stdout, unless --quiet was given. A stdout that has gone away (piped to head,
+// say) is ignored rather than panicking, since the exit status already says how things went.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !crate::quiet() {
+            use std::io::Write as _;
+            let _ = writeln!(std::io::stdout(), $($arg)*);
+        }
+    };
+}
+
+// Prints a warning to stderr, unless --quiet was given. Errors are always printed.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if !crate::quiet() {
+            eprintln!("WARNING: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+mod aggregate;
+mod bench;
+mod compare;
+mod config;
+mod delta;
+mod differential;
+mod duplicates;
+mod estimate;
+mod export;
+mod have;
+mod history;
+mod inspect;
+mod logging;
+mod merge;
+mod multipart;
+mod oci;
+mod reflink;
+mod report;
+mod scan;
+mod signature;
+mod skipped;
+mod stability;
+mod testdata;
+mod tune;
+mod verify;
+mod watch;
+
+// 2^16 one-byte registers gives a standard error of about 0.4%
+pub const HLL_PRECISION: u8 = 16;
+// How many of the most duplicated chunks to list in the report
+pub const TOP_CHUNKS: usize = 10;
+// How many file types to break the report down into
+pub const TOP_FILE_TYPES: usize = 15;
+// How many directories, and pairs of directories that share data, to break the report down into
+pub const TOP_DIRECTORIES: usize = 15;
+
+// What the process exits with, so scripts can tell how it went without reading the output. Collisions win over skipped
+// files when there were both. A panic exits with 101.
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FATAL: i32 = 1;
+pub const EXIT_SKIPPED: i32 = 2;
+pub const EXIT_COLLISIONS: i32 = 3;
+
+// Set by --quiet
+static QUIET: atomic::AtomicBool = atomic::AtomicBool::new(false);
 
 // RESULTS OF TESTING
 // 1) Even with only 18 bytes per key, there are just too many keys to hold in memory for small clusters. It's very
@@ -23,363 +79,1056 @@ pub const MAX_CHUNK_SIZE: usize = 11300;
 //    especially as files are edited for subsequent backups.
 
 fn main() {
-    let started = time::Instant::now();
-    let matches = clap::App::new("Test Backup Chunks")
-                            .version("1.0")
-                            .author("Benjamin Heatwole <bheatwole@cwi-va.com")
-                            .about("Tests the requirements for backup chunking on a particular directory")
-                            .arg(clap::Arg::with_name("directory")
-                                           .short("d")
-                                           .long("directory")
-                                           .value_name("DIR")
-                                           .help("The directory to scan for chunks.")
-                                           .takes_value(true)
-                                           .required(true))
-                            .arg(clap::Arg::with_name("output")
-                                           .short("o")
-                                           .long("output")
-                                           .value_name("DIR")
-                                           .help("The directory to store the output.")
-                                           .takes_value(true)
-                                           .required(true))
-                            .arg(clap::Arg::with_name("memory")
-                                           .short("m")
-                                           .long("memory")
-                                           .value_name("BYTES")
-                                           .help("The amount of memory to use for sorting. Use 'K', 'M' and 'G' abbreviations. I.E. 100M.")
-                                           .takes_value(true)
-                                           .required(true))
-                            .arg(clap::Arg::with_name("fixed")
-                                           .short("f")
-                                           .help("If set, a fixed size chunk of 4096 will be used instead of the variable sized chunks"))
-                            .get_matches();
-
-    // When chunking large directories, we can run out of memory to store all the chunk hashes. Determine how much the
-    // user is willing to set aside and then use that as the max for the chunk btree. The actual usage will probably be
-    // close to double that because the hash tends to insert into the tree pretty balanced which leaves plenty of nodes
-    // with about half the space empty.
-    let memory_usage = parse_memory_usage(matches.value_of("memory").unwrap());
-    let btree_max_entries = ((memory_usage as usize / 10) * 8) / ENTRY_LEN;
-    let mut memtree = collections::BTreeMap::new();
-    let mut statistics = Statistics {
-        unique_chunks: 0,
-        duplicates: 0,
-        unique_chunk_bytes: 0,
-        duplicate_chunk_bytes: 0,
-        collisions: 0,
+    // Settings from a config file are added to the command line, which takes precedence over them. Until they are,
+    // required arguments may still be missing.
+    let args = match config::args(&app(false).get_matches()) {
+        Ok(Some(args)) => args,
+        Ok(None) => std::env::args_os().collect(),
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            process::exit(EXIT_FATAL);
+        }
     };
-    let mut next_mem_id: usize = 0;
-
-    // Confirm the output directory exists
-    let out_dir = path::Path::new(matches.value_of("output").unwrap());
-    if !out_dir.is_dir() {
-        println!(
-            "ERROR: the output directory '{:?}' does not exist or is a file",
-            out_dir
-        );
-        return;
+    let matches = app(true).get_matches_from(args);
+    if let (_, Some(sub_matches)) = matches.subcommand() {
+        QUIET.store(sub_matches.is_present("quiet"), atomic::Ordering::Relaxed);
+        logging::init(sub_matches);
     }
 
-    // Create the chunk hasher
-    use rabin::ExtendableHashExt;
-    use sha3::Digest;
-    let mut hasher = sha3::Sha3_256::new();
-
-    // Iterate through all the directories
-    visit_dirs(
-        path::Path::new(matches.value_of("directory").unwrap()),
-        &mut |e| {
-            // Chunk each file using either the variable-sized or fixed-size chunking algorithm
-            chunk_file(&e.path(), matches.is_present("fixed"), &mut |c| {
-                let key = hasher.hash_chunk_144(c);
-                let check = sha2_check(c);
-
-                let data = EntryData {
-                    check: check,
-                    size: c.len() as u16,
-                };
-
-                // Check to see if we already know about this chunk
-                match memtree.insert(key, data) {
-                    None => {
-                        // Unique chunk, never seen before
-                        statistics.unique_chunks += 1;
-                        statistics.unique_chunk_bytes += c.len() as u64;
-                    }
-                    Some(old_data) => {
-                        if old_data == data {
-                            // The size of the data and both the SHA2 and SHA3 hashes match for the chunk, so the odds of it
-                            // not being a perfect match are statistically miniscule.
-                            statistics.duplicates += 1;
-                            statistics.duplicate_chunk_bytes += c.len() as u64;
-                        } else {
-                            // COLLISION!!! Something didn't match, so the partial SHA3 hash we used as an ID is no good. We
-                            // probably just need to increase the bits from 144
-                            statistics.collisions += 1;
-                        }
-                    }
-                };
-
-                // If we have more entries in the memtree than we're supposed to, write the whole memtree to disk and
-                // clear it for another round.
-                if memtree.len() >= btree_max_entries {
-                    write_memtree_file(out_dir.join(format!("mem_{}", next_mem_id)), &mut memtree);
-                    next_mem_id += 1;
-                }
-            });
-        },
-    );
+    let status = match matches.subcommand() {
+        ("scan", Some(sub_matches)) => scan::run(sub_matches),
+        ("merge", Some(sub_matches)) => merge::run(sub_matches),
+        ("report", Some(sub_matches)) => report::run(sub_matches),
+        ("history", Some(sub_matches)) => history::run(sub_matches),
+        ("compare", Some(sub_matches)) => compare::run(sub_matches),
+        ("aggregate", Some(sub_matches)) => aggregate::run(sub_matches),
+        ("verify", Some(sub_matches)) => verify::run(sub_matches),
+        ("watch", Some(sub_matches)) => watch::run(sub_matches),
+        ("bench", Some(sub_matches)) => bench::run(sub_matches),
+        ("tune", Some(sub_matches)) => tune::run(sub_matches),
+        ("differential", Some(sub_matches)) => differential::run(sub_matches),
+        ("stability", Some(sub_matches)) => stability::run(sub_matches),
+        ("gen-testdata", Some(sub_matches)) => testdata::run(sub_matches),
+        ("signature", Some(sub_matches)) => signature::run(sub_matches),
+        ("delta", Some(sub_matches)) => delta::run(sub_matches),
+        ("apply", Some(sub_matches)) => delta::apply(sub_matches),
+        ("serve-chunks", Some(sub_matches)) => have::serve(sub_matches),
+        ("query-chunks", Some(sub_matches)) => have::query(sub_matches),
+        ("multipart", Some(sub_matches)) => multipart::run(sub_matches),
+        ("oci", Some(sub_matches)) => oci::run(sub_matches),
+        ("apply-reflink", Some(sub_matches)) => reflink::run(sub_matches),
+        ("apply-dedupe", Some(sub_matches)) => reflink::dedupe(sub_matches),
+        ("duplicate-files", Some(sub_matches)) => duplicates::run(sub_matches),
+        _ => unreachable!(),
+    };
+    process::exit(status);
+}
 
-    // Write the last file
-    if memtree.len() > 0 {
-        write_memtree_file(out_dir.join(format!("mem_{}", next_mem_id)), &mut memtree);
-        next_mem_id += 1;
-    }
+// Whether to leave out everything but errors and the results that were asked for
+pub fn quiet() -> bool {
+    QUIET.load(atomic::Ordering::Relaxed)
+}
 
-    // === Sorting Algorithm ===
-    // The keys will be inserted into an in-memory sorted array until the sorting memory buffer is full. It will then
-    // write out that chunk of sorted data to a temp file and start with a new empty buffer.
-    //
-    // When chunking is complete, the sorted temp files will be merged into a single file and the calculations on
-    // compression level, chunk size and collisions will be performed.
-    //
-    // When 'merging' we don't actually care about the contents except to see if there are duplicates and/or collisions
-    let mut merge_files = vec![];
-    let mut merge_data: Vec<Option<Entry>> = vec![];
-    for i in 0..next_mem_id {
-        merge_files.push(io::BufReader::new(
-            fs::File::open(out_dir.join(format!("mem_{}", i))).unwrap(),
-        ));
-        merge_data.push(Some(
-            bincode::deserialize_from(merge_files.get_mut(i).unwrap()).unwrap(),
-        ));
+// The exit status of a scan or merge that finished with these results
+pub fn exit_status(collisions: u64, skipped: u64) -> i32 {
+    if collisions > 0 {
+        EXIT_COLLISIONS
+    } else if skipped > 0 {
+        EXIT_SKIPPED
+    } else {
+        EXIT_SUCCESS
     }
+}
 
-    loop {
-        let mut smallest_entry: Option<Entry> = None;
-        let mut smallest_index = 0;
-
-        // First find the smallest key in sorted order
-        for i in 0..next_mem_id {
-            let test_entry = merge_data.get(i).unwrap();
-            match (smallest_entry, test_entry) {
-                (_, None) => {}
-                (None, Some(e)) => {
-                    let copy = *e;
-                    smallest_entry = Some(copy);
-                    smallest_index = i;
-                }
-                (Some(left), Some(right)) => {
-                    if right.key < left.key {
-                        let copy = *right;
-                        smallest_entry = Some(copy);
-                        smallest_index = i;
-                    }
-                }
-            }
-        }
-
-        // If there is no smallest, then we're totally done!
-        if None == smallest_entry {
-            break;
-        }
-
-        // Starting with the entry we found, check all remaining entries for duplicates and grab the next data element
-        // from their file
-        for i in smallest_index..next_mem_id {
-            if i == smallest_index {
-                // The first index is the one we found. It's not a duplicate, but it was also recorded earlier, so just
-                // update the data
-                merge_data[i] = match bincode::deserialize_from(merge_files.get_mut(i).unwrap()) {
-                    Ok(e) => Some(e),
-                    _ => None,
-                }
-            } else {
-                let current_entry = merge_data.get(i).unwrap();
-                match (smallest_entry, current_entry) {
-                    (_, None) => {
-                        // The file at this index is all done
-                    }
-                    (None, Some(_)) => {
-                        // not possible because we check for None above
-                    }
-                    (Some(smallest), Some(borrowed)) => {
-                        let current = *borrowed;
-                        if current.key == smallest.key {
-                            // Keys are duplicate. Check for collision
-                            statistics.unique_chunks -= 1;
-                            statistics.unique_chunk_bytes -= current.size as u64;
-                            if current != smallest {
-                                statistics.collisions += 1;
-                            } else {
-                                statistics.duplicates += 1;
-                                statistics.duplicate_chunk_bytes += current.size as u64;
-                            }
-
-                            // Need to load the next element from the file
-                            merge_data[i] =
-                                match bincode::deserialize_from(merge_files.get_mut(i).unwrap()) {
-                                    Ok(e) => Some(e),
-                                    _ => None,
-                                }
-                        }
+fn app<'a, 'b>(check_required: bool) -> clap::App<'a, 'b> {
+    clap::App::new("Test Backup Chunks")
+        .version("1.0")
+        .author("Benjamin Heatwole <bheatwole@cwi-va.com")
+        .about("Tests the requirements for backup chunking on a particular directory")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            clap::Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Read settings from a TOML file with a table for each subcommand, i.e. [scan]. Options given on the command line take precedence.")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Log what is going on to stderr. Give it more than once (-vv, -vvv) for more detail.")
+                .multiple(true)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Print nothing but errors, apart from the output of report, history, compare, aggregate, bench, tune, differential and stability. The exit status tells how it went.")
+                .conflicts_with("verbose")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("log-json")
+                .long("log-json")
+                .help("Write the log as JSON, one object per line.")
+                .global(true),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("scan")
+                .about("Chunks directories and commits sorted runs of chunk ids to the output directory")
+                .arg({
+                    let directory = clap::Arg::with_name("directory")
+                        .short("d")
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("A directory to scan for chunks. May be given more than once to scan several directories together, and the report then shows how much of each one is unique and how much they share.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1);
+                    if check_required {
+                        directory.required_unless_one(&["directories", "files-from"])
+                    } else {
+                        directory
                     }
-                }
-            }
-        }
-    }
-
-    // Generate a report
-    let total_bytes = statistics.duplicate_chunk_bytes + statistics.unique_chunk_bytes;
-    println!("{}s elapsed", started.elapsed().as_secs());
-    println!("{} total bytes scanned", total_bytes);
-    println!(
-        "{} bytes {:0.4}% were unique",
-        statistics.unique_chunk_bytes,
-        ((statistics.unique_chunk_bytes * 100) as f64) / (total_bytes as f64)
-    );
-    println!("{} chunks", statistics.unique_chunks);
-    println!(
-        "{} bytes per chunk",
-        statistics.unique_chunk_bytes / statistics.unique_chunks as u64
-    );
-    println!("{} collisions", statistics.collisions);
+                })
+                .arg(
+                    clap::Arg::with_name("directories")
+                        .value_name("DIR")
+                        .help("More directories to scan, the same as giving each with -d.")
+                        .multiple(true),
+                )
+                .arg(
+                    clap::Arg::with_name("files-from")
+                        .long("files-from")
+                        .value_name("FILE")
+                        .help("Scan the files named in FILE instead of walking directories, or in stdin if FILE is '-'. The names are separated by NUL bytes, as find -print0 writes them. Directories in the list aren't entered and nothing is excluded.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["directory", "directories", "estimate", "exclude"]),
+                )
+                .arg(if check_required {
+                    output_arg().required_unless_one(&["hll", "estimate", "approximate"])
+                } else {
+                    output_arg()
+                })
+                .arg(
+                    clap::Arg::with_name("memory")
+                        .short("m")
+                        .long("memory")
+                        .value_name("BYTES")
+                        .help("The amount of memory to use for sorting. Use 'K', 'M' and 'G' abbreviations. I.E. 100M. Defaults to a quarter of the available memory.")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("append")
+                        .short("a")
+                        .long("append")
+                        .help("Add this scan to the runs already in the output directory instead of starting over. Any number of appending scans may share an output directory at once."),
+                )
+                .arg(
+                    clap::Arg::with_name("overwrite")
+                        .long("overwrite")
+                        .help("Start over even if the output directory has runs from an earlier scan that were never merged, throwing them away.")
+                        .conflicts_with_all(&["append", "resume"]),
+                )
+                .arg(
+                    clap::Arg::with_name("resume")
+                        .long("resume")
+                        .help("Carry on with a scan of the same directory that was killed or crashed, instead of starting over. Files it finished aren't read again. The chunk settings must match the interrupted scan's.")
+                        .conflicts_with("hll"),
+                )
+                .arg(
+                    clap::Arg::with_name("baseline")
+                        .long("baseline")
+                        .value_name("DIR")
+                        .help("The output directory of an earlier, merged scan to treat as a backup repository that already exists. Chunks it already has are counted but not stored, the way an incremental backup would work. The baseline isn't changed.")
+                        .takes_value(true)
+                        .conflicts_with("hll"),
+                )
+                .arg(
+                    clap::Arg::with_name("hll")
+                        .long("hll")
+                        .help("Estimate the number of unique chunks with a HyperLogLog sketch instead of storing every key. Uses a fixed 64K of memory and writes no output files."),
+                )
+                .arg(
+                    clap::Arg::with_name("approximate")
+                        .long("approximate")
+                        .help("Count the unique chunks in memory instead of writing runs, for machines that can't write to disk. Exact while the unique chunks fit in --memory; past that, estimated from a sample of them that shrinks to fit, and printed with the uncertainty. Writes no output files.")
+                        .conflicts_with_all(&["hll", "append", "resume", "baseline", "cache", "compress"]),
+                )
+                .arg(
+                    clap::Arg::with_name("sub-blocks")
+                        .long("sub-blocks")
+                        .value_name("SIZE")
+                        .help("Also cut every chunk at each multiple of this many bytes from the start of its file (i.e. 4K or 8K, as storage arrays dedup), and count how many of those blocks are unique in a quarter of --memory. Printed at the end of the scan for the files it scanned.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["hll", "estimate", "resume", "cache"]),
+                )
+                .arg(
+                    clap::Arg::with_name("estimate")
+                        .long("estimate")
+                        .value_name("PERCENT")
+                        .help("Only chunk a random PERCENT of the files, sampled from each range of file sizes, and estimate how much of the whole directory would be unique. Writes no output files.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["hll", "append", "resume", "baseline", "compress"]),
+                )
+                .arg(
+                    clap::Arg::with_name("cache")
+                        .long("cache")
+                        .help("Keep the chunks of every file scanned in the output directory's history database, and take the chunks of files whose size, modification time and inode haven't changed since an earlier scan from it instead of reading them again.")
+                        .conflicts_with_all(&["estimate", "hll"]),
+                )
+                .arg(
+                    clap::Arg::with_name("compress")
+                        .long("compress")
+                        .help("Compress the chunks with zstd (level 3) so the report can show how small the unique data would be after compression as well as deduplication.")
+                        .conflicts_with("hll"),
+                )
+                .arg(
+                    clap::Arg::with_name("compress-every")
+                        .long("compress-every")
+                        .value_name("COUNT")
+                        .help("Only compress one chunk in this many, and estimate the rest from them. Chunks are picked by id, so every copy of a chunk is treated the same.")
+                        .takes_value(true)
+                        .requires("compress"),
+                )
+                .arg(
+                    clap::Arg::with_name("directory-depth")
+                        .long("directory-depth")
+                        .value_name("DEPTH")
+                        .help("How many directories deep below the scanned directory to break the report down by directory.")
+                        .default_value("1"),
+                )
+                .args(&chunking_args())
+                .arg(fixed_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("watch")
+                .about("Chunks directories and keeps their statistics up to date as files change, until it is stopped")
+                .arg(
+                    clap::Arg::with_name("directory")
+                        .short("d")
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("A directory to watch. May be given more than once.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(check_required),
+                )
+                .arg(output_arg().required(check_required))
+                .arg(
+                    clap::Arg::with_name("settle")
+                        .long("settle")
+                        .value_name("SECONDS")
+                        .help("How long the directories have to go without changing before the files that changed are chunked again.")
+                        .default_value("2"),
+                )
+                .args(&chunking_args())
+                .arg(fixed_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("bench")
+                .about("Chunks directories with each chunking algorithm in turn and compares their speed and deduplication")
+                .arg(
+                    clap::Arg::with_name("directory")
+                        .short("d")
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("A directory to chunk. May be given more than once.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("algorithms")
+                        .long("algorithms")
+                        .value_name("NAMES")
+                        .help("The algorithms to compare, separated by commas, in the order to run them. Defaults to all of them. The variable-size ones use --min-chunk, --max-chunk and --avg-chunk.")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .possible_values(bench::ALGORITHMS),
+                )
+                .args(&chunking_args()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("tune")
+                .about("Tries a range of chunk sizes on directories and recommends the one that would store them most cheaply")
+                .arg(
+                    clap::Arg::with_name("directory")
+                        .short("d")
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("A directory to chunk. May be given more than once.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("sample")
+                        .long("sample")
+                        .value_name("PERCENT")
+                        .help("Only chunk a random PERCENT of the files, sampled from each range of file sizes the way scan --estimate does.")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("averages")
+                        .long("averages")
+                        .value_name("SIZES")
+                        .help("The average chunk sizes to try, separated by commas (i.e. 4k,8k). Each is tried with a minimum of a quarter and a half of it and a maximum of twice and four times it. Defaults to 2k up to 64k.")
+                        .takes_value(true)
+                        .use_delimiter(true),
+                )
+                .arg(
+                    clap::Arg::with_name("entry-cost")
+                        .long("entry-cost")
+                        .value_name("BYTES")
+                        .help("What each unique chunk costs in the index, on top of its data. Higher costs favor larger chunks.")
+                        .default_value("64"),
+                )
+                .args(&chunking_args()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("differential")
+                .about("Chunks directories with two implementations of the chunking and checks that they find the same chunks")
+                .arg(
+                    clap::Arg::with_name("directory")
+                        .short("d")
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("A directory to chunk. May be given more than once.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("implementations")
+                        .long("implementations")
+                        .value_name("NAMES")
+                        .help("The two implementations to compare, separated by a comma.")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .min_values(2)
+                        .max_values(2)
+                        .possible_values(dedup_core::differential::IMPLEMENTATIONS)
+                        .default_value("slice,stream"),
+                )
+                .arg(
+                    clap::Arg::with_name("buffer")
+                        .long("buffer")
+                        .value_name("SIZE")
+                        .help("How much the stream implementation reads at a time. Small buffers cut more chunks at the edge of one.")
+                        .default_value("64K"),
+                )
+                .args(&chunking_args())
+                .arg(fixed_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("stability")
+                .about("Makes random edits to a copy of a file and measures how much of it the original's chunks could store")
+                .arg(
+                    clap::Arg::with_name("file")
+                        .long("file")
+                        .value_name("FILE")
+                        .help("The file to edit. It is read into memory and never changed.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("edit")
+                        .long("edit")
+                        .value_name("KINDS")
+                        .help("The kinds of edit to make, separated by commas: insert, delete or overwrite bytes. Defaults to each of them in turn.")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .possible_values(dedup_core::stability::EDITS)
+                        .default_value("insert,delete,overwrite"),
+                )
+                .arg(
+                    clap::Arg::with_name("bytes")
+                        .long("bytes")
+                        .value_name("SIZE")
+                        .help("How many bytes each edit inserts, deletes or overwrites.")
+                        .default_value("1"),
+                )
+                .arg(
+                    clap::Arg::with_name("edits")
+                        .long("edits")
+                        .value_name("COUNT")
+                        .help("How many edits each trial makes, at random offsets.")
+                        .default_value("1"),
+                )
+                .arg(
+                    clap::Arg::with_name("trials")
+                        .long("trials")
+                        .value_name("COUNT")
+                        .help("How many times each kind of edit is tried, with different offsets each time.")
+                        .default_value("10"),
+                )
+                .arg(
+                    clap::Arg::with_name("seed")
+                        .long("seed")
+                        .value_name("NUMBER")
+                        .help("Where the random offsets start. The same seed makes the same edits.")
+                        .default_value("1"),
+                )
+                .args(&chunking_args())
+                .arg(fixed_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("gen-testdata")
+                .about("Writes a directory tree of made-up files with a known amount of duplication, the same every time for the same settings")
+                .arg(
+                    clap::Arg::with_name("out")
+                        .long("out")
+                        .value_name("DIR")
+                        .help("The directory to write the files in. It mustn't exist yet.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("files")
+                        .long("files")
+                        .value_name("COUNT")
+                        .help("How many files to write.")
+                        .default_value("1000"),
+                )
+                .arg(
+                    clap::Arg::with_name("min-size")
+                        .long("min-size")
+                        .value_name("SIZE")
+                        .help("The smallest a new file can be (i.e. 4k).")
+                        .default_value("4k"),
+                )
+                .arg(
+                    clap::Arg::with_name("max-size")
+                        .long("max-size")
+                        .value_name("SIZE")
+                        .help("The largest a new file can be (i.e. 4m).")
+                        .default_value("4m"),
+                )
+                .arg(
+                    clap::Arg::with_name("size-distribution")
+                        .long("size-distribution")
+                        .value_name("SHAPE")
+                        .help("How the sizes of new files are spread between the smallest and largest: all the smallest, evenly, or evenly over the powers of two (many small files and a few large ones).")
+                        .takes_value(true)
+                        .possible_values(dedup_core::testdata::SIZE_DISTRIBUTIONS)
+                        .default_value("log-uniform"),
+                )
+                .arg(
+                    clap::Arg::with_name("duplicates")
+                        .long("duplicates")
+                        .value_name("FRACTION")
+                        .help("The fraction of files, from 0 to 1, that are copies of a file written earlier instead of new data.")
+                        .default_value("0.3"),
+                )
+                .arg(
+                    clap::Arg::with_name("edit-rate")
+                        .long("edit-rate")
+                        .value_name("EDITS")
+                        .help("How many edits are made to each copy per MiB in it, each inserting, deleting or overwriting up to 64 bytes. 0 leaves the copies identical.")
+                        .default_value("1"),
+                )
+                .arg(
+                    clap::Arg::with_name("compressibility")
+                        .long("compressibility")
+                        .value_name("FRACTION")
+                        .help("The fraction of each new file, from 0 to 1, that is text that compresses well. The rest is random bytes.")
+                        .default_value("0.2"),
+                )
+                .arg(
+                    clap::Arg::with_name("files-per-directory")
+                        .long("files-per-directory")
+                        .value_name("COUNT")
+                        .help("How many files go in each directory of the tree.")
+                        .default_value("100"),
+                )
+                .arg(
+                    clap::Arg::with_name("seed")
+                        .long("seed")
+                        .value_name("NUMBER")
+                        .help("Where the random numbers start. The same seed and settings write the same files.")
+                        .default_value("1"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("signature")
+                .about("Writes the offset, weak hash and id of every chunk in a file or directory tree to a signature file")
+                .arg(
+                    clap::Arg::with_name("source")
+                        .long("source")
+                        .value_name("PATH")
+                        .help("The file or directory to sign. The files in a directory are signed under their paths relative to it.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("signature")
+                        .long("signature")
+                        .value_name("FILE")
+                        .help("Where to write the signature.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .args(&chunking_args())
+                .arg(fixed_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("delta")
+                .about("Writes a delta that turns the data a signature was made from into a newer copy of it")
+                .arg(
+                    clap::Arg::with_name("signature")
+                        .long("signature")
+                        .value_name("FILE")
+                        .help("The signature of the older copy. The newer one is chunked with the same settings.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("source")
+                        .long("source")
+                        .value_name("PATH")
+                        .help("The newer copy: a file, or a directory to rebuild as a whole.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("delta")
+                        .long("delta")
+                        .value_name("FILE")
+                        .help("Where to write the delta.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("follow-symlinks")
+                        .long("follow-symlinks")
+                        .help("Include whatever symbolic links in the directory point to, as if it were in the link's place."),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("apply")
+                .about("Rebuilds the newer copy of the data from the older one and a delta")
+                .arg(
+                    clap::Arg::with_name("base")
+                        .long("base")
+                        .value_name("PATH")
+                        .help("The older copy that was signed: the file, or the directory the signed paths are relative to. It isn't changed.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("delta")
+                        .long("delta")
+                        .value_name("FILE")
+                        .help("The delta made against the older copy's signature.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("out")
+                        .long("out")
+                        .value_name("PATH")
+                        .help("Where to write the newer copy: the file, or the directory to rebuild the tree in. It mustn't exist yet.")
+                        .takes_value(true)
+                        .required(check_required),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("serve-chunks")
+                .about("Answers query-chunks with which chunks the last merge in the output directory found, until it is killed")
+                .arg(output_arg().required(check_required))
+                .arg(
+                    clap::Arg::with_name("listen")
+                        .long("listen")
+                        .value_name("ADDRESS")
+                        .help("The address and port to listen on.")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:7878"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("query-chunks")
+                .about("Asks serve-chunks which of the chunks the last merge in the output directory found it already has, and counts what would be left to send")
+                .arg(output_arg().required(check_required))
+                .arg(
+                    clap::Arg::with_name("server")
+                        .long("server")
+                        .value_name("ADDRESS")
+                        .help("The address and port serve-chunks is listening on.")
+                        .takes_value(true)
+                        .required(check_required),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("multipart")
+                .about("Prints the parts a multipart upload of a file would be split into, each a run of whole chunks, so unchanged data makes the same parts every time")
+                .arg(
+                    clap::Arg::with_name("source")
+                        .long("source")
+                        .value_name("FILE")
+                        .help("The file to plan the upload of.")
+                        .takes_value(true)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("part-size")
+                        .long("part-size")
+                        .value_name("BYTES")
+                        .help("The smallest a part can be, other than the last. Use 'K', 'M' and 'G' abbreviations. Defaults to 5M, doubled as often as it takes to keep the file within 10000 parts.")
+                        .takes_value(true),
+                )
+                .args(&chunking_args())
+                .arg(fixed_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("oci")
+                .about("Chunks the files in every layer of container images, and reports how much more would be shared storing and distributing the images a chunk at a time rather than a layer at a time")
+                .arg(
+                    clap::Arg::with_name("image")
+                        .long("image")
+                        .value_name("PATH")
+                        .help("An OCI image layout directory, or a tar file made by docker save. May be given more than once, and every image in each is read.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print the analysis as plain text or as JSON for other programs to read.")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                )
+                .args(&chunking_args())
+                .arg(fixed_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("apply-reflink")
+                .about("Clones the first copy of every whole-file duplicate the last merge found over the others, so they share storage (on Btrfs, XFS and other Linux filesystems that can clone, and ReFS on Windows)")
+                .arg(output_arg().required(check_required))
+                .arg(
+                    clap::Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Check the duplicates and list the clones that would be made, without making them."),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("duplicate-files")
+                .about("Lists the files the last merge found to be identical, and which of them could be hard links or clones of one copy")
+                .arg(output_arg().required(check_required))
+                .arg(
+                    clap::Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print the list as plain text or as JSON for other programs to read.")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                )
+                .arg(
+                    clap::Arg::with_name("script")
+                        .long("script")
+                        .value_name("FILE")
+                        .help("Also write a shell script that replaces each duplicate with a hard link or clone of the kept copy, after checking it still holds the same bytes.")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("script-action")
+                        .long("script-action")
+                        .value_name("ACTION")
+                        .help("Whether the script makes hard links, which turn the copies into one file, or clones (cp --reflink=always), which stay separate files sharing storage.")
+                        .takes_value(true)
+                        .possible_values(&["hardlink", "reflink"])
+                        .default_value("reflink"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("apply-dedupe")
+                .about("Makes every duplicate chunk the last merge found share storage with its first copy, (on Btrfs, XFS and other Linux filesystems that can share extents, and ReFS on Windows)")
+                .arg(output_arg().required(check_required))
+                .arg(
+                    clap::Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Find the duplicate ranges and list the ones that would be shared, without sharing them."),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("merge")
+                .about("Merges every committed run in the output directory and computes the statistics")
+                .arg(output_arg().required(check_required))
+                .arg(
+                    clap::Arg::with_name("memory")
+                        .short("m")
+                        .long("memory")
+                        .value_name("BYTES")
+                        .help("The amount of memory to share out between the runs' read buffers. Use 'K', 'M' and 'G' abbreviations. Defaults to a quarter of the available memory.")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("keep-intermediate")
+                        .long("keep-intermediate")
+                        .help("Keep the runs after merging them, so that scans appended later can be merged with them."),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("report")
+                .about("Prints the statistics from the last merge")
+                .arg(output_arg().required(check_required))
+                .arg(
+                    clap::Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print the report as plain text or as JSON for other programs to read.")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                )
+                .arg(
+                    clap::Arg::with_name("report-file")
+                        .long("report-file")
+                        .value_name("FILE")
+                        .help("Write the report to this file instead of stdout.")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("csv")
+                        .long("csv")
+                        .value_name("FILE")
+                        .help("Also write a CSV file with one row per scanned file showing how much of it was unique.")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("parquet")
+                        .long("parquet")
+                        .value_name("FILE")
+                        .help("Also write every unique chunk to a Parquet file, with its id, size, occurrences, whether it was duplicated, and the file and offset it was first found at.")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("sample-chunks")
+                        .long("sample-chunks")
+                        .value_name("DIR")
+                        .help("Also read a random sample of the duplicate and unique chunks back from the files they were found in, and write their raw bytes to this directory with an index of what each one is.")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("sample-count")
+                        .long("sample-count")
+                        .value_name("COUNT")
+                        .help("How many duplicate chunks, and how many unique ones, --sample-chunks writes.")
+                        .default_value("20"),
+                )
+                .arg(
+                    clap::Arg::with_name("report-html")
+                        .long("report-html")
+                        .value_name("FILE")
+                        .help("Also write the report as a self-contained HTML page, with charts of the chunk sizes and the deduplication of each file type and directory.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("history")
+                .about("Shows how the deduplication of the output directory has changed from one merge to the next")
+                .arg(output_arg().required(check_required)),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("compare")
+                .about("Measures how much of a later scan was already in an earlier one, as an incremental backup would see it")
+                .arg(output_arg().required(check_required).help("The output directory of the later scan."))
+                .arg(
+                    clap::Arg::with_name("base")
+                        .long("base")
+                        .value_name("DIR")
+                        .help("The output directory of the earlier scan.")
+                        .takes_value(true)
+                        .required(check_required),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("aggregate")
+                .about("Combines the merged output directories of several scans of the same data over time into the data each pair of them shares and how the unique data grew")
+                .arg(
+                    clap::Arg::with_name("dirs")
+                        .long("dirs")
+                        .value_name("DIR")
+                        .help("The output directories to combine, oldest first. Each has to be merged first.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .min_values(2)
+                        .required(check_required),
+                )
+                .arg(
+                    clap::Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print the results as plain text or as JSON for other programs to read.")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("verify")
+                .about("Checks that every committed run and the merged file are complete and sorted")
+                .arg(output_arg().required(check_required)),
+        )
 }
 
-// Quickly stuffs all the entries in the btree into a file. The btreemap iterator is sorted, which we need.
-fn write_memtree_file(
-    mem_file_name: path::PathBuf,
-    memtree: &mut collections::BTreeMap<[u8; 18], EntryData>,
-) {
-    let mem_file = fs::File::create(mem_file_name).unwrap();
-    let mut buffer = io::BufWriter::new(&mem_file);
-    let mut entry = Entry::default();
-    for (key, value) in memtree.iter() {
-        entry.key = *key;
-        entry.size = value.size;
-        entry.check = value.check;
-        bincode::serialize_into(&mut buffer, &entry).unwrap();
-    }
-    memtree.clear();
+// How scan and watch find files and chunk them
+fn chunking_args<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::with_name("threads")
+            .long("threads")
+            .value_name("COUNT")
+            .help("The number of threads to hash chunks on. Reading, finding chunk boundaries and storing keys each get a thread of their own. Defaults to the number of CPUs, or of --hash-cpus.")
+            .takes_value(true),
+        clap::Arg::with_name("hash-cpus")
+            .long("hash-cpus")
+            .value_name("CPUS")
+            .help("Pin each hashing thread to one of these CPUs in turn (i.e. 0-7,16-23). Linux only.")
+            .takes_value(true),
+        clap::Arg::with_name("io-cpus")
+            .long("io-cpus")
+            .value_name("CPUS")
+            .help("Only run the threads that read files and find chunk boundaries on these CPUs (i.e. 8-9). Linux only.")
+            .takes_value(true),
+        clap::Arg::with_name("exclude")
+            .long("exclude")
+            .value_name("PATTERN")
+            .help("Skip files and directories that match this gitignore-style pattern. May be given more than once. The output directory is always skipped.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        clap::Arg::with_name("follow-symlinks")
+            .long("follow-symlinks")
+            .help("Scan what symbolic links point to. Each directory is only scanned once, no matter how many links lead to it, so link loops are safe. By default links are skipped."),
+        clap::Arg::with_name("record-symlinks")
+            .long("record-symlinks")
+            .help("Scan symbolic links as small files holding the path they point to, the way a backup would store them.")
+            .conflicts_with("follow-symlinks"),
+        clap::Arg::with_name("count-hard-links")
+            .long("count-hard-links")
+            .help("Scan every hard link to a file as if it were a separate copy. By default only the first link found is scanned."),
+        clap::Arg::with_name("alternate-streams")
+            .long("alternate-streams")
+            .help("Also scan the alternate data streams of files on NTFS, each as a file of its own. Windows only."),
+        clap::Arg::with_name("anchors")
+            .long("anchors")
+            .value_name("KINDS")
+            .help("Also cut files of these kinds at the edges of their records, so each record is chunked the same wherever it is: mbox cuts mailboxes at every message, like the files of a Maildir, and sql cuts database dumps between rows and tables. Files are recognized by their contents.")
+            .takes_value(true)
+            .use_delimiter(true)
+            .possible_values(&["mbox", "sql"]),
+        clap::Arg::with_name("disk-images")
+            .long("disk-images")
+            .help("Chunk the disk inside qcow2, sparse VMDK and VHD images, as the virtual machine sees it, rather than the image file. Images of the same disk then dedup against each other and against raw copies of it."),
+        clap::Arg::with_name("archives")
+            .long("archives")
+            .help("Chunk each file inside tar (compressed or not), zip and 7z archives as a file of its own, named by the archive's path and its path in the archive, rather than the archive as one file."),
+        clap::Arg::with_name("compressed")
+            .long("compressed")
+            .value_name("POLICY")
+            .help("What to do with files that look already compressed (gzip, zstd, xz, JPEG and the like, or anything that starts out looking random): chunk them like any other file, skip them, flag them, which chunks them as they are but counts them in the report, or decompress the gzip, zstd, xz and bzip2 ones and chunk the data inside (flagging the rest). Compressed data dedups poorly however it is chunked.")
+            .takes_value(true)
+            .possible_values(&["chunk", "skip", "flag", "decompress"])
+            .default_value("chunk"),
+        clap::Arg::with_name("min-file-size")
+            .long("min-file-size")
+            .value_name("SIZE")
+            .help("Skip files smaller than this (i.e. 4k). Tiny files are rarely worth deduplicating at chunk granularity.")
+            .takes_value(true),
+        clap::Arg::with_name("max-file-size")
+            .long("max-file-size")
+            .value_name("SIZE")
+            .help("Skip files larger than this (i.e. 1G).")
+            .takes_value(true),
+        clap::Arg::with_name("no-mmap")
+            .long("no-mmap")
+            .help("Read files through a buffer instead of mapping them into memory. Slower, but safe for files that change during the scan and for network filesystems."),
+        clap::Arg::with_name("io-uring")
+            .long("io-uring")
+            .value_name("DEPTH")
+            .help("Read files with io_uring instead of mapping them, keeping DEPTH reads of 1MiB in flight (i.e. 8). Keeps fast NVMe drives and network storage busier than one read at a time can. Linux only.")
+            .takes_value(true),
+        clap::Arg::with_name("direct-io")
+            .long("direct-io")
+            .conflicts_with("io-uring")
+            .help("Read files through a buffer without going through the page cache, so scanning far more data than fits in memory doesn't push everything else out of it. Linux and macOS only."),
+        clap::Arg::with_name("key-bits")
+            .long("key-bits")
+            .value_name("BITS")
+            .help("How many bits of each chunk's SHA3 hash to use as its id. Shorter ids make smaller run files but collide more often.")
+            .possible_values(&["112", "128", "144", "160", "256"])
+            .default_value("144"),
+        clap::Arg::with_name("min-chunk")
+            .long("min-chunk")
+            .value_name("SIZE")
+            .help("The smallest chunk the variable-size algorithm will make (i.e. 2k). Defaults to 1856 bytes.")
+            .takes_value(true),
+        clap::Arg::with_name("max-chunk")
+            .long("max-chunk")
+            .value_name("SIZE")
+            .help("The largest chunk the variable-size algorithm will make (i.e. 64k). Defaults to 11300 bytes.")
+            .takes_value(true),
+        clap::Arg::with_name("avg-chunk")
+            .long("avg-chunk")
+            .value_name("SIZE")
+            .help("Roughly how large chunks should be on average. Sets how rare a chunk boundary is; the default averages about 2k more than the minimum.")
+            .takes_value(true),
+        clap::Arg::with_name("align")
+            .long("align")
+            .value_name("SIZE")
+            .help("Only cut chunks at multiples of this many bytes from the start of each file, moving each boundary on to the next one, to see how the data would dedup on a filesystem that dedups blocks of this size (i.e. 128K for ZFS's default recordsize). --max-chunk has to be at least this large.")
+            .takes_value(true),
+    ]
 }
 
-// Call the specified callback function once for each file, recursing into sub-directories
-fn visit_dirs(dir: &path::Path, callback: &mut dyn FnMut(&fs::DirEntry)) {
-    let dir_result = fs::read_dir(dir);
-    if !dir_result.is_ok() {
-        return;
-    }
-
-    for entry in dir_result.unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_dir() {
-            visit_dirs(&path, callback);
-        } else {
-            callback(&entry);
-        }
-    }
+// Whether to use fixed-size chunks instead of variable-size ones
+fn fixed_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("fixed")
+        .short("f")
+        .help(
+            "If set, a fixed size chunk of 4096 will be used instead of the variable sized chunks",
+        )
+        .conflicts_with_all(&["min-chunk", "max-chunk", "avg-chunk", "align"])
 }
 
-// Run either a variable-sized or fixed-size chunking algorithm on the specified file. Call the specified callback
-// function once for each chunk found.
-fn chunk_file(path: &path::Path, fixed_size: bool, callback: &mut dyn FnMut(&[u8])) {
-    // Open the file if we can
-    let file = fs::OpenOptions::new().read(true).open(path);
-    if !file.is_ok() {
-        return;
-    }
-    let file = file.unwrap();
-
-    // Can't mmap zero-length files
-    let metadata = file.metadata().unwrap();
-    if 0 == metadata.len() {
-        return;
-    }
-
-    // Chunk it
-    let mmap = unsafe { memmap::Mmap::map(&file).unwrap() };
-    if fixed_size {
-        let remainder: &[u8] = &mmap;
-        for chunk in remainder.chunks(4096) {
-            callback(chunk);
-        }
-    } else {
-        let chunker = rabin::chunker::Chunker::new(&mmap, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
-        for chunk in chunker {
-            callback(chunk);
-        }
-    }
+// Every subcommand works on the same output directory
+fn output_arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .value_name("DIR")
+        .help("The directory to store the output.")
+        .takes_value(true)
 }
 
-// SHA2 and SHA3 are completely different algorithms. It is extremely unlikely that and particular piece of data will
-// have even just these four bytes of SHA2 match another piece of data with the same SHA3 hash.
-fn sha2_check(chunk: &[u8]) -> u32 {
-    let hash = rabin::hash_chunk_sha256(chunk);
+// Confirms the output directory exists and locks it. Prints an error and returns None if either fails.
+fn open_output<'a>(
+    matches: &'a clap::ArgMatches,
+    kind: lock::LockKind,
+) -> Option<(&'a path::Path, lock::Lock)> {
+    open_dir(matches, "output", kind)
+}
 
-    (hash[0] as u32) << 24 | (hash[1] as u32) << 16 | (hash[2] as u32) << 8 | (hash[3] as u32)
+// The same as open_output, for an output directory given by another argument
+fn open_dir<'a>(
+    matches: &'a clap::ArgMatches,
+    arg: &str,
+    kind: lock::LockKind,
+) -> Option<(&'a path::Path, lock::Lock)> {
+    let out_dir = path::Path::new(matches.value_of(arg).unwrap());
+    lock_dir(out_dir, kind).map(|lock| (out_dir, lock))
 }
 
-fn parse_memory_usage(mem_str: &str) -> u64 {
-    let re = regex::Regex::new(r"(\d+)([bBkKmMgG]?)").unwrap();
-    let caps = re.captures(mem_str).unwrap();
+// Locks an output directory that has already been found, printing an error and returning None if it can't be used
+fn lock_dir(out_dir: &path::Path, kind: lock::LockKind) -> Option<lock::Lock> {
+    if !out_dir.is_dir() {
+        eprintln!(
+            "ERROR: the output directory '{:?}' does not exist or is a file",
+            out_dir
+        );
+        return None;
+    }
 
-    let bytes = caps
-        .get(1)
-        .map_or("0", |m| m.as_str())
-        .parse::<u64>()
-        .unwrap();
-    match caps.get(2).map_or("", |m| m.as_str()) {
-        "G" => bytes * 1024 * 1024 * 1024,
-        "g" => bytes * 1024 * 1024 * 1024,
-        "M" => bytes * 1024 * 1024,
-        "m" => bytes * 1024 * 1024,
-        "K" => bytes * 1024,
-        "k" => bytes * 1024,
-        _ => bytes,
+    match lock::Lock::acquire(out_dir, kind) {
+        Ok(lock) => Some(lock),
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            None
+        }
     }
 }
 
-struct Statistics {
-    unique_chunks: u32,
-    duplicates: u32,
-    unique_chunk_bytes: u64,
-    duplicate_chunk_bytes: u64,
-    collisions: u32,
+// Opens something a merge wrote in the output directory with open, printing an error and returning the exit status if
+// it can't be: telling the user to merge first if it's missing, or what went wrong otherwise
+fn open_merged<T>(
+    out_dir: &path::Path,
+    open: impl FnOnce(&path::Path) -> io::Result<T>,
+) -> Result<T, i32> {
+    open(out_dir).map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            eprintln!(
+                "ERROR: nothing has been merged in {:?} yet; run 'merge' first",
+                out_dir
+            );
+        } else {
+            eprintln!("ERROR: {}", e);
+        }
+        EXIT_FATAL
+    })
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
-struct Entry {
-    key: [u8; KEY_LEN],
-    size: u16,
-    check: u32,
-}
+// Reads a number of bytes, which may be given in KiB, MiB or GiB (i.e. 64K, 64KB or 64KiB). The error says what was
+// wrong with it, for the caller to print after the argument's name.
+fn parse_memory_usage(mem_str: &str) -> Result<u64, String> {
+    let re = regex::Regex::new(r"^(\d+)(?:([kKmMgG])(?:i?[bB])?|[bB])?$").unwrap();
+    let caps = re
+        .captures(mem_str.trim())
+        .ok_or_else(|| format!("{:?} isn't a size, such as 512, 64K, 16M or 2G", mem_str))?;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-struct EntryData {
-    check: u32,
-    size: u16,
+    // Only digits are matched, so a number that can't be parsed is too large for a u64
+    let too_large = || format!("{:?} is too large", mem_str);
+    let bytes = caps[1].parse::<u64>().map_err(|_| too_large())?;
+    let unit = match caps.get(2).map_or("", |m| m.as_str()) {
+        "G" | "g" => 1024 * 1024 * 1024,
+        "M" | "m" => 1024 * 1024,
+        "K" | "k" => 1024,
+        _ => 1,
+    };
+    bytes.checked_mul(unit).ok_or_else(too_large)
 }
 
 #[cfg(test)]
 mod tests {
 
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(crate::exit_status(0, 0), crate::EXIT_SUCCESS);
+        assert_eq!(crate::exit_status(0, 5), crate::EXIT_SKIPPED);
+        assert_eq!(crate::exit_status(1, 0), crate::EXIT_COLLISIONS);
+        assert_eq!(crate::exit_status(1, 5), crate::EXIT_COLLISIONS);
+    }
+
     #[test]
     fn test_parse_memory_usage() {
-        assert_eq!(crate::parse_memory_usage("100"), 100u64);
-        assert_eq!(crate::parse_memory_usage("100b"), 100u64);
-        assert_eq!(crate::parse_memory_usage("100B"), 100u64);
-        assert_eq!(crate::parse_memory_usage("100k"), 100u64 * 1024);
-        assert_eq!(crate::parse_memory_usage("100K"), 100u64 * 1024);
-        assert_eq!(crate::parse_memory_usage("100m"), 100u64 * 1024 * 1024);
-        assert_eq!(crate::parse_memory_usage("100M"), 100u64 * 1024 * 1024);
+        assert_eq!(crate::parse_memory_usage("100"), Ok(100u64));
+        assert_eq!(crate::parse_memory_usage("100b"), Ok(100u64));
+        assert_eq!(crate::parse_memory_usage("100B"), Ok(100u64));
+        assert_eq!(crate::parse_memory_usage("100k"), Ok(100u64 * 1024));
+        assert_eq!(crate::parse_memory_usage("100K"), Ok(100u64 * 1024));
+        assert_eq!(crate::parse_memory_usage("100m"), Ok(100u64 * 1024 * 1024));
+        assert_eq!(crate::parse_memory_usage("100M"), Ok(100u64 * 1024 * 1024));
         assert_eq!(
             crate::parse_memory_usage("100g"),
-            100u64 * 1024 * 1024 * 1024
+            Ok(100u64 * 1024 * 1024 * 1024)
         );
         assert_eq!(
             crate::parse_memory_usage("100G"),
-            100u64 * 1024 * 1024 * 1024
+            Ok(100u64 * 1024 * 1024 * 1024)
+        );
+
+        // Sizes that aren't numbers, or don't fit in 64 bits, are errors rather than panics
+        assert_eq!(crate::parse_memory_usage("64KiB"), Ok(64 * 1024));
+        assert_eq!(crate::parse_memory_usage("2GB"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(crate::parse_memory_usage("lots").is_err());
+        assert!(crate::parse_memory_usage("1z").is_err());
+        assert!(crate::parse_memory_usage("1.5G").is_err());
+        assert!(crate::parse_memory_usage("99999999999999999999").is_err());
+        assert!(crate::parse_memory_usage("17179869184G").is_err());
+        assert_eq!(
+            crate::parse_memory_usage("17179869183G"),
+            Ok(17179869183 * 1024 * 1024 * 1024)
         );
     }
 }
@@ -0,0 +1,91 @@
+use std::io;
+use std::time;
+
+// The budget for the buffers when it wasn't given and the available memory can't be found
+const FALLBACK_MEMORY: u64 = 64 * 1024 * 1024;
+
+// Combines every committed run in the output directory into the merged file and writes the summary statistics the
+// report is built from. Once the merge has succeeded the runs are removed, unless --keep-intermediate says to keep
+// them so that scans appended later can be merged with them. Merging again then starts over from the runs. Returns the
+// exit status.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let started = time::Instant::now();
+    let (out_dir, mut lock) =
+        match crate::open_output(matches, dedup_core::lock::LockKind::Exclusive) {
+            Some(output) => output,
+            None => return crate::EXIT_FATAL,
+        };
+
+    let memory = match matches.value_of("memory").map(crate::parse_memory_usage) {
+        Some(Ok(memory)) => memory,
+        Some(Err(e)) => {
+            eprintln!("ERROR: --memory: {}", e);
+            return crate::EXIT_FATAL;
+        }
+        None => dedup_core::memory::default_budget().unwrap_or(FALLBACK_MEMORY),
+    };
+
+    let mut wal = match dedup_core::wal::Wal::open(out_dir) {
+        Ok(wal) => wal,
+        Err(e) => {
+            eprintln!(
+                "ERROR: can't open the write-ahead log in {:?}: {}",
+                out_dir, e
+            );
+            return crate::EXIT_FATAL;
+        }
+    };
+    let committed = wal.committed().clone();
+    // Merging the nothing that is left would replace a good merged file with an empty one
+    if committed.merged && committed.scans.is_empty() {
+        say!(
+            "{:?} has already been merged and its runs removed; there is nothing new to merge",
+            out_dir
+        );
+        return crate::EXIT_SUCCESS;
+    }
+    let statistics =
+        match dedup_core::merge::merge_runs(out_dir, &committed, memory, &mut || lock.refresh()) {
+            Ok(statistics) => statistics,
+            // Runs that can't be merged with each other, or that were written by a different version
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("ERROR: {}", e);
+                return crate::EXIT_FATAL;
+            }
+            Err(e) => {
+                eprintln!("ERROR: the merge failed: {}", e);
+                return crate::EXIT_FATAL;
+            }
+        };
+
+    // The history is kept for the trend, so a merge whose statistics can't be added to it still succeeded
+    let files = dedup_core::files::read_records(&out_dir.join(dedup_core::files::FILE_REPORT_NAME))
+        .map_or(0, |files| files.len() as u64);
+    let run = dedup_core::history::Run {
+        finished: dedup_core::history::now_ms(),
+        files,
+        statistics: statistics.clone(),
+    };
+    if let Err(e) =
+        dedup_core::history::open(out_dir).and_then(|db| dedup_core::history::record_run(&db, &run))
+    {
+        warning!("the merge wasn't added to the history: {}", e);
+    }
+
+    let cleaned_up = if matches.is_present("keep-intermediate") {
+        wal.mark_merged()
+    } else {
+        wal.reset().and_then(|_| wal.mark_merged())
+    };
+    if let Err(e) = cleaned_up {
+        warning!("the runs weren't cleaned up after the merge: {}", e);
+    }
+
+    say!("{}s elapsed", started.elapsed().as_secs());
+    say!(
+        "{} runs merged into {} chunks",
+        committed.runs.len(),
+        statistics.unique_chunks
+    );
+    crate::exit_status(statistics.collisions, statistics.skipped)
+}
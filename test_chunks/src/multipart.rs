@@ -0,0 +1,69 @@
+use std::path;
+
+// Prints the parts a multipart upload of a file would be split into, grouping its chunks so that the parts of an
+// unchanged prefix, and most parts after an edit, come out the same as last time (see dedup_core::multipart)
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let options = match crate::scan::pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+    let source = path::Path::new(matches.value_of("source").unwrap());
+    if !source.is_file() {
+        eprintln!("ERROR: {:?} doesn't exist or isn't a file", source);
+        return crate::EXIT_FATAL;
+    }
+
+    let (signature, skipped) = dedup_core::signature::sign(source, &options);
+    let file = match signature.files.first() {
+        Some(file) => file,
+        None => {
+            crate::skipped::print(&skipped);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let planner = match matches.value_of("part-size") {
+        Some(size) => {
+            let size = match crate::parse_memory_usage(size) {
+                Ok(size) => size,
+                Err(e) => {
+                    eprintln!("ERROR: --part-size: {}", e);
+                    return crate::EXIT_FATAL;
+                }
+            };
+            if size < dedup_core::multipart::MIN_PART_SIZE {
+                eprintln!(
+                    "ERROR: parts have to be at least {} bytes",
+                    dedup_core::multipart::MIN_PART_SIZE
+                );
+                return crate::EXIT_FATAL;
+            }
+            dedup_core::multipart::Planner::new(size)
+        }
+        None => dedup_core::multipart::Planner::for_size(file.size),
+    };
+    let parts = match planner.plan_file(file, options.key_len) {
+        Ok(parts) => parts,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+
+    for part in parts.iter() {
+        say!(
+            "{:>5} {:>14} {:>12} {}",
+            part.number,
+            part.offset,
+            part.size,
+            dedup_core::hex_key(&part.id)
+        );
+    }
+    say!(
+        "{} bytes in {} chunks make {} parts of at least {} bytes",
+        file.size,
+        file.chunks.len(),
+        parts.len(),
+        planner.min_size
+    );
+    crate::EXIT_SUCCESS
+}
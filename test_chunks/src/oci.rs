@@ -0,0 +1,116 @@
+use std::io;
+use std::io::Write;
+use std::path;
+
+// How many hex digits of a layer's id to print
+const ID_DIGITS: usize = 12;
+
+// Chunks the files in every layer of the container images given, and reports how much more would be shared if images
+// were stored and distributed a chunk at a time rather than a layer at a time
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let options = match crate::scan::pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+    let mut analyzer = dedup_core::oci::Analyzer::new(options.chunking, options.key_len);
+    for image in matches.values_of("image").unwrap() {
+        let path = path::Path::new(image);
+        if !path.exists() {
+            eprintln!(
+                "ERROR: {:?} doesn't exist. An image in a registry has to be copied into an OCI layout first (for example, skopeo copy docker://{} oci:DIR) or saved with docker save.",
+                image, image
+            );
+            return crate::EXIT_FATAL;
+        }
+        match analyzer.add(path) {
+            Ok(images) => tracing::info!(%image, images, "read"),
+            Err(e) => {
+                eprintln!("ERROR: can't read the images in {:?}: {}", image, e);
+                return crate::EXIT_FATAL;
+            }
+        }
+    }
+    let analysis = analyzer.finish();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let written = match matches.value_of("format") {
+        Some("json") => serde_json::to_writer_pretty(&mut out, &analysis)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(out)),
+        _ => write_text(&mut out, &analysis),
+    };
+    if let Err(e) = written.and_then(|_| out.flush()) {
+        eprintln!("ERROR: can't write the analysis: {}", e);
+        return crate::EXIT_FATAL;
+    }
+    crate::EXIT_SUCCESS
+}
+
+fn write_text(out: &mut dyn Write, analysis: &dedup_core::oci::Analysis) -> io::Result<()> {
+    let percent = |part: u64, whole: u64| 100.0 * part as f64 / whole.max(1) as f64;
+    let layers: usize = analysis.images.iter().map(|i| i.layers.len()).sum();
+    let (image_bytes, layer_bytes) = (analysis.image_bytes(), analysis.layer_bytes());
+    let sharing = analysis.sharing();
+    writeln!(
+        out,
+        "{} images with {} layers between them, {} of them distinct",
+        analysis.images.len(),
+        layers,
+        analysis.layers.len()
+    )?;
+    writeln!(out, "{} bytes of files with nothing shared", image_bytes)?;
+    writeln!(
+        out,
+        "{} bytes ({:.4}%) sharing whole layers, {} bytes as the layers are stored",
+        layer_bytes,
+        percent(layer_bytes, image_bytes),
+        analysis.blob_bytes()
+    )?;
+    writeln!(
+        out,
+        "{} bytes ({:.4}%) sharing chunks, in {} unique chunks: {:.4}% less than sharing whole layers",
+        sharing.new,
+        percent(sharing.new, image_bytes),
+        analysis.unique_chunks,
+        100.0 - percent(sharing.new, layer_bytes)
+    )?;
+    writeln!(
+        out,
+        "Of the bytes in distinct layers, {:.4}% repeat within a layer, {:.4}% another layer of the same image and {:.4}% a layer of another image",
+        percent(sharing.same_layer, layer_bytes),
+        percent(sharing.other_layer, layer_bytes),
+        percent(sharing.other_image, layer_bytes)
+    )?;
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{:<w$} {:>6} {:>8} {:>14} {:>9}  first image",
+        "layer",
+        "images",
+        "files",
+        "bytes",
+        "new",
+        w = ID_DIGITS
+    )?;
+    for layer in analysis.layers.iter() {
+        // The digest without its algorithm, or the start of the blob's name
+        let id = layer
+            .id
+            .split_once(':')
+            .map_or(&layer.id[..], |(_, hex)| hex);
+        writeln!(
+            out,
+            "{:<w$} {:>6} {:>8} {:>14} {:>8.4}%  {}",
+            id.chars().take(ID_DIGITS).collect::<String>(),
+            layer.images,
+            layer.files,
+            layer.sharing.total(),
+            percent(layer.sharing.new, layer.sharing.total()),
+            layer.image,
+            w = ID_DIGITS
+        )?;
+    }
+    Ok(())
+}
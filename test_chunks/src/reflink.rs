@@ -0,0 +1,268 @@
+use std::collections;
+use std::fs;
+use std::io;
+use std::path;
+
+// How many of the files that duplicates were first found in apply-dedupe keeps open at once
+const MAX_OPEN_SOURCES: usize = 64;
+
+// Makes the whole-file duplicates the last merge found share their storage, by cloning the first copy of each file
+// over the others. Every copy is compared with the first byte for byte before it is cloned, so files that changed
+// since the scan are left alone.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+    let files = match crate::open_merged(out_dir, |dir| {
+        dedup_core::files::read_records(&dir.join(dedup_core::files::FILE_REPORT_NAME))
+    }) {
+        Ok(files) => files,
+        Err(status) => return status,
+    };
+    let dry_run = matches.is_present("dry-run");
+
+    let (mut cloned, mut cloned_bytes, mut linked, mut changed, mut failed) = (0, 0, 0, 0, 0);
+    for group in dedup_core::reflink::duplicate_files(&files) {
+        let source = path::Path::new(&files[group[0]].path);
+        for &i in group[1..].iter() {
+            let dest = path::Path::new(&files[i].path);
+            let checked = dedup_core::reflink::same_file(source, dest).and_then(|same| {
+                if same {
+                    return Ok(None);
+                }
+                dedup_core::reflink::same_contents(source, dest).map(Some)
+            });
+            match checked {
+                // Hard links to one file already share everything
+                Ok(None) => {
+                    linked += 1;
+                    continue;
+                }
+                Ok(Some(false)) => {
+                    warning!(
+                        "{:?} has changed since it was scanned, so it wasn't cloned",
+                        dest
+                    );
+                    changed += 1;
+                    continue;
+                }
+                Ok(Some(true)) => {}
+                Err(e) => {
+                    warning!("can't compare {:?} with {:?}: {}", dest, source, e);
+                    failed += 1;
+                    continue;
+                }
+            }
+            if dry_run {
+                say!("would clone {:?} to {:?}", source, dest);
+            } else if let Err(e) = dedup_core::reflink::clone_file(source, dest) {
+                warning!("can't clone {:?} to {:?}: {}", source, dest, e);
+                failed += 1;
+                continue;
+            }
+            tracing::debug!(source = %source.display(), dest = %dest.display(), "cloned");
+            cloned += 1;
+            cloned_bytes += files[i].size;
+        }
+    }
+
+    say!(
+        "{} duplicate files ({} bytes) {} storage with an identical file",
+        cloned,
+        cloned_bytes,
+        if dry_run { "would share" } else { "now share" }
+    );
+    if linked > 0 {
+        say!(
+            "{} duplicates were already hard links to the same file",
+            linked
+        );
+    }
+    if changed > 0 {
+        say!(
+            "{} duplicates had changed since the scan and were left alone",
+            changed
+        );
+    }
+    if failed > 0 {
+        say!("{} duplicates couldn't be cloned", failed);
+        return crate::EXIT_SKIPPED;
+    }
+    crate::EXIT_SUCCESS
+}
+
+// Makes the duplicate chunks the last merge found share storage with their first copies, a range at a time (see
+// dedup_core::reflink::dedupe_range). Each file that has duplicates is chunked again to find where they are, since
+// the merged file only records the first copy of each chunk. Every range is compared before it is shared, so files
+// that changed since the scan lose nothing.
+pub fn dedupe(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+    let (files, merged) = match crate::open_merged(out_dir, |dir| {
+        let files =
+            dedup_core::files::read_records(&dir.join(dedup_core::files::FILE_REPORT_NAME))?;
+        let merged =
+            dedup_core::run::RunIndex::open(&dir.join(dedup_core::merge::MERGED_FILE_NAME))?;
+        Ok((files, merged))
+    }) {
+        Ok(merged) => merged,
+        Err(status) => return status,
+    };
+    let chunking = match merged.params().chunking() {
+        Some(chunking) => chunking,
+        None => {
+            eprintln!(
+                "ERROR: the scan used {}, which this build can't chunk with",
+                merged.params()
+            );
+            return crate::EXIT_FATAL;
+        }
+    };
+    let dry_run = matches.is_present("dry-run");
+
+    let (mut shared, mut shared_bytes, mut unaligned_bytes, mut changed, mut failed) =
+        (0, 0, 0, 0, 0);
+    let mut sources: collections::HashMap<u32, fs::File> = collections::HashMap::new();
+    for (i, file) in files.iter().enumerate() {
+        // Files whose chunks were all found there first have nothing to share
+        if file.duplicate_bytes() == 0 {
+            continue;
+        }
+        let dest_path = path::Path::new(&file.path);
+        let ranges =
+            match dedup_core::reflink::duplicate_ranges(&merged, &chunking, i as u32, dest_path) {
+                Ok(ranges) => ranges,
+                Err(e) => {
+                    warning!("can't read {:?}: {}", dest_path, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+        if ranges.is_empty() {
+            continue;
+        }
+        let dest = match fs::File::open(dest_path) {
+            Ok(dest) => dest,
+            Err(e) => {
+                warning!("can't open {:?}: {}", dest_path, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let (dest_size, block) = match dest
+            .metadata()
+            .and_then(|metadata| Ok((metadata.len(), dedup_core::reflink::block_size(&dest)?)))
+        {
+            Ok(sizes) => sizes,
+            Err(e) => {
+                warning!("can't read the metadata of {:?}: {}", dest_path, e);
+                failed += 1;
+                continue;
+            }
+        };
+        for range in ranges {
+            let source_path = path::Path::new(&files[range.source as usize].path);
+            if !sources.contains_key(&range.source) {
+                // Only so many files are kept open at once
+                if sources.len() >= MAX_OPEN_SOURCES {
+                    sources.clear();
+                }
+                match fs::File::open(source_path) {
+                    Ok(source) => {
+                        sources.insert(range.source, source);
+                    }
+                    Err(e) => {
+                        warning!("can't open {:?}: {}", source_path, e);
+                        failed += 1;
+                        continue;
+                    }
+                }
+            }
+            let source = &sources[&range.source];
+            let source_size = match source.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(e) => {
+                    warning!("can't read the metadata of {:?}: {}", source_path, e);
+                    failed += 1;
+                    continue;
+                }
+            };
+            let aligned = match range.aligned(block, source_size, dest_size) {
+                Some(aligned) => aligned,
+                None => {
+                    unaligned_bytes += range.len;
+                    continue;
+                }
+            };
+            unaligned_bytes += range.len - aligned.len;
+            if dry_run {
+                say!(
+                    "would share {} bytes of {:?} at {} with {:?} at {}",
+                    aligned.len,
+                    dest_path,
+                    aligned.dest_offset,
+                    source_path,
+                    aligned.source_offset
+                );
+                shared += 1;
+                shared_bytes += aligned.len;
+                continue;
+            }
+            match dedup_core::reflink::dedupe_range(
+                source,
+                aligned.source_offset,
+                &dest,
+                aligned.dest_offset,
+                aligned.len,
+            ) {
+                Ok(bytes) => {
+                    tracing::debug!(source = %source_path.display(), dest = %dest_path.display(), bytes, "shared");
+                    shared += 1;
+                    shared_bytes += bytes;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => changed += 1,
+                Err(e) => {
+                    warning!(
+                        "can't share {:?} at {} with {:?}: {}",
+                        dest_path,
+                        aligned.dest_offset,
+                        source_path,
+                        e
+                    );
+                    failed += 1;
+                    // Nothing else on a filesystem that can't share will work either
+                    if e.kind() == io::ErrorKind::Unsupported {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    say!(
+        "{} duplicate ranges ({} bytes) {} storage with their first copies",
+        shared,
+        shared_bytes,
+        if dry_run { "would share" } else { "now share" }
+    );
+    if unaligned_bytes > 0 {
+        say!(
+            "{} duplicate bytes don't line up with the filesystem's blocks and can't be shared",
+            unaligned_bytes
+        );
+    }
+    if changed > 0 {
+        say!(
+            "{} ranges had changed since the scan and were left alone",
+            changed
+        );
+    }
+    if failed > 0 {
+        say!("{} files or ranges couldn't be shared", failed);
+        return crate::EXIT_SKIPPED;
+    }
+    crate::EXIT_SUCCESS
+}
@@ -0,0 +1,881 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+
+use serde_derive::Serialize;
+
+// How many characters the longest bar of the chunk size histogram takes up
+const HISTOGRAM_WIDTH: u64 = 40;
+
+// The size of the chunk size chart in the HTML report, in pixels
+const CHART_WIDTH: u64 = 800;
+const CHART_HEIGHT: u64 = 240;
+
+// The HTML report has no scripts and nothing to fetch, so it can be mailed around or opened from anywhere
+const HTML_STYLE: &str =
+    "body { font-family: sans-serif; margin: 2em auto; max-width: 60em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1.5em; }
+th, td { padding: 0.25em 0.75em; border-bottom: 1px solid #ddd; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+.bar { background: #ddd; width: 12em; height: 0.9em; }
+.bar div { background: #3a7bd5; height: 100%; }
+.split { display: flex; height: 2em; margin-bottom: 0.5em; }
+.split div { color: white; padding: 0.4em; white-space: nowrap; overflow: hidden; }
+.unique { background: #3a7bd5; }
+.duplicate { background: #8cc152; }
+.chart rect { fill: #3a7bd5; }
+.chart text { font-size: 10px; fill: #444; }
+";
+
+// Everything the report says, in a form that can be printed for people or serialized for scripts
+#[derive(Debug, Serialize)]
+struct Report {
+    total_bytes: u64,
+    logical_bytes: u64,
+    hole_bytes: u64,
+    baseline_bytes: u64,
+    baseline_chunks: u64,
+    unique_bytes: u64,
+    duplicate_bytes: u64,
+    unique_percent: f64,
+    // The unique bytes once compressed, estimated from the chunks the scans compressed. Both are 0 if they didn't.
+    compressed_bytes: u64,
+    compressed_percent: f64,
+    compression_sampled_bytes: u64,
+    // Scanned bytes in chunks that look already compressed or encrypted
+    high_entropy_bytes: u64,
+    high_entropy_percent: f64,
+    unique_chunks: u64,
+    duplicate_chunks: u64,
+    bytes_per_chunk: u64,
+    collisions: u64,
+    chunks_occurring_more_than_once: u64,
+    top_one_percent_savings_percent: f64,
+    most_duplicated: Vec<DuplicatedChunk>,
+    // How many chunks of each size were made, duplicates included, and how many were exactly the largest size
+    chunk_sizes: Vec<dedup_core::sizes::SizeRange>,
+    largest_chunks: dedup_core::sizes::SizeRange,
+    // How well each kind of file deduplicated, for the kinds with the most bytes
+    file_types: Vec<dedup_core::files::Breakdown>,
+    // The same for each directory that was scanned, and the pairs of them that share the most unique data
+    roots: Vec<dedup_core::files::Breakdown>,
+    root_overlap: Vec<dedup_core::merge::Overlap>,
+    // The same for each directory group
+    directories: Vec<dedup_core::files::Breakdown>,
+    directory_overlap: Vec<dedup_core::merge::Overlap>,
+    hard_links: u64,
+    hard_link_bytes: u64,
+    // Files left out for being outside the file size range, which none of the other numbers include either
+    size_filtered_files: u64,
+    size_filtered_bytes: u64,
+    // Files that looked already compressed, either left out (which none of the other numbers include) or chunked as
+    // they were (which the numbers do include, and which dedup poorly)
+    skipped_compressed_files: u64,
+    skipped_compressed_bytes: u64,
+    flagged_compressed_files: u64,
+    flagged_compressed_bytes: u64,
+    // Files and directories that couldn't be read, which none of the other numbers include
+    skipped: u64,
+    scan_seconds: f64,
+    merge_seconds: f64,
+    // How busy each stage of the scans and the merge was, to show which one held the others back
+    phases: Vec<PhaseTime>,
+}
+
+#[derive(Debug, Serialize)]
+struct PhaseTime {
+    name: &'static str,
+    seconds: f64,
+    bytes_per_second: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicatedChunk {
+    key: String,
+    occurrences: u32,
+    size: u32,
+}
+
+// Prints the statistics from the last merge along with how the duplicates are spread across chunks.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+
+    let statistics = match dedup_core::merge::read_summary(out_dir) {
+        Ok(statistics) => statistics,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "ERROR: nothing has been merged in '{:?}' yet; run 'merge' first",
+                out_dir
+            );
+            return crate::EXIT_FATAL;
+        }
+        Err(e) => {
+            eprintln!("ERROR: can't read the summary of the merge: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+
+    let files =
+        match dedup_core::files::read_records(&out_dir.join(dedup_core::files::FILE_REPORT_NAME)) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("ERROR: can't read the file report: {}", e);
+                return crate::EXIT_FATAL;
+            }
+        };
+
+    // The merged file holds the total number of occurrences of every chunk
+    let mut popularity = dedup_core::popularity::Popularity::new(crate::TOP_CHUNKS);
+    let mut sizes = dedup_core::sizes::SizeHistogram::default();
+    let mut merged = match dedup_core::run::RunReader::open(
+        &out_dir.join(dedup_core::merge::MERGED_FILE_NAME),
+    ) {
+        Ok(merged) => merged,
+        Err(e) => {
+            eprintln!("ERROR: can't open the merged file: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let key_len = merged.key_len();
+    let mut parquet = match matches.value_of("parquet") {
+        Some(name) => match crate::export::ChunkWriter::create(std::path::Path::new(name), key_len)
+        {
+            Ok(parquet) => Some(parquet),
+            Err(e) => {
+                eprintln!("ERROR: can't create {:?}: {}", name, e);
+                return crate::EXIT_FATAL;
+            }
+        },
+        None => None,
+    };
+    let mut sampler = match matches.value_of("sample-chunks") {
+        None => None,
+        Some(_) => match matches.value_of("sample-count").unwrap().parse::<usize>() {
+            Ok(count) => Some(crate::inspect::Sampler::new(count)),
+            Err(_) => {
+                eprintln!("ERROR: --sample-count takes a number of chunks");
+                return crate::EXIT_FATAL;
+            }
+        },
+    };
+    loop {
+        let entry = match merged.next_entry() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("ERROR: can't read the merged file: {}", e);
+                return crate::EXIT_FATAL;
+            }
+        };
+        popularity.record(entry.key, entry.count, entry.size);
+        sizes.record(entry.size, entry.count);
+        if let Some(sampler) = sampler.as_mut() {
+            sampler.record(&entry);
+        }
+        // An entry's file is its position in the file report
+        if let Some(parquet) = parquet.as_mut() {
+            let file = files
+                .get(entry.file as usize)
+                .map_or("", |f| f.path.as_str());
+            if let Err(e) = parquet.write(&entry, file) {
+                eprintln!("ERROR: can't write the chunks to parquet: {}", e);
+                return crate::EXIT_FATAL;
+            }
+        }
+    }
+    if let Some(parquet) = parquet {
+        if let Err(e) = parquet.finish() {
+            eprintln!("ERROR: can't write the chunks to parquet: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    }
+    if let (Some(sampler), Some(dir)) = (sampler, matches.value_of("sample-chunks")) {
+        let dir = std::path::Path::new(dir);
+        match sampler.write(dir, &files, key_len) {
+            // The report itself may be JSON on stdout, so this only goes to the log
+            Ok(samples) => {
+                tracing::info!(samples = samples.len(), dir = ?dir, "sampled chunks written")
+            }
+            Err(e) => {
+                eprintln!("ERROR: can't write the sampled chunks to {:?}: {}", dir, e);
+                return crate::EXIT_FATAL;
+            }
+        }
+    }
+
+    // Chunks a baseline already had were scanned, but never stored
+    let total_bytes = statistics.duplicate_chunk_bytes
+        + statistics.unique_chunk_bytes
+        + statistics.baseline_bytes;
+    // The sample is picked by chunk id, so its compression ratio should hold for the rest of the unique chunks
+    let compressed_bytes = match statistics.compression_sampled_bytes {
+        0 => 0,
+        sampled => {
+            (statistics.unique_chunk_bytes as f64 * statistics.compressed_bytes as f64
+                / sampled as f64) as u64
+        }
+    };
+    let report = Report {
+        total_bytes,
+        logical_bytes: total_bytes + statistics.hole_bytes,
+        hole_bytes: statistics.hole_bytes,
+        baseline_bytes: statistics.baseline_bytes,
+        baseline_chunks: statistics.baseline_chunks,
+        unique_bytes: statistics.unique_chunk_bytes,
+        duplicate_bytes: statistics.duplicate_chunk_bytes,
+        unique_percent: ((statistics.unique_chunk_bytes * 100) as f64) / (total_bytes as f64),
+        compressed_bytes,
+        compressed_percent: (compressed_bytes * 100) as f64 / total_bytes as f64,
+        compression_sampled_bytes: statistics.compression_sampled_bytes,
+        high_entropy_bytes: statistics.high_entropy_bytes,
+        high_entropy_percent: (statistics.high_entropy_bytes * 100) as f64 / total_bytes as f64,
+        unique_chunks: statistics.unique_chunks,
+        duplicate_chunks: statistics.duplicates,
+        bytes_per_chunk: statistics
+            .unique_chunk_bytes
+            .checked_div(statistics.unique_chunks)
+            .unwrap_or(0),
+        collisions: statistics.collisions,
+        chunks_occurring_more_than_once: popularity.duplicated_chunks(),
+        top_one_percent_savings_percent: popularity.savings_from_top(0.01) * 100.0,
+        most_duplicated: popularity
+            .most_duplicated()
+            .into_iter()
+            .map(|(key, occurrences, size)| DuplicatedChunk {
+                key: dedup_core::hex_key(&key[..key_len]),
+                occurrences,
+                size,
+            })
+            .collect(),
+        chunk_sizes: sizes.ranges(),
+        largest_chunks: sizes.largest(),
+        file_types: dedup_core::files::breakdown(&files, |f| f.kind.clone(), crate::TOP_FILE_TYPES),
+        roots: dedup_core::files::breakdown(&files, |f| f.root.clone(), crate::TOP_DIRECTORIES),
+        root_overlap: statistics
+            .root_overlap
+            .iter()
+            .take(crate::TOP_DIRECTORIES)
+            .cloned()
+            .collect(),
+        directories: dedup_core::files::breakdown(
+            &files,
+            |f| f.group.clone(),
+            crate::TOP_DIRECTORIES,
+        ),
+        directory_overlap: statistics
+            .directory_overlap
+            .iter()
+            .take(crate::TOP_DIRECTORIES)
+            .cloned()
+            .collect(),
+        hard_links: statistics.hard_links,
+        hard_link_bytes: statistics.hard_link_bytes,
+        size_filtered_files: statistics.size_filtered_files,
+        size_filtered_bytes: statistics.size_filtered_bytes,
+        skipped_compressed_files: statistics.skipped_compressed_files,
+        skipped_compressed_bytes: statistics.skipped_compressed_bytes,
+        flagged_compressed_files: statistics.flagged_compressed_files,
+        flagged_compressed_bytes: statistics.flagged_compressed_bytes,
+        skipped: statistics.skipped,
+        scan_seconds: statistics.scan_ms as f64 / 1000.0,
+        merge_seconds: statistics.merge_ms as f64 / 1000.0,
+        phases: phases(&statistics)
+            .into_iter()
+            .map(|(name, phase)| PhaseTime {
+                name,
+                seconds: phase.seconds(),
+                bytes_per_second: phase.throughput(),
+            })
+            .collect(),
+    };
+
+    if let Some(name) = matches.value_of("csv") {
+        if let Err(e) = write_file(name, |out| dedup_core::files::write_csv(out, &files)) {
+            eprintln!("ERROR: can't write {:?}: {}", name, e);
+            return crate::EXIT_FATAL;
+        }
+    }
+
+    if let Some(name) = matches.value_of("report-html") {
+        if let Err(e) = write_file(name, |out| write_html(out, &report)) {
+            eprintln!("ERROR: can't write {:?}: {}", name, e);
+            return crate::EXIT_FATAL;
+        }
+    }
+
+    // Write the report to stdout unless a file was asked for
+    let format = matches.value_of("format");
+    let written = match matches.value_of("report-file") {
+        Some(name) => write_file(name, |out| write_report(out, format, &report)),
+        None => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            write_report(&mut out, format, &report).and_then(|_| out.flush())
+        }
+    };
+    if let Err(e) = written {
+        eprintln!("ERROR: can't write the report: {}", e);
+        return crate::EXIT_FATAL;
+    }
+    crate::EXIT_SUCCESS
+}
+
+// Creates (or replaces) the file and writes it
+fn write_file(name: &str, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+    let mut out = io::BufWriter::new(fs::File::create(name)?);
+    write(&mut out)?;
+    out.flush()
+}
+
+fn write_report(out: &mut dyn Write, format: Option<&str>, report: &Report) -> io::Result<()> {
+    match format {
+        Some("json") => {
+            serde_json::to_writer_pretty(&mut *out, report)?;
+            writeln!(out)
+        }
+        _ => write_text(out, report),
+    }
+}
+
+fn write_text(out: &mut dyn Write, report: &Report) -> io::Result<()> {
+    writeln!(out, "{} total bytes scanned", report.total_bytes)?;
+    if report.hole_bytes > 0 {
+        writeln!(
+            out,
+            "{} logical bytes, including {} bytes in holes of sparse files that were skipped",
+            report.logical_bytes, report.hole_bytes
+        )?;
+    }
+    if report.baseline_chunks > 0 {
+        writeln!(
+            out,
+            "{} bytes in {} chunks were already in the baseline",
+            report.baseline_bytes, report.baseline_chunks
+        )?;
+    }
+    writeln!(
+        out,
+        "{} bytes {:0.4}% were unique",
+        report.unique_bytes, report.unique_percent
+    )?;
+    if report.compression_sampled_bytes > 0 {
+        writeln!(
+            out,
+            "~{} bytes {:0.4}% once the unique chunks are compressed (measured on {} bytes of them)",
+            report.compressed_bytes, report.compressed_percent, report.compression_sampled_bytes
+        )?;
+    }
+    if report.high_entropy_bytes > 0 {
+        writeln!(
+            out,
+            "{} bytes {:0.4}% looked already compressed or encrypted",
+            report.high_entropy_bytes, report.high_entropy_percent
+        )?;
+    }
+    writeln!(out, "{} chunks", report.unique_chunks)?;
+    writeln!(out, "{} bytes per chunk", report.bytes_per_chunk)?;
+    writeln!(out, "{} collisions", report.collisions)?;
+    if report.hard_links > 0 {
+        writeln!(
+            out,
+            "{} hard links to files that were already scanned were skipped ({} bytes)",
+            report.hard_links, report.hard_link_bytes
+        )?;
+    }
+    if report.size_filtered_files > 0 {
+        writeln!(
+            out,
+            "{} files outside the file size range were excluded ({} bytes)",
+            report.size_filtered_files, report.size_filtered_bytes
+        )?;
+    }
+    if report.skipped_compressed_files > 0 {
+        writeln!(
+            out,
+            "{} files that looked already compressed were excluded ({} bytes)",
+            report.skipped_compressed_files, report.skipped_compressed_bytes
+        )?;
+    }
+    if report.flagged_compressed_files > 0 {
+        writeln!(
+            out,
+            "{} files that looked already compressed were chunked as they were ({} bytes)",
+            report.flagged_compressed_files, report.flagged_compressed_bytes
+        )?;
+    }
+    if report.skipped > 0 {
+        writeln!(
+            out,
+            "{} files or directories couldn't be read and are missing from these numbers",
+            report.skipped
+        )?;
+    }
+
+    // Show whether the savings come from a few hot chunks or from broad similarity
+    writeln!(
+        out,
+        "{} chunks occurred more than once",
+        report.chunks_occurring_more_than_once
+    )?;
+    writeln!(
+        out,
+        "{:0.4}% of duplicate bytes came from the most duplicated 1% of chunks",
+        report.top_one_percent_savings_percent
+    )?;
+    for chunk in report.most_duplicated.iter() {
+        writeln!(
+            out,
+            "  {} occurred {} times ({} bytes)",
+            chunk.key, chunk.occurrences, chunk.size
+        )?;
+    }
+
+    // The minimum and maximum chunk sizes show up as the edges of the distribution, and a spike at the largest size
+    // means many chunks found no boundary before the maximum
+    writeln!(out, "chunk sizes:")?;
+    let all_chunks: u64 = report.chunk_sizes.iter().map(|r| r.chunks).sum();
+    let most_chunks = report
+        .chunk_sizes
+        .iter()
+        .map(|r| r.chunks)
+        .max()
+        .unwrap_or(0);
+    for range in report.chunk_sizes.iter() {
+        writeln!(
+            out,
+            "  {:>7}-{:<7} {:>10} chunks {:>8.4}% {}",
+            range.min,
+            range.max,
+            range.chunks,
+            (range.chunks * 100) as f64 / all_chunks as f64,
+            "#".repeat((range.chunks * HISTOGRAM_WIDTH / most_chunks) as usize)
+        )?;
+    }
+    if report.largest_chunks.chunks > 0 {
+        writeln!(
+            out,
+            "{} chunks {:0.4}% were exactly the largest size of {} bytes",
+            report.largest_chunks.chunks,
+            (report.largest_chunks.chunks * 100) as f64 / all_chunks as f64,
+            report.largest_chunks.max
+        )?;
+    }
+
+    // Some kinds of files deduplicate far better than others
+    writeln!(out, "deduplication by file type:")?;
+    for file_type in report.file_types.iter() {
+        writeln!(
+            out,
+            "  {}: {} files, {} bytes {:0.4}% deduplicated",
+            file_type.name, file_type.files, file_type.bytes, file_type.percent_deduplicated
+        )?;
+    }
+
+    // A chunk counts as unique in the first directory it was found in, so the overlap shows where the rest of it went
+    if report.roots.len() > 1 {
+        writeln!(out, "deduplication by scanned directory:")?;
+        write_breakdown(out, &report.roots, &report.root_overlap)?;
+    }
+    writeln!(out, "deduplication by directory:")?;
+    write_breakdown(out, &report.directories, &report.directory_overlap)?;
+
+    writeln!(
+        out,
+        "{:.3}s scanning, {:.3}s merging",
+        report.scan_seconds, report.merge_seconds
+    )?;
+    writeln!(out, "time each stage was busy:")?;
+    for phase in report.phases.iter() {
+        writeln!(
+            out,
+            "  {}: {}",
+            phase.name,
+            format_phase(phase.seconds, phase.bytes_per_second)
+        )?;
+    }
+    Ok(())
+}
+
+// The stages of a scan, by the names the report gives them
+pub fn scan_phases(
+    timings: &dedup_core::pipeline::Timings,
+) -> Vec<(&'static str, dedup_core::pipeline::Phase)> {
+    vec![
+        ("reading", timings.read),
+        ("boundary detection", timings.boundary),
+        ("hashing (summed across threads)", timings.hash),
+        ("index insertion", timings.insert),
+    ]
+}
+
+// The stages of the scans followed by the stages of the merge
+fn phases(
+    statistics: &dedup_core::merge::Statistics,
+) -> Vec<(&'static str, dedup_core::pipeline::Phase)> {
+    let untimed = |ms: u64| dedup_core::pipeline::Phase {
+        bytes: 0,
+        micros: ms * 1000,
+    };
+    let mut phases = scan_phases(&statistics.scan_timings);
+    phases.push(("merge: opening runs", untimed(statistics.merge_open_ms)));
+    phases.push(("merge: combining runs", statistics.merge_combine));
+    phases.push(("merge: writing reports", untimed(statistics.merge_write_ms)));
+    phases
+}
+
+// A stage's busy time, with its throughput in MB/s if it has one
+pub fn format_phase(seconds: f64, bytes_per_second: Option<f64>) -> String {
+    match bytes_per_second {
+        Some(throughput) => format!("{:.3}s, {:.1} MB/s", seconds, throughput / 1_000_000.0),
+        None => format!("{:.3}s", seconds),
+    }
+}
+
+fn write_breakdown(
+    out: &mut dyn Write,
+    directories: &[dedup_core::files::Breakdown],
+    overlaps: &[dedup_core::merge::Overlap],
+) -> io::Result<()> {
+    for directory in directories.iter() {
+        writeln!(
+            out,
+            "  {}: {} files, {} bytes {:0.4}% deduplicated",
+            directory.name, directory.files, directory.bytes, directory.percent_deduplicated
+        )?;
+    }
+    for overlap in overlaps.iter() {
+        writeln!(
+            out,
+            "  {} and {} share {} bytes",
+            overlap.first, overlap.second, overlap.bytes
+        )?;
+    }
+    Ok(())
+}
+
+// Writes the report as a single HTML page, with bars in place of the percentages and a chart of the chunk sizes
+fn write_html(out: &mut dyn Write, report: &Report) -> io::Result<()> {
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(
+        out,
+        "<html><head><meta charset=\"utf-8\"><title>Deduplication report</title>"
+    )?;
+    writeln!(out, "<style>\n{}</style></head><body>", HTML_STYLE)?;
+    writeln!(out, "<h1>Deduplication report</h1>")?;
+
+    // How the scanned bytes split between unique and duplicate, at a glance
+    let unique_percent = report.unique_percent.clamp(0.0, 100.0);
+    writeln!(
+        out,
+        "<div class=\"split\"><div class=\"unique\" style=\"width: {:.2}%\">{:.1}% unique</div><div class=\"duplicate\" style=\"width: {:.2}%\">{:.1}% duplicate</div></div>",
+        unique_percent,
+        unique_percent,
+        100.0 - unique_percent,
+        100.0 - unique_percent
+    )?;
+
+    writeln!(out, "<h2>Summary</h2><table>")?;
+    let mut row = |label: &str, value: String| {
+        writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td></tr>",
+            label,
+            escape(&value)
+        )
+    };
+    row("Total bytes scanned", human_bytes(report.total_bytes))?;
+    if report.hole_bytes > 0 {
+        row(
+            "Bytes in holes of sparse files",
+            human_bytes(report.hole_bytes),
+        )?;
+    }
+    if report.baseline_chunks > 0 {
+        row(
+            "Already in the baseline",
+            human_bytes(report.baseline_bytes),
+        )?;
+    }
+    row(
+        "Unique bytes",
+        format!(
+            "{} ({:.4}%)",
+            human_bytes(report.unique_bytes),
+            report.unique_percent
+        ),
+    )?;
+    row("Duplicate bytes", human_bytes(report.duplicate_bytes))?;
+    if report.compression_sampled_bytes > 0 {
+        row(
+            "Unique bytes once compressed",
+            format!(
+                "~{} ({:.4}%)",
+                human_bytes(report.compressed_bytes),
+                report.compressed_percent
+            ),
+        )?;
+    }
+    if report.high_entropy_bytes > 0 {
+        row(
+            "Already compressed or encrypted",
+            format!(
+                "{} ({:.4}%)",
+                human_bytes(report.high_entropy_bytes),
+                report.high_entropy_percent
+            ),
+        )?;
+    }
+    row("Unique chunks", report.unique_chunks.to_string())?;
+    row("Duplicate chunks", report.duplicate_chunks.to_string())?;
+    row("Bytes per chunk", report.bytes_per_chunk.to_string())?;
+    row("Collisions", report.collisions.to_string())?;
+    if report.hard_links > 0 {
+        row(
+            "Hard links skipped",
+            format!(
+                "{} ({})",
+                report.hard_links,
+                human_bytes(report.hard_link_bytes)
+            ),
+        )?;
+    }
+    if report.size_filtered_files > 0 {
+        row(
+            "Files outside the size range",
+            format!(
+                "{} ({})",
+                report.size_filtered_files,
+                human_bytes(report.size_filtered_bytes)
+            ),
+        )?;
+    }
+    if report.skipped_compressed_files > 0 {
+        row(
+            "Compressed files skipped",
+            format!(
+                "{} ({})",
+                report.skipped_compressed_files,
+                human_bytes(report.skipped_compressed_bytes)
+            ),
+        )?;
+    }
+    if report.flagged_compressed_files > 0 {
+        row(
+            "Compressed files chunked as they were",
+            format!(
+                "{} ({})",
+                report.flagged_compressed_files,
+                human_bytes(report.flagged_compressed_bytes)
+            ),
+        )?;
+    }
+    if report.skipped > 0 {
+        row(
+            "Files or directories that couldn't be read",
+            report.skipped.to_string(),
+        )?;
+    }
+    row(
+        "Duplicate bytes from the most duplicated 1% of chunks",
+        format!("{:.4}%", report.top_one_percent_savings_percent),
+    )?;
+    row(
+        "Time",
+        format!(
+            "{:.3}s scanning, {:.3}s merging",
+            report.scan_seconds, report.merge_seconds
+        ),
+    )?;
+    for phase in report.phases.iter() {
+        row(
+            &format!("Time {}", phase.name),
+            format_phase(phase.seconds, phase.bytes_per_second),
+        )?;
+    }
+    writeln!(out, "</table>")?;
+
+    writeln!(out, "<h2>Chunk sizes</h2>")?;
+    write_size_chart(out, report)?;
+    if report.largest_chunks.chunks > 0 {
+        writeln!(
+            out,
+            "<p>{} chunks were exactly the largest size of {} bytes.</p>",
+            report.largest_chunks.chunks, report.largest_chunks.max
+        )?;
+    }
+
+    if !report.most_duplicated.is_empty() {
+        writeln!(out, "<h2>Most duplicated chunks</h2><table>")?;
+        writeln!(
+            out,
+            "<tr><th>Chunk</th><th>Occurrences</th><th>Size</th></tr>"
+        )?;
+        for chunk in report.most_duplicated.iter() {
+            writeln!(
+                out,
+                "<tr><td><code>{}</code></td><td>{}</td><td>{}</td></tr>",
+                chunk.key, chunk.occurrences, chunk.size
+            )?;
+        }
+        writeln!(out, "</table>")?;
+    }
+
+    writeln!(out, "<h2>By file type</h2>")?;
+    write_html_breakdown(out, "File type", &report.file_types, &[])?;
+    if report.roots.len() > 1 {
+        writeln!(out, "<h2>By scanned directory</h2>")?;
+        write_html_breakdown(out, "Directory", &report.roots, &report.root_overlap)?;
+    }
+    writeln!(out, "<h2>By directory</h2>")?;
+    write_html_breakdown(
+        out,
+        "Directory",
+        &report.directories,
+        &report.directory_overlap,
+    )?;
+
+    writeln!(out, "</body></html>")
+}
+
+// A bar for each range of chunk sizes, drawn as an inline SVG
+fn write_size_chart(out: &mut dyn Write, report: &Report) -> io::Result<()> {
+    let most_chunks = report
+        .chunk_sizes
+        .iter()
+        .map(|r| r.chunks)
+        .max()
+        .unwrap_or(0);
+    if most_chunks == 0 {
+        return writeln!(out, "<p>No chunks were made.</p>");
+    }
+    // Room for the labels under the bars
+    let bars_height = CHART_HEIGHT - 20;
+    let bar_width = CHART_WIDTH / report.chunk_sizes.len() as u64;
+    writeln!(
+        out,
+        "<svg class=\"chart\" width=\"{}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">",
+        CHART_WIDTH, CHART_HEIGHT
+    )?;
+    for (i, range) in report.chunk_sizes.iter().enumerate() {
+        let height = range.chunks * bars_height / most_chunks;
+        let x = i as u64 * bar_width;
+        writeln!(
+            out,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"><title>{}-{} bytes: {} chunks</title></rect>",
+            x + 1,
+            bars_height - height,
+            bar_width - 2,
+            height,
+            range.min,
+            range.max,
+            range.chunks
+        )?;
+        // Every other label, so they don't run into each other
+        if i % 2 == 0 {
+            writeln!(
+                out,
+                "<text x=\"{}\" y=\"{}\">{}</text>",
+                x + 1,
+                CHART_HEIGHT - 6,
+                human_bytes(range.min as u64)
+            )?;
+        }
+    }
+    writeln!(out, "</svg>")
+}
+
+fn write_html_breakdown(
+    out: &mut dyn Write,
+    heading: &str,
+    groups: &[dedup_core::files::Breakdown],
+    overlaps: &[dedup_core::merge::Overlap],
+) -> io::Result<()> {
+    writeln!(out, "<table>")?;
+    writeln!(
+        out,
+        "<tr><th>{}</th><th>Files</th><th>Bytes</th><th>Unique</th><th>Deduplicated</th><th></th></tr>",
+        heading
+    )?;
+    for group in groups.iter() {
+        writeln!(
+            out,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.2}%</td><td><div class=\"bar\"><div style=\"width: {:.2}%\"></div></div></td></tr>",
+            escape(&group.name),
+            group.files,
+            human_bytes(group.bytes),
+            human_bytes(group.unique_bytes),
+            group.percent_deduplicated,
+            group.percent_deduplicated.clamp(0.0, 100.0)
+        )?;
+    }
+    writeln!(out, "</table>")?;
+
+    if !overlaps.is_empty() {
+        writeln!(out, "<table>")?;
+        writeln!(
+            out,
+            "<tr><th>Shared between</th><th>And</th><th>Bytes</th></tr>"
+        )?;
+        for overlap in overlaps.iter() {
+            writeln!(
+                out,
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&overlap.first),
+                escape(&overlap.second),
+                human_bytes(overlap.bytes)
+            )?;
+        }
+        writeln!(out, "</table>")?;
+    }
+    Ok(())
+}
+
+// Paths and file types can have any characters in them
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Bytes in the largest binary unit that leaves at least 1 of them, i.e. 1.50 GiB
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["bytes", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_html_helpers() {
+        assert_eq!(
+            crate::report::escape("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(crate::report::human_bytes(0), "0 bytes");
+        assert_eq!(crate::report::human_bytes(1023), "1023 bytes");
+        assert_eq!(crate::report::human_bytes(1536), "1.50 KiB");
+        assert_eq!(crate::report::human_bytes(5 << 30), "5.00 GiB");
+    }
+}
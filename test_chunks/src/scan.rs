@@ -0,0 +1,1079 @@
+use std::collections;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::thread;
+use std::time;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct EntryData {
+    check: u32,
+    size: u32,
+    count: u32,
+    file: u32,
+    offset: u64,
+    compressed: u32,
+    groups: u64,
+}
+
+// Chunks every file in the directory and commits the sorted runs of chunk ids to the output directory. The runs aren't
+// combined with each other; that is the job of the merge.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let started = time::Instant::now();
+
+    let input = match input(matches) {
+        Some(input) => input,
+        None => return crate::EXIT_FATAL,
+    };
+    let mut options = match pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+
+    // The estimate doesn't store any keys, so none of the sorting machinery below is needed
+    if matches.is_present("hll") {
+        let status = estimate_with_hll(input, &options);
+        say!("{}s elapsed", started.elapsed().as_secs());
+        return status;
+    }
+
+    if let Some(percent) = matches.value_of("estimate") {
+        return match percent.parse::<f64>() {
+            Ok(percent) if percent > 0.0 && percent <= 100.0 => {
+                // The sample is picked by walking, so --estimate can't be given a list of files
+                let roots = match input {
+                    dedup_core::pipeline::Input::Walk(roots) => roots,
+                    dedup_core::pipeline::Input::List { .. } => unreachable!(),
+                };
+                let status = crate::estimate::run(roots, &mut options, percent);
+                say!("{}s elapsed", started.elapsed().as_secs());
+                status
+            }
+            _ => {
+                eprintln!(
+                    "ERROR: --estimate takes a percentage of the files above 0 and up to 100"
+                );
+                crate::EXIT_FATAL
+            }
+        };
+    }
+
+    // When chunking large directories, we can run out of memory to store all the chunk hashes. Determine how much the
+    // user is willing to set aside, or how much can be spared if they didn't say, and then use that as the max for the
+    // chunk btree.
+    let memory_usage = match matches.value_of("memory").map(crate::parse_memory_usage) {
+        Some(Ok(memory)) => memory,
+        Some(Err(e)) => {
+            eprintln!("ERROR: --memory: {}", e);
+            return crate::EXIT_FATAL;
+        }
+        None => match dedup_core::memory::default_budget() {
+            Some(budget) => {
+                tracing::info!(bytes = budget, "memory for sorting");
+                budget
+            }
+            None => {
+                eprintln!("ERROR: the available memory can't be found on this system; give the amount to use with --memory");
+                return crate::EXIT_FATAL;
+            }
+        },
+    };
+    // Nothing is written to disk, so the keys have to fit in memory or be sampled until they do
+    if matches.is_present("approximate") {
+        let status = estimate_approximately(input, &options, memory_usage);
+        say!("{}s elapsed", started.elapsed().as_secs());
+        return status;
+    }
+    let sub_block_memory = sub_block_memory(&options, memory_usage);
+    let mut sub_blocks = options
+        .sub_blocks
+        .map(|_| dedup_core::approximate::Index::new(sub_block_memory));
+    let btree_max_entries = memtree_capacity(memory_usage - sub_block_memory);
+    let params = dedup_core::run::Params::new(&options.chunking, options.key_len);
+    let mut memtree = collections::BTreeMap::new();
+
+    // Make sure no other process can start over in the same output directory while we use it. Appending (and resumed)
+    // scans can share the directory with each other; a fresh scan needs it to itself.
+    let append = matches.is_present("append");
+    let resume = matches.is_present("resume");
+    let lock_kind = if append || resume {
+        dedup_core::lock::LockKind::Shared
+    } else {
+        dedup_core::lock::LockKind::Exclusive
+    };
+    let (out_dir, mut lock) = match crate::open_output(matches, lock_kind) {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+    if matches.is_present("cache") {
+        match dedup_core::cache::Cache::open(out_dir, dedup_core::cache::settings(&options)) {
+            Ok(cache) => options.cache = Some(cache),
+            // Another scan appending to the directory has the database open
+            Err(e) => warning!("scanning without the cache: {}", e),
+        }
+    }
+    // Once a merge has removed the runs, scans appended to the directory could only be merged without them
+    if append || resume {
+        let committed = match dedup_core::wal::read_committed(out_dir) {
+            Ok(committed) => committed,
+            Err(e) => {
+                eprintln!(
+                    "ERROR: can't read the write-ahead log in {:?}: {}",
+                    out_dir, e
+                );
+                return crate::EXIT_FATAL;
+            }
+        };
+        if committed.merged && committed.scans.is_empty() {
+            eprintln!(
+                "ERROR: the runs in {:?} were removed when it was merged, so nothing can be added to them; start a new scan, or merge with --keep-intermediate to keep the runs for appending",
+                out_dir
+            );
+            return crate::EXIT_FATAL;
+        }
+    }
+    let baseline = match matches.value_of("baseline") {
+        Some(_) => match open_baseline(matches, options.key_len) {
+            Some(baseline) => Some(baseline),
+            None => return crate::EXIT_FATAL,
+        },
+        None => None,
+    };
+    let baseline = baseline.as_ref().map(|(baseline, _lock)| baseline);
+
+    // A fresh scan clears out the write-ahead log and the runs it committed (which also cleans up after any scan that
+    // was interrupted) along with anything that crashed sessions left in the staging area. Runs that were never merged
+    // are only thrown away when --overwrite says so.
+    if !append && !resume {
+        let mut wal = match dedup_core::wal::Wal::open(out_dir) {
+            Ok(wal) => wal,
+            Err(e) => {
+                eprintln!(
+                    "ERROR: can't open the write-ahead log in {:?}: {}",
+                    out_dir, e
+                );
+                return crate::EXIT_FATAL;
+            }
+        };
+        let committed = wal.committed();
+        if !committed.merged && !committed.scans.is_empty() && !matches.is_present("overwrite") {
+            eprintln!(
+                "ERROR: {:?} has runs from an earlier scan that haven't been merged; merge them, add this scan to them with --append, or throw them away with --overwrite",
+                out_dir
+            );
+            return crate::EXIT_FATAL;
+        }
+        let cleared = wal
+            .reset()
+            .and_then(|_| dedup_core::txn::clear_staging(out_dir))
+            .and_then(|_| {
+                match fs::remove_file(out_dir.join(dedup_core::collisions::SCAN_COLLISIONS_NAME)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    result => result,
+                }
+            });
+        if let Err(e) = cleared {
+            eprintln!(
+                "ERROR: can't clear out the last scan in {:?}: {}",
+                out_dir, e
+            );
+            return crate::EXIT_FATAL;
+        }
+    }
+
+    // Run files are staged by a transaction and only become part of the output directory when it commits, so a crash
+    // can never leave a truncated run behind for the merge to read. Each time a run is staged the transaction also
+    // checkpoints everything the scan knows, which is how a scan that was killed picks up where it stopped.
+    let directory_depth = match matches
+        .value_of("directory-depth")
+        .unwrap()
+        .parse::<usize>()
+    {
+        Ok(depth) => depth,
+        Err(_) => {
+            eprintln!("ERROR: --directory-depth takes a number of directories");
+            return crate::EXIT_FATAL;
+        }
+    };
+    let settings =
+        dedup_core::checkpoint::settings(&options, matches.value_of("baseline"), directory_depth);
+    let directories = input.names();
+    let (mut transaction, mut state) = if resume {
+        match resume_session(out_dir, &directories, &settings) {
+            Ok(Some(resumed)) => resumed,
+            Err(e) => {
+                eprintln!(
+                    "ERROR: can't look for an interrupted scan in {:?}: {}",
+                    out_dir, e
+                );
+                return crate::EXIT_FATAL;
+            }
+            Ok(None) => {
+                eprintln!(
+                    "ERROR: no interrupted scan of {:?} with the same chunk settings was found in {:?}",
+                    directories, out_dir
+                );
+                return crate::EXIT_FATAL;
+            }
+        }
+    } else {
+        let state = dedup_core::checkpoint::Checkpoint {
+            directories: directories.clone(),
+            settings,
+            ..Default::default()
+        };
+        match dedup_core::txn::Transaction::begin(out_dir) {
+            Ok(transaction) => (transaction, state),
+            Err(e) => {
+                eprintln!("ERROR: can't start a scan in {:?}: {}", out_dir, e);
+                return crate::EXIT_FATAL;
+            }
+        }
+    };
+    let resumed_ms = state.elapsed_ms;
+    let mut groups = dedup_core::files::Groups::default();
+    let mut group_bits: Vec<u64> = state.files.iter().map(|f| groups.bit(&f.group)).collect();
+    let mut total_bytes: u64 = state.files.iter().map(|f| f.size).sum();
+    options.resume = state
+        .files
+        .iter()
+        .map(|f| f.path.clone())
+        .zip(state.progress.iter().cloned())
+        .collect();
+
+    // Chunk, hash and insert each file using either the variable-sized or fixed-size chunking algorithm. The pipeline
+    // can't be stopped part way, so once something fails the rest of what it finds is ignored.
+    let mut failed = None;
+    let scanned = dedup_core::pipeline::run(input, &options, &mut |event| {
+        if failed.is_some() {
+            return;
+        }
+        if let Err(e) = lock.refresh().and_then(|_| transaction.refresh()) {
+            failed = Some(e);
+            return;
+        }
+
+        let batch = match event {
+            // Runs refer to files by their position in the list that is committed along with them. A file is always
+            // found before anything else about it arrives.
+            dedup_core::pipeline::Event::Found {
+                file,
+                root,
+                path,
+                metadata,
+                decompressed,
+                ..
+            } => {
+                if state.files.len() <= file as usize {
+                    state
+                        .files
+                        .resize(file as usize + 1, dedup_core::files::FileRecord::default());
+                    state.progress.resize(
+                        file as usize + 1,
+                        dedup_core::checkpoint::Progress::default(),
+                    );
+                }
+                let root = &directories[root as usize];
+                let mut group = dedup_core::files::directory_group(
+                    path::Path::new(root),
+                    path::Path::new(&path),
+                    directory_depth,
+                );
+                // Groups from different directories can have the same name, so they are told apart by their directory
+                if directories.len() > 1 {
+                    group = dedup_core::files::rooted_group(root, &group);
+                }
+                group_bits.resize(state.files.len(), 0);
+                group_bits[file as usize] = groups.bit(&group);
+                state.files[file as usize].group = group;
+                state.files[file as usize].root = root.clone();
+                state.files[file as usize].kind =
+                    dedup_core::filetype::file_type(path::Path::new(&path));
+                state.files[file as usize].metadata = metadata;
+                state.files[file as usize].decompressed = decompressed;
+                state.files[file as usize].path = path;
+                return;
+            }
+            dedup_core::pipeline::Event::Finished { file, chunks } => {
+                state.progress[file as usize].finish(chunks);
+                return;
+            }
+            dedup_core::pipeline::Event::Hashed(batch) => batch,
+        };
+
+        if let Some(index) = sub_blocks.as_mut() {
+            for block in batch.sub_blocks.iter() {
+                index.insert(&block.fingerprint.to_be_bytes(), block.size);
+            }
+        }
+        let file = batch.file;
+        for (i, c) in batch.chunks.into_iter().enumerate() {
+            total_bytes += c.size as u64;
+            state.files[file as usize].size += c.size as u64;
+            state.files[file as usize].chunks += 1;
+            state.files[file as usize].add_chunk(batch.first_chunk + i as u64, &c.key);
+            if c.high_entropy {
+                state.high_entropy_bytes += c.size as u64;
+            }
+
+            let data = EntryData {
+                check: c.check,
+                size: c.size,
+                count: 1,
+                file,
+                offset: c.offset,
+                compressed: c.compressed,
+                groups: group_bits[file as usize],
+            };
+
+            // A chunk the baseline already has wouldn't be stored again
+            let existing = baseline.and_then(|b| b.find(&c.key).map(|e| (b, e)));
+            let store = match existing {
+                Some((_, e)) if e.size == data.size && e.check == data.check => {
+                    state.baseline_chunks += 1;
+                    state.baseline_bytes += c.size as u64;
+                    false
+                }
+                Some((b, e)) => {
+                    // A different chunk in the baseline has the same id, so this one still has to be stored
+                    state.collisions += 1;
+                    let collision = (c.key, b.location(&e), location(&state.files, &data));
+                    state.collided.push(collision);
+                    true
+                }
+                None => true,
+            };
+
+            if store {
+                // Check to see if we already know about this chunk
+                match memtree.entry(c.key) {
+                    collections::btree_map::Entry::Vacant(vacant) => {
+                        // Unique chunk, never seen before
+                        vacant.insert(data);
+                    }
+                    collections::btree_map::Entry::Occupied(mut occupied) => {
+                        let old_data = occupied.get_mut();
+                        if old_data.check == data.check && old_data.size == data.size {
+                            // The size of the data and both the SHA2 and SHA3 hashes match for the chunk, so the odds
+                            // of it not being a perfect match are statistically miniscule. Files are hashed in
+                            // parallel, so the first file to contain the chunk isn't necessarily the first one we heard
+                            // about.
+                            old_data.count += 1;
+                            old_data.groups |= data.groups;
+                            if (file, c.offset) < (old_data.file, old_data.offset) {
+                                old_data.file = file;
+                                old_data.offset = c.offset;
+                            }
+                        } else {
+                            // COLLISION!!! Something didn't match, so the partial SHA3 hash we used as an ID is no
+                            // good. We probably just need to increase the bits from 144
+                            state.collisions += 1;
+                            let collision = (
+                                c.key,
+                                location(&state.files, old_data),
+                                location(&state.files, &data),
+                            );
+                            state.collided.push(collision);
+                        }
+                    }
+                };
+            }
+            let chunk = batch.first_chunk + i as u64;
+            state.progress[file as usize].add(chunk..chunk + 1);
+
+            // If we have more entries in the memtree than we're supposed to, write the whole memtree to disk and
+            // clear it for another round.
+            if memtree.len() >= btree_max_entries {
+                let checkpointed = write_memtree_file(&mut transaction, &mut memtree, &params)
+                    .and_then(|_| {
+                        state.runs = transaction.staged_runs();
+                        state.elapsed_ms = resumed_ms + started.elapsed().as_millis() as u64;
+                        transaction.checkpoint(&state)
+                    });
+                if let Err(e) = checkpointed {
+                    failed = Some(e);
+                    return;
+                }
+                tracing::debug!(runs = state.runs, "checkpoint written");
+            }
+        }
+    });
+    if let Some(e) = failed {
+        eprintln!("ERROR: the scan failed: {}", e);
+        return crate::EXIT_FATAL;
+    }
+
+    // Every file was found (here or by the scan being resumed), so the list should already be complete
+    let mut files = state.files;
+    files.resize(
+        scanned.paths.len(),
+        dedup_core::files::FileRecord::default(),
+    );
+    for (record, path) in files.iter_mut().zip(scanned.paths) {
+        record.path = path;
+    }
+    if !state.collided.is_empty() {
+        if let Err(e) = write_collisions(out_dir, &state.collided, options.key_len) {
+            eprintln!("ERROR: can't write the collisions: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    }
+
+    // Write the last file
+    if !memtree.is_empty() {
+        if let Err(e) = write_memtree_file(&mut transaction, &mut memtree, &params) {
+            eprintln!("ERROR: can't write the last run: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    }
+    let collisions = state.collisions;
+    let elapsed_ms = resumed_ms + started.elapsed().as_millis() as u64;
+    let totals = dedup_core::wal::ScanTotals {
+        collisions,
+        elapsed_ms,
+        hard_links: scanned.skipped_hard_links.count,
+        hard_link_bytes: scanned.skipped_hard_links.bytes,
+        size_filtered_files: scanned.size_filtered_files,
+        size_filtered_bytes: scanned.size_filtered_bytes,
+        skipped_compressed_files: scanned.skipped_compressed_files,
+        skipped_compressed_bytes: scanned.skipped_compressed_bytes,
+        flagged_compressed_files: scanned.flagged_compressed_files,
+        flagged_compressed_bytes: scanned.flagged_compressed_bytes,
+        hole_bytes: scanned.hole_bytes,
+        baseline_chunks: state.baseline_chunks,
+        baseline_bytes: state.baseline_bytes,
+        high_entropy_bytes: state.high_entropy_bytes,
+        skipped: scanned.skipped.count(),
+        timings: scanned.timings,
+    };
+    let runs = match transaction.commit(&files, totals) {
+        Ok(runs) => runs,
+        Err(e) => {
+            eprintln!("ERROR: can't commit the scan: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+
+    say!("{}s elapsed", elapsed_ms / 1000);
+    for (name, phase) in crate::report::scan_phases(&scanned.timings) {
+        say!(
+            "  {}: {}",
+            name,
+            crate::report::format_phase(phase.seconds(), phase.throughput())
+        );
+    }
+    say!("{} total bytes scanned", total_bytes);
+    if scanned.hole_bytes > 0 {
+        say!(
+            "{} bytes in holes of sparse files were skipped",
+            scanned.hole_bytes
+        );
+    }
+    if baseline.is_some() {
+        say!(
+            "{} bytes in {} chunks were already in the baseline and weren't stored",
+            state.baseline_bytes,
+            state.baseline_chunks
+        );
+    }
+    print_high_entropy(state.high_entropy_bytes, total_bytes);
+    if let (Some(index), Some(block)) = (sub_blocks.as_ref(), options.sub_blocks) {
+        print_sub_blocks(index, block, sub_block_memory);
+    }
+    say!("{} runs committed", runs.len());
+    say!("{} collisions", collisions);
+    if collisions > 0 {
+        say!(
+            "details of each collision were added to {:?}",
+            out_dir.join(dedup_core::collisions::SCAN_COLLISIONS_NAME)
+        );
+    }
+    if scanned.skipped_hard_links.count > 0 {
+        say!(
+            "{} hard links to files that were already scanned were skipped ({} bytes)",
+            scanned.skipped_hard_links.count,
+            scanned.skipped_hard_links.bytes
+        );
+    }
+    if scanned.size_filtered_files > 0 {
+        say!(
+            "{} files outside the file size range were skipped ({} bytes)",
+            scanned.size_filtered_files,
+            scanned.size_filtered_bytes
+        );
+    }
+    if scanned.skipped_compressed_files > 0 {
+        say!(
+            "{} files that look already compressed were skipped ({} bytes)",
+            scanned.skipped_compressed_files,
+            scanned.skipped_compressed_bytes
+        );
+    }
+    if scanned.flagged_compressed_files > 0 {
+        say!(
+            "{} files that look already compressed were chunked as they are ({} bytes); they will dedup poorly",
+            scanned.flagged_compressed_files,
+            scanned.flagged_compressed_bytes
+        );
+    }
+    crate::skipped::print(&scanned.skipped);
+    crate::exit_status(collisions, scanned.skipped.count())
+}
+
+// Opens and locks the baseline, which has to have been scanned with the same key length. Prints an error and returns
+// None if it can't be used.
+fn open_baseline(
+    matches: &clap::ArgMatches,
+    key_len: usize,
+) -> Option<(dedup_core::baseline::Baseline, dedup_core::lock::Lock)> {
+    let (base_dir, lock) =
+        crate::open_dir(matches, "baseline", dedup_core::lock::LockKind::Shared)?;
+    let baseline = match dedup_core::baseline::Baseline::open(base_dir) {
+        Ok(baseline) => baseline,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "ERROR: the baseline {:?} has to be merged before it can be used",
+                base_dir
+            );
+            return None;
+        }
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return None;
+        }
+    };
+    if baseline.key_len() != key_len {
+        eprintln!(
+            "ERROR: the baseline was scanned with --key-bits {}, so this scan has to be too",
+            baseline.key_len() * 8
+        );
+        return None;
+    }
+    Some((baseline, lock))
+}
+
+// Finds the newest checkpointed session that was scanning the same directories with the same settings and isn't still
+// running, and takes it over. Returns None if there isn't one.
+fn resume_session(
+    out_dir: &path::Path,
+    directories: &[String],
+    settings: &str,
+) -> io::Result<
+    Option<(
+        dedup_core::txn::Transaction,
+        dedup_core::checkpoint::Checkpoint,
+    )>,
+> {
+    for staging in dedup_core::txn::checkpointed(out_dir)? {
+        match dedup_core::txn::Transaction::resume(out_dir, &staging) {
+            Ok((transaction, checkpoint)) => {
+                if checkpoint.directories == directories && checkpoint.settings == settings {
+                    return Ok(Some((transaction, checkpoint)));
+                }
+            }
+            // Still running
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => tracing::warn!("can't resume the scan in {:?}: {}", staging, e),
+        }
+    }
+    Ok(None)
+}
+
+// Where to find the files to scan: the list given with --files-from ('-' for stdin), or else the directories. Prints an
+// error and returns None if the list can't be opened or the directories' exclusions are invalid.
+fn input(matches: &clap::ArgMatches) -> Option<dedup_core::pipeline::Input> {
+    let name = match matches.value_of("files-from") {
+        Some(name) => name,
+        None => return roots(matches).map(dedup_core::pipeline::Input::Walk),
+    };
+    let list: Box<dyn io::BufRead + Send> = if name == "-" {
+        Box::new(io::BufReader::new(io::stdin()))
+    } else {
+        match fs::File::open(name) {
+            Ok(file) => Box::new(io::BufReader::new(file)),
+            Err(e) => {
+                eprintln!("ERROR: can't read {}: {}", name, e);
+                return None;
+            }
+        }
+    };
+    Some(dedup_core::pipeline::Input::List {
+        name: name.to_string(),
+        list,
+    })
+}
+
+// Reads the directories to scan from the command line, each with its own exclusions. Prints an error and returns None
+// if any of the exclude patterns are invalid.
+pub fn roots(matches: &clap::ArgMatches) -> Option<Vec<dedup_core::pipeline::Root>> {
+    // Never scan our own output, which would skew the statistics more with every run
+    let patterns: Vec<&str> = matches.values_of("exclude").map_or(vec![], |v| v.collect());
+    let output: Vec<&path::Path> = matches
+        .value_of("output")
+        .map(path::Path::new)
+        .into_iter()
+        .collect();
+
+    let mut roots = vec![];
+    for dir in directories(matches) {
+        let dir = dedup_core::walk::long_path(path::Path::new(dir));
+        let exclude = match dedup_core::walk::exclusions(&dir, &patterns, &output) {
+            Ok(exclude) => exclude,
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                return None;
+            }
+        };
+        roots.push(dedup_core::pipeline::Root { dir, exclude });
+    }
+    Some(roots)
+}
+
+// The directories given with -d and the ones given on their own, in that order
+fn directories<'a>(matches: &'a clap::ArgMatches) -> Vec<&'a str> {
+    matches
+        .values_of("directory")
+        .into_iter()
+        .flatten()
+        .chain(matches.values_of("directories").into_iter().flatten())
+        .collect()
+}
+
+// Works out how the pipeline should find and chunk files from the command line. Prints an error and returns None if
+// the chunk sizes don't make sense.
+pub fn pipeline_options(matches: &clap::ArgMatches) -> Option<dedup_core::pipeline::Options> {
+    let chunking = if matches.is_present("fixed") {
+        dedup_core::pipeline::Chunking::Fixed
+    } else {
+        dedup_core::pipeline::Chunking::Variable(chunker_builder(matches)?)
+    };
+
+    let cpus = |name| match matches.value_of(name) {
+        None => Ok(None),
+        Some(_) if !cfg!(any(target_os = "linux", target_os = "android")) => {
+            Err(format!("--{} is only supported on Linux", name))
+        }
+        Some(list) => dedup_core::affinity::parse_cpus(list)
+            .map(Some)
+            .map_err(|e| format!("--{}: {}", name, e)),
+    };
+    let (hash_cpus, io_cpus) = match (cpus("hash-cpus"), cpus("io-cpus")) {
+        (Ok(hash_cpus), Ok(io_cpus)) => (hash_cpus, io_cpus),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("ERROR: {}", e);
+            return None;
+        }
+    };
+
+    // Hashing is spread across this many threads, by default one for each CPU it may use
+    let threads = match (matches.value_of("threads"), &hash_cpus) {
+        (Some(threads), _) => match threads.parse::<usize>() {
+            Ok(threads) if threads > 0 => threads,
+            _ => {
+                eprintln!("ERROR: --threads takes a number of threads above 0");
+                return None;
+            }
+        },
+        (None, Some(cpus)) => cpus.len(),
+        (None, None) => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    let symlinks = if matches.is_present("follow-symlinks") {
+        dedup_core::walk::Symlinks::Follow
+    } else if matches.is_present("record-symlinks") {
+        dedup_core::walk::Symlinks::Record
+    } else {
+        dedup_core::walk::Symlinks::Skip
+    };
+
+    // Only NTFS has alternate data streams
+    let streams = matches.is_present("alternate-streams");
+    if streams && !cfg!(windows) {
+        eprintln!("ERROR: --alternate-streams is only supported on Windows");
+        return None;
+    }
+
+    let uring = match matches
+        .value_of("io-uring")
+        .map(|depth| depth.parse::<usize>())
+    {
+        None => None,
+        Some(_) if !cfg!(target_os = "linux") => {
+            eprintln!("ERROR: --io-uring is only supported on Linux");
+            return None;
+        }
+        Some(Ok(depth)) if depth > 0 => Some(depth),
+        Some(_) => {
+            eprintln!("ERROR: --io-uring takes how many reads to keep in flight, which can't be 0");
+            return None;
+        }
+    };
+
+    let direct_io = matches.is_present("direct-io");
+    if direct_io
+        && !cfg!(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos"
+        ))
+    {
+        eprintln!("ERROR: --direct-io is only supported on Linux and macOS");
+        return None;
+    }
+
+    let (min_file_size, max_file_size) = match (
+        size_arg(matches, "min-file-size"),
+        size_arg(matches, "max-file-size"),
+    ) {
+        (Ok(min), Ok(max)) => (min, max),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("ERROR: {}", e);
+            return None;
+        }
+    };
+    if let (Some(min), Some(max)) = (min_file_size, max_file_size) {
+        if max < min {
+            eprintln!(
+                "ERROR: the maximum file size ({}) can't be smaller than the minimum ({})",
+                max, min
+            );
+            return None;
+        }
+    }
+
+    let sub_blocks = match size_arg(matches, "sub-blocks") {
+        Ok(size) => size.map(|size| size as usize),
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return None;
+        }
+    };
+    if sub_blocks == Some(0) {
+        eprintln!("ERROR: --sub-blocks takes a block size above 0");
+        return None;
+    }
+
+    let compress_every = match matches.value_of("compress-every").map(|n| n.parse::<u32>()) {
+        _ if !matches.is_present("compress") => None,
+        None => Some(1),
+        Some(Ok(every)) => Some(every.max(1)),
+        Some(Err(_)) => {
+            eprintln!("ERROR: --compress-every takes a number of chunks");
+            return None;
+        }
+    };
+
+    Some(dedup_core::pipeline::Options {
+        chunking,
+        key_len: matches
+            .value_of("key-bits")
+            .unwrap()
+            .parse::<usize>()
+            .unwrap()
+            / 8,
+        threads,
+        hash_cpus,
+        io_cpus,
+        symlinks,
+        detect_hard_links: !matches.is_present("count-hard-links"),
+        streams,
+        mmap: !matches.is_present("no-mmap"),
+        uring,
+        direct_io,
+        resume: vec![],
+        compress_every,
+        only: None,
+        min_file_size,
+        max_file_size,
+        cache: None,
+        sub_blocks,
+        disk_images: matches.is_present("disk-images"),
+        anchors: matches.values_of("anchors").map_or(vec![], |kinds| {
+            kinds
+                .map(|kind| dedup_core::anchor::Kind::from_name(kind).unwrap())
+                .collect()
+        }),
+        compressed: dedup_core::compressed::Policy::from_name(
+            matches.value_of("compressed").unwrap_or("chunk"),
+        )
+        .unwrap(),
+        archives: matches.is_present("archives"),
+    })
+}
+
+// The size given for the argument, if it was. The error names the argument.
+fn size_arg(matches: &clap::ArgMatches, name: &str) -> Result<Option<u64>, String> {
+    matches
+        .value_of(name)
+        .map(crate::parse_memory_usage)
+        .transpose()
+        .map_err(|e| format!("--{}: {}", name, e))
+}
+
+// Reads the variable-size chunking parameters, falling back to the defaults for any that weren't given
+fn chunker_builder(matches: &clap::ArgMatches) -> Option<rabin::chunker::ChunkerBuilder> {
+    let size = |name| size_arg(matches, name).map(|size| size.map(|size| size as usize));
+    let (min, max, average, alignment) = match (
+        size("min-chunk"),
+        size("max-chunk"),
+        size("avg-chunk"),
+        size("align"),
+    ) {
+        (Ok(min), Ok(max), Ok(average), Ok(alignment)) => (
+            min.unwrap_or(dedup_core::MIN_CHUNK_SIZE),
+            max.unwrap_or(dedup_core::MAX_CHUNK_SIZE),
+            average,
+            alignment,
+        ),
+        (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+            eprintln!("ERROR: {}", e);
+            return None;
+        }
+    };
+    let builder = rabin::chunker::ChunkerBuilder::new(min, max)
+        .and_then(|builder| match average {
+            None => Ok(builder),
+            Some(average) => builder.average(average),
+        })
+        .and_then(|builder| match alignment {
+            None => Ok(builder),
+            Some(alignment) => builder.with_alignment(alignment),
+        });
+    match builder {
+        Ok(builder) => Some(builder),
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            None
+        }
+    }
+}
+
+// Chunks every file in the directories and estimates how many of the chunks are unique using constant memory. The
+// sketch only counts chunks, so the unique bytes are estimated assuming unique chunks are of average size. Returns the
+// exit status.
+fn estimate_with_hll(
+    input: dedup_core::pipeline::Input,
+    options: &dedup_core::pipeline::Options,
+) -> i32 {
+    let mut hll = dedup_core::hll::HyperLogLog::new(crate::HLL_PRECISION);
+    let mut total_chunks = 0u64;
+    let mut total_bytes = 0u64;
+    let mut high_entropy_bytes = 0u64;
+
+    let scanned = dedup_core::pipeline::run(input, options, &mut |event| {
+        let batch = match event {
+            dedup_core::pipeline::Event::Hashed(batch) => batch,
+            _ => return,
+        };
+        for c in batch.chunks {
+            // The chunk id is already a cryptographic hash, so any 64 bits of it are as good as hashing it again
+            let mut prefix = [0u8; 8];
+            prefix.copy_from_slice(&c.key[0..8]);
+            hll.insert(u64::from_be_bytes(prefix));
+
+            total_chunks += 1;
+            total_bytes += c.size as u64;
+            if c.high_entropy {
+                high_entropy_bytes += c.size as u64;
+            }
+        }
+    });
+
+    if total_chunks == 0 {
+        say!("0 total bytes scanned");
+        crate::skipped::print(&scanned.skipped);
+        return crate::exit_status(0, scanned.skipped.count());
+    }
+
+    let unique_chunks = hll.estimate().min(total_chunks as f64);
+    let unique_bytes = unique_chunks * total_bytes as f64 / total_chunks as f64;
+    say!("{} total bytes scanned", total_bytes);
+    say!(
+        "~{:.0} bytes {:0.4}% were unique (+/- {:0.2}%)",
+        unique_bytes,
+        unique_bytes * 100.0 / total_bytes as f64,
+        hll.standard_error() * 100.0
+    );
+    say!("~{:.0} chunks", unique_chunks);
+    print_high_entropy(high_entropy_bytes, total_bytes);
+    crate::skipped::print(&scanned.skipped);
+    crate::exit_status(0, scanned.skipped.count())
+}
+
+// Chunks every file in the directories and counts the unique chunks in memory, within the memory budget and without
+// writing anything. If the unique chunks don't all fit, the results are estimated from a sample of them and printed
+// with their standard error. Returns the exit status.
+fn estimate_approximately(
+    input: dedup_core::pipeline::Input,
+    options: &dedup_core::pipeline::Options,
+    memory: u64,
+) -> i32 {
+    let sub_block_memory = sub_block_memory(options, memory);
+    let mut sub_blocks = options
+        .sub_blocks
+        .map(|_| dedup_core::approximate::Index::new(sub_block_memory));
+    let mut index = dedup_core::approximate::Index::new(memory - sub_block_memory);
+    let mut high_entropy_bytes = 0u64;
+    let scanned = dedup_core::pipeline::run(input, options, &mut |event| {
+        let batch = match event {
+            dedup_core::pipeline::Event::Hashed(batch) => batch,
+            _ => return,
+        };
+        if let Some(index) = sub_blocks.as_mut() {
+            for block in batch.sub_blocks.iter() {
+                index.insert(&block.fingerprint.to_be_bytes(), block.size);
+            }
+        }
+        for c in batch.chunks {
+            index.insert(&c.key, c.size);
+            if c.high_entropy {
+                high_entropy_bytes += c.size as u64;
+            }
+        }
+    });
+
+    let estimate = index.estimate();
+    say!("{} total bytes scanned", estimate.total_bytes);
+    let total_bytes = estimate.total_bytes.max(1) as f64;
+    if estimate.exact() {
+        say!(
+            "{:.0} bytes {:0.4}% were unique",
+            estimate.unique_bytes,
+            estimate.unique_bytes * 100.0 / total_bytes
+        );
+        say!("{:.0} chunks", estimate.unique_chunks);
+    } else {
+        say!(
+            "~{:.0} bytes {:0.4}% were unique (+/- {:0.2}%)",
+            estimate.unique_bytes,
+            estimate.unique_bytes * 100.0 / total_bytes,
+            estimate.unique_bytes_error * 100.0 / total_bytes
+        );
+        say!(
+            "~{:.0} chunks (+/- {:.0})",
+            estimate.unique_chunks,
+            estimate.unique_chunks_error
+        );
+        say!(
+            "the unique chunks didn't fit in {} bytes, so these are estimated from 1 in {} of them; give more --memory for exact numbers",
+            memory - sub_block_memory,
+            (1.0 / estimate.sample_rate).round()
+        );
+    }
+    print_high_entropy(high_entropy_bytes, estimate.total_bytes);
+    if let (Some(index), Some(block)) = (sub_blocks.as_ref(), options.sub_blocks) {
+        print_sub_blocks(index, block, sub_block_memory);
+    }
+    crate::skipped::print(&scanned.skipped);
+    crate::exit_status(0, scanned.skipped.count())
+}
+
+// How much of the memory budget counting the unique sub-blocks gets: a quarter of it, if the scan was asked for them
+fn sub_block_memory(options: &dedup_core::pipeline::Options, memory: u64) -> u64 {
+    options.sub_blocks.map_or(0, |_| memory / 4)
+}
+
+// How the data would dedup in fixed blocks, next to the chunks the rest of the results are about
+fn print_sub_blocks(index: &dedup_core::approximate::Index, block: usize, memory: u64) {
+    let estimate = index.estimate();
+    let total_bytes = estimate.total_bytes.max(1) as f64;
+    if estimate.exact() {
+        say!(
+            "in {} byte blocks, {:.0} bytes {:0.4}% were unique ({:.0} of {} blocks)",
+            block,
+            estimate.unique_bytes,
+            estimate.unique_bytes * 100.0 / total_bytes,
+            estimate.unique_chunks,
+            estimate.total_chunks
+        );
+    } else {
+        say!(
+            "in {} byte blocks, ~{:.0} bytes {:0.4}% were unique (+/- {:0.2}%), estimated from 1 in {} of the unique blocks that fit in {} bytes",
+            block,
+            estimate.unique_bytes,
+            estimate.unique_bytes * 100.0 / total_bytes,
+            estimate.unique_bytes_error * 100.0 / total_bytes,
+            (1.0 / estimate.sample_rate).round(),
+            memory
+        );
+    }
+}
+
+// Poor deduplication of data that is already compressed or encrypted is no surprise, and compressing it again won't
+// help either
+fn print_high_entropy(high_entropy_bytes: u64, total_bytes: u64) {
+    if high_entropy_bytes > 0 {
+        say!(
+            "{} bytes {:0.4}% looked already compressed or encrypted",
+            high_entropy_bytes,
+            (high_entropy_bytes * 100) as f64 / total_bytes as f64
+        );
+    }
+}
+
+// How many entries fit in the memtree within the memory budget. Keys arrive in random order, which leaves the tree's
+// nodes only about two thirds full on average, so each entry really costs about half again its own size.
+fn memtree_capacity(memory: u64) -> usize {
+    let entry = std::mem::size_of::<(dedup_core::run::Key, EntryData)>();
+    (memory as usize / (entry * 3 / 2)).max(1)
+}
+
+// Where a chunk that collided was found
+fn location(
+    files: &[dedup_core::files::FileRecord],
+    data: &EntryData,
+) -> dedup_core::collisions::Location {
+    dedup_core::collisions::Location {
+        path: files[data.file as usize].path.clone(),
+        offset: data.offset,
+        size: data.size,
+        check: data.check,
+    }
+}
+
+// Appends the details of each collision to the output directory, where researchers can get at the colliding data
+fn write_collisions(
+    out_dir: &path::Path,
+    collided: &[dedup_core::collisions::Collision],
+    key_len: usize,
+) -> io::Result<()> {
+    let mut out = vec![];
+    for (key, first, second) in collided.iter() {
+        dedup_core::collisions::write(&mut out, "scan", &key[..key_len], first, second)?;
+    }
+
+    // Appending scans may share the file, so add everything in one write
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(out_dir.join(dedup_core::collisions::SCAN_COLLISIONS_NAME))?
+        .write_all(&out)
+}
+
+// Quickly stuffs all the entries in the btree into a run file. The btreemap iterator is sorted, which we need.
+fn write_memtree_file(
+    transaction: &mut dedup_core::txn::Transaction,
+    memtree: &mut collections::BTreeMap<dedup_core::run::Key, EntryData>,
+    params: &dedup_core::run::Params,
+) -> io::Result<()> {
+    tracing::info!(entries = memtree.len(), "writing a run");
+    transaction.write_run(|buffer| {
+        dedup_core::run::write_header(&mut *buffer, params)?;
+        let mut entry = dedup_core::run::Entry::default();
+        for (key, value) in memtree.iter() {
+            entry.key = *key;
+            entry.size = value.size;
+            entry.check = value.check;
+            entry.count = value.count;
+            entry.file = value.file;
+            entry.offset = value.offset;
+            entry.compressed = value.compressed;
+            entry.groups = value.groups;
+            dedup_core::run::write_entry(&mut *buffer, &entry, params.key_len)?;
+        }
+        Ok(())
+    })?;
+    memtree.clear();
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use std::fs;
+use std::io;
+use std::path;
+
+// Writes the signature of a file or directory tree: the offset, weak hash and id of every chunk in it, for working out
+// what a newer copy of the data has that this one doesn't (see delta)
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let options = match crate::scan::pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+    let source = path::Path::new(matches.value_of("source").unwrap());
+    if !source.exists() {
+        eprintln!("ERROR: {:?} doesn't exist", source);
+        return crate::EXIT_FATAL;
+    }
+    let name = matches.value_of("signature").unwrap();
+
+    let (signature, skipped) = dedup_core::signature::sign(source, &options);
+    let written = fs::File::create(name).and_then(|file| {
+        let mut out = io::BufWriter::new(file);
+        signature.write(&mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()
+    });
+    if let Err(e) = written {
+        eprintln!("ERROR: can't write the signature to {:?}: {}", name, e);
+        return crate::EXIT_FATAL;
+    }
+
+    let bytes: u64 = signature.files.iter().map(|f| f.size).sum();
+    say!(
+        "{} files ({} bytes) in {} chunks signed into {:?} ({} bytes)",
+        signature.files.len(),
+        bytes,
+        signature.chunks(),
+        name,
+        fs::metadata(name).map_or(0, |m| m.len())
+    );
+    crate::skipped::print(&skipped);
+    crate::exit_status(0, skipped.count())
+}
@@ -0,0 +1,19 @@
+// Warns of how many files and directories a scan skipped and the first few paths, if anything was skipped
+pub fn print(skipped: &dedup_core::skipped::Skipped) {
+    if skipped.reasons.is_empty() || crate::quiet() {
+        return;
+    }
+    let reasons: Vec<String> = skipped
+        .reasons
+        .iter()
+        .map(|(reason, count)| format!("{} {}", count, reason))
+        .collect();
+    warning!(
+        "{} files or directories were skipped: {}",
+        skipped.count(),
+        reasons.join(", ")
+    );
+    for (path, error) in skipped.examples.iter() {
+        eprintln!("  {}: {}", path, error);
+    }
+}
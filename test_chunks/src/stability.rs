@@ -0,0 +1,77 @@
+use std::fs;
+
+// Edits a copy of the file the way a user might, chunks it again and reports how much of it could be stored with the
+// chunks the original already had. Each kind of edit is made --trials times, at different random offsets each time.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let options = match crate::scan::pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+    let file = matches.value_of("file").unwrap();
+    let original = match fs::read(file) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("ERROR: can't read {:?}: {}", file, e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let number = |name| match matches.value_of(name).unwrap().parse::<u64>() {
+        Ok(n) if n > 0 => Some(n),
+        _ => {
+            eprintln!("ERROR: --{} takes a number above 0", name);
+            None
+        }
+    };
+    let (edits, trials, seed) = match (number("edits"), number("trials"), number("seed")) {
+        (Some(edits), Some(trials), Some(seed)) => (edits as usize, trials, seed),
+        _ => return crate::EXIT_FATAL,
+    };
+    let bytes = match crate::parse_memory_usage(matches.value_of("bytes").unwrap()) {
+        Ok(bytes) => bytes as usize,
+        Err(e) => {
+            eprintln!("ERROR: --bytes: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+
+    println!(
+        "{} ({} bytes), with {} edits of {} bytes each per trial:",
+        file,
+        original.len(),
+        edits,
+        bytes
+    );
+    println!(
+        "{:<10} {:>6} {:>16} {:>9} {:>9} {:>14} {:>14}",
+        "edit", "trials", "reused bytes", "reused", "worst", "reused chunks", "chunks"
+    );
+    for name in matches.values_of("edit").unwrap() {
+        let edit = dedup_core::stability::Edit::from_name(name).unwrap();
+        let mut total = dedup_core::stability::Stability::default();
+        let mut worst = 100.0f64;
+        for trial in 0..trials {
+            let edited =
+                dedup_core::stability::perturb(&original, edit, bytes, edits, seed + trial);
+            let stability = dedup_core::stability::measure(&original, &edited, &options.chunking);
+            tracing::debug!(
+                edit = name,
+                trial,
+                reused = stability.reused_bytes,
+                "measured"
+            );
+            worst = worst.min(stability.reused_percent());
+            total.add(&stability);
+        }
+        println!(
+            "{:<10} {:>6} {:>16} {:>8.4}% {:>8.4}% {:>14} {:>14}",
+            edit.name(),
+            trials,
+            total.reused_bytes,
+            total.reused_percent(),
+            worst,
+            total.reused_chunks,
+            total.chunks
+        );
+    }
+    crate::EXIT_SUCCESS
+}
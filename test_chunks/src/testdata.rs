@@ -0,0 +1,101 @@
+use std::path;
+
+// Writes a tree of made-up files with a known amount of duplication, for benchmarks and tests that need the same data
+// on every machine
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let out = path::Path::new(matches.value_of("out").unwrap());
+    if out.exists() {
+        eprintln!("ERROR: {} already exists", out.display());
+        return crate::EXIT_FATAL;
+    }
+    let number = |name| match matches.value_of(name).unwrap().parse::<u64>() {
+        Ok(n) if n > 0 => Some(n),
+        _ => {
+            eprintln!("ERROR: --{} takes a number above 0", name);
+            None
+        }
+    };
+    let fraction = |name| match matches.value_of(name).unwrap().parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Some(f),
+        _ => {
+            eprintln!("ERROR: --{} takes a fraction from 0 to 1", name);
+            None
+        }
+    };
+    let (files, files_per_directory, seed) = match (
+        number("files"),
+        number("files-per-directory"),
+        number("seed"),
+    ) {
+        (Some(files), Some(per_directory), Some(seed)) => {
+            (files as usize, per_directory as usize, seed)
+        }
+        _ => return crate::EXIT_FATAL,
+    };
+    let (duplicates, compressibility) = match (fraction("duplicates"), fraction("compressibility"))
+    {
+        (Some(duplicates), Some(compressibility)) => (duplicates, compressibility),
+        _ => return crate::EXIT_FATAL,
+    };
+    let edit_rate = match matches.value_of("edit-rate").unwrap().parse::<f64>() {
+        Ok(rate) if rate >= 0.0 => rate,
+        _ => {
+            eprintln!("ERROR: --edit-rate takes a number of edits per MiB, 0 or more");
+            return crate::EXIT_FATAL;
+        }
+    };
+    let size = |name| match crate::parse_memory_usage(matches.value_of(name).unwrap()) {
+        Ok(size) => Some(size),
+        Err(e) => {
+            eprintln!("ERROR: --{}: {}", name, e);
+            None
+        }
+    };
+    let (min_size, max_size) = match (size("min-size"), size("max-size")) {
+        (Some(min_size), Some(max_size)) => (min_size, max_size),
+        _ => return crate::EXIT_FATAL,
+    };
+    if max_size < min_size {
+        eprintln!(
+            "ERROR: the largest file size ({}) is below the smallest ({})",
+            max_size, min_size
+        );
+        return crate::EXIT_FATAL;
+    }
+
+    let settings = dedup_core::testdata::Settings {
+        files,
+        min_size,
+        max_size,
+        sizes: dedup_core::testdata::SizeDistribution::from_name(
+            matches.value_of("size-distribution").unwrap(),
+        )
+        .unwrap(),
+        duplicates,
+        edit_rate,
+        compressibility,
+        files_per_directory,
+        seed,
+    };
+    tracing::info!(?settings, "generating");
+    let generated = match dedup_core::testdata::generate(out, &settings) {
+        Ok(generated) => generated,
+        Err(e) => {
+            eprintln!("ERROR: can't write the files in {}: {}", out.display(), e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    say!(
+        "Wrote {} files ({} bytes) to {}",
+        generated.files,
+        generated.bytes,
+        out.display()
+    );
+    say!(
+        "{} of them ({} bytes) are copies of another, with {} edits between them",
+        generated.copies,
+        generated.copied_bytes,
+        generated.edits
+    );
+    crate::EXIT_SUCCESS
+}
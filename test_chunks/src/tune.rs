@@ -0,0 +1,217 @@
+// The average chunk sizes tried unless --averages gives others
+const AVERAGES: &[usize] = &[2048, 4096, 8192, 16384, 32768, 65536];
+
+// Each average is tried with minimums of these fractions of it, and maximums of these multiples of it
+const MIN_DIVISORS: &[usize] = &[4, 2];
+const MAX_MULTIPLES: &[usize] = &[2, 4];
+
+// One setting of the chunk sizes to try. The average is None for the settings the command line gave, which may have
+// left it to the default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    min: usize,
+    average: Option<usize>,
+    max: usize,
+}
+
+// Chunks the directories (or a sample of their files) with a range of chunk sizes and recommends the one that would
+// store the data most cheaply. Smaller chunks find more duplicates but every unique chunk costs an index entry, so
+// each setting is scored by its unique bytes plus its unique chunks times --entry-cost.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let roots = match crate::scan::roots(matches) {
+        Some(roots) => roots,
+        None => return crate::EXIT_FATAL,
+    };
+    let mut options = match crate::scan::pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+    let entry_cost = match matches.value_of("entry-cost").unwrap().parse::<u64>() {
+        Ok(cost) => cost,
+        Err(_) => {
+            eprintln!("ERROR: --entry-cost takes a number of bytes");
+            return crate::EXIT_FATAL;
+        }
+    };
+    let averages: Vec<usize> = match matches.values_of("averages") {
+        Some(averages) => match averages
+            .map(|average| crate::parse_memory_usage(average).map(|a| a as usize))
+            .collect()
+        {
+            Ok(averages) => averages,
+            Err(e) => {
+                eprintln!("ERROR: --averages: {}", e);
+                return crate::EXIT_FATAL;
+            }
+        },
+        None => AVERAGES.to_vec(),
+    };
+    if averages
+        .iter()
+        .any(|&average| average < 2 * MIN_DIVISORS[0])
+    {
+        eprintln!("ERROR: the averages are too small to have a minimum chunk size below them");
+        return crate::EXIT_FATAL;
+    }
+
+    // Every setting chunks the same files, so the sample is only picked once
+    if let Some(percent) = matches.value_of("sample") {
+        match percent.parse::<f64>() {
+            Ok(percent) if percent > 0.0 && percent <= 100.0 => {
+                options.only = Some(crate::estimate::sample(&roots, &options, percent))
+            }
+            _ => {
+                eprintln!("ERROR: --sample takes a percentage of the files above 0 and up to 100");
+                return crate::EXIT_FATAL;
+            }
+        }
+    }
+
+    let given = options.chunking;
+    let alignment = match given {
+        dedup_core::pipeline::Chunking::Variable(builder) => builder.alignment(),
+        dedup_core::pipeline::Chunking::Fixed => None,
+    };
+    let current = match given {
+        dedup_core::pipeline::Chunking::Variable(builder) => Candidate {
+            min: builder.min(),
+            average: None,
+            max: builder.max(),
+        },
+        dedup_core::pipeline::Chunking::Fixed => unreachable!(),
+    };
+    let mut candidates = vec![current];
+    candidates.extend(candidates_for(&averages));
+
+    let mut results = vec![];
+    let mut skipped = dedup_core::skipped::Skipped::default();
+    for (i, candidate) in candidates.iter().enumerate() {
+        options.chunking = match candidate.average {
+            Some(average) => {
+                // Every setting is aligned the same way as the one given
+                match rabin::chunker::ChunkerBuilder::new(candidate.min, candidate.max)
+                    .and_then(|builder| builder.average(average))
+                    .and_then(|builder| match alignment {
+                        None => Ok(builder),
+                        Some(alignment) => builder.with_alignment(alignment),
+                    }) {
+                    Ok(builder) => dedup_core::pipeline::Chunking::Variable(builder),
+                    Err(e) => {
+                        eprintln!("ERROR: {}", e);
+                        return crate::EXIT_FATAL;
+                    }
+                }
+            }
+            None => given,
+        };
+        tracing::info!(
+            min = candidate.min,
+            average = candidate.average,
+            max = candidate.max,
+            "trying"
+        );
+        let (measured, skipped_now) =
+            crate::bench::measure(dedup_core::pipeline::Input::Walk(roots.clone()), &options);
+        if i == 0 {
+            skipped = skipped_now;
+        }
+        results.push(measured);
+    }
+
+    println!(
+        "  {:>8} {:>8} {:>8} {:>10} {:>16} {:>9} {:>12} {:>16}",
+        "min", "average", "max", "avg chunk", "unique bytes", "unique", "entries", "cost"
+    );
+    let best = best(&results, entry_cost);
+    for (i, (candidate, m)) in candidates.iter().zip(results.iter()).enumerate() {
+        println!(
+            "{} {:>8} {:>8} {:>8} {:>10} {:>16} {:>8.4}% {:>12} {:>16}",
+            if Some(i) == best { '*' } else { ' ' },
+            candidate.min,
+            candidate
+                .average
+                .map_or("current".to_string(), |a| a.to_string()),
+            candidate.max,
+            m.bytes / m.chunks.max(1),
+            m.unique_bytes,
+            (m.unique_bytes * 100) as f64 / m.bytes.max(1) as f64,
+            m.unique_chunks,
+            cost(m, entry_cost)
+        );
+    }
+    match best.map(|i| candidates[i]) {
+        None => println!("nothing was chunked"),
+        Some(Candidate { average: None, .. }) => {
+            println!("The current chunk sizes are the best of these")
+        }
+        Some(Candidate {
+            min,
+            average: Some(average),
+            max,
+        }) => println!(
+            "Recommended: --min-chunk {} --avg-chunk {} --max-chunk {}",
+            min, average, max
+        ),
+    }
+    crate::skipped::print(&skipped);
+    crate::EXIT_SUCCESS
+}
+
+// Every combination of minimum and maximum for each average
+fn candidates_for(averages: &[usize]) -> Vec<Candidate> {
+    let mut candidates = vec![];
+    for &average in averages {
+        for &divisor in MIN_DIVISORS {
+            for &multiple in MAX_MULTIPLES {
+                candidates.push(Candidate {
+                    min: average / divisor,
+                    average: Some(average),
+                    max: average * multiple,
+                });
+            }
+        }
+    }
+    candidates
+}
+
+// What storing the data would cost in bytes: the unique chunks themselves and an index entry for each
+fn cost(measured: &crate::bench::Measured, entry_cost: u64) -> u64 {
+    measured.unique_bytes + measured.unique_chunks * entry_cost
+}
+
+// The setting that costs the least. The first is kept on a tie, so the current setting wins when nothing beats it.
+fn best(results: &[crate::bench::Measured], entry_cost: u64) -> Option<usize> {
+    if results.iter().all(|m| m.bytes == 0) {
+        return None;
+    }
+    (0..results.len()).min_by_key(|&i| cost(&results[i], entry_cost))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_best() {
+        let candidates = crate::tune::candidates_for(&[4096]);
+        assert_eq!(candidates.len(), 4);
+        assert_eq!(
+            candidates[0],
+            crate::tune::Candidate {
+                min: 1024,
+                average: Some(4096),
+                max: 8192
+            }
+        );
+
+        let measured = |unique_bytes, unique_chunks| crate::bench::Measured {
+            bytes: 1000,
+            unique_bytes,
+            unique_chunks,
+            ..Default::default()
+        };
+        // Small chunks find a few more duplicates, but need far more index entries to do it
+        let results = [measured(600, 10), measured(500, 40), measured(600, 10)];
+        assert_eq!(crate::tune::best(&results, 0), Some(1));
+        assert_eq!(crate::tune::best(&results, 64), Some(0));
+        assert_eq!(crate::tune::best(&[], 64), None);
+    }
+}
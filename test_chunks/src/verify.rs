@@ -0,0 +1,111 @@
+use std::io;
+use std::path;
+
+// Checks that everything the write-ahead log says was committed is really there: every run file (and the merged file,
+// if there is one) must exist, decode to the very end and be strictly sorted by key. Returns a non-zero exit status if
+// anything is wrong so that scripts can tell.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let (out_dir, _lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared) {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+
+    // Without the write-ahead log there's no telling which files should be there, so that alone fails verification
+    let committed = match dedup_core::wal::read_committed(out_dir) {
+        Ok(committed) => committed,
+        Err(e) => {
+            eprintln!("ERROR: can't read the write-ahead log: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    let mut files: Vec<path::PathBuf> = committed
+        .runs
+        .iter()
+        .map(|&run| dedup_core::wal::run_file_name(out_dir, run))
+        .collect();
+    let merged = out_dir.join(dedup_core::merge::MERGED_FILE_NAME);
+    if merged.exists() {
+        files.push(merged);
+    }
+
+    let mut problems = 0;
+    for file in files.iter() {
+        match verify_file(file) {
+            Ok(entries) => say!("{:?}: {} entries", file, entries),
+            Err(e) => {
+                eprintln!("ERROR: {:?}: {}", file, e);
+                problems += 1;
+            }
+        }
+    }
+
+    if problems > 0 {
+        say!("{} of {} files failed verification", problems, files.len());
+        return crate::EXIT_FATAL;
+    }
+    say!("{} files verified", files.len());
+    crate::EXIT_SUCCESS
+}
+
+// Reads every entry in the file, returning how many there were.
+fn verify_file(path: &path::Path) -> io::Result<u64> {
+    let mut reader = dedup_core::run::RunReader::open(path)?;
+    let mut previous: Option<dedup_core::run::Entry> = None;
+    let mut entries = 0;
+    while let Some(entry) = reader.next_entry()? {
+        if previous.is_some_and(|p| entry.key <= p.key) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("entry {} is out of order", entries),
+            ));
+        }
+        previous = Some(entry);
+        entries += 1;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    #[test]
+    fn test_verify_file() {
+        let dir = std::env::temp_dir().join(format!("test_chunks_verify_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let entry = |key: u8| dedup_core::run::Entry {
+            key: dedup_core::run::key_from(&[key; dedup_core::KEY_LEN], dedup_core::KEY_LEN),
+            ..dedup_core::run::Entry::default()
+        };
+        let write = |name: &str, entries: &[dedup_core::run::Entry]| {
+            let mut bytes = vec![];
+            let params = dedup_core::run::Params {
+                key_len: dedup_core::KEY_LEN,
+                ..Default::default()
+            };
+            dedup_core::run::write_header(&mut bytes, &params).unwrap();
+            for e in entries {
+                dedup_core::run::write_entry(&mut bytes, e, dedup_core::KEY_LEN).unwrap();
+            }
+            fs::write(dir.join(name), &bytes).unwrap();
+            bytes
+        };
+
+        write("sorted", &[entry(1), entry(2), entry(3)]);
+        assert_eq!(crate::verify::verify_file(&dir.join("sorted")).unwrap(), 3);
+
+        write("unsorted", &[entry(1), entry(3), entry(2)]);
+        assert!(crate::verify::verify_file(&dir.join("unsorted")).is_err());
+
+        let bytes = write("truncated", &[entry(1), entry(2)]);
+        fs::write(dir.join("truncated"), &bytes[..bytes.len() - 1]).unwrap();
+        assert!(crate::verify::verify_file(&dir.join("truncated")).is_err());
+
+        // Files without the header were written by an older version
+        fs::write(dir.join("headerless"), &bytes[7..]).unwrap();
+        assert!(crate::verify::verify_file(&dir.join("headerless")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections;
+use std::fs;
+use std::io;
+use std::path;
+use std::sync::mpsc;
+use std::time;
+
+use serde_derive::Serialize;
+
+// Rewritten in the output directory each time the statistics change
+pub const WATCH_STATUS_NAME: &str = "watch_status.json";
+
+// A file that never stops changing would otherwise hold back every other change forever
+const MAX_BATCH_WAIT: time::Duration = time::Duration::from_secs(60);
+
+// How many times a chunk is in the watched files, and how big it is
+#[derive(Debug, Clone, Copy)]
+struct IndexedChunk {
+    size: u32,
+    count: u32,
+}
+
+// The current statistics of the watched directories
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Status {
+    pub files: u64,
+    pub total_bytes: u64,
+    pub chunks: u64,
+    pub unique_bytes: u64,
+    pub unique_chunks: u64,
+    // When the statistics last changed, in seconds since the Unix epoch
+    pub updated: u64,
+}
+
+// Every watched file and the chunks it is made of. Unlike the runs a scan writes, files can be taken out again, so a
+// file that changes has its old chunks removed before its new ones are added. Chunks are only told apart by their id;
+// the size and SHA2 check that a scan uses to find collisions aren't kept.
+#[derive(Debug, Default)]
+pub struct Index {
+    chunks: collections::HashMap<dedup_core::run::Key, IndexedChunk>,
+    // Sorted, so everything in a directory is together
+    files: collections::BTreeMap<path::PathBuf, Vec<dedup_core::run::Key>>,
+    status: Status,
+}
+
+impl Index {
+    // Adds a file, replacing whatever the index had for it
+    pub fn insert(&mut self, path: path::PathBuf, chunks: Vec<(dedup_core::run::Key, u32)>) {
+        self.remove(&path);
+        self.status.files += 1;
+        let mut keys = Vec::with_capacity(chunks.len());
+        for (key, size) in chunks {
+            let chunk = self
+                .chunks
+                .entry(key)
+                .or_insert(IndexedChunk { size, count: 0 });
+            if chunk.count == 0 {
+                self.status.unique_chunks += 1;
+                self.status.unique_bytes += size as u64;
+            }
+            chunk.count += 1;
+            self.status.chunks += 1;
+            self.status.total_bytes += size as u64;
+            keys.push(key);
+        }
+        self.files.insert(path, keys);
+    }
+
+    pub fn remove(&mut self, path: &path::Path) {
+        let keys = match self.files.remove(path) {
+            Some(keys) => keys,
+            None => return,
+        };
+        self.status.files -= 1;
+        for key in keys {
+            let chunk = self.chunks.get_mut(&key).unwrap();
+            chunk.count -= 1;
+            self.status.chunks -= 1;
+            self.status.total_bytes -= chunk.size as u64;
+            if chunk.count == 0 {
+                self.status.unique_chunks -= 1;
+                self.status.unique_bytes -= chunk.size as u64;
+                self.chunks.remove(&key);
+            }
+        }
+    }
+
+    // Removes the file at the path, or everything under it if it was a directory
+    pub fn remove_all(&mut self, path: &path::Path) {
+        for file in self.files_under(path) {
+            self.remove(&file);
+        }
+    }
+
+    // The files in the index at or under the path
+    fn files_under(&self, path: &path::Path) -> Vec<path::PathBuf> {
+        self.files
+            .range(path.to_path_buf()..)
+            .map(|(file, _)| file)
+            .take_while(|file| file.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+}
+
+// Chunks the directories and then keeps the statistics up to date as files change, until it is killed. Changes are
+// gathered until the directories have been quiet for a moment, and then the files that changed are chunked again.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    let roots = match crate::scan::roots(matches) {
+        Some(roots) => roots,
+        None => return crate::EXIT_FATAL,
+    };
+    let options = match crate::scan::pipeline_options(matches) {
+        Some(options) => options,
+        None => return crate::EXIT_FATAL,
+    };
+    let settle = match matches
+        .value_of("settle")
+        .unwrap()
+        .parse::<f64>()
+        .map(time::Duration::try_from_secs_f64)
+    {
+        Ok(Ok(settle)) => settle,
+        _ => {
+            eprintln!("ERROR: --settle takes a number of seconds, 0 or more");
+            return crate::EXIT_FATAL;
+        }
+    };
+    let (out_dir, mut lock) = match crate::open_output(matches, dedup_core::lock::LockKind::Shared)
+    {
+        Some(output) => output,
+        None => return crate::EXIT_FATAL,
+    };
+
+    // Start watching before the first scan so nothing that changes during it is missed
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("ERROR: can't watch for changes: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    for root in roots.iter() {
+        if let Err(e) =
+            notify::Watcher::watch(&mut watcher, &root.dir, notify::RecursiveMode::Recursive)
+        {
+            eprintln!("ERROR: can't watch {:?}: {}", root.dir, e);
+            return crate::EXIT_FATAL;
+        }
+    }
+
+    let mut index = Index::default();
+    let skipped = match chunk(
+        dedup_core::pipeline::Input::Walk(roots.clone()),
+        &options,
+        &mut index,
+        &mut lock,
+    ) {
+        Ok(skipped) => skipped,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    };
+    crate::skipped::print(&skipped);
+    if let Err(e) = update_status(out_dir, &mut index) {
+        eprintln!("ERROR: can't write the status: {}", e);
+        return crate::EXIT_FATAL;
+    }
+
+    loop {
+        // The lock is kept fresh while nothing is changing too
+        if let Err(e) = lock.refresh() {
+            eprintln!("ERROR: {}", e);
+            return crate::EXIT_FATAL;
+        }
+        let changed = match next_changes(&receiver, settle) {
+            Some(changed) => changed,
+            None => return crate::EXIT_SUCCESS,
+        };
+
+        // Events were lost, so the only way to be sure of what changed is to start over
+        let changed = match changed {
+            Changes::Rescan => {
+                tracing::warn!("too many changes to keep track of; scanning everything again");
+                index = Index::default();
+                if let Err(e) = chunk(
+                    dedup_core::pipeline::Input::Walk(roots.clone()),
+                    &options,
+                    &mut index,
+                    &mut lock,
+                ) {
+                    eprintln!("ERROR: {}", e);
+                    return crate::EXIT_FATAL;
+                }
+                if let Err(e) = update_status(out_dir, &mut index) {
+                    eprintln!("ERROR: can't write the status: {}", e);
+                    return crate::EXIT_FATAL;
+                }
+                continue;
+            }
+            // Reading files (the scan's own reads included) wakes the watcher up too
+            Changes::Paths(changed) if changed.is_empty() => continue,
+            Changes::Paths(changed) => changed,
+        };
+        tracing::info!(paths = changed.len(), "changed");
+
+        // Files that changed are chunked again, and files and directories that are gone are taken out. Directories
+        // that are already in the index only changed because something in them did, which has an event of its own;
+        // any other directory was just created or moved here.
+        let mut list = vec![];
+        let mut walker = dedup_core::walk::Walker::new(
+            options.symlinks,
+            options.detect_hard_links,
+            options.streams,
+        );
+        for path in changed.iter() {
+            let is_dir = match fs::symlink_metadata(path) {
+                Ok(metadata) => metadata.is_dir(),
+                Err(_) => {
+                    index.remove_all(path);
+                    continue;
+                }
+            };
+            if is_dir && !index.files_under(path).is_empty() {
+                continue;
+            }
+            index.remove(path);
+            let root = match roots.iter().find(|r| path.starts_with(&r.dir)) {
+                Some(root) => root,
+                None => continue,
+            };
+            if root
+                .exclude
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore()
+            {
+                continue;
+            }
+            if is_dir {
+                walker.walk(path, &root.exclude, &mut |path, _| {
+                    list.push(path.to_path_buf())
+                });
+            } else {
+                list.push(path.clone());
+            }
+        }
+        list.sort();
+        list.dedup();
+
+        let mut names = vec![];
+        for path in list.iter() {
+            names.extend_from_slice(path.as_os_str().as_encoded_bytes());
+            names.push(0);
+        }
+        let input = dedup_core::pipeline::Input::List {
+            name: "changes".to_string(),
+            list: Box::new(io::Cursor::new(names)),
+        };
+        if let Err(e) = chunk(input, &options, &mut index, &mut lock) {
+            eprintln!("ERROR: {}", e);
+            return crate::EXIT_FATAL;
+        }
+        if let Err(e) = update_status(out_dir, &mut index) {
+            eprintln!("ERROR: can't write the status: {}", e);
+            return crate::EXIT_FATAL;
+        }
+    }
+}
+
+// What changed in the watched directories
+enum Changes {
+    Paths(collections::BTreeSet<path::PathBuf>),
+    // The system lost track of some changes
+    Rescan,
+}
+
+// Waits for something to change, and then gathers changes until there have been none for 'settle'. Returns None once
+// the watcher has stopped, and no changes if nothing changed for as long as a lock can go without being refreshed.
+fn next_changes(
+    receiver: &mpsc::Receiver<notify::Result<notify::Event>>,
+    settle: time::Duration,
+) -> Option<Changes> {
+    let mut changed = collections::BTreeSet::new();
+    let mut event = match receiver.recv_timeout(dedup_core::lock::REFRESH_EVERY) {
+        Ok(event) => event,
+        Err(mpsc::RecvTimeoutError::Timeout) => return Some(Changes::Paths(changed)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+    };
+    let started = time::Instant::now();
+    loop {
+        match event {
+            Ok(event) if event.need_rescan() => return Some(Changes::Rescan),
+            Ok(event) => {
+                // Reading a file doesn't change it, but finishing writing it does
+                let read = match event.kind {
+                    notify::EventKind::Access(notify::event::AccessKind::Close(
+                        notify::event::AccessMode::Write,
+                    )) => false,
+                    kind => kind.is_access(),
+                };
+                if !read {
+                    changed.extend(event.paths);
+                }
+            }
+            Err(e) => tracing::warn!("{}", e),
+        }
+
+        if started.elapsed() > MAX_BATCH_WAIT {
+            break;
+        }
+        event = match receiver.recv_timeout(settle) {
+            Ok(event) => event,
+            Err(mpsc::RecvTimeoutError::Timeout) => break,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+        };
+    }
+    Some(Changes::Paths(changed))
+}
+
+// Chunks the files and puts them in the index, returning what couldn't be read. The lock is refreshed as the files are
+// chunked, and chunking fails if it can't be.
+fn chunk(
+    input: dedup_core::pipeline::Input,
+    options: &dedup_core::pipeline::Options,
+    index: &mut Index,
+    lock: &mut dedup_core::lock::Lock,
+) -> io::Result<dedup_core::skipped::Skipped> {
+    let mut files: collections::HashMap<u32, (path::PathBuf, Vec<(dedup_core::run::Key, u32)>)> =
+        collections::HashMap::new();
+    let mut refreshed = Ok(());
+    let scanned = dedup_core::pipeline::run(input, options, &mut |event| {
+        if refreshed.is_ok() {
+            refreshed = lock.refresh();
+        }
+        match event {
+            dedup_core::pipeline::Event::Found { file, path, .. } => {
+                files.insert(file, (path::PathBuf::from(path), vec![]));
+            }
+            dedup_core::pipeline::Event::Hashed(batch) => {
+                let chunks = &mut files.get_mut(&batch.file).unwrap().1;
+                chunks.extend(batch.chunks.iter().map(|c| (c.key, c.size)));
+            }
+            dedup_core::pipeline::Event::Finished { .. } => {}
+        }
+    });
+    refreshed?;
+    for (_, (path, chunks)) in files {
+        index.insert(path, chunks);
+    }
+    Ok(scanned.skipped)
+}
+
+// Prints the statistics and rewrites the status file. The file is replaced in one step, so anything reading it never
+// sees half of it.
+fn update_status(out_dir: &path::Path, index: &mut Index) -> io::Result<()> {
+    index.status.updated = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let status = index.status();
+    say!(
+        "{} files, {} total bytes, {} bytes {:0.4}% unique",
+        status.files,
+        status.total_bytes,
+        status.unique_bytes,
+        (status.unique_bytes * 100) as f64 / status.total_bytes.max(1) as f64
+    );
+
+    let name = out_dir.join(WATCH_STATUS_NAME);
+    let partial = name.with_extension("partial");
+    fs::write(
+        &partial,
+        serde_json::to_vec(status).map_err(io::Error::other)?,
+    )?;
+    fs::rename(&partial, &name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    #[test]
+    fn test_index() {
+        let key = |k: u8| dedup_core::run::key_from(&[k; dedup_core::KEY_LEN], dedup_core::KEY_LEN);
+        let mut index = crate::watch::Index::default();
+        index.insert("/w/a".into(), vec![(key(1), 100), (key(2), 200)]);
+        index.insert("/w/sub/b".into(), vec![(key(2), 200), (key(3), 300)]);
+        index.insert("/w/sub/empty".into(), vec![]);
+        let status = index.status().clone();
+        assert_eq!(status.files, 3);
+        assert_eq!((status.chunks, status.total_bytes), (4, 800));
+        assert_eq!((status.unique_chunks, status.unique_bytes), (3, 600));
+
+        // Changing a file replaces its chunks
+        index.insert("/w/a".into(), vec![(key(3), 300)]);
+        let status = index.status().clone();
+        assert_eq!(status.files, 3);
+        assert_eq!((status.chunks, status.total_bytes), (3, 800));
+        assert_eq!((status.unique_chunks, status.unique_bytes), (2, 500));
+
+        // Removing a directory removes everything in it, and nothing else
+        index.insert("/w/sub2".into(), vec![]);
+        index.remove_all(path::Path::new("/w/sub"));
+        assert_eq!(index.status().files, 2);
+        index.remove(path::Path::new("/w/sub2"));
+        let status = index.status().clone();
+        assert_eq!(status.files, 1);
+        assert_eq!((status.chunks, status.total_bytes), (1, 300));
+        assert_eq!((status.unique_chunks, status.unique_bytes), (1, 300));
+
+        index.remove(path::Path::new("/w/a"));
+        assert_eq!(index.status(), &crate::watch::Status::default());
+    }
+}